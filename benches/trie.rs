@@ -1,9 +1,11 @@
-use criterion::{criterion_group, criterion_main, Criterion};
+use std::collections::HashSet;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
 
 use uuid::Uuid;
 
 use cita_trie::MemoryDB;
-use cita_trie::{PatriciaTrie, Trie};
+use cita_trie::{PatriciaTrie, PatriciaTrieBuilder, Trie};
 
 fn insert_worse_case_benchmark(c: &mut Criterion) {
     c.bench_function("insert one", |b| {
@@ -52,6 +54,191 @@ fn insert_worse_case_benchmark(c: &mut Criterion) {
         });
     });
 
+    c.bench_function("get 1k of 10k", |b| {
+        let mut trie = PatriciaTrie::new(MemoryDB::new(false));
+
+        let (keys, values) = random_data(10000);
+        for i in 0..keys.len() {
+            trie.insert(keys[i].clone(), values[i].clone()).unwrap()
+        }
+        trie.root().unwrap();
+
+        b.iter(|| {
+            for key in keys.iter().take(1000) {
+                trie.get(key).unwrap();
+            }
+        });
+    });
+
+    // Every batch reads from a freshly opened trie, so the node cache starts empty. It
+    // is large enough for every node on the paths.
+    for prefetch in [false, true] {
+        let name = if prefetch {
+            "get 1k of 10k prefetched"
+        } else {
+            "get 1k of 10k cold"
+        };
+        c.bench_function(name, |b| {
+            let db = MemoryDB::new(false);
+            let mut trie = PatriciaTrie::new(db.clone());
+
+            let (keys, values) = random_data(10000);
+            for i in 0..keys.len() {
+                trie.insert(keys[i].clone(), values[i].clone()).unwrap()
+            }
+            let root = trie.root().unwrap();
+
+            let queries = keys
+                .iter()
+                .take(1000)
+                .map(|k| k.as_slice())
+                .collect::<Vec<_>>();
+            b.iter_batched(
+                || {
+                    let trie = PatriciaTrieBuilder::new()
+                        .db(db.clone())
+                        .root(&root)
+                        .cache_capacity(4096)
+                        .build_from_root()
+                        .unwrap();
+                    if prefetch {
+                        trie.prefetch(&queries).unwrap();
+                    }
+                    trie
+                },
+                |trie| {
+                    for key in queries.iter() {
+                        trie.get(key).unwrap();
+                    }
+                    trie
+                },
+                BatchSize::SmallInput,
+            );
+        });
+    }
+
+    c.bench_function("get 4KiB values", |b| {
+        let mut trie = PatriciaTrie::new(MemoryDB::new(false));
+
+        let (keys, _) = random_data(1000);
+        for key in keys.iter() {
+            trie.insert(key.clone(), vec![1; 4096]).unwrap()
+        }
+        trie.root().unwrap();
+
+        b.iter(|| {
+            for key in keys.iter() {
+                assert_eq!(trie.get(key).unwrap().unwrap().len(), 4096);
+            }
+        });
+    });
+
+    c.bench_function("with_value 4KiB values", |b| {
+        let mut trie = PatriciaTrie::new(MemoryDB::new(false));
+
+        let (keys, _) = random_data(1000);
+        for key in keys.iter() {
+            trie.insert(key.clone(), vec![1; 4096]).unwrap()
+        }
+        trie.root().unwrap();
+
+        b.iter(|| {
+            for key in keys.iter() {
+                let len = trie.with_value(key, |value| value.unwrap().len()).unwrap();
+                assert_eq!(len, 4096);
+            }
+        });
+    });
+
+    c.bench_function("iter map 10k", |b| {
+        let mut trie = PatriciaTrie::new(MemoryDB::new(false));
+
+        let (keys, values) = random_data(10000);
+        for i in 0..keys.len() {
+            trie.insert(keys[i].clone(), values[i].clone()).unwrap()
+        }
+        trie.root().unwrap();
+
+        b.iter(|| {
+            trie.iter()
+                .map(|(key, value)| key[0] as usize + value.len())
+                .sum::<usize>()
+        });
+    });
+
+    c.bench_function("iter_map 10k", |b| {
+        let mut trie = PatriciaTrie::new(MemoryDB::new(false));
+
+        let (keys, values) = random_data(10000);
+        for i in 0..keys.len() {
+            trie.insert(keys[i].clone(), values[i].clone()).unwrap()
+        }
+        trie.root().unwrap();
+
+        b.iter(|| {
+            trie.iter_map(|key, value| key[0] as usize + value.len())
+                .sum::<usize>()
+        });
+    });
+
+    c.bench_function("get_many 1k of 10k", |b| {
+        let mut trie = PatriciaTrie::new(MemoryDB::new(false));
+
+        let (keys, values) = random_data(10000);
+        for i in 0..keys.len() {
+            trie.insert(keys[i].clone(), values[i].clone()).unwrap()
+        }
+        trie.root().unwrap();
+
+        let queries = keys
+            .iter()
+            .take(1000)
+            .map(|k| k.as_slice())
+            .collect::<Vec<_>>();
+        b.iter(|| {
+            trie.get_many(&queries).unwrap();
+        });
+    });
+
+    // The compact multiproof against the union of the single proofs, the sizes are
+    // printed once.
+    {
+        let mut trie = PatriciaTrie::new(MemoryDB::new(false));
+        let (keys, values) = random_data(10000);
+        for i in 0..keys.len() {
+            trie.insert(keys[i].clone(), values[i].clone()).unwrap()
+        }
+        trie.root().unwrap();
+
+        let queries = keys
+            .iter()
+            .take(200)
+            .map(|k| k.as_slice())
+            .collect::<Vec<_>>();
+        let union = |trie: &PatriciaTrie<MemoryDB>| {
+            queries
+                .iter()
+                .flat_map(|key| trie.get_proof(key).unwrap())
+                .collect::<HashSet<_>>()
+        };
+        let compact_size = trie
+            .get_compact_multiproof(&queries)
+            .unwrap()
+            .as_bytes()
+            .len();
+        let union_size = union(&trie).iter().map(Vec::len).sum::<usize>();
+        println!(
+            "200 of 10k: compact multiproof {compact_size} bytes, proof union {union_size} bytes"
+        );
+
+        c.bench_function("compact multiproof 200 of 10k", |b| {
+            b.iter(|| trie.get_compact_multiproof(&queries).unwrap());
+        });
+        c.bench_function("proof union 200 of 10k", |b| {
+            b.iter(|| union(&trie));
+        });
+    }
+
     c.bench_function("remove 1k", |b| {
         let mut trie = PatriciaTrie::new(MemoryDB::new(false));
 
@@ -67,6 +254,20 @@ fn insert_worse_case_benchmark(c: &mut Criterion) {
         });
     });
 
+    c.bench_function("remove_batch 1k", |b| {
+        let mut trie = PatriciaTrie::new(MemoryDB::new(false));
+
+        let (keys, values) = random_data(1000);
+        for i in 0..keys.len() {
+            trie.insert(keys[i].clone(), values[i].clone()).unwrap()
+        }
+
+        let keys = keys.iter().map(|key| key.as_slice()).collect::<Vec<_>>();
+        b.iter(|| {
+            trie.remove_batch(&keys).unwrap();
+        });
+    });
+
     c.bench_function("remove 10k", |b| {
         let mut trie = PatriciaTrie::new(MemoryDB::new(false));
 
@@ -81,6 +282,27 @@ fn insert_worse_case_benchmark(c: &mut Criterion) {
             }
         });
     });
+
+    // A block of 200 transactions, each followed by `root`.
+    for defer in [false, true] {
+        let name = if defer {
+            "block replay deferred"
+        } else {
+            "block replay"
+        };
+        c.bench_function(name, |b| {
+            let (keys, values) = random_data(200);
+            b.iter(|| {
+                let mut trie = PatriciaTrie::new(MemoryDB::new(false));
+                trie.set_defer_commit(defer);
+                for i in 0..keys.len() {
+                    trie.insert(keys[i].clone(), values[i].clone()).unwrap();
+                    trie.root().unwrap();
+                }
+                trie.flush_commit().unwrap()
+            });
+        });
+    }
 }
 
 fn random_data(n: usize) -> (Vec<Vec<u8>>, Vec<Vec<u8>>) {
@@ -0,0 +1,108 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+/// A map holding at most `capacity` entries, evicting the least recently used one
+/// when a new entry doesn't fit.
+#[derive(Debug)]
+pub(crate) struct LruCache<K, V> {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<K, (V, u64)>,
+    // Access tick of each entry, the smallest tick is the least recently used entry.
+    order: BTreeMap<u64, K>,
+}
+
+impl<K, V> LruCache<K, V>
+where
+    K: Hash + Eq + Clone,
+{
+    pub fn new(capacity: usize) -> Self {
+        LruCache {
+            capacity,
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    #[cfg(test)]
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.entries.values().map(|(value, _)| value)
+    }
+
+    /// Returns the value for key and marks it as the most recently used.
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        let tick = self.next_tick();
+        let (value, last_used) = self.entries.get_mut(key)?;
+        self.order.remove(last_used);
+        self.order.insert(tick, key.clone());
+        *last_used = tick;
+        Some(value)
+    }
+
+    /// Inserts value for key, evicting the least recently used entries if the cache is full.
+    /// Evicted values are dropped.
+    pub fn insert(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let tick = self.next_tick();
+        if let Some((_, last_used)) = self.entries.insert(key.clone(), (value, tick)) {
+            self.order.remove(&last_used);
+        }
+        self.order.insert(tick, key);
+
+        while self.entries.len() > self.capacity {
+            let (_, lru_key) = self.order.pop_first().unwrap();
+            self.entries.remove(&lru_key);
+        }
+    }
+
+    /// Drops all entries.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LruCache;
+
+    #[test]
+    fn test_lru_evicts_least_recently_used() {
+        let mut cache = LruCache::new(2);
+        cache.insert(1, "a");
+        cache.insert(2, "b");
+        assert_eq!(cache.get(&1), Some(&"a"));
+
+        cache.insert(3, "c");
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(cache.get(&1), Some(&"a"));
+        assert_eq!(cache.get(&3), Some(&"c"));
+    }
+
+    #[test]
+    fn test_lru_zero_capacity() {
+        let mut cache = LruCache::new(0);
+        cache.insert(1, "a");
+        assert_eq!(cache.len(), 0);
+        assert_eq!(cache.get(&1), None);
+    }
+}
@@ -0,0 +1,344 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+
+use rlp::{Prototype, Rlp, RlpStream};
+
+use crate::errors::{TrieError, TrieResult};
+use crate::nibbles::{NibbleSlice, NibbleVec};
+
+const HASH_SIZE: usize = 32;
+
+/// The default of `PatriciaTrie::set_max_decode_depth`. Nodes of valid tries are
+/// embedded at most once per key nibble, far below it.
+pub const DEFAULT_MAX_DECODE_DEPTH: usize = 64;
+
+/// A node as seen by a codec.
+///
+/// Keys and prefixes are in hex form, one nibble per byte, and a leaf key is terminated
+/// by `16`. Children are references produced by the trie: either a 32 byte hash of
+/// the child or the encoding of a child embedded in its parent. References are decoded
+/// again with [`NodeCodec::decode`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodedNode {
+    Empty,
+    Leaf(Vec<u8>, Vec<u8>),
+    Extension(Vec<u8>, Vec<u8>),
+    Branch(Box<[Vec<u8>; 16]>, Option<Vec<u8>>),
+    Hash([u8; 32]),
+}
+
+/// "NodeCodec" defines how trie nodes are serialized.
+pub trait NodeCodec {
+    /// Nodes with an encoding shorter than this are embedded in their parent instead
+    /// of being referenced by hash. It must not exceed the 32 byte hash length, so that
+    /// an embedded child can be told apart from a hash.
+    const INLINE_THRESHOLD: usize = HASH_SIZE;
+
+    /// Returns the encoding of an empty node.
+    fn encode_empty() -> Vec<u8>;
+
+    fn encode_leaf(key: &[u8], value: &[u8]) -> Vec<u8>;
+
+    /// `child` is either a hash or an embedded encoding, see [`DecodedNode`].
+    fn encode_extension(prefix: &[u8], child: &[u8]) -> Vec<u8>;
+
+    /// `children` are the 16 child references, see [`DecodedNode`].
+    fn encode_branch(children: &[Vec<u8>; 16], value: Option<&[u8]>) -> Vec<u8>;
+
+    /// Decodes a node or a child reference.
+    fn decode(data: &[u8]) -> TrieResult<DecodedNode>;
+}
+
+/// The Ethereum node encoding: RLP with compact encoded keys.
+#[derive(Default, Debug, Clone, Copy)]
+pub struct RlpCodec;
+
+impl RlpCodec {
+    fn append_child(stream: &mut RlpStream, child: &[u8]) {
+        if child.len() == HASH_SIZE {
+            stream.append(&child);
+        } else {
+            stream.append_raw(child, 1);
+        }
+    }
+}
+
+impl NodeCodec for RlpCodec {
+    fn encode_empty() -> Vec<u8> {
+        rlp::NULL_RLP.to_vec()
+    }
+
+    fn encode_leaf(key: &[u8], value: &[u8]) -> Vec<u8> {
+        let mut stream = RlpStream::new_list(2);
+        stream.append(&NibbleSlice::from_hex(key).encode_compact());
+        stream.append(&value);
+        stream.out().to_vec()
+    }
+
+    fn encode_extension(prefix: &[u8], child: &[u8]) -> Vec<u8> {
+        let mut stream = RlpStream::new_list(2);
+        stream.append(&NibbleSlice::from_hex(prefix).encode_compact());
+        Self::append_child(&mut stream, child);
+        stream.out().to_vec()
+    }
+
+    fn encode_branch(children: &[Vec<u8>; 16], value: Option<&[u8]>) -> Vec<u8> {
+        let mut stream = RlpStream::new_list(17);
+        for child in children.iter() {
+            Self::append_child(&mut stream, child);
+        }
+
+        match value {
+            Some(v) => stream.append(&v),
+            None => stream.append_empty_data(),
+        };
+        stream.out().to_vec()
+    }
+
+    fn decode(data: &[u8]) -> TrieResult<DecodedNode> {
+        let r = Rlp::new(data);
+
+        match r.prototype()? {
+            Prototype::Data(0) => Ok(DecodedNode::Empty),
+            Prototype::List(2) => {
+                let key = r.at(0)?.data()?;
+                // Checked here, `from_compact` panics on invalid keys.
+                if key.first().is_none_or(|flag| flag & 0b1100_0000 != 0) {
+                    return Err(TrieError::InvalidData);
+                }
+                let key = NibbleVec::from_compact(key.to_vec());
+
+                if key.is_leaf() {
+                    Ok(DecodedNode::Leaf(key.into_hex(), r.at(1)?.data()?.to_vec()))
+                } else {
+                    Ok(DecodedNode::Extension(
+                        key.into_hex(),
+                        r.at(1)?.as_raw().to_vec(),
+                    ))
+                }
+            }
+            Prototype::List(17) => {
+                let mut children: [Vec<u8>; 16] = Default::default();
+                for (i, child) in children.iter_mut().enumerate() {
+                    *child = r.at(i)?.as_raw().to_vec();
+                }
+
+                // The last element is a value node.
+                let value_rlp = r.at(16)?;
+                let value = if value_rlp.is_empty() {
+                    None
+                } else {
+                    Some(value_rlp.data()?.to_vec())
+                };
+
+                Ok(DecodedNode::Branch(Box::new(children), value))
+            }
+            _ => {
+                if r.is_data() && r.size() == HASH_SIZE {
+                    Ok(DecodedNode::Hash(r.data()?.try_into().unwrap()))
+                } else {
+                    Err(TrieError::InvalidData)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+
+    use super::{DecodedNode, NodeCodec, RlpCodec};
+    use crate::db::{MemoryDB, DB};
+    use crate::errors::TrieError;
+    use crate::hasher::{Hasher, HasherKeccak};
+    use crate::trie::{PatriciaTrie, Trie, TrieResult};
+
+    /// Tags every node with its type, children are length prefixed.
+    struct TaggedCodec;
+
+    impl TaggedCodec {
+        fn append_child(out: &mut Vec<u8>, child: &[u8]) {
+            if child.len() == 32 {
+                out.push(33);
+                out.push(4);
+            } else {
+                out.push(child.len() as u8);
+            }
+            out.extend_from_slice(child);
+        }
+
+        fn read_child(data: &[u8]) -> TrieResult<(&[u8], &[u8])> {
+            let len = *data.first().ok_or(TrieError::InvalidData)? as usize;
+            if data.len() < len + 1 {
+                return Err(TrieError::InvalidData);
+            }
+            Ok((&data[1..len + 1], &data[len + 1..]))
+        }
+    }
+
+    impl NodeCodec for TaggedCodec {
+        fn encode_empty() -> Vec<u8> {
+            vec![0]
+        }
+
+        fn encode_leaf(key: &[u8], value: &[u8]) -> Vec<u8> {
+            let mut out = vec![1, key.len() as u8];
+            out.extend_from_slice(key);
+            out.extend_from_slice(value);
+            out
+        }
+
+        fn encode_extension(prefix: &[u8], child: &[u8]) -> Vec<u8> {
+            let mut out = vec![2, prefix.len() as u8];
+            out.extend_from_slice(prefix);
+            Self::append_child(&mut out, child);
+            out
+        }
+
+        fn encode_branch(children: &[Vec<u8>; 16], value: Option<&[u8]>) -> Vec<u8> {
+            let mut out = vec![3];
+            for child in children.iter() {
+                Self::append_child(&mut out, child);
+            }
+            if let Some(value) = value {
+                out.extend_from_slice(value);
+            }
+            out
+        }
+
+        fn decode(data: &[u8]) -> TrieResult<DecodedNode> {
+            match data.split_first() {
+                Some((0, _)) => Ok(DecodedNode::Empty),
+                Some((1, rest)) | Some((2, rest)) => {
+                    let (key, rest) = Self::read_child(rest)?;
+                    if data[0] == 1 {
+                        Ok(DecodedNode::Leaf(key.to_vec(), rest.to_vec()))
+                    } else {
+                        let (child, _) = Self::read_child(rest)?;
+                        Ok(DecodedNode::Extension(key.to_vec(), child.to_vec()))
+                    }
+                }
+                Some((3, mut rest)) => {
+                    let mut children: [Vec<u8>; 16] = Default::default();
+                    for child in children.iter_mut() {
+                        let (c, r) = Self::read_child(rest)?;
+                        *child = c.to_vec();
+                        rest = r;
+                    }
+                    let value = if rest.is_empty() {
+                        None
+                    } else {
+                        Some(rest.to_vec())
+                    };
+                    Ok(DecodedNode::Branch(Box::new(children), value))
+                }
+                Some((4, hash)) => Ok(DecodedNode::Hash(
+                    hash.try_into().map_err(|_| TrieError::InvalidData)?,
+                )),
+                _ => Err(TrieError::InvalidData),
+            }
+        }
+    }
+
+    #[test]
+    fn test_rlp_decode_invalid_key() {
+        let mut stream = rlp::RlpStream::new_list(2);
+        stream.append(&vec![0x40u8, 0x12]).append(&vec![1u8]);
+        assert_eq!(RlpCodec::decode(&stream.out()), Err(TrieError::InvalidData));
+
+        let mut stream = rlp::RlpStream::new_list(2);
+        stream.append_empty_data().append(&vec![1u8]);
+        assert_eq!(RlpCodec::decode(&stream.out()), Err(TrieError::InvalidData));
+    }
+
+    #[test]
+    fn test_custom_codec() {
+        let memdb = MemoryDB::new(true);
+        let kv = [
+            (b"test".to_vec(), b"test".to_vec()),
+            (b"test1".to_vec(), b"test1".to_vec()),
+            (
+                b"test23".to_vec(),
+                b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa".to_vec(),
+            ),
+            (b"other".to_vec(), b"other".to_vec()),
+        ];
+
+        let mut trie = PatriciaTrie::<_, HasherKeccak, TaggedCodec>::create(memdb.clone());
+        let mut rlp_trie = PatriciaTrie::<_, HasherKeccak, RlpCodec>::create(MemoryDB::new(true));
+        for (k, v) in kv.iter() {
+            trie.insert(k.clone(), v.clone()).unwrap();
+            rlp_trie.insert(k.clone(), v.clone()).unwrap();
+        }
+        let root = trie.root().unwrap();
+        assert_ne!(root, rlp_trie.root().unwrap());
+
+        let trie = PatriciaTrie::<_, HasherKeccak, TaggedCodec>::open(memdb, &root).unwrap();
+        for (k, v) in kv.iter() {
+            assert_eq!(trie.get(k).unwrap(), Some(v.clone()));
+        }
+        assert_eq!(trie.iter().count(), kv.len());
+
+        let proof = trie.get_proof(b"test23").unwrap();
+        let value = trie.verify_proof(&root, b"test23", proof).unwrap();
+        assert_eq!(value, Some(kv[2].1.clone()));
+    }
+
+    /// The RLP encoding, with nodes of 16 bytes or more referenced by hash.
+    struct ShortInlineCodec;
+
+    impl NodeCodec for ShortInlineCodec {
+        const INLINE_THRESHOLD: usize = 16;
+
+        fn encode_empty() -> Vec<u8> {
+            RlpCodec::encode_empty()
+        }
+
+        fn encode_leaf(key: &[u8], value: &[u8]) -> Vec<u8> {
+            RlpCodec::encode_leaf(key, value)
+        }
+
+        fn encode_extension(prefix: &[u8], child: &[u8]) -> Vec<u8> {
+            RlpCodec::encode_extension(prefix, child)
+        }
+
+        fn encode_branch(children: &[Vec<u8>; 16], value: Option<&[u8]>) -> Vec<u8> {
+            RlpCodec::encode_branch(children, value)
+        }
+
+        fn decode(data: &[u8]) -> TrieResult<DecodedNode> {
+            RlpCodec::decode(data)
+        }
+    }
+
+    #[test]
+    fn test_inline_threshold() {
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::<_, HasherKeccak, ShortInlineCodec>::create(memdb.clone());
+        let mut rlp_trie = PatriciaTrie::<_, HasherKeccak, RlpCodec>::create(MemoryDB::new(true));
+        for i in 0..64u8 {
+            // Leaves of about 20 bytes: hashed here, embedded with the default threshold.
+            trie.insert(vec![i, i / 16], vec![i; 16]).unwrap();
+            rlp_trie.insert(vec![i, i / 16], vec![i; 16]).unwrap();
+        }
+        let root = trie.root().unwrap();
+        assert_ne!(root, rlp_trie.root().unwrap());
+
+        let leaf = trie.get_proof(&[7, 0]).unwrap().pop().unwrap();
+        assert!(leaf.len() >= 16 && leaf.len() < 32);
+        assert!(memdb.contains(&HasherKeccak::digest(&leaf)).unwrap());
+
+        let trie = PatriciaTrie::<_, HasherKeccak, ShortInlineCodec>::open(memdb, &root).unwrap();
+        for i in 0..64u8 {
+            assert_eq!(trie.get(&[i, i / 16]).unwrap(), Some(vec![i; 16]));
+        }
+        assert_eq!(trie.iter().count(), 64);
+        trie.verify_integrity().unwrap();
+
+        let proof = trie.get_proof(&[42, 2]).unwrap();
+        let value = trie.verify_proof(&root, &[42, 2], proof).unwrap();
+        assert_eq!(value, Some(vec![42; 16]));
+    }
+}
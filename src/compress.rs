@@ -0,0 +1,206 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use crate::db::DB;
+
+/// "CompressionCodec" defines how values are compressed before they reach the database.
+pub trait CompressionCodec: Send + Sync {
+    fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>>;
+
+    fn decompress(&self, data: &[u8]) -> io::Result<Vec<u8>>;
+}
+
+/// Zstandard compression at the given level.
+#[cfg(feature = "zstd")]
+#[derive(Debug, Clone, Copy)]
+pub struct ZstdCodec {
+    pub level: i32,
+}
+
+#[cfg(feature = "zstd")]
+impl Default for ZstdCodec {
+    fn default() -> Self {
+        ZstdCodec {
+            level: zstd::DEFAULT_COMPRESSION_LEVEL,
+        }
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl CompressionCodec for ZstdCodec {
+    fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        zstd::encode_all(data, self.level)
+    }
+
+    fn decompress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        zstd::decode_all(data)
+    }
+}
+
+/// A database wrapper which transparently compresses the stored values with `C`.
+/// Keys are stored as is.
+#[derive(Default, Debug, Clone)]
+pub struct CompressingDB<D, C> {
+    db: D,
+    codec: C,
+}
+
+impl<D, C> CompressingDB<D, C> {
+    pub fn new(db: D, codec: C) -> Self {
+        CompressingDB { db, codec }
+    }
+
+    /// Returns the wrapped database, which holds the compressed values.
+    pub fn inner(&self) -> &D {
+        &self.db
+    }
+}
+
+#[derive(Debug)]
+pub enum CompressingDBError<E> {
+    DB(E),
+    Codec(io::Error),
+}
+
+impl<E: Error> Error for CompressingDBError<E> {}
+
+impl<E: fmt::Display> fmt::Display for CompressingDBError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CompressingDBError::DB(ref err) => write!(f, "compressing db error: {}", err),
+            CompressingDBError::Codec(ref err) => write!(f, "compressing db codec error: {}", err),
+        }
+    }
+}
+
+impl<D, C> DB for CompressingDB<D, C>
+where
+    D: DB,
+    C: CompressionCodec,
+{
+    type Error = CompressingDBError<D::Error>;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        match self.db.get(key).map_err(CompressingDBError::DB)? {
+            Some(value) => Ok(Some(
+                self.codec
+                    .decompress(&value)
+                    .map_err(CompressingDBError::Codec)?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    fn contains(&self, key: &[u8]) -> Result<bool, Self::Error> {
+        self.db.contains(key).map_err(CompressingDBError::DB)
+    }
+
+    fn insert(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        let value = self
+            .codec
+            .compress(&value)
+            .map_err(CompressingDBError::Codec)?;
+        self.db.insert(key, value).map_err(CompressingDBError::DB)
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), Self::Error> {
+        self.db.remove(key).map_err(CompressingDBError::DB)
+    }
+
+    fn insert_batch<I>(&self, items: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    {
+        let items = items
+            .into_iter()
+            .map(|(key, value)| Ok((key, self.codec.compress(&value)?)))
+            .collect::<io::Result<Vec<_>>>()
+            .map_err(CompressingDBError::Codec)?;
+        self.db.insert_batch(items).map_err(CompressingDBError::DB)
+    }
+
+    fn remove_batch<I: IntoIterator<Item = A>, A: AsRef<[u8]>>(
+        &self,
+        keys: I,
+    ) -> Result<(), Self::Error> {
+        self.db.remove_batch(keys).map_err(CompressingDBError::DB)
+    }
+
+    fn flush(&self) -> Result<(), Self::Error> {
+        self.db.flush().map_err(CompressingDBError::DB)
+    }
+
+    fn keys(&self) -> Result<Option<Vec<Vec<u8>>>, Self::Error> {
+        self.db.keys().map_err(CompressingDBError::DB)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::{CompressingDB, CompressionCodec};
+    use crate::db::{MemoryDB, DB};
+    use crate::hasher::{Hasher, HasherKeccak};
+    use crate::trie::{PatriciaTrie, Trie};
+
+    /// Run-length encoding as (count, byte) pairs.
+    #[derive(Clone)]
+    struct RleCodec;
+
+    impl CompressionCodec for RleCodec {
+        fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+            let mut out = vec![];
+            for chunk in data.chunk_by(|a, b| a == b) {
+                for part in chunk.chunks(255) {
+                    out.push(part.len() as u8);
+                    out.push(part[0]);
+                }
+            }
+            Ok(out)
+        }
+
+        fn decompress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+            if !data.len().is_multiple_of(2) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "odd length"));
+            }
+            Ok(data
+                .chunks(2)
+                .flat_map(|pair| std::iter::repeat_n(pair[1], pair[0] as usize))
+                .collect())
+        }
+    }
+
+    fn assert_round_trip<C: CompressionCodec + Clone>(codec: C) {
+        let memdb = MemoryDB::new(true);
+        let db = CompressingDB::new(memdb.clone(), codec);
+        let mut trie = PatriciaTrie::new(db.clone());
+        for i in 0..50u8 {
+            trie.insert(vec![i, i + 1], vec![i; 1000]).unwrap();
+        }
+        let root = trie.root().unwrap();
+
+        let leaf = trie.get_proof(&[0, 1]).unwrap().pop().unwrap();
+        let leaf_hash = HasherKeccak::digest(&leaf);
+        let stored = memdb.get(&leaf_hash).unwrap().unwrap();
+        assert_eq!(db.get(&leaf_hash).unwrap(), Some(leaf.clone()));
+        assert!(stored.len() < leaf.len());
+
+        let trie = PatriciaTrie::from(db, &root).unwrap();
+        for i in 0..50u8 {
+            assert_eq!(trie.get(&[i, i + 1]).unwrap(), Some(vec![i; 1000]));
+        }
+    }
+
+    #[test]
+    fn test_compressing_db_round_trip() {
+        assert_round_trip(RleCodec);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_compressing_db_zstd() {
+        assert_round_trip(super::ZstdCodec::default());
+    }
+}
@@ -4,7 +4,7 @@ use std::sync::Arc;
 
 use parking_lot::RwLock;
 
-use crate::errors::MemDBError;
+use crate::errors::{MemDBError, NullDBError};
 
 /// "DB" defines the "trait" of trie and database interaction.
 /// You should first write the data to the cache and write the data
@@ -47,6 +47,11 @@ pub trait DB: Send + Sync {
 
     /// Flushes data to the DB from the cache.
     fn flush(&self) -> Result<(), Self::Error>;
+
+    /// Returns every stored key, or `None` if the database can't list its keys.
+    fn keys(&self) -> Result<Option<Vec<Vec<u8>>>, Self::Error> {
+        Ok(None)
+    }
 }
 
 #[derive(Default, Debug, Clone)]
@@ -63,6 +68,40 @@ impl MemoryDB {
             storage: Arc::new(RwLock::new(HashMap::new())),
         }
     }
+
+    /// Same as `new`, with room for `capacity` entries before reallocating.
+    pub fn with_capacity(capacity: usize, light: bool) -> Self {
+        MemoryDB {
+            light,
+            storage: Arc::new(RwLock::new(HashMap::with_capacity(capacity))),
+        }
+    }
+
+    /// Returns the total size of the stored keys and values in bytes.
+    pub fn bytes_len(&self) -> usize {
+        self.storage
+            .read()
+            .iter()
+            .map(|(key, value)| key.len() + value.len())
+            .sum()
+    }
+
+    /// Removes every entry, keeping the allocated capacity for reuse.
+    /// Clones share the storage and are cleared as well.
+    pub fn clear(&self) {
+        self.storage.write().clear()
+    }
+
+    /// Moves every entry into `dest` with one `insert_batch` and flushes it, e.g. to
+    /// persist the nodes of a trie built in memory. Clones share the storage and are
+    /// emptied as well. On an error the entries are kept here, `dest` may hold some.
+    pub fn drain_into<D: DB>(self, dest: &D) -> Result<(), D::Error> {
+        let entries = self.storage.read().clone();
+        dest.insert_batch(entries)?;
+        dest.flush()?;
+        self.clear();
+        Ok(())
+    }
 }
 
 impl DB for MemoryDB {
@@ -91,6 +130,39 @@ impl DB for MemoryDB {
     fn flush(&self) -> Result<(), Self::Error> {
         Ok(())
     }
+
+    fn keys(&self) -> Result<Option<Vec<Vec<u8>>>, Self::Error> {
+        Ok(Some(self.storage.read().keys().cloned().collect()))
+    }
+}
+
+/// A database failing on every read and write, for tries which must stay in memory,
+/// e.g. to compute a root with `PatriciaTrie::compute_root`.
+#[derive(Default, Debug, Clone, Copy)]
+pub struct NullDB;
+
+impl DB for NullDB {
+    type Error = NullDBError;
+
+    fn get(&self, _key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        Err(NullDBError("get"))
+    }
+
+    fn contains(&self, _key: &[u8]) -> Result<bool, Self::Error> {
+        Err(NullDBError("contains"))
+    }
+
+    fn insert(&self, _key: Vec<u8>, _value: Vec<u8>) -> Result<(), Self::Error> {
+        Err(NullDBError("insert"))
+    }
+
+    fn remove(&self, _key: &[u8]) -> Result<(), Self::Error> {
+        Err(NullDBError("remove"))
+    }
+
+    fn flush(&self) -> Result<(), Self::Error> {
+        Ok(())
+    }
 }
 
 impl<T: ?Sized + DB> DB for Arc<T> {
@@ -115,6 +187,10 @@ impl<T: ?Sized + DB> DB for Arc<T> {
     fn flush(&self) -> Result<(), Self::Error> {
         T::flush(self)
     }
+
+    fn keys(&self) -> Result<Option<Vec<Vec<u8>>>, Self::Error> {
+        T::keys(self)
+    }
 }
 
 #[cfg(test)]
@@ -150,4 +226,82 @@ mod tests {
         let contains = memdb.contains(b"test").unwrap();
         assert!(!contains)
     }
+
+    #[test]
+    fn test_memdb_stats() {
+        use crate::{PatriciaTrie, Trie};
+
+        let memdb = MemoryDB::with_capacity(64, true);
+        assert_eq!(memdb.bytes_len(), 0);
+        let mut trie = PatriciaTrie::new(memdb.clone());
+        for i in 0..20u8 {
+            trie.insert(vec![i], vec![i; 40]).unwrap();
+        }
+        trie.root().unwrap();
+        let stored = memdb
+            .storage
+            .read()
+            .values()
+            .map(|node| 32 + node.len())
+            .sum::<usize>();
+        assert_eq!(memdb.bytes_len(), stored);
+        assert!(stored > 20 * 40);
+
+        memdb.clear();
+        assert_eq!(memdb.bytes_len(), 0);
+        assert!(memdb.storage.read().capacity() >= 64);
+    }
+
+    #[test]
+    fn test_memdb_drain_into() {
+        use crate::{PatriciaTrie, Trie};
+
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::new(memdb.clone());
+        for i in 0..100u8 {
+            trie.insert(vec![i, i / 4], vec![i; 40]).unwrap();
+        }
+        let root = trie.root().unwrap();
+        let len = memdb.keys().unwrap().unwrap().len();
+
+        let dest = MemoryDB::new(true);
+        memdb.clone().drain_into(&dest).unwrap();
+        assert_eq!(memdb.keys().unwrap().unwrap().len(), 0);
+        assert_eq!(dest.keys().unwrap().unwrap().len(), len);
+
+        let trie = PatriciaTrie::from(dest, &root).unwrap();
+        trie.verify_integrity().unwrap();
+        for i in 0..100u8 {
+            assert_eq!(trie.get(&[i, i / 4]).unwrap(), Some(vec![i; 40]));
+        }
+
+        // Nothing is lost if the destination fails.
+        let memdb = trie.db().clone();
+        assert!(memdb.clone().drain_into(&NullDB).is_err());
+        assert_eq!(memdb.keys().unwrap().unwrap().len(), len);
+    }
+
+    #[test]
+    fn test_null_db() {
+        use crate::{PatriciaTrie, Trie};
+
+        let mut trie = PatriciaTrie::new(NullDB);
+        for i in 0..100u8 {
+            trie.insert(vec![i, i / 4], vec![i; 40]).unwrap();
+        }
+        assert!(trie.remove(&[3, 0]).unwrap());
+        assert_eq!(trie.get(&[4, 1]).unwrap(), Some(vec![4; 40]));
+        let root = trie.compute_root();
+
+        let mut expected = PatriciaTrie::new(MemoryDB::new(true));
+        for i in (0..100u8).filter(|&i| i != 3) {
+            expected.insert(vec![i, i / 4], vec![i; 40]).unwrap();
+        }
+        assert_eq!(root, expected.root().unwrap());
+        assert!(trie.is_dirty());
+
+        // Persisting fails.
+        assert!(matches!(trie.root(), Err(crate::TrieError::DB(_))));
+        assert!(PatriciaTrie::from(NullDB, &root).is_err());
+    }
 }
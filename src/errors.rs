@@ -1,40 +1,102 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+#[cfg(feature = "std")]
 use std::error::Error;
-use std::fmt;
 
 use rlp::DecoderError;
 
-#[derive(Debug)]
+pub type TrieResult<T> = Result<T, TrieError>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum TrieError {
     DB(String),
     Decoder(DecoderError),
     InvalidData,
-    InvalidStateRoot,
+    /// The root hash which could not be found in the database, or whose stored node
+    /// does not hash to it.
+    InvalidStateRoot(Vec<u8>),
     InvalidProof,
+    /// The hash of a node whose stored data does not hash to it.
+    CorruptedNode(Vec<u8>),
+    /// The hash of a referenced node which could not be found in the database.
+    MissingNode(Vec<u8>),
+    Io(String),
+    /// The trie holds more entries than the limit, see `PatriciaTrie::try_collect_limited`.
+    TooManyEntries(usize),
+    /// The key is longer than the limit in bytes, see `PatriciaTrieBuilder::max_key_len`.
+    KeyTooLong(usize),
 }
 
+#[cfg(feature = "std")]
 impl Error for TrieError {}
 
 impl fmt::Display for TrieError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            TrieError::DB(ref err) => write!(f, "trie error: {:?}", err),
-            TrieError::Decoder(ref err) => write!(f, "trie error: {:?}", err),
+            TrieError::DB(ref err) => write!(f, "trie error: db error: {}", err),
+            TrieError::Decoder(ref err) => write!(f, "trie error: decoder error: {}", err),
             TrieError::InvalidData => f.write_str("trie error: invalid data"),
-            TrieError::InvalidStateRoot => f.write_str("trie error: invalid state root"),
+            TrieError::InvalidStateRoot(ref root) => {
+                write!(f, "trie error: invalid state root {}", Hex(root))
+            }
             TrieError::InvalidProof => f.write_str("trie error: invalid proof"),
+            TrieError::CorruptedNode(ref hash) => {
+                write!(f, "trie error: node does not match its hash {}", Hex(hash))
+            }
+            TrieError::MissingNode(ref hash) => {
+                write!(f, "trie error: missing node {}", Hex(hash))
+            }
+            TrieError::Io(ref err) => write!(f, "trie error: io error: {}", err),
+            TrieError::TooManyEntries(max) => {
+                write!(f, "trie error: more than {} entries", max)
+            }
+            TrieError::KeyTooLong(max) => {
+                write!(f, "trie error: key longer than {} bytes", max)
+            }
         }
     }
 }
 
+pub(crate) struct Hex<'a>(pub &'a [u8]);
+
+impl fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("0x")?;
+        self.0.iter().try_for_each(|b| write!(f, "{:02x}", b))
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<std::io::Error> for TrieError {
+    fn from(error: std::io::Error) -> Self {
+        TrieError::Io(error.to_string())
+    }
+}
+
 impl From<DecoderError> for TrieError {
     fn from(error: DecoderError) -> Self {
         TrieError::Decoder(error)
     }
 }
 
-#[derive(Debug)]
+/// The access a `NullDB` refused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NullDBError(pub &'static str);
+
+#[cfg(feature = "std")]
+impl Error for NullDBError {}
+
+impl fmt::Display for NullDBError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "null db: unexpected {}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum MemDBError {}
 
+#[cfg(feature = "std")]
 impl Error for MemDBError {}
 
 impl fmt::Display for MemDBError {
@@ -0,0 +1,16 @@
+use sha3::Digest;
+
+/// "Hasher" defines the hash function used to reference nodes in the trie.
+pub trait Hasher {
+    fn digest(data: &[u8]) -> [u8; 32];
+}
+
+/// Keccak-256, the hash function of Ethereum tries.
+#[derive(Default, Debug, Clone, Copy)]
+pub struct HasherKeccak;
+
+impl Hasher for HasherKeccak {
+    fn digest(data: &[u8]) -> [u8; 32] {
+        sha3::Keccak256::digest(data).into()
+    }
+}
@@ -0,0 +1,304 @@
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use rlp::{DecoderError, Rlp, RlpStream};
+
+use crate::db::DB;
+
+/// The key of the journal in the wrapped database, never a node hash.
+pub const JOURNAL_KEY: &[u8] = b"cita-trie:journal";
+
+/// A database wrapper which makes every batch write all or nothing, for backends without
+/// transactions.
+///
+/// A commit writes its nodes with `insert_batch` and prunes with `remove_batch`, a crash
+/// in between leaves some of them written. Here a batch is first stored as a journal
+/// under `JOURNAL_KEY` and flushed, then applied, then the journal is removed. `open`
+/// applies a journal left by a crash again, so the database holds either the whole batch
+/// or none of it. The backend must write a single key atomically.
+///
+/// Every batch is written twice, and batches are applied one at a time. Clones share the
+/// journal.
+#[derive(Debug, Clone)]
+pub struct JournalingDB<D> {
+    db: D,
+    // Whether the journal holds a batch which failed to apply, applied before the next.
+    pending: Arc<Mutex<bool>>,
+}
+
+#[derive(Debug)]
+pub enum JournalingDBError<E> {
+    DB(E),
+    Journal(DecoderError),
+}
+
+impl<E: Error> Error for JournalingDBError<E> {}
+
+impl<E: fmt::Display> fmt::Display for JournalingDBError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            JournalingDBError::DB(ref err) => write!(f, "journaling db error: {}", err),
+            JournalingDBError::Journal(ref err) => {
+                write!(f, "journaling db invalid journal: {}", err)
+            }
+        }
+    }
+}
+
+enum Op {
+    Insert(Vec<u8>, Vec<u8>),
+    Remove(Vec<u8>),
+}
+
+impl<D: DB> JournalingDB<D> {
+    /// Wraps db, first applying the batch journaled by a crash if any.
+    pub fn open(db: D) -> Result<Self, JournalingDBError<D::Error>> {
+        let journaling = JournalingDB {
+            db,
+            pending: Arc::new(Mutex::new(true)),
+        };
+        journaling.recover(&mut journaling.pending.lock())?;
+        Ok(journaling)
+    }
+
+    pub fn inner(&self) -> &D {
+        &self.db
+    }
+
+    fn recover(&self, pending: &mut bool) -> Result<(), JournalingDBError<D::Error>> {
+        if !*pending {
+            return Ok(());
+        }
+        if let Some(journal) = self.db.get(JOURNAL_KEY).map_err(JournalingDBError::DB)? {
+            let ops = decode(&journal).map_err(JournalingDBError::Journal)?;
+            self.apply(ops)?;
+        }
+        *pending = false;
+        Ok(())
+    }
+
+    fn write(&self, ops: Vec<Op>) -> Result<(), JournalingDBError<D::Error>> {
+        if ops.is_empty() {
+            return Ok(());
+        }
+        let mut pending = self.pending.lock();
+        self.recover(&mut pending)?;
+        self.db
+            .insert(JOURNAL_KEY.to_vec(), encode(&ops))
+            .and_then(|_| self.db.flush())
+            .map_err(JournalingDBError::DB)?;
+        *pending = true;
+        self.apply(ops)?;
+        *pending = false;
+        Ok(())
+    }
+
+    // Applying twice is the same as once, so a journal may be applied again.
+    fn apply(&self, ops: Vec<Op>) -> Result<(), JournalingDBError<D::Error>> {
+        let (mut inserts, mut removes) = (vec![], vec![]);
+        for op in ops {
+            match op {
+                Op::Insert(key, value) => inserts.push((key, value)),
+                Op::Remove(key) => removes.push(key),
+            }
+        }
+        self.db
+            .insert_batch(inserts)
+            .and_then(|_| self.db.remove_batch(removes))
+            .and_then(|_| self.db.flush())
+            .and_then(|_| self.db.remove(JOURNAL_KEY))
+            .map_err(JournalingDBError::DB)
+    }
+}
+
+fn encode(ops: &[Op]) -> Vec<u8> {
+    let mut stream = RlpStream::new_list(ops.len());
+    for op in ops {
+        match op {
+            Op::Insert(key, value) => stream.begin_list(2).append(key).append(value),
+            Op::Remove(key) => stream.begin_list(1).append(key),
+        };
+    }
+    stream.out().to_vec()
+}
+
+fn decode(journal: &[u8]) -> Result<Vec<Op>, DecoderError> {
+    Rlp::new(journal)
+        .iter()
+        .map(|op| match op.item_count()? {
+            2 => Ok(Op::Insert(op.val_at(0)?, op.val_at(1)?)),
+            1 => Ok(Op::Remove(op.val_at(0)?)),
+            _ => Err(DecoderError::RlpIncorrectListLen),
+        })
+        .collect()
+}
+
+impl<D: DB> DB for JournalingDB<D> {
+    type Error = JournalingDBError<D::Error>;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.db.get(key).map_err(JournalingDBError::DB)
+    }
+
+    fn contains(&self, key: &[u8]) -> Result<bool, Self::Error> {
+        self.db.contains(key).map_err(JournalingDBError::DB)
+    }
+
+    fn insert(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        self.db.insert(key, value).map_err(JournalingDBError::DB)
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), Self::Error> {
+        self.db.remove(key).map_err(JournalingDBError::DB)
+    }
+
+    fn insert_batch<I>(&self, items: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    {
+        self.write(
+            items
+                .into_iter()
+                .map(|(key, value)| Op::Insert(key, value))
+                .collect(),
+        )
+    }
+
+    fn remove_batch<I: IntoIterator<Item = A>, A: AsRef<[u8]>>(
+        &self,
+        keys: I,
+    ) -> Result<(), Self::Error> {
+        self.write(
+            keys.into_iter()
+                .map(|key| Op::Remove(key.as_ref().to_vec()))
+                .collect(),
+        )
+    }
+
+    fn flush(&self) -> Result<(), Self::Error> {
+        self.db.flush().map_err(JournalingDBError::DB)
+    }
+
+    fn keys(&self) -> Result<Option<Vec<Vec<u8>>>, Self::Error> {
+        let keys = self.db.keys().map_err(JournalingDBError::DB)?;
+        Ok(keys.map(|keys| keys.into_iter().filter(|key| key != JOURNAL_KEY).collect()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use parking_lot::Mutex;
+
+    use super::{JournalingDB, JOURNAL_KEY};
+    use crate::db::{MemoryDB, DB};
+    use crate::errors::NullDBError;
+    use crate::trie::{PatriciaTrie, Trie};
+
+    /// Fails every write once `writes_left` reaches zero, as if the process crashed.
+    #[derive(Clone)]
+    struct CrashingDB {
+        db: MemoryDB,
+        writes_left: Arc<Mutex<usize>>,
+    }
+
+    impl CrashingDB {
+        fn write(&self) -> Result<(), NullDBError> {
+            let mut writes_left = self.writes_left.lock();
+            if *writes_left == 0 {
+                return Err(NullDBError("write after the crash"));
+            }
+            *writes_left -= 1;
+            Ok(())
+        }
+    }
+
+    impl DB for CrashingDB {
+        type Error = NullDBError;
+
+        fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+            Ok(self.db.get(key).unwrap())
+        }
+
+        fn contains(&self, key: &[u8]) -> Result<bool, Self::Error> {
+            Ok(self.db.contains(key).unwrap())
+        }
+
+        fn insert(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+            self.write()?;
+            self.db.insert(key, value).unwrap();
+            Ok(())
+        }
+
+        fn remove(&self, key: &[u8]) -> Result<(), Self::Error> {
+            self.write()?;
+            self.db.remove(key).unwrap();
+            Ok(())
+        }
+
+        fn flush(&self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn keys(&self) -> Result<Option<Vec<Vec<u8>>>, Self::Error> {
+            Ok(self.db.keys().unwrap())
+        }
+    }
+
+    #[test]
+    fn test_journaling_db_crash_recovery() {
+        let entries = (0..50u8).map(|i| (vec![i, i / 3], vec![i; 40]));
+        let mut reference = PatriciaTrie::new(MemoryDB::new(true));
+        for (key, value) in entries.clone() {
+            reference.insert(key, value).unwrap();
+        }
+        let old_root = reference.root().unwrap();
+        for (key, value) in entries.clone().step_by(4) {
+            reference.insert(key, [&value[..], b"v2"].concat()).unwrap();
+        }
+        reference.remove(&[7, 2]).unwrap();
+        let new_root = reference.root().unwrap();
+
+        let mut crashes = vec![];
+        for crash_at in 0..100 {
+            let memdb = MemoryDB::new(true);
+            let mut trie = PatriciaTrie::new(memdb.clone());
+            for (key, value) in entries.clone() {
+                trie.insert(key, value).unwrap();
+            }
+            trie.root().unwrap();
+
+            let writes_left = Arc::new(Mutex::new(crash_at));
+            let db = CrashingDB {
+                db: memdb.clone(),
+                writes_left: writes_left.clone(),
+            };
+            let mut trie = PatriciaTrie::from(JournalingDB::open(db).unwrap(), &old_root).unwrap();
+            for (key, value) in entries.clone().step_by(4) {
+                trie.insert(key, [&value[..], b"v2"].concat()).unwrap();
+            }
+            trie.remove(&[7, 2]).unwrap();
+            let crashed = trie.root().is_err();
+            drop(trie);
+
+            *writes_left.lock() = usize::MAX;
+            let db = CrashingDB {
+                db: memdb.clone(),
+                writes_left,
+            };
+            let db = JournalingDB::open(db).unwrap();
+            assert!(!memdb.contains(JOURNAL_KEY).unwrap());
+            // Only a crash before the journal of the nodes is written loses the commit.
+            let root = if crash_at == 0 { &old_root } else { &new_root };
+            let trie = PatriciaTrie::from(db.clone(), root).unwrap();
+            trie.verify_integrity().unwrap();
+            assert_eq!(trie.iter().count(), 50 - (crash_at > 0) as usize);
+            crashes.push(crashed);
+        }
+        // Crashes at every write of the commit were covered.
+        assert!(crashes[0] && !crashes[99]);
+    }
+}
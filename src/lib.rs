@@ -34,45 +34,291 @@
 //!
 //! }
 //! ```
+//!
+//! ## `no_std`
+//!
+//! Without the default `std` feature only the node codecs, the nibbles and the proof
+//! verification are built, using `alloc`, e.g. for light clients verifying proofs on
+//! embedded targets. `PatriciaTrie` and the databases need `std`.
+
+#![cfg_attr(not(feature = "std"), no_std)]
 
-mod nibbles;
+extern crate alloc;
+
+#[cfg(feature = "std")]
+mod cache;
+mod codec;
+#[cfg(feature = "std")]
+mod compress;
+mod hasher;
+#[cfg(feature = "std")]
+mod journal;
+#[cfg(feature = "std")]
+mod multiproof;
+pub mod nibbles;
+#[cfg(feature = "std")]
 mod node;
+#[cfg(feature = "std")]
+mod replay;
+#[cfg(feature = "std")]
+mod shared;
+#[cfg(feature = "std")]
 mod tests;
 
+#[cfg(feature = "std")]
 mod db;
 mod errors;
+#[cfg(feature = "std")]
 mod trie;
 
-pub use db::{MemoryDB, DB};
-pub use errors::{MemDBError, TrieError};
-pub use trie::{PatriciaTrie, Trie};
-pub use verify::verify_proof;
+pub use codec::{DecodedNode, NodeCodec, RlpCodec, DEFAULT_MAX_DECODE_DEPTH};
+#[cfg(feature = "zstd")]
+pub use compress::ZstdCodec;
+#[cfg(feature = "std")]
+pub use compress::{CompressingDB, CompressingDBError, CompressionCodec};
+#[cfg(feature = "std")]
+pub use db::{MemoryDB, NullDB, DB};
+pub use errors::{MemDBError, NullDBError, TrieError};
+pub use hasher::{Hasher, HasherKeccak};
+#[cfg(feature = "std")]
+pub use journal::{JournalingDB, JournalingDBError, JOURNAL_KEY};
+#[cfg(feature = "std")]
+pub use multiproof::{verify_compact_multiproof, CompactMultiproof};
+pub use nibbles::{NibbleSlice, NibbleVec};
+#[cfg(feature = "std")]
+pub use replay::{DBAccess, RecordingDB, ReplayDB};
+#[cfg(feature = "std")]
+pub use root::{ordered_trie_root, trie_root};
+#[cfg(feature = "std")]
+pub use shared::SharedDB;
+#[cfg(feature = "std")]
+pub use trie::{
+    ChangeSet, Checkpoint, CommitPreview, InsertCost, InsertKind, LeafMeta, NodeCache,
+    PatriciaTrie, PatriciaTrieBuilder, ProofKind, RootHistory, Subtree, Trie, ValueStore,
+    VecRootHistory, WitnessRecorder, DEFAULT_CACHE_CAPACITY, DEFAULT_MAX_KEY_LEN,
+};
+#[cfg(feature = "std")]
+pub use verify::verify_proof_streaming;
+pub use verify::{verify_proof, verify_proof_any, verify_proof_eq, verify_proof_with};
+
+#[cfg(feature = "std")]
+mod root {
+    use crate::{NullDB, PatriciaTrieBuilder, Trie};
+
+    /// Computes the root of the trie holding `pairs`, e.g. the state root of a set of
+    /// accounts, without keeping any of its nodes. Later pairs overwrite earlier ones
+    /// with the same key. Keys of any length are accepted, `DEFAULT_MAX_KEY_LEN` does
+    /// not apply.
+    pub fn trie_root(pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Vec<u8> {
+        let mut trie = PatriciaTrieBuilder::new()
+            .db(NullDB)
+            .max_key_len(usize::MAX)
+            .build();
+        for (key, value) in pairs {
+            trie.insert(key, value)
+                .expect("a trie built in memory never reads the db");
+        }
+        trie.compute_root()
+    }
+
+    /// Computes the root of the trie mapping the RLP encoded index of each item to the
+    /// item, as used for the transactions and receipts roots of a block.
+    pub fn ordered_trie_root(items: Vec<Vec<u8>>) -> Vec<u8> {
+        trie_root(
+            items
+                .into_iter()
+                .enumerate()
+                .map(|(i, item)| (rlp::encode(&(i as u64)).to_vec(), item))
+                .collect(),
+        )
+    }
+}
 
 mod verify {
-    use std::sync::Arc;
+    use alloc::collections::BTreeMap;
+    use alloc::vec::Vec;
+    #[cfg(feature = "std")]
+    use std::io::Write;
 
-    use sha3::Digest;
+    use crate::errors::{TrieError, TrieResult};
+    use crate::nibbles::{NibbleSlice, NibbleVec};
+    use crate::DEFAULT_MAX_DECODE_DEPTH;
+    use crate::{DecodedNode, Hasher, HasherKeccak, NodeCodec, RlpCodec};
 
-    use crate::{trie::TrieResult, MemoryDB, PatriciaTrie, Trie, TrieError, DB};
+    type ProofNodes = BTreeMap<Vec<u8>, Vec<u8>>;
 
+    /// Verifies a proof of a trie with the default settings, see `Trie::verify_proof`.
+    ///
+    /// The values of a trie with `allow_empty_values` or a value store are returned
+    /// in their stored encoding, verify their proofs with `Trie::verify_proof` of a
+    /// trie with the same settings.
     pub fn verify_proof(
         root_hash: &[u8],
         key: &[u8],
         proof: Vec<Vec<u8>>,
     ) -> TrieResult<Option<Vec<u8>>> {
-        let memdb = Arc::new(MemoryDB::new(true));
+        verify_proof_with::<HasherKeccak, RlpCodec>(root_hash, key, proof)
+    }
+
+    /// Checks that the proof shows key to have the expected value, or with `None` to be
+    /// absent. An invalid proof is an error rather than `false`.
+    pub fn verify_proof_eq(
+        root_hash: &[u8],
+        key: &[u8],
+        expected: Option<&[u8]>,
+        proof: Vec<Vec<u8>>,
+    ) -> TrieResult<bool> {
+        Ok(verify_proof(root_hash, key, proof)?.as_deref() == expected)
+    }
+
+    /// Same as `verify_proof`, for the proofs of a trie with another hasher or codec.
+    /// A proof checked with other ones than those of its trie is invalid.
+    pub fn verify_proof_with<H: Hasher, C: NodeCodec>(
+        root_hash: &[u8],
+        key: &[u8],
+        proof: Vec<Vec<u8>>,
+    ) -> TrieResult<Option<Vec<u8>>> {
+        let nodes = proof_nodes::<H, C>(&[root_hash], proof);
+        verify_in::<H, C, _, _>(&nodes, root_hash, key, |value| value.map(<[u8]>::to_vec))
+    }
+
+    /// Same as `verify_proof`, but the proven value is written to `writer`
+    /// instead of being returned. Returns `false` if the proof shows the key is absent.
+    #[cfg(feature = "std")]
+    pub fn verify_proof_streaming<W: Write>(
+        root_hash: &[u8],
+        key: &[u8],
+        proof: Vec<Vec<u8>>,
+        mut writer: W,
+    ) -> TrieResult<bool> {
+        let nodes = proof_nodes::<HasherKeccak, RlpCodec>(&[root_hash], proof);
+        // The value is written from the decoded node, without a copy.
+        verify_in::<HasherKeccak, RlpCodec, _, _>(&nodes, root_hash, key, |value| match value {
+            Some(value) => writer.write_all(value).map(|_| true),
+            None => Ok(false),
+        })?
+        .map_err(TrieError::from)
+    }
+
+    /// Checks the proof against each of roots, e.g. the candidate roots of a reorg, and
+    /// returns the index of the first root it is valid for, with the proven value.
+    /// `None` if the proof is invalid for every root.
+    ///
+    /// The nodes of the proof are hashed once for all the roots.
+    pub fn verify_proof_any(
+        roots: &[&[u8]],
+        key: &[u8],
+        proof: &[Vec<u8>],
+    ) -> TrieResult<Option<(usize, Option<Vec<u8>>)>> {
+        let nodes = proof_nodes::<HasherKeccak, RlpCodec>(roots, proof.to_vec());
+        Ok(roots.iter().enumerate().find_map(|(i, root)| {
+            verify_in::<HasherKeccak, RlpCodec, _, _>(&nodes, root, key, |value| {
+                value.map(<[u8]>::to_vec)
+            })
+            .ok()
+            .map(|value| (i, value))
+        }))
+    }
+
+    // Keeps the nodes of the proof which are roots or referenced by hash.
+    fn proof_nodes<H: Hasher, C: NodeCodec>(roots: &[&[u8]], proof: Vec<Vec<u8>>) -> ProofNodes {
+        let mut nodes = ProofNodes::new();
         for node_encoded in proof.into_iter() {
-            let hash = sha3::Keccak256::digest(&node_encoded);
+            let hash = H::digest(&node_encoded);
 
-            if root_hash == hash.as_slice() || node_encoded.len() >= sha3::Keccak256::output_size()
-            {
-                memdb.insert(hash.to_vec(), node_encoded).unwrap();
+            if roots.contains(&hash.as_slice()) || node_encoded.len() >= C::INLINE_THRESHOLD {
+                nodes.insert(hash.to_vec(), node_encoded);
             }
         }
+        nodes
+    }
+
+    // Looks the key up the way a trie opened at the root would, without building one,
+    // and passes the value found to `f`.
+    fn verify_in<H, C, F, R>(
+        nodes: &ProofNodes,
+        root_hash: &[u8],
+        key: &[u8],
+        f: F,
+    ) -> TrieResult<R>
+    where
+        H: Hasher,
+        C: NodeCodec,
+        F: FnOnce(Option<&[u8]>) -> R,
+    {
+        if root_hash == H::digest(&C::encode_empty()) {
+            return Ok(f(None));
+        }
+        let root = nodes.get(root_hash).ok_or(TrieError::InvalidProof)?;
+        let partial = NibbleVec::from_raw(key.to_vec(), true);
+        value_at::<C, F, R>(nodes, root, &partial, f).or(Err(TrieError::InvalidProof))
+    }
 
-        PatriciaTrie::from(memdb, root_hash)
-            .or(Err(TrieError::InvalidProof))?
-            .get(key)
-            .or(Err(TrieError::InvalidProof))
+    fn value_at<C, F, R>(
+        nodes: &ProofNodes,
+        data: &[u8],
+        mut partial: &NibbleSlice,
+        f: F,
+    ) -> TrieResult<R>
+    where
+        C: NodeCodec,
+        F: FnOnce(Option<&[u8]>) -> R,
+    {
+        let mut node = decode_checked::<C>(data, 0)?;
+        loop {
+            let child = match node {
+                DecodedNode::Empty => return Ok(f(None)),
+                DecodedNode::Leaf(key, value) => {
+                    let found = NibbleSlice::from_hex(&key) == partial;
+                    return Ok(f(Some(&value[..]).filter(|_| found)));
+                }
+                DecodedNode::Branch(mut children, value) => {
+                    if partial.is_empty() || partial.at(0) == 16 {
+                        return Ok(f(value.as_deref()));
+                    }
+                    let index = partial.at(0);
+                    partial = partial.offset(1);
+                    core::mem::take(&mut children[index])
+                }
+                DecodedNode::Extension(prefix, child) => {
+                    let match_len = partial.common_prefix(NibbleSlice::from_hex(&prefix));
+                    if match_len != prefix.len() {
+                        return Ok(f(None));
+                    }
+                    partial = partial.offset(match_len);
+                    child
+                }
+                DecodedNode::Hash(hash) => {
+                    let data = nodes
+                        .get(&hash[..])
+                        .ok_or_else(|| TrieError::MissingNode(hash.to_vec()))?;
+                    node = decode_checked::<C>(data, 0)?;
+                    continue;
+                }
+            };
+            // Embedded children were checked with their parent.
+            node = C::decode(&child)?;
+        }
+    }
+
+    // Decodes a node and checks the nodes embedded in it, as `PatriciaTrie` does with
+    // the default max decode depth.
+    fn decode_checked<C: NodeCodec>(data: &[u8], depth: usize) -> TrieResult<DecodedNode> {
+        if depth > DEFAULT_MAX_DECODE_DEPTH {
+            return Err(TrieError::InvalidData);
+        }
+        let node = C::decode(data)?;
+        match &node {
+            DecodedNode::Extension(_, child) => {
+                decode_checked::<C>(child, depth + 1)?;
+            }
+            DecodedNode::Branch(children, _) => {
+                for child in children.iter() {
+                    decode_checked::<C>(child, depth + 1)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(node)
     }
 }
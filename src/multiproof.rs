@@ -0,0 +1,452 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+use crate::codec::NodeCodec;
+use crate::db::{NullDB, DB};
+use crate::errors::TrieError;
+use crate::hasher::Hasher;
+use crate::nibbles::{NibbleSlice, NibbleVec};
+use crate::node::{empty_children, Node};
+use crate::trie::{PatriciaTrie, Trie, TrieResult};
+
+/// A proof for several keys at once, see `PatriciaTrie::get_compact_multiproof`.
+///
+/// Instead of the encodings of the nodes on the paths to the keys, the proof holds
+/// the nodes themselves in depth-first order. A branch is a bitmap of its present
+/// children followed by only these children, and a child on a path is the child node
+/// itself, so only the children off the paths are referenced by hash, or embedded if
+/// they are small. `verify_compact_multiproof` rebuilds the nodes and hashes them to
+/// check the root.
+///
+/// The saving over the union of the proofs grows with the number of keys sharing the
+/// nodes on their paths.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompactMultiproof(Vec<u8>);
+
+impl CompactMultiproof {
+    /// Reads a proof returned by `as_bytes`, it is only checked by the verifier.
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        CompactMultiproof(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+const EMPTY: u8 = 0;
+const LEAF: u8 = 1;
+const EXTENSION: u8 = 2;
+const BRANCH: u8 = 3;
+
+// How the child of an extension is given, a branch uses bitmaps instead.
+const CHILD_HASH: u8 = 0;
+const CHILD_EMBEDDED: u8 = 1;
+const CHILD_NODE: u8 = 2;
+
+// Deeper proofs are rejected instead of overflowing the stack, real paths are at most
+// two nodes per key nibble.
+const MAX_DEPTH: usize = 1024;
+
+pub(crate) fn encode<D, H, C>(
+    trie: &PatriciaTrie<D, H, C>,
+    keys: &[&[u8]],
+) -> TrieResult<CompactMultiproof>
+where
+    D: DB + Clone,
+    H: Hasher,
+    C: NodeCodec,
+{
+    let mut keys = keys
+        .iter()
+        .map(|key| NibbleVec::from_raw(key.to_vec(), true))
+        .collect::<Vec<_>>();
+    keys.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+    keys.dedup();
+    let partials = keys.iter().map(|key| &**key).collect::<Vec<_>>();
+    let mut out = vec![];
+    write_node(trie, trie.root.clone(), &partials, &mut out)?;
+    Ok(CompactMultiproof(out))
+}
+
+// `partials` are the rest of the keys whose path runs through `n`.
+fn write_node<D, H, C>(
+    trie: &PatriciaTrie<D, H, C>,
+    n: Node,
+    partials: &[&NibbleSlice],
+    out: &mut Vec<u8>,
+) -> TrieResult<()>
+where
+    D: DB + Clone,
+    H: Hasher,
+    C: NodeCodec,
+{
+    match n {
+        Node::Empty => out.push(EMPTY),
+        Node::Leaf(leaf) => {
+            let leaf_ref = unsafe { leaf.as_ref() };
+            out.push(LEAF);
+            let key = leaf_ref.key.as_bytes();
+            write_nibbles(&key[..key.len() - 1], out);
+            write_bytes(&leaf_ref.value, out);
+        }
+        Node::Extension(ext) => {
+            let (prefix, child) = {
+                let ext_ref = unsafe { ext.as_ref() };
+                (ext_ref.prefix.clone(), ext_ref.node.clone())
+            };
+            out.push(EXTENSION);
+            write_nibbles(prefix.as_bytes(), out);
+            let matched = partials
+                .iter()
+                .filter(|partial| partial.common_prefix(&prefix) == prefix.len())
+                .map(|partial| partial.offset(prefix.len()))
+                .collect::<Vec<_>>();
+            if matched.is_empty() {
+                let data = trie.encode_node(child, &mut HashMap::new());
+                if data.len() < C::INLINE_THRESHOLD {
+                    out.extend_from_slice(&[CHILD_EMBEDDED, data.len() as u8]);
+                } else {
+                    out.push(CHILD_HASH);
+                }
+                out.extend_from_slice(&data);
+            } else {
+                out.push(CHILD_NODE);
+                write_node(trie, child, &matched, out)?;
+            }
+        }
+        Node::Branch(branch) => {
+            let (children, value) = {
+                let branch_ref = unsafe { branch.as_ref() };
+                (branch_ref.children.clone(), branch_ref.value.clone())
+            };
+            // Bitmaps of the present children, of those on a path and of the embedded
+            // ones, the others are hashes.
+            let (mut present, mut expanded, mut embedded) = (0u16, 0u16, 0u16);
+            let mut refs = vec![];
+            let mut groups = vec![];
+            for (i, child) in children.iter().enumerate() {
+                if let Node::Empty = child {
+                    continue;
+                }
+                present |= 1 << i;
+                let group = partials
+                    .iter()
+                    .filter(|partial| !partial.is_empty() && partial.at(0) == i)
+                    .map(|partial| partial.offset(1))
+                    .collect::<Vec<_>>();
+                if group.is_empty() {
+                    let data = trie.encode_node(child.clone(), &mut HashMap::new());
+                    if data.len() < C::INLINE_THRESHOLD {
+                        embedded |= 1 << i;
+                    }
+                    refs.push(data);
+                } else {
+                    expanded |= 1 << i;
+                    groups.push(group);
+                }
+            }
+            out.push(BRANCH);
+            for bitmap in [present, expanded, embedded] {
+                out.extend_from_slice(&bitmap.to_be_bytes());
+            }
+            match value {
+                Some(value) => {
+                    out.push(1);
+                    write_bytes(&value, out);
+                }
+                None => out.push(0),
+            }
+            let (mut refs, mut groups) = (refs.into_iter(), groups.into_iter());
+            for (i, child) in children.iter().enumerate() {
+                if expanded & 1 << i != 0 {
+                    write_node(trie, child.clone(), &groups.next().unwrap(), out)?;
+                } else if present & 1 << i != 0 {
+                    let data = refs.next().unwrap();
+                    if embedded & 1 << i != 0 {
+                        out.push(data.len() as u8);
+                    }
+                    out.extend_from_slice(&data);
+                }
+            }
+        }
+        Node::Hash(hash_node) => {
+            let n = trie.recover_from_db(&unsafe { hash_node.as_ref() }.hash)?;
+            let result = write_node(trie, n.clone(), partials, out);
+            unsafe { Node::dealloc(n) };
+            result?;
+        }
+    }
+    Ok(())
+}
+
+// LEB128, seven bits per byte starting with the lowest ones.
+fn write_len(mut len: usize, out: &mut Vec<u8>) {
+    while len >= 0x80 {
+        out.push(len as u8 | 0x80);
+        len >>= 7;
+    }
+    out.push(len as u8);
+}
+
+// The number of nibbles, then two nibbles per byte.
+fn write_nibbles(nibbles: &[u8], out: &mut Vec<u8>) {
+    write_len(nibbles.len(), out);
+    out.extend(
+        nibbles
+            .chunks(2)
+            .map(|pair| pair[0] << 4 | pair.get(1).unwrap_or(&0)),
+    );
+}
+
+fn write_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    write_len(bytes.len(), out);
+    out.extend_from_slice(bytes);
+}
+
+/// Checks a `CompactMultiproof` for keys against the root hash and returns the value of
+/// each key, `None` for the keys the proof shows to be absent.
+///
+/// Returns `TrieError::InvalidProof` if the proof is malformed, doesn't hash to the root
+/// or doesn't cover every key. Only proofs of tries with the default hasher and codec
+/// and without a `ValueStore` can be checked.
+pub fn verify_compact_multiproof(
+    root_hash: &[u8],
+    keys: &[&[u8]],
+    proof: &CompactMultiproof,
+) -> TrieResult<Vec<Option<Vec<u8>>>> {
+    let mut trie = PatriciaTrie::new(NullDB);
+    let mut reader = Reader(proof.as_bytes());
+    trie.root = read_node(&trie, &mut reader, 0)?;
+    if !reader.0.is_empty() || trie.compute_root() != root_hash {
+        return Err(TrieError::InvalidProof);
+    }
+    // A path leaving the proof ends at a hash node, which the db can't expand.
+    keys.iter()
+        .map(|key| trie.get(key).or(Err(TrieError::InvalidProof)))
+        .collect()
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> TrieResult<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(TrieError::InvalidProof);
+        }
+        let (data, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(data)
+    }
+
+    fn u8(&mut self) -> TrieResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> TrieResult<u16> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn len(&mut self) -> TrieResult<usize> {
+        let mut len = 0;
+        for shift in (0..32).step_by(7) {
+            let byte = self.u8()?;
+            // Only the shortest encoding is valid, so a proof can't be altered.
+            if byte == 0 && shift > 0 {
+                break;
+            }
+            len |= ((byte & 0x7f) as usize) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(len);
+            }
+        }
+        Err(TrieError::InvalidProof)
+    }
+
+    fn nibbles(&mut self) -> TrieResult<Vec<u8>> {
+        let len = self.len()?;
+        let bytes = self.take(len.div_ceil(2))?;
+        // The same holds for the padding of an odd number of nibbles.
+        if len % 2 == 1 && bytes[bytes.len() - 1] & 0x0f != 0 {
+            return Err(TrieError::InvalidProof);
+        }
+        let mut nibbles = bytes
+            .iter()
+            .flat_map(|byte| [byte >> 4, byte & 0x0f])
+            .collect::<Vec<_>>();
+        nibbles.truncate(len);
+        Ok(nibbles)
+    }
+
+    fn bytes(&mut self) -> TrieResult<Vec<u8>> {
+        let len = self.len()?;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn hash(&mut self) -> TrieResult<Node> {
+        Ok(Node::from_hash(self.take(32)?.try_into().unwrap()))
+    }
+}
+
+fn read_node<D, H, C>(
+    trie: &PatriciaTrie<D, H, C>,
+    reader: &mut Reader,
+    depth: usize,
+) -> TrieResult<Node>
+where
+    D: DB + Clone,
+    H: Hasher,
+    C: NodeCodec,
+{
+    if depth > MAX_DEPTH {
+        return Err(TrieError::InvalidProof);
+    }
+    match reader.u8()? {
+        // Only the root of the empty trie is empty.
+        EMPTY if depth == 0 => Ok(Node::Empty),
+        LEAF => {
+            let mut key = reader.nibbles()?;
+            key.push(16);
+            let value = reader.bytes()?;
+            Ok(Node::from_leaf(NibbleVec::from_hex(key), value))
+        }
+        EXTENSION => {
+            let prefix = reader.nibbles()?;
+            let child = match reader.u8()? {
+                CHILD_HASH => reader.hash(),
+                CHILD_EMBEDDED => read_embedded(trie, reader),
+                CHILD_NODE => read_node(trie, reader, depth + 1),
+                _ => Err(TrieError::InvalidProof),
+            }?;
+            Ok(Node::from_extension(NibbleVec::from_hex(prefix), child))
+        }
+        BRANCH => {
+            let present = reader.u16()?;
+            let expanded = reader.u16()?;
+            let embedded = reader.u16()?;
+            if (expanded | embedded) & !present != 0 || expanded & embedded != 0 {
+                return Err(TrieError::InvalidProof);
+            }
+            let value = match reader.u8()? {
+                0 => None,
+                1 => Some(reader.bytes()?),
+                _ => return Err(TrieError::InvalidProof),
+            };
+            let mut children = empty_children();
+            for (i, child) in children.iter_mut().enumerate() {
+                let read = if expanded & 1 << i != 0 {
+                    read_node(trie, reader, depth + 1)
+                } else if embedded & 1 << i != 0 {
+                    read_embedded(trie, reader)
+                } else if present & 1 << i != 0 {
+                    reader.hash()
+                } else {
+                    continue;
+                };
+                match read {
+                    Ok(n) => *child = n,
+                    Err(e) => {
+                        for n in children {
+                            unsafe { Node::dealloc(n) };
+                        }
+                        return Err(e);
+                    }
+                }
+            }
+            Ok(Node::from_branch(children, value))
+        }
+        _ => Err(TrieError::InvalidProof),
+    }
+}
+
+fn read_embedded<D, H, C>(trie: &PatriciaTrie<D, H, C>, reader: &mut Reader) -> TrieResult<Node>
+where
+    D: DB + Clone,
+    H: Hasher,
+    C: NodeCodec,
+{
+    let len = reader.u8()? as usize;
+    let data = reader.take(len)?;
+    trie.decode_node(data).or(Err(TrieError::InvalidProof))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::{verify_compact_multiproof, CompactMultiproof};
+    use crate::db::MemoryDB;
+    use crate::errors::TrieError;
+    use crate::hasher::{Hasher, HasherKeccak};
+    use crate::trie::{PatriciaTrie, Trie};
+
+    #[test]
+    fn test_compact_multiproof() {
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::new(memdb.clone());
+        // Fixed keys, so the size of the proof compared below doesn't vary.
+        let keys = (0..2000u32)
+            .map(|i| HasherKeccak::digest(&i.to_be_bytes())[..8].to_vec())
+            .collect::<Vec<_>>();
+        for key in keys.iter() {
+            trie.insert(key.clone(), HasherKeccak::digest(key).to_vec())
+                .unwrap();
+        }
+        let root = trie.root().unwrap();
+        let trie = PatriciaTrie::from(memdb, &root).unwrap();
+
+        let mut queries = keys
+            .iter()
+            .take(200)
+            .map(|key| key.as_slice())
+            .collect::<Vec<_>>();
+        // Absent keys, and a key ending inside a path.
+        queries.extend_from_slice(&[&[1, 2, 3][..], &[200; 8], &keys[7][..4], &[]]);
+        let proof = trie.get_compact_multiproof(&queries).unwrap();
+        let values = verify_compact_multiproof(&root, &queries, &proof).unwrap();
+        for (key, value) in queries.iter().zip(values) {
+            assert_eq!(value, trie.get(key).unwrap());
+        }
+
+        let union = queries
+            .iter()
+            .flat_map(|key| trie.get_proof(key).unwrap())
+            .collect::<HashSet<_>>();
+        let union_size = union.iter().map(Vec::len).sum::<usize>();
+        // The top of the trie is shared by the keys, about 30% smaller here.
+        assert!(proof.as_bytes().len() < union_size * 4 / 5);
+
+        // A key left out of the proof can't be answered.
+        assert_eq!(
+            verify_compact_multiproof(&root, &[&keys[1000][..]], &proof),
+            Err(TrieError::InvalidProof)
+        );
+        // Any change is detected, as well as truncation and trailing data.
+        let queries = &queries[199..201];
+        let proof = trie.get_compact_multiproof(queries).unwrap();
+        let bytes = proof.into_bytes();
+        for i in 0..bytes.len() {
+            let mut tampered = bytes.clone();
+            tampered[i] ^= 1 << (i % 8);
+            let tampered = CompactMultiproof::from_bytes(tampered);
+            assert!(verify_compact_multiproof(&root, queries, &tampered).is_err());
+        }
+        let truncated = CompactMultiproof::from_bytes(bytes[..bytes.len() - 1].to_vec());
+        assert!(verify_compact_multiproof(&root, queries, &truncated).is_err());
+        let mut extended = bytes;
+        extended.push(0);
+        let extended = CompactMultiproof::from_bytes(extended);
+        assert!(verify_compact_multiproof(&root, queries, &extended).is_err());
+
+        let empty = PatriciaTrie::new(MemoryDB::new(true));
+        let proof = empty.get_compact_multiproof(&[&[1]]).unwrap();
+        assert_eq!(
+            verify_compact_multiproof(&empty.compute_root(), &[&[1]], &proof).unwrap(),
+            vec![None]
+        );
+    }
+}
@@ -1,11 +1,26 @@
-use std::borrow::Borrow;
-use std::ops::Deref;
+//! Keys as sequences of nibbles, the half bytes the trie branches on.
+//!
+//! A byte key has two nibbles per byte, high nibble first. Leaf keys end with the
+//! terminator nibble `16`. Node encodings store key nibbles in the compact form of the
+//! Ethereum yellow paper, see `NibbleSlice::encode_compact`.
 
+use alloc::borrow::{Borrow, ToOwned};
+use alloc::vec::Vec;
+use core::ops::Deref;
+
+/// A borrowed sequence of nibbles, see `NibbleVec`.
 #[derive(Debug, Eq, PartialEq)]
 #[repr(transparent)]
 pub struct NibbleSlice([u8]);
 
 impl NibbleSlice {
+    /// Wraps nibbles given one per byte, each below 16 except a terminating `16`.
+    pub fn from_hex(hex: &[u8]) -> &Self {
+        // safety: NibbleSlice is `repr(transparent)` over `[u8]`, so transmutes between the two are okay.
+        unsafe { core::mem::transmute::<&[u8], &Self>(hex) }
+    }
+
+    /// Number of nibbles, including the terminator.
     pub fn len(&self) -> usize {
         self.0.len()
     }
@@ -14,10 +29,14 @@ impl NibbleSlice {
         self.len() == 0
     }
 
+    /// Returns the nibble at `i`.
+    ///
+    /// `Panics` if `i` >= slice length
     pub fn at(&self, i: usize) -> usize {
         self.0[i] as usize
     }
 
+    /// Returns the number of leading nibbles both slices share.
     pub fn common_prefix(&self, other_partial: &Self) -> usize {
         self.0
             .iter()
@@ -33,18 +52,77 @@ impl NibbleSlice {
         self.slice(index, self.len())
     }
 
-    pub fn _as_bytes(&self) -> &[u8] {
+    /// Returns the nibbles, one per byte.
+    pub fn as_bytes(&self) -> &[u8] {
         &self.0
     }
 
-    pub fn slice(&self, start: usize, end: usize) -> &Self {
-        let data = &self.0[start..end];
-        // safety: NibbleSlice is `repr(transparent)` over `[u8]`, so transmutes between the two are okay.
-        unsafe {
-            let slice = data;
-            std::mem::transmute::<&[u8], &Self>(slice)
+    /// Checks whether the nibbles end with the leaf terminator `16`.
+    pub fn is_leaf(&self) -> bool {
+        self.0.last() == Some(&16)
+    }
+
+    /// Packs the nibbles two per byte behind a flag nibble telling whether they are a
+    /// leaf key and are odd in number, the inverse of `NibbleVec::from_compact`.
+    pub fn encode_compact(&self) -> Vec<u8> {
+        let is_leaf = self.is_leaf();
+        let mut hex = if is_leaf {
+            &self.0[0..self.0.len() - 1]
+        } else {
+            &self.0[0..]
+        };
+        // node type    path length    |    prefix    hexchar
+        // --------------------------------------------------
+        // extension    even           |    0000      0x0
+        // extension    odd            |    0001      0x1
+        // leaf         even           |    0010      0x2
+        // leaf         odd            |    0011      0x3
+        let v = if hex.len() % 2 == 1 {
+            let v = 0x10 + hex[0];
+            hex = &hex[1..];
+            v
+        } else {
+            0x00
+        };
+
+        let mut compact = Vec::with_capacity(hex.len() / 2 + 1);
+
+        compact.push(v + if is_leaf { 0x20 } else { 0x00 });
+        for hex in hex.chunks_exact(2) {
+            compact.push((hex[0] * 16) + (hex[1]));
+        }
+
+        compact
+    }
+
+    /// Packs the nibbles two per byte and tells whether they are a leaf key, the
+    /// inverse of `NibbleVec::from_raw` for keys of whole bytes.
+    pub fn encode_raw(&self) -> (Vec<u8>, bool) {
+        let mut raw = Vec::with_capacity(self.0.len() / 2);
+        self.encode_raw_into(&mut raw);
+        (raw, self.is_leaf())
+    }
+
+    /// Same as `encode_raw`, but packs the nibbles into `raw`, replacing its content.
+    pub fn encode_raw_into(&self, raw: &mut Vec<u8>) {
+        let hex = if self.is_leaf() {
+            &self.0[..self.0.len() - 1]
+        } else {
+            &self.0[..]
+        };
+        raw.clear();
+        // The last nibble of an odd length key is dropped.
+        for hex in hex.chunks_exact(2) {
+            raw.push((hex[0] * 16) + (hex[1]));
         }
     }
+
+    /// Takes the nibbles in `start..end`.
+    ///
+    /// `Panics` if the range is out of bounds
+    pub fn slice(&self, start: usize, end: usize) -> &Self {
+        Self::from_hex(&self.0[start..end])
+    }
 }
 
 impl ToOwned for NibbleSlice {
@@ -62,21 +140,30 @@ impl Borrow<NibbleSlice> for NibbleVec {
         // safety: NibbleSlice is `repr(transparent)` over `[u8]`, so transmutes between the two are okay.
         unsafe {
             let slice = self.hex_data.as_slice();
-            std::mem::transmute::<&[u8], &NibbleSlice>(slice)
+            core::mem::transmute::<&[u8], &NibbleSlice>(slice)
         }
     }
 }
 
+/// An owned sequence of nibbles, dereferencing to `NibbleSlice`.
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct NibbleVec {
     hex_data: Vec<u8>,
 }
 
 impl NibbleVec {
+    /// Takes nibbles given one per byte, see `NibbleSlice::from_hex`.
     pub fn from_hex(hex: Vec<u8>) -> Self {
         NibbleVec { hex_data: hex }
     }
 
+    /// Returns the nibbles, one per byte.
+    pub fn into_hex(self) -> Vec<u8> {
+        self.hex_data
+    }
+
+    /// Splits every byte of a key into two nibbles, appending the terminator for a
+    /// leaf key.
     pub fn from_raw(raw: Vec<u8>, is_leaf: bool) -> Self {
         let mut hex_data = Vec::with_capacity(raw.len() * 2 + is_leaf as usize);
         for item in raw.into_iter() {
@@ -89,6 +176,9 @@ impl NibbleVec {
         NibbleVec { hex_data }
     }
 
+    /// Unpacks nibbles encoded by `NibbleSlice::encode_compact`.
+    ///
+    /// `Panics` if `compact` is empty or uses the reserved flag bits
     pub fn from_compact(compact: Vec<u8>) -> Self {
         let flag = compact[0];
 
@@ -114,52 +204,7 @@ impl NibbleVec {
         NibbleVec { hex_data: hex }
     }
 
-    pub fn is_leaf(&self) -> bool {
-        *self.hex_data.last().unwrap() == 16
-    }
-
-    pub fn encode_compact(&self) -> Vec<u8> {
-        let is_leaf = self.is_leaf();
-        let mut hex = if is_leaf {
-            &self.hex_data[0..self.hex_data.len() - 1]
-        } else {
-            &self.hex_data[0..]
-        };
-        // node type    path length    |    prefix    hexchar
-        // --------------------------------------------------
-        // extension    even           |    0000      0x0
-        // extension    odd            |    0001      0x1
-        // leaf         even           |    0010      0x2
-        // leaf         odd            |    0011      0x3
-        let v = if hex.len() % 2 == 1 {
-            let v = 0x10 + hex[0];
-            hex = &hex[1..];
-            v
-        } else {
-            0x00
-        };
-
-        let mut compact = Vec::with_capacity(hex.len() / 2 + 1);
-
-        compact.push(v + if is_leaf { 0x20 } else { 0x00 });
-        for hex in hex.chunks_exact(2) {
-            compact.push((hex[0] * 16) + (hex[1]));
-        }
-
-        compact
-    }
-
-    pub fn encode_raw(&self) -> (Vec<u8>, bool) {
-        let is_leaf = self.is_leaf();
-        let mut raw = Vec::with_capacity(self.hex_data.len() / 2);
-        // if `is_leaf` then we don't care about the last nibble anyway.
-        for hex in self.hex_data.chunks_exact(2) {
-            raw.push((hex[0] * 16) + (hex[1]));
-        }
-
-        (raw, is_leaf)
-    }
-
+    /// Returns the nibbles of `self` followed by those of `b`.
     pub fn join(&self, b: &NibbleVec) -> NibbleVec {
         let mut hex_data = Vec::with_capacity(self.hex_data.len() + b.hex_data.len());
         hex_data.extend_from_slice(&self.hex_data);
@@ -167,18 +212,22 @@ impl NibbleVec {
         NibbleVec::from_hex(hex_data)
     }
 
+    /// Appends the nibbles of `b`.
     pub fn extend_from_slice(&mut self, b: &NibbleSlice) {
         self.hex_data.extend_from_slice(&b.0);
     }
 
+    /// Keeps the first `len` nibbles.
     pub fn truncate(&mut self, len: usize) {
         self.hex_data.truncate(len)
     }
 
+    /// Removes the last nibble and returns it, `None` if there are none.
     pub fn pop(&mut self) -> Option<u8> {
         self.hex_data.pop()
     }
 
+    /// Appends a nibble, below 16 or the terminator `16`.
     pub fn push(&mut self, e: u8) {
         self.hex_data.push(e)
     }
@@ -205,4 +254,54 @@ mod tests {
         assert!(is_leaf);
         assert_eq!(raw, b"key1");
     }
+
+    #[test]
+    fn test_nibble_encode_raw_odd() {
+        let n = NibbleVec::from_hex(vec![1, 2, 15, 16]);
+        assert_eq!(n.encode_raw(), (vec![0x12], true));
+        let n = NibbleVec::from_hex(vec![1, 2, 15]);
+        assert_eq!(n.encode_raw(), (vec![0x12], false));
+    }
+
+    #[test]
+    fn test_nibble_compact_round_trip() {
+        // (nibbles, compact encoding) for odd and even lengths, leaf and not.
+        let cases: [(&[u8], &[u8]); 8] = [
+            (&[], &[0x00]),
+            (&[16], &[0x20]),
+            (&[1], &[0x11]),
+            (&[1, 16], &[0x31]),
+            (&[1, 2, 3, 4], &[0x00, 0x12, 0x34]),
+            (&[1, 2, 3, 4, 16], &[0x20, 0x12, 0x34]),
+            (&[15, 1, 12, 11, 8], &[0x1f, 0x1c, 0xb8]),
+            (&[15, 1, 12, 11, 8, 16], &[0x3f, 0x1c, 0xb8]),
+        ];
+        for (hex, compact) in cases.iter() {
+            let n = NibbleSlice::from_hex(hex);
+            assert_eq!(n.len(), hex.len());
+            assert_eq!(n.is_leaf(), hex.last() == Some(&16));
+            assert_eq!(&n.encode_compact(), compact);
+
+            let decoded = NibbleVec::from_compact(compact.to_vec());
+            assert_eq!(decoded.as_bytes(), *hex);
+            assert_eq!(decoded.is_leaf(), n.is_leaf());
+        }
+    }
+
+    #[test]
+    fn test_nibble_vec_push_pop() {
+        let mut n = NibbleVec::from_raw(vec![0xab], false);
+        n.push(12);
+        assert_eq!(n.as_bytes(), &[10, 11, 12]);
+        assert!(!n.is_leaf());
+        n.push(16);
+        assert!(n.is_leaf());
+        assert_eq!(n.encode_compact(), vec![0x3a, 0xbc]);
+        assert_eq!(n.pop(), Some(16));
+        assert_eq!(n.len(), 3);
+        assert_eq!(
+            n.join(&NibbleVec::from_hex(vec![13])).encode_raw().0,
+            vec![0xab, 0xcd]
+        );
+    }
 }
@@ -1,19 +1,35 @@
 use std::fmt::{Debug, Formatter};
+#[cfg(not(feature = "safe-node"))]
 use std::ptr::NonNull;
 
 use crate::nibbles::NibbleVec;
 
+#[cfg(feature = "safe-node")]
+pub(crate) use safe::{alloc, NodePtr};
+
+/// A pointer to a leaked node, freed by `Node::dealloc`.
+#[cfg(not(feature = "safe-node"))]
+pub(crate) type NodePtr<T> = NonNull<T>;
+
+/// Leaks the node.
+#[cfg(not(feature = "safe-node"))]
+pub(crate) fn alloc<T>(node: T) -> NodePtr<T> {
+    NonNull::from(Box::leak(Box::new(node)))
+}
+
 #[derive(Clone)]
 pub enum Node {
     Empty,
-    Leaf(NonNull<LeafNode>),
-    Extension(NonNull<ExtensionNode>),
-    Branch(NonNull<BranchNode>),
-    Hash(NonNull<HashNode>),
+    Leaf(NodePtr<LeafNode>),
+    Extension(NodePtr<ExtensionNode>),
+    Branch(NodePtr<BranchNode>),
+    Hash(NodePtr<HashNode>),
 }
 
+#[cfg(not(feature = "safe-node"))]
 unsafe impl Send for Node {}
 
+#[cfg(not(feature = "safe-node"))]
 unsafe impl Sync for Node {}
 
 impl Debug for Node {
@@ -43,43 +59,57 @@ impl Debug for Node {
 impl Node {
     /// Creates a node from leaf and leaks it
     pub(crate) fn from_leaf(key: NibbleVec, value: Vec<u8>) -> Self {
-        unsafe {
-            Node::Leaf(NonNull::new_unchecked(Box::leak(Box::new(LeafNode {
-                key,
-                value,
-            }))))
-        }
+        Node::Leaf(alloc(LeafNode { key, value }))
     }
 
     /// Creates a node from branch and leaks it
     pub(crate) fn from_branch(children: [Node; 16], value: Option<Vec<u8>>) -> Self {
-        unsafe {
-            Node::Branch(NonNull::new_unchecked(Box::leak(Box::new(BranchNode {
-                children,
-                value,
-            }))))
-        }
+        Node::Branch(alloc(BranchNode { children, value }))
     }
 
     /// Creates a node from extension and leaks it
     pub(crate) fn from_extension(prefix: NibbleVec, node: Node) -> Self {
-        unsafe {
-            Node::Extension(NonNull::new_unchecked(Box::leak(Box::new(ExtensionNode {
-                prefix,
-                node,
-            }))))
-        }
+        Node::Extension(alloc(ExtensionNode { prefix, node }))
     }
 
     /// Creates a node from hash and leaks it
     pub(crate) fn from_hash(hash: [u8; 32]) -> Self {
+        Node::Hash(alloc(HashNode { hash }))
+    }
+
+    /// Copies the node together with all its in-memory children,
+    /// hash nodes are copied as is without being expanded.
+    pub(crate) fn deep_copy(&self) -> Self {
         unsafe {
-            Node::Hash(NonNull::new_unchecked(Box::leak(Box::new(HashNode {
-                hash,
-            }))))
+            match self {
+                Node::Empty => Node::Empty,
+                Node::Leaf(leaf) => {
+                    let leaf_ref = leaf.as_ref();
+                    Node::from_leaf(leaf_ref.key.clone(), leaf_ref.value.clone())
+                }
+                Node::Extension(ext) => {
+                    let ext_ref = ext.as_ref();
+                    Node::from_extension(ext_ref.prefix.clone(), ext_ref.node.deep_copy())
+                }
+                Node::Branch(branch) => {
+                    let branch_ref = branch.as_ref();
+                    let mut children = empty_children();
+                    for (i, child) in branch_ref.children.iter().enumerate() {
+                        children[i] = child.deep_copy();
+                    }
+                    Node::from_branch(children, branch_ref.value.clone())
+                }
+                Node::Hash(hash_node) => Node::from_hash(hash_node.as_ref().hash),
+            }
         }
     }
 
+    #[cfg(feature = "safe-node")]
+    pub(crate) unsafe fn dealloc(node: Self) {
+        drop(node)
+    }
+
+    #[cfg(not(feature = "safe-node"))]
     pub(crate) unsafe fn dealloc(node: Self) {
         match node {
             Node::Empty => {}
@@ -103,7 +133,7 @@ impl Node {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct LeafNode {
     pub key: NibbleVec,
     pub value: Vec<u8>,
@@ -112,11 +142,69 @@ pub struct LeafNode {
 /// Dereferences a pointer to a node and returns and owned value.
 ///
 /// See [Box::from_raw]
-pub(crate) unsafe fn to_owned<T, N: Into<NonNull<T>>>(ptr: N) -> Box<T> {
-    Box::from_raw(ptr.into().as_mut())
+#[cfg(not(feature = "safe-node"))]
+pub(crate) unsafe fn to_owned<T>(ptr: NodePtr<T>) -> Box<T> {
+    Box::from_raw(ptr.as_ptr())
+}
+
+/// Takes the node out of the pointer, copying it if the node is still shared.
+#[cfg(feature = "safe-node")]
+pub(crate) unsafe fn to_owned<T: Clone>(ptr: NodePtr<T>) -> Box<T> {
+    Box::new(ptr.into_inner())
+}
+
+/// Reference counted nodes, enabled by the `safe-node` feature.
+///
+/// `NodePtr` mirrors the part of the `NonNull` API the trie uses, so the trie code is
+/// the same with both representations. Nodes are freed when the last pointer is dropped
+/// and an access conflicting with a live borrow panics instead of aliasing.
+#[cfg(feature = "safe-node")]
+mod safe {
+    use std::sync::Arc;
+
+    use parking_lot::lock_api::{ArcRwLockReadGuard, ArcRwLockWriteGuard};
+    use parking_lot::{RawRwLock, RwLock};
+
+    pub(crate) struct NodePtr<T>(Arc<RwLock<T>>);
+
+    impl<T> Clone for NodePtr<T> {
+        fn clone(&self) -> Self {
+            NodePtr(self.0.clone())
+        }
+    }
+
+    pub(crate) fn alloc<T>(node: T) -> NodePtr<T> {
+        NodePtr(Arc::new(RwLock::new(node)))
+    }
+
+    impl<T> NodePtr<T> {
+        /// # Safety
+        ///
+        /// Always safe, `unsafe` only for parity with `NonNull::as_ref`.
+        pub(crate) unsafe fn as_ref(&self) -> ArcRwLockReadGuard<RawRwLock, T> {
+            self.0.try_read_arc().expect("node is borrowed mutably")
+        }
+
+        /// # Safety
+        ///
+        /// Always safe, `unsafe` only for parity with `NonNull::as_mut`.
+        pub(crate) unsafe fn as_mut(&mut self) -> ArcRwLockWriteGuard<RawRwLock, T> {
+            self.0.try_write_arc().expect("node is already borrowed")
+        }
+
+        pub(crate) fn into_inner(self) -> T
+        where
+            T: Clone,
+        {
+            match Arc::try_unwrap(self.0) {
+                Ok(lock) => lock.into_inner(),
+                Err(shared) => shared.try_read().expect("node is borrowed mutably").clone(),
+            }
+        }
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct BranchNode {
     pub children: [Node; 16],
     pub value: Option<Vec<u8>>,
@@ -139,13 +227,13 @@ impl BranchNode {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ExtensionNode {
     pub prefix: NibbleVec,
     pub node: Node,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct HashNode {
     pub hash: [u8; 32],
 }
@@ -0,0 +1,258 @@
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+use crate::db::DB;
+use crate::errors::MemDBError;
+
+/// A database access logged by `RecordingDB`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DBAccess {
+    Get(Vec<u8>, Option<Vec<u8>>),
+    Contains(Vec<u8>, bool),
+    Insert(Vec<u8>, Vec<u8>),
+    Remove(Vec<u8>),
+    Flush,
+}
+
+/// A database wrapper which logs every access in order, see `ReplayDB`.
+///
+/// Clones share the log. Failed accesses are not logged.
+#[derive(Default, Debug, Clone)]
+pub struct RecordingDB<D> {
+    db: D,
+    log: Arc<Mutex<Vec<DBAccess>>>,
+}
+
+impl<D> RecordingDB<D> {
+    pub fn new(db: D) -> Self {
+        RecordingDB {
+            db,
+            log: Default::default(),
+        }
+    }
+
+    /// Returns the accesses logged so far.
+    pub fn log(&self) -> Vec<DBAccess> {
+        self.log.lock().clone()
+    }
+
+    /// Drops the accesses logged so far, e.g. to record a single operation.
+    pub fn clear_log(&self) {
+        self.log.lock().clear()
+    }
+
+    pub fn inner(&self) -> &D {
+        &self.db
+    }
+}
+
+impl<D: DB> DB for RecordingDB<D> {
+    type Error = D::Error;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        let value = self.db.get(key)?;
+        self.log
+            .lock()
+            .push(DBAccess::Get(key.to_vec(), value.clone()));
+        Ok(value)
+    }
+
+    fn contains(&self, key: &[u8]) -> Result<bool, Self::Error> {
+        let contains = self.db.contains(key)?;
+        self.log
+            .lock()
+            .push(DBAccess::Contains(key.to_vec(), contains));
+        Ok(contains)
+    }
+
+    fn insert(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        self.db.insert(key.clone(), value.clone())?;
+        self.log.lock().push(DBAccess::Insert(key, value));
+        Ok(())
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), Self::Error> {
+        self.db.remove(key)?;
+        self.log.lock().push(DBAccess::Remove(key.to_vec()));
+        Ok(())
+    }
+
+    fn insert_batch<I>(&self, items: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    {
+        let items = items.into_iter().collect::<Vec<_>>();
+        self.db.insert_batch(items.clone())?;
+        self.log.lock().extend(
+            items
+                .into_iter()
+                .map(|(key, value)| DBAccess::Insert(key, value)),
+        );
+        Ok(())
+    }
+
+    fn remove_batch<I: IntoIterator<Item = A>, A: AsRef<[u8]>>(
+        &self,
+        keys: I,
+    ) -> Result<(), Self::Error> {
+        let keys = keys
+            .into_iter()
+            .map(|key| key.as_ref().to_vec())
+            .collect::<Vec<_>>();
+        self.db.remove_batch(&keys)?;
+        self.log
+            .lock()
+            .extend(keys.into_iter().map(DBAccess::Remove));
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Self::Error> {
+        self.db.flush()?;
+        self.log.lock().push(DBAccess::Flush);
+        Ok(())
+    }
+
+    fn keys(&self) -> Result<Option<Vec<Vec<u8>>>, Self::Error> {
+        self.db.keys()
+    }
+}
+
+/// A database serving the accesses logged by a `RecordingDB`, in the same order.
+///
+/// Reads return the recorded results and writes are only checked against the log.
+/// Panics on any access which is not the next one in the log. Clones share the position
+/// in the log.
+#[derive(Debug, Clone)]
+pub struct ReplayDB {
+    log: Arc<Vec<DBAccess>>,
+    next: Arc<Mutex<usize>>,
+}
+
+impl ReplayDB {
+    pub fn new(log: Vec<DBAccess>) -> Self {
+        ReplayDB {
+            log: Arc::new(log),
+            next: Default::default(),
+        }
+    }
+
+    /// Returns the number of logged accesses which were not replayed yet.
+    pub fn remaining(&self) -> usize {
+        self.log.len() - *self.next.lock()
+    }
+
+    fn replay(&self, access: DBAccess) -> &DBAccess {
+        let mut next = self.next.lock();
+        let expected = match self.log.get(*next) {
+            Some(expected) => expected,
+            None => panic!("unexpected db access {:?}, the log is exhausted", access),
+        };
+        let matches = match (expected, &access) {
+            (DBAccess::Get(a, _), DBAccess::Get(b, _)) => a == b,
+            (DBAccess::Contains(a, _), DBAccess::Contains(b, _)) => a == b,
+            (a, b) => a == b,
+        };
+        if !matches {
+            panic!(
+                "unexpected db access {:?} at {}, expected {:?}",
+                access, *next, expected
+            );
+        }
+        *next += 1;
+        expected
+    }
+}
+
+impl DB for ReplayDB {
+    type Error = MemDBError;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        match self.replay(DBAccess::Get(key.to_vec(), None)) {
+            DBAccess::Get(_, value) => Ok(value.clone()),
+            _ => unreachable!(),
+        }
+    }
+
+    fn contains(&self, key: &[u8]) -> Result<bool, Self::Error> {
+        match self.replay(DBAccess::Contains(key.to_vec(), false)) {
+            DBAccess::Contains(_, contains) => Ok(*contains),
+            _ => unreachable!(),
+        }
+    }
+
+    fn insert(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        self.replay(DBAccess::Insert(key, value));
+        Ok(())
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), Self::Error> {
+        self.replay(DBAccess::Remove(key.to_vec()));
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Self::Error> {
+        self.replay(DBAccess::Flush);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DBAccess, RecordingDB, ReplayDB};
+    use crate::db::MemoryDB;
+    use crate::trie::{PatriciaTrie, Trie};
+
+    fn recorded_trie() -> (RecordingDB<MemoryDB>, Vec<u8>) {
+        let db = RecordingDB::new(MemoryDB::new(true));
+        let mut trie = PatriciaTrie::new(db.clone());
+        for i in 0..100u8 {
+            trie.insert(vec![i, i / 5], vec![i; 40]).unwrap();
+        }
+        let root = trie.root().unwrap();
+        db.clear_log();
+        (db, root)
+    }
+
+    #[test]
+    fn test_record_replay_get_proof() {
+        let (db, root) = recorded_trie();
+        let trie = PatriciaTrie::from(db.clone(), &root).unwrap();
+        let proof = trie.get_proof(&[42, 8]).unwrap();
+        let log = db.log();
+        assert!(log.len() > 1);
+        assert!(log
+            .iter()
+            .all(|access| matches!(access, DBAccess::Get(_, Some(_)))));
+
+        let replay = ReplayDB::new(log);
+        let trie = PatriciaTrie::from(replay, &root).unwrap();
+        assert_eq!(trie.get_proof(&[42, 8]).unwrap(), proof);
+        assert_eq!(trie.db().remaining(), 0);
+    }
+
+    #[test]
+    fn test_record_replay_flush() {
+        let (db, root) = recorded_trie();
+        let trie = PatriciaTrie::from(db.clone(), &root).unwrap();
+        trie.get(&[42, 8]).unwrap();
+        trie.flush().unwrap();
+        assert_eq!(db.log().last(), Some(&DBAccess::Flush));
+
+        let trie = PatriciaTrie::from(ReplayDB::new(db.log()), &root).unwrap();
+        trie.get(&[42, 8]).unwrap();
+        trie.flush().unwrap();
+        assert_eq!(trie.db().remaining(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "unexpected db access")]
+    fn test_replay_unexpected_access() {
+        let (db, root) = recorded_trie();
+        let trie = PatriciaTrie::from(db.clone(), &root).unwrap();
+        trie.get_proof(&[42, 8]).unwrap();
+
+        let trie = PatriciaTrie::from(ReplayDB::new(db.log()), &root).unwrap();
+        trie.get_proof(&[43, 8]).unwrap();
+    }
+}
@@ -0,0 +1,221 @@
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use parking_lot::RwLock;
+
+use crate::codec::{NodeCodec, RlpCodec};
+use crate::db::DB;
+use crate::hasher::{Hasher, HasherKeccak};
+use crate::trie::{PatriciaTrie, TrieResult};
+
+#[derive(Debug, Default)]
+struct PinnedRoot {
+    count: usize,
+    hashes: HashSet<[u8; 32]>,
+}
+
+/// A database wrapper for tries sharing one database, which keeps the nodes of the
+/// pinned roots.
+///
+/// A commit prunes the nodes it replaced, which another trie opened at an older root may
+/// still refer to. Removing a node reachable from a pinned root is skipped, so pin the
+/// roots other tries are opened at and unpin them once they are not used any more.
+/// Clones share the pinned roots. `H` and `C` must match the tries using the database.
+#[derive(Debug)]
+pub struct SharedDB<D, H = HasherKeccak, C = RlpCodec> {
+    db: D,
+    pinned: Arc<RwLock<HashMap<Vec<u8>, PinnedRoot>>>,
+    phantom: PhantomData<fn() -> (H, C)>,
+}
+
+impl<D: Clone, H, C> Clone for SharedDB<D, H, C> {
+    fn clone(&self) -> Self {
+        SharedDB {
+            db: self.db.clone(),
+            pinned: self.pinned.clone(),
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<D> SharedDB<D> {
+    pub fn new(db: D) -> Self {
+        SharedDB::with_codec(db)
+    }
+}
+
+impl<D, H, C> SharedDB<D, H, C> {
+    /// Same as `new`, for tries with another hasher or codec.
+    pub fn with_codec(db: D) -> Self {
+        SharedDB {
+            db,
+            pinned: Default::default(),
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn inner(&self) -> &D {
+        &self.db
+    }
+
+    /// Checks whether the node with hash is kept for a pinned root.
+    pub fn is_pinned(&self, hash: &[u8]) -> bool {
+        let hash: [u8; 32] = match hash.try_into() {
+            Ok(hash) => hash,
+            Err(_) => return false,
+        };
+        self.pinned
+            .read()
+            .values()
+            .any(|root| root.hashes.contains(&hash))
+    }
+
+    /// Releases a root pinned by `pin`, its nodes can be pruned again once every
+    /// `pin` of the root is matched by an `unpin`.
+    pub fn unpin(&self, root: &[u8]) {
+        let mut pinned = self.pinned.write();
+        if let Some(pinned_root) = pinned.get_mut(root) {
+            pinned_root.count -= 1;
+            if pinned_root.count == 0 {
+                pinned.remove(root);
+            }
+        }
+    }
+}
+
+impl<D, H, C> SharedDB<D, H, C>
+where
+    D: DB + Clone,
+    H: Hasher,
+    C: NodeCodec,
+{
+    /// Keeps the nodes reachable from root until it is unpinned, the root must be
+    /// committed. Pinning a root again only counts the pin.
+    pub fn pin(&self, root: &[u8]) -> TrieResult<()> {
+        if let Some(pinned_root) = self.pinned.write().get_mut(root) {
+            pinned_root.count += 1;
+            return Ok(());
+        }
+        let hashes = PatriciaTrie::<D, H, C>::open(self.db.clone(), root)?.reachable_hashes()?;
+        self.pinned
+            .write()
+            .entry(root.to_vec())
+            .or_insert(PinnedRoot { count: 0, hashes })
+            .count += 1;
+        Ok(())
+    }
+}
+
+impl<D: DB, H, C> DB for SharedDB<D, H, C> {
+    type Error = D::Error;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.db.get(key)
+    }
+
+    fn contains(&self, key: &[u8]) -> Result<bool, Self::Error> {
+        self.db.contains(key)
+    }
+
+    fn insert(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        self.db.insert(key, value)
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), Self::Error> {
+        if self.is_pinned(key) {
+            return Ok(());
+        }
+        self.db.remove(key)
+    }
+
+    fn insert_batch<I>(&self, items: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    {
+        self.db.insert_batch(items)
+    }
+
+    fn remove_batch<I: IntoIterator<Item = A>, A: AsRef<[u8]>>(
+        &self,
+        keys: I,
+    ) -> Result<(), Self::Error> {
+        let keys = keys
+            .into_iter()
+            .filter(|key| !self.is_pinned(key.as_ref()))
+            .collect::<Vec<_>>();
+        self.db.remove_batch(keys)
+    }
+
+    fn flush(&self) -> Result<(), Self::Error> {
+        self.db.flush()
+    }
+
+    fn keys(&self) -> Result<Option<Vec<Vec<u8>>>, Self::Error> {
+        self.db.keys()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SharedDB;
+    use crate::db::{MemoryDB, DB};
+    use crate::hasher::{Hasher, HasherKeccak};
+    use crate::trie::{PatriciaTrie, Trie};
+
+    #[test]
+    fn test_shared_db_interleaved_commits() {
+        let memdb = MemoryDB::new(true);
+        let db = SharedDB::new(memdb.clone());
+        let mut trie = PatriciaTrie::new(db.clone());
+        for i in 0..100u8 {
+            trie.insert(vec![i, i / 4], vec![i; 40]).unwrap();
+        }
+        let root = trie.root().unwrap();
+        // One pin for each trie opened at the root.
+        db.pin(&root).unwrap();
+        db.pin(&root).unwrap();
+
+        let mut a = PatriciaTrie::from(db.clone(), &root).unwrap();
+        let mut b = PatriciaTrie::from(db.clone(), &root).unwrap();
+        let mut a_root = root.clone();
+        let mut b_root = root.clone();
+        for i in 0..20u8 {
+            // Every commit of one trie replaces nodes the other one still uses.
+            let (trie, trie_root, value) = if i % 2 == 0 {
+                (&mut a, &mut a_root, b"a")
+            } else {
+                (&mut b, &mut b_root, b"b")
+            };
+            trie.insert(vec![i * 5, i * 5 / 4], value.to_vec()).unwrap();
+            let new_root = trie.root().unwrap();
+            db.pin(&new_root).unwrap();
+            db.unpin(trie_root);
+            *trie_root = new_root;
+        }
+
+        for (trie, root, value) in [(&a, &a_root, b"a"), (&b, &b_root, b"b")] {
+            let reopened = PatriciaTrie::from(db.clone(), root).unwrap();
+            reopened.verify_integrity().unwrap();
+            for i in 0..100u8 {
+                let expected = if i % 5 == 0 && (i / 5) % 2 == (value == b"b") as u8 {
+                    value.to_vec()
+                } else {
+                    vec![i; 40]
+                };
+                assert_eq!(trie.get(&[i, i / 4]).unwrap(), Some(expected.clone()));
+                assert_eq!(reopened.get(&[i, i / 4]).unwrap(), Some(expected));
+            }
+            assert_eq!(reopened.iter().count(), 100);
+        }
+
+        // Once unpinned, the nodes of the first root are pruned by the next commit.
+        db.unpin(&a_root);
+        let leaf = a.get_proof(&[10, 2]).unwrap().pop().unwrap();
+        let leaf_hash = HasherKeccak::digest(&leaf);
+        a.insert(vec![10, 2], b"c".to_vec()).unwrap();
+        a.root().unwrap();
+        assert!(!memdb.contains(&leaf_hash).unwrap());
+    }
+}
@@ -4,8 +4,11 @@ mod trie_tests {
     use rand::Rng;
 
     use crate::db::MemoryDB;
-    use crate::trie::{PatriciaTrie, Trie};
-    use crate::verify_proof;
+    use crate::trie::{PatriciaTrie, ProofKind, Trie};
+    use crate::{
+        ordered_trie_root, trie_root, verify_proof, verify_proof_any, verify_proof_eq,
+        verify_proof_streaming, Hasher, HasherKeccak,
+    };
 
     fn assert_root(data: Vec<(&[u8], &[u8])>, hash: &str) {
         let memdb = MemoryDB::new(true);
@@ -644,6 +647,99 @@ mod trie_tests {
         }
     }
 
+    #[test]
+    fn test_proof_any() {
+        let mut trie = PatriciaTrie::new(MemoryDB::new(true));
+        let mut roots = vec![];
+        for i in 0..5u8 {
+            for j in 0..50u8 {
+                trie.insert(vec![j, j / 3], vec![i; 40]).unwrap();
+            }
+            roots.push(trie.root().unwrap());
+        }
+        let proof = trie.get_proof(&[42, 14]).unwrap();
+        let mut candidates = roots.iter().map(|root| root.as_slice()).collect::<Vec<_>>();
+        // The candidates before the matching root are invalid for the proof.
+        candidates.swap(0, 4);
+        assert_eq!(
+            verify_proof_any(&candidates, &[42, 14], &proof).unwrap(),
+            Some((0, Some(vec![4; 40])))
+        );
+        candidates.swap(0, 3);
+        assert_eq!(
+            verify_proof_any(&candidates, &[42, 14], &proof).unwrap(),
+            Some((3, Some(vec![4; 40])))
+        );
+        // An exclusion proof.
+        let absent = trie.get_proof(&[42, 15]).unwrap();
+        assert_eq!(
+            verify_proof_any(&candidates, &[42, 15], &absent).unwrap(),
+            Some((3, None))
+        );
+        assert_eq!(
+            verify_proof_any(&candidates[..3], &[42, 14], &proof).unwrap(),
+            None
+        );
+        assert_eq!(verify_proof_any(&[], &[42, 14], &proof).unwrap(), None);
+    }
+
+    #[test]
+    fn test_proof_eq() {
+        let mut trie = PatriciaTrie::new(MemoryDB::new(true));
+        for i in 0..50u8 {
+            trie.insert(vec![i, i / 3], vec![i; 40]).unwrap();
+        }
+        let root = trie.root().unwrap();
+
+        let proof = trie.get_proof(&[42, 14]).unwrap();
+        let verify = |expected: Option<&[u8]>| {
+            verify_proof_eq(&root, &[42, 14], expected, proof.clone()).unwrap()
+        };
+        assert!(verify(Some(&[42; 40])));
+        assert!(!verify(Some(&[42; 39])));
+        assert!(!verify(Some(&[])));
+        assert!(!verify(None));
+
+        // An exclusion proof.
+        let absent = trie.get_proof(&[42, 15]).unwrap();
+        assert!(verify_proof_eq(&root, &[42, 15], None, absent.clone()).unwrap());
+        assert!(!verify_proof_eq(&root, &[42, 15], Some(&[]), absent).unwrap());
+
+        // An invalid proof is an error whatever the expected value.
+        let truncated = proof[..proof.len() - 1].to_vec();
+        assert!(verify_proof_eq(&root, &[42, 14], Some(&[42; 40]), truncated.clone()).is_err());
+        assert!(verify_proof_eq(&root, &[42, 14], None, truncated).is_err());
+    }
+
+    #[test]
+    fn test_proof_custom_hasher() {
+        use crate::{verify_proof_with, Hasher, HasherKeccak, RlpCodec};
+        use sha3::Digest;
+
+        struct HasherSha3;
+
+        impl Hasher for HasherSha3 {
+            fn digest(data: &[u8]) -> [u8; 32] {
+                sha3::Sha3_256::digest(data).into()
+            }
+        }
+
+        let mut trie = PatriciaTrie::<_, HasherSha3, RlpCodec>::create(MemoryDB::new(true));
+        for i in 0..100u8 {
+            trie.insert(vec![i, i / 3], vec![i; 40]).unwrap();
+        }
+        let root = trie.root().unwrap();
+        for key in [vec![42, 14], vec![42, 15]] {
+            let proof = trie.get_proof(&key).unwrap();
+            let value = verify_proof_with::<HasherSha3, RlpCodec>(&root, &key, proof.clone());
+            assert_eq!(value.unwrap(), trie.get(&key).unwrap());
+            assert!(
+                verify_proof_with::<HasherKeccak, RlpCodec>(&root, &key, proof.clone()).is_err()
+            );
+            assert!(verify_proof(&root, &key, proof).is_err());
+        }
+    }
+
     #[test]
     fn test_proof_empty_trie() {
         let memdb = MemoryDB::new(true);
@@ -674,4 +770,145 @@ mod trie_tests {
             Some(b"v".to_vec())
         );
     }
+
+    #[test]
+    fn test_proof_streaming() {
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::new(memdb);
+        let large = vec![7u8; 1 << 20];
+        trie.insert(b"doe".to_vec(), b"reindeer".to_vec()).unwrap();
+        trie.insert(b"dog".to_vec(), large.clone()).unwrap();
+        trie.insert(b"dogglesworth".to_vec(), b"cat".to_vec())
+            .unwrap();
+        let root = trie.root().unwrap();
+
+        let proof = trie.get_proof(b"dog").unwrap();
+        let mut sink = vec![];
+        assert!(verify_proof_streaming(&root, b"dog", proof, &mut sink).unwrap());
+        assert_eq!(sink, large);
+
+        let proof = trie.get_proof(b"dogg").unwrap();
+        let mut sink = vec![];
+        assert!(!verify_proof_streaming(&root, b"dogg", proof, &mut sink).unwrap());
+        assert!(sink.is_empty());
+
+        let proof = vec![b"aaa".to_vec(), b"ccc".to_vec()];
+        assert!(verify_proof_streaming(&root, b"dog", proof, &mut sink).is_err());
+    }
+
+    #[test]
+    fn test_proof_kind() {
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::new(memdb);
+        trie.insert(b"doe".to_vec(), b"reindeer".to_vec()).unwrap();
+        trie.insert(b"dog".to_vec(), b"puppy".to_vec()).unwrap();
+        trie.insert(b"dogglesworth".to_vec(), b"cat".to_vec())
+            .unwrap();
+        let root = trie.root().unwrap();
+
+        let (kind, proof) = trie.get_proof_kind(b"dog").unwrap();
+        assert_eq!(kind, ProofKind::Inclusion(b"puppy".to_vec()));
+        assert_eq!(proof, trie.get_proof(b"dog").unwrap());
+
+        // "dox" diverges at the branch below "do", "dogg" is a strict prefix of a key.
+        for key in [&b"dox"[..], b"dogg"] {
+            let (kind, proof) = trie.get_proof_kind(key).unwrap();
+            assert_eq!(kind, ProofKind::Exclusion);
+            assert_eq!(verify_proof(&root, key, proof.clone()).unwrap(), None);
+
+            // The last node is the one proving the divergence.
+            let mut truncated = proof.clone();
+            truncated.pop();
+            assert!(verify_proof(&root, key, truncated).is_err());
+        }
+    }
+
+    #[test]
+    fn test_proof_size() {
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::new(memdb.clone());
+        let size = |trie: &PatriciaTrie<MemoryDB>, key: &[u8]| {
+            let expected = trie.get_proof(key).unwrap().iter().map(Vec::len).sum();
+            assert_eq!(trie.proof_size(key).unwrap(), expected);
+            expected
+        };
+        assert_eq!(size(&trie, b"key"), 0);
+
+        let mut rng = rand::thread_rng();
+        let mut keys = vec![];
+        for _ in 0..500 {
+            let key: Vec<u8> = (0..rng.gen_range(1, 8)).map(|_| rng.gen()).collect();
+            let value = vec![0; rng.gen_range(1, 80)];
+            trie.insert(key.clone(), value).unwrap();
+            keys.push(key);
+        }
+        // Uncommitted nodes are encoded from memory.
+        assert!(size(&trie, &keys[0]) > 0);
+
+        let root = trie.root().unwrap();
+        let trie = PatriciaTrie::from(memdb, &root).unwrap();
+        for key in keys.iter() {
+            assert!(size(&trie, key) > 0);
+            let mut missing = key.clone();
+            missing.push(rng.gen());
+            size(&trie, &missing);
+        }
+        size(&trie, b"");
+    }
+
+    #[test]
+    fn test_static_roots() {
+        // The transactions and receipts root of every mainnet block without transactions.
+        assert_eq!(
+            hex::encode(ordered_trie_root(vec![])),
+            "56e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421"
+        );
+        assert_eq!(
+            hex::encode(trie_root(vec![
+                (b"doe".to_vec(), b"reindeer".to_vec()),
+                (b"dog".to_vec(), b"puppy".to_vec()),
+                (b"dogglesworth".to_vec(), b"cat".to_vec()),
+            ])),
+            "8aad789dff2f538bca5d8ea56e8abe10f4c7ba3a5dea95fea4cd6e7c3a1168d3"
+        );
+
+        // Mainnet block 46147, the first one with a transaction: the raw transaction,
+        // its hash and the transactions root of the block header.
+        let tx = Vec::from_hex(
+            "f86780862d79883d2000825208945df9b87991262f6ba471f09758cde1c0fc1de734827a69801c\
+             a088ff6cf0fefd94db46111149ae4bfc179e9b94721fffd821d38d16464b3f71d0a045e0aff800\
+             961cfce805daef7016b9b675c137a6a41a548f7b60a3484c06a33a",
+        )
+        .unwrap();
+        assert_eq!(
+            hex::encode(HasherKeccak::digest(&tx)),
+            "5c504ed432cb51138bcf09aa5e8a410dd4a1e204ef84bfed1be16dfba1b22060"
+        );
+        assert_eq!(
+            hex::encode(ordered_trie_root(vec![tx])),
+            "4513310fcb9f6f616972a3b948dc5d547f280849a87ebb5af0191f98b87be598"
+        );
+
+        // Indices from 128 on are RLP encoded on more than one byte.
+        let items = (0..300u32)
+            .map(|i| i.to_be_bytes().repeat(10))
+            .collect::<Vec<_>>();
+        let mut keys = vec![vec![0x80]];
+        keys.extend((1..0x80u8).map(|i| vec![i]));
+        keys.extend((0x80..0x100u32).map(|i| vec![0x81, i as u8]));
+        keys.extend((0x100..300u32).map(|i| vec![0x82, (i >> 8) as u8, i as u8]));
+        assert_eq!(
+            ordered_trie_root(items.clone()),
+            trie_root(keys.into_iter().zip(items).collect())
+        );
+
+        // Keys of any length are accepted.
+        let long = vec![7; crate::DEFAULT_MAX_KEY_LEN + 1];
+        let mut trie = crate::PatriciaTrieBuilder::new()
+            .db(MemoryDB::new(true))
+            .max_key_len(usize::MAX)
+            .build();
+        trie.insert(long.clone(), b"v".to_vec()).unwrap();
+        assert_eq!(trie_root(vec![(long, b"v".to_vec())]), trie.root().unwrap());
+    }
 }
@@ -1,22 +1,47 @@
+use std::borrow::Cow;
 use std::cell::RefCell;
+use std::cmp::Ordering;
 use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
+use std::fmt;
+use std::io::{Read, Write};
 use std::iter::FromIterator;
-use std::ptr::NonNull;
+use std::marker::PhantomData;
 use std::rc::Rc;
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
 
-use rlp::{Prototype, Rlp, RlpStream};
-use sha3::Digest;
+use parking_lot::{Mutex, RwLock};
+#[cfg(feature = "rayon")]
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
 
+use crate::cache::LruCache;
+use crate::codec::{DecodedNode, NodeCodec, RlpCodec, DEFAULT_MAX_DECODE_DEPTH};
 use crate::db::{MemoryDB, DB};
-use crate::errors::TrieError;
+use crate::errors::{Hex, TrieError};
+use crate::hasher::{Hasher, HasherKeccak};
+use crate::multiproof::{self, CompactMultiproof};
 use crate::nibbles::{NibbleSlice, NibbleVec};
 use crate::node::{empty_children, to_owned, BranchNode, Node};
 
-const KECCAK_SIZE: usize = 32;
+const HASH_SIZE: usize = 32;
 
-pub type TrieResult<T> = Result<T, TrieError>;
+/// Appended to a root hash, the key of the metadata stored by `commit_with_meta`.
+const META_SUFFIX: &[u8] = b"meta";
+
+/// Header of the format written by `PatriciaTrie::to_writer`, followed by a version.
+const SERIALIZED_MAGIC: &[u8; 4] = b"ctri";
+const SERIALIZED_VERSION: u8 = 1;
+
+/// Number of value bytes `PatriciaTrie::dump` shows.
+const DUMP_VALUE_LEN: usize = 16;
+
+/// Default number of expanded nodes kept in `cached_tries`.
+pub const DEFAULT_CACHE_CAPACITY: usize = 1024;
+
+/// Default maximum length of a key in bytes, see `PatriciaTrieBuilder::max_key_len`.
+pub const DEFAULT_MAX_KEY_LEN: usize = 8192;
+
+pub use crate::errors::TrieResult;
 
 pub trait Trie<D: DB> {
     /// Returns the value for key stored in the trie.
@@ -33,6 +58,9 @@ pub trait Trie<D: DB> {
 
     /// Saves all the nodes in the db, clears the cache data, recalculates the root.
     /// Returns the root hash of the trie.
+    ///
+    /// The nodes are written but not flushed, whether they survive a crash depends on
+    /// the database, see `PatriciaTrie::flush`.
     fn root(&mut self) -> TrieResult<Vec<u8>>;
 
     /// Prove constructs a merkle proof for key. The result contains all encoded nodes
@@ -54,22 +82,417 @@ pub trait Trie<D: DB> {
 }
 
 #[derive(Debug, Clone)]
-pub struct PatriciaTrie<D> {
-    root: Node,
+pub struct PatriciaTrie<D, H = HasherKeccak, C = RlpCodec> {
+    pub(crate) root: Node,
     root_hash: Vec<u8>,
 
     db: D,
     backup_db: Option<D>,
 
+    /// Checks the hash of every node recovered from the database.
+    strict: bool,
+
+    /// Stores large values by hash, `None` keeps them in the leaves.
+    value_store: Option<ValueStore>,
+
+    /// Stores empty values instead of removing the key, see `allow_empty_values`.
+    allow_empty_values: bool,
+
+    /// Maximum number of parents a node can be embedded in when decoding.
+    max_decode_depth: usize,
+
+    /// Embeds the small nodes in their parents, see `PatriciaTrieBuilder::inline_small_nodes`.
+    inline_small_nodes: bool,
+
+    /// Longer keys are rejected, see `PatriciaTrieBuilder::max_key_len`.
+    max_key_len: usize,
+
+    /// Root hash of the empty trie.
+    empty_root: [u8; 32],
+
+    /// Commits keep their nodes in `deferred_nodes` until `flush_commit`.
+    defer_commit: bool,
+
+    /// Nodes of deferred commits, read before the database. Shared with the tries
+    /// expanded from this one.
+    deferred_nodes: DeferredNodes,
+
+    /// Nodes replaced by deferred commits, removed from the database by `flush_commit`.
+    deferred_pruned: HashSet<[u8; 32]>,
+
+    /// Whether the trie was modified since the last commit.
+    dirty: bool,
+
     /// Hashes of nodes that were expanded during insert/delete ops.
     /// If expanded node is modified, it is deleted from the database.
     recovered_nodes_hashes: HashSet<[u8; 32]>,
 
     /// Expanded nodes in `get` op. We cache them not to expand each time.
-    cached_tries: Arc<RwLock<HashMap<[u8; 32], PatriciaTrie<D>>>>,
+    /// Least recently used nodes are evicted once the capacity is reached.
+    /// `None` if the read cache is disabled, see `PatriciaTrieBuilder::enable_read_cache`.
+    cached_tries: Option<CachedTries<D, H, C>>,
+
+    /// Proofs served by `get_proof` for the committed root, see `with_proof_cache`.
+    proof_cache: ProofCache,
+
+    /// Records the nodes read from the database, see `with_witness_recorder`.
+    witness: Option<WitnessRecorder>,
+
+    /// Notified of every commit, see `with_root_history`.
+    root_history: Option<Arc<dyn RootHistory>>,
+
+    phantom: PhantomData<fn() -> (H, C)>,
+}
+
+/// Configures and creates a `PatriciaTrie`.
+///
+/// `new`, `from` and the other constructors are shorthands for common configurations.
+#[derive(Debug, Clone)]
+pub struct PatriciaTrieBuilder<D, H = HasherKeccak, C = RlpCodec> {
+    db: Option<D>,
+    backup_db: Option<D>,
+    cache_capacity: usize,
+    node_cache: Option<NodeCache<D, H, C>>,
+    read_cache: bool,
+    proof_cache_capacity: usize,
+    strict: bool,
+    value_store: Option<ValueStore>,
+    allow_empty_values: bool,
+    max_decode_depth: usize,
+    inline_small_nodes: bool,
+    max_key_len: usize,
+    empty_root: Option<[u8; 32]>,
+    defer_commit: bool,
+    deferred_nodes: Option<DeferredNodes>,
+    witness: Option<WitnessRecorder>,
+    root_history: Option<Arc<dyn RootHistory>>,
+    root: Option<Vec<u8>>,
+    phantom: PhantomData<fn() -> (H, C)>,
+}
+
+impl<D, H, C> Default for PatriciaTrieBuilder<D, H, C> {
+    fn default() -> Self {
+        PatriciaTrieBuilder {
+            db: None,
+            backup_db: None,
+            cache_capacity: DEFAULT_CACHE_CAPACITY,
+            node_cache: None,
+            read_cache: true,
+            proof_cache_capacity: 0,
+            strict: false,
+            value_store: None,
+            allow_empty_values: false,
+            max_decode_depth: DEFAULT_MAX_DECODE_DEPTH,
+            inline_small_nodes: true,
+            max_key_len: DEFAULT_MAX_KEY_LEN,
+            empty_root: None,
+            defer_commit: false,
+            deferred_nodes: None,
+            witness: None,
+            root_history: None,
+            root: None,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<D> PatriciaTrieBuilder<D> {
+    /// Creates a builder for the default hasher and codec, use `default` for others.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<D, H, C> PatriciaTrieBuilder<D, H, C>
+where
+    D: DB + Clone,
+    H: Hasher,
+    C: NodeCodec,
+{
+    /// Sets the database holding the nodes, required.
+    pub fn db(mut self, db: D) -> Self {
+        self.db = Some(db);
+        self
+    }
+
+    /// Sets the database `extract_backup` copies the nodes to.
+    pub fn backup_db(mut self, backup_db: D) -> Self {
+        self.backup_db = Some(backup_db);
+        self
+    }
+
+    /// Sets how many nodes expanded in `get` op are kept, `DEFAULT_CACHE_CAPACITY` by default.
+    pub fn cache_capacity(mut self, cache_capacity: usize) -> Self {
+        self.cache_capacity = cache_capacity;
+        self
+    }
+
+    /// Keeps the nodes expanded in `get` op in `cache`, shared with the other tries using
+    /// it. The cache capacity is ignored.
+    pub fn node_cache(mut self, cache: NodeCache<D, H, C>) -> Self {
+        self.node_cache = Some(cache);
+        self
+    }
+
+    /// Whether the nodes expanded in `get` op are cached, enabled by default. Without
+    /// the cache every read expands the stored nodes on its path again, e.g. for
+    /// imports which write far more than they read. The capacity and `node_cache` are
+    /// ignored once disabled.
+    pub fn enable_read_cache(mut self, enable: bool) -> Self {
+        self.read_cache = enable;
+        self
+    }
+
+    /// Sets how many proofs `get_proof` keeps, see `PatriciaTrie::with_proof_cache`.
+    /// Proofs are not cached by default.
+    pub fn proof_cache_capacity(mut self, capacity: usize) -> Self {
+        self.proof_cache_capacity = capacity;
+        self
+    }
+
+    /// Checks the hash of every node recovered from the database, see `from_strict`.
+    pub fn strict_integrity(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Stores large values by hash, see `ValueStore`.
+    pub fn value_store(mut self, value_store: ValueStore) -> Self {
+        self.value_store = Some(value_store);
+        self
+    }
+
+    /// Stores empty values as present, `get` returns `Some(vec![])` for them and `iter`
+    /// yields them. By default an empty value removes the key, as in Ethereum.
+    ///
+    /// A branch can't otherwise tell an empty value from none, so values are tagged as
+    /// with a `ValueStore` and the roots differ from those of a default trie.
+    pub fn allow_empty_values(mut self, allow: bool) -> Self {
+        self.allow_empty_values = allow;
+        self
+    }
+
+    /// See `PatriciaTrie::set_max_decode_depth`.
+    pub fn max_decode_depth(mut self, max_depth: usize) -> Self {
+        self.max_decode_depth = max_depth;
+        self
+    }
+
+    /// Whether nodes encoded in less than `C::INLINE_THRESHOLD` bytes are embedded in
+    /// their parent, enabled by default as in Ethereum.
+    ///
+    /// Once disabled every node is stored under its hash, so the database holds one
+    /// entry per node, e.g. for experiments on proof sizes. The roots differ from
+    /// Ethereum's, and proofs of such a trie are only accepted by its `verify_proof`,
+    /// the free functions drop the small nodes of a proof.
+    pub fn inline_small_nodes(mut self, inline: bool) -> Self {
+        self.inline_small_nodes = inline;
+        self
+    }
+
+    /// Sets the maximum length of a key in bytes, `DEFAULT_MAX_KEY_LEN` by default.
+    ///
+    /// Every method taking a longer key, key prefix or range bound fails with
+    /// `TrieError::KeyTooLong`, as the nodes are handled recursively and a long key of
+    /// untrusted input would build a chain of nodes deep enough to overflow the stack.
+    /// `range` can't fail and doesn't check its bounds, its descent stops at the stored
+    /// keys.
+    pub fn max_key_len(mut self, max_len: usize) -> Self {
+        self.max_key_len = max_len;
+        self
+    }
+
+    /// Sets the root hash of the empty trie, the hash of the empty node by default.
+    ///
+    /// For chains following another convention, e.g. an all-zero hash.
+    pub fn empty_root(mut self, empty_root: [u8; 32]) -> Self {
+        self.empty_root = Some(empty_root);
+        self
+    }
+
+    /// See `PatriciaTrie::set_defer_commit`.
+    pub fn defer_commit(mut self, defer: bool) -> Self {
+        self.defer_commit = defer;
+        self
+    }
+
+    /// Records the nodes the trie reads from the database in `recorder`, including the
+    /// root read by `build_from_root`. See `PatriciaTrie::with_witness_recorder`.
+    pub fn witness_recorder(mut self, recorder: WitnessRecorder) -> Self {
+        self.witness = Some(recorder);
+        self
+    }
+
+    /// See `PatriciaTrie::with_root_history`.
+    pub fn root_history(mut self, history: impl RootHistory + 'static) -> Self {
+        self.root_history = Some(Arc::new(history));
+        self
+    }
+
+    /// Sets the root hash `build_from_root` opens the trie at.
+    pub fn root(mut self, root: &[u8]) -> Self {
+        self.root = Some(root.to_vec());
+        self
+    }
+
+    /// Creates an empty trie, the root is ignored.
+    ///
+    /// Panics if no database was set.
+    pub fn build(self) -> PatriciaTrie<D, H, C> {
+        let empty_root = self
+            .empty_root
+            .unwrap_or_else(|| H::digest(&C::encode_empty()));
+        PatriciaTrie {
+            root: Node::Empty,
+            root_hash: empty_root.to_vec(),
+
+            recovered_nodes_hashes: Default::default(),
+
+            db: self.db.expect("PatriciaTrieBuilder requires a db"),
+            backup_db: self.backup_db,
+            strict: self.strict,
+            value_store: self.value_store,
+            allow_empty_values: self.allow_empty_values,
+            max_decode_depth: self.max_decode_depth,
+            inline_small_nodes: self.inline_small_nodes,
+            max_key_len: self.max_key_len,
+            empty_root,
+            defer_commit: self.defer_commit,
+            deferred_nodes: self.deferred_nodes.unwrap_or_default(),
+            deferred_pruned: Default::default(),
+            dirty: false,
+
+            cached_tries: match self.node_cache {
+                _ if !self.read_cache => None,
+                Some(cache) => Some(cache.0),
+                None => Some(Arc::new(Mutex::new(LruCache::new(self.cache_capacity)))),
+            },
+            proof_cache: Arc::new(Mutex::new(LruCache::new(self.proof_cache_capacity))),
+            witness: self.witness,
+            root_history: self.root_history,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Opens the trie at the root hash, returns `TrieError::InvalidStateRoot`
+    /// if the database doesn't have it or the stored root doesn't match the hash.
+    /// The empty root is always valid.
+    ///
+    /// Panics if no database or no root was set.
+    pub fn build_from_root(mut self) -> TrieResult<PatriciaTrie<D, H, C>> {
+        let root = self
+            .root
+            .take()
+            .expect("PatriciaTrieBuilder::build_from_root requires a root");
+        self.build().load_state_root(&root)
+    }
+}
+
+/// A saved state of the uncommitted trie, see `PatriciaTrie::checkpoint`.
+#[derive(Debug)]
+pub struct Checkpoint {
+    root: Node,
+    root_hash: Vec<u8>,
+    dirty: bool,
+    recovered_nodes_hashes: HashSet<[u8; 32]>,
+}
+
+impl Drop for Checkpoint {
+    fn drop(&mut self) {
+        unsafe { Node::dealloc(self.root.clone()) }
+    }
+}
+
+/// A chunk of entries and the key to resume from, see `iter_chunked`.
+type Chunk = (Vec<(Vec<u8>, Vec<u8>)>, Option<Vec<u8>>);
+
+type CachedTries<D, H, C> = Arc<Mutex<LruCache<[u8; 32], Arc<PatriciaTrie<D, H, C>>>>>;
+
+/// Nodes expanded in `get` op, shared by the tries built with it, see
+/// `PatriciaTrieBuilder::node_cache`.
+///
+/// Clones share the cache. Nodes are decoded with the settings of the trie expanding
+/// them, so the tries sharing a cache must use the same database and settings.
+#[derive(Debug)]
+pub struct NodeCache<D, H = HasherKeccak, C = RlpCodec>(CachedTries<D, H, C>);
+
+impl<D, H, C> NodeCache<D, H, C> {
+    /// Creates a cache keeping at most `capacity` nodes.
+    pub fn new(capacity: usize) -> Self {
+        NodeCache(Arc::new(Mutex::new(LruCache::new(capacity))))
+    }
+}
+
+impl<D, H, C> Clone for NodeCache<D, H, C> {
+    fn clone(&self) -> Self {
+        NodeCache(self.0.clone())
+    }
+}
+
+/// Collects the nodes a trie reads from the database, a witness for the operations
+/// run on the trie, see `PatriciaTrie::with_witness_recorder`.
+///
+/// Clones share the recorded nodes.
+#[derive(Debug, Clone, Default)]
+pub struct WitnessRecorder(Arc<Mutex<HashMap<Vec<u8>, Vec<u8>>>>);
+
+impl WitnessRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn record(&self, hash: &[u8], data: &[u8]) {
+        self.0
+            .lock()
+            .entry(hash.to_vec())
+            .or_insert_with(|| data.to_vec());
+    }
+
+    /// Returns the nodes recorded so far, sorted by hash, and starts over.
+    pub fn take(&self) -> Vec<Vec<u8>> {
+        let mut nodes = self.0.lock().drain().collect::<Vec<_>>();
+        nodes.sort_unstable();
+        nodes.into_iter().map(|(_, data)| data).collect()
+    }
+}
+
+/// Notified of the roots committed by a trie, see `PatriciaTrie::with_root_history`.
+pub trait RootHistory: fmt::Debug + Send + Sync {
+    /// Called once the commit replacing `previous_root` by `new_root` succeeded. The
+    /// roots are equal for a commit without changes.
+    fn record(&self, previous_root: &[u8], new_root: &[u8]);
+}
+
+/// A `RootHistory` keeping every committed root in order.
+///
+/// Clones share the roots.
+#[derive(Debug, Clone, Default)]
+pub struct VecRootHistory(Arc<Mutex<Vec<Vec<u8>>>>);
+
+impl VecRootHistory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the roots committed so far, oldest first.
+    pub fn roots(&self) -> Vec<Vec<u8>> {
+        self.0.lock().clone()
+    }
+}
+
+impl RootHistory for VecRootHistory {
+    fn record(&self, _previous_root: &[u8], new_root: &[u8]) {
+        self.0.lock().push(new_root.to_vec());
+    }
 }
 
-impl<D> Drop for PatriciaTrie<D> {
+/// Encoded nodes keyed by hash.
+type DeferredNodes = Arc<RwLock<HashMap<Vec<u8>, Vec<u8>>>>;
+
+/// Proofs keyed by (root hash, key).
+type ProofCache = Arc<Mutex<LruCache<(Vec<u8>, Vec<u8>), Vec<Vec<u8>>>>>;
+
+impl<D, H, C> Drop for PatriciaTrie<D, H, C> {
     fn drop(&mut self) {
         unsafe { Node::dealloc(self.root.clone()) }
     }
@@ -87,17 +510,20 @@ enum TraceStatus {
 struct TraceNode {
     node: Node,
     status: TraceStatus,
+    // Hash the node was recovered from, `None` for embedded and modified nodes.
+    hash: Option<[u8; 32]>,
 }
 
 impl TraceNode {
-    fn advance(&mut self) {
-        self.status = match &self.status {
-            TraceStatus::Start => TraceStatus::Doing,
-            TraceStatus::Doing => match self.node {
-                Node::Branch(_) => TraceStatus::Child(0),
-                _ => TraceStatus::End,
-            },
-            TraceStatus::Child(i) if *i < 15 => TraceStatus::Child(i + 1),
+    // In reverse the children of a branch are visited from 15 down to 0 before its value.
+    fn advance(&mut self, reverse: bool) {
+        self.status = match (&self.status, &self.node, reverse) {
+            (TraceStatus::Start, Node::Branch(_), true) => TraceStatus::Child(15),
+            (TraceStatus::Start, _, _) => TraceStatus::Doing,
+            (TraceStatus::Doing, Node::Branch(_), false) => TraceStatus::Child(0),
+            (TraceStatus::Child(i), _, false) if *i < 15 => TraceStatus::Child(i + 1),
+            (TraceStatus::Child(i), _, true) if *i > 0 => TraceStatus::Child(i - 1),
+            (TraceStatus::Child(_), _, true) => TraceStatus::Doing,
             _ => TraceStatus::End,
         }
     }
@@ -108,31 +534,410 @@ impl From<Node> for TraceNode {
         TraceNode {
             node,
             status: TraceStatus::Start,
+            hash: None,
         }
     }
 }
 
-pub struct TrieIterator<'a, D>
+/// Stores values longer than `threshold` bytes under their hash in the database,
+/// so identical large values are stored once and leaves only hold a reference.
+///
+/// Values are tagged as inline or referenced, so this changes the node encoding and
+/// the roots differ from those of a default trie with the same content. Referenced
+/// values may be shared, so they are never pruned. Proofs end with the values the
+/// nodes on the path refer to, verify them with `Trie::verify_proof` of a trie with
+/// the same store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValueStore {
+    pub threshold: usize,
+}
+
+const INLINE_VALUE: u8 = 0;
+const VALUE_REF: u8 = 1;
+
+/// The database changes of a commit, see `commit_preview`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommitPreview {
+    /// The root hash after the commit.
+    pub root_hash: Vec<u8>,
+    /// Number of nodes the commit inserts.
+    pub new_nodes: usize,
+    /// Total encoded size of the inserted nodes.
+    pub new_bytes: usize,
+    /// Hashes of the recovered nodes the commit removes.
+    pub pruned: Vec<[u8; 32]>,
+}
+
+/// The database writes of a commit, see `commit_with_changeset`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChangeSet {
+    /// The inserted nodes keyed by hash, sorted by hash.
+    pub inserted: Vec<(Vec<u8>, Vec<u8>)>,
+    /// Hashes of the removed nodes.
+    pub removed: Vec<Vec<u8>>,
+}
+
+/// What a proof returned by `get_proof_kind` proves.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProofKind {
+    /// The key is in the trie with this value.
+    Inclusion(Vec<u8>),
+    /// The key is not in the trie.
+    Exclusion,
+}
+
+struct PendingCommit {
+    root_hash: Vec<u8>,
+    nodes: HashMap<Vec<u8>, Vec<u8>>,
+    pruned: Vec<[u8; 32]>,
+}
+
+/// Where an entry yielded by `iter_with_meta` sits in the trie.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LeafMeta {
+    /// Number of key nibbles consumed above the node holding the value.
+    pub depth: usize,
+    /// Hash of the parent of the node holding the value, `None` if the parent is
+    /// embedded in its own parent, modified since the last commit or doesn't exist.
+    pub parent_hash: Option<[u8; 32]>,
+}
+
+/// How an insert changes the structure of the trie, see `insert_cost`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InsertKind {
+    /// The key exists, only its value is replaced.
+    Overwrite,
+    /// The key gets a new leaf, or the empty value slot of a branch.
+    NewLeaf,
+    /// A leaf is split into a branch holding both keys.
+    SplitLeaf,
+    /// An extension is split by a branch where the key leaves its prefix.
+    SplitExtension,
+}
+
+/// The structural cost of an insert, see `insert_cost`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InsertCost {
+    pub kind: InsertKind,
+    /// Number of existing nodes on the path which are modified, so they are hashed
+    /// again on the next commit.
+    pub modified_nodes: usize,
+    /// Number of nodes the insert creates, hashed on the next commit.
+    pub new_nodes: usize,
+}
+
+pub struct TrieIterator<'a, D, H = HasherKeccak, C = RlpCodec>
 where
     D: DB + Clone,
+    H: Hasher,
+    C: NodeCodec,
 {
-    trie: &'a PatriciaTrie<D>,
+    trie: &'a PatriciaTrie<D, H, C>,
     nibble: NibbleVec,
     nodes: Vec<TraceNode>,
     recovered_nodes: Rc<RefCell<Vec<Node>>>,
+    reverse: bool,
+    // Whether this end yielded an entry, whose key is then still in `nibble`.
+    started: bool,
+    // The other end, created by the first `next_back`.
+    back: Option<Cursor>,
+    // The error which ended the iteration early, if any.
+    error: Option<TrieError>,
+}
+
+/// The entries under a key prefix, see `PatriciaTrie::subtree`.
+///
+/// Keys are relative to the prefix, i.e. without it. The handle keeps the node the
+/// prefix leads to, so its operations don't descend from the root again.
+pub struct Subtree<'a, D, H = HasherKeccak, C = RlpCodec>
+where
+    D: DB + Clone,
+    H: Hasher,
+    C: NodeCodec,
+{
+    trie: &'a PatriciaTrie<D, H, C>,
+    prefix: Vec<u8>,
+    // The first node whose keys all start with the prefix, and the number of nibbles
+    // of its own key or extension prefix which are part of the prefix.
+    node: Node,
+    skip: usize,
+    // The expanded trie the node belongs to, when it was found below a hash node.
+    _owner: Option<Arc<PatriciaTrie<D, H, C>>>,
+}
+
+impl<'a, D, H, C> Subtree<'a, D, H, C>
+where
+    D: DB + Clone,
+    H: Hasher,
+    C: NodeCodec,
+{
+    pub fn prefix(&self) -> &[u8] {
+        &self.prefix
+    }
+
+    /// Iterates over the entries in ascending key order, with the keys relative to the
+    /// prefix. Same as `PatriciaTrie::iter`, the iteration ends early if a node can't
+    /// be recovered from the database.
+    pub fn iter(&self) -> impl Iterator<Item = (Vec<u8>, Vec<u8>)> + '_ {
+        let strip = self.prefix.len();
+        self.entries()
+            .map(move |(key, value)| (key[strip..].to_vec(), value))
+    }
+
+    /// Returns the number of entries.
+    pub fn len(&self) -> TrieResult<usize> {
+        let mut entries = self.entries();
+        let len = (&mut entries).count();
+        match entries.error.take() {
+            Some(e) => Err(e),
+            None => Ok(len),
+        }
+    }
+
+    /// Always `false`, a `Subtree` holds at least one entry.
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
+    /// Returns the root hash of the trie holding the entries with their relative keys,
+    /// computed from the nodes in memory.
+    pub fn hash(&self) -> Vec<u8> {
+        let encoded = match self.node {
+            _ if self.skip == 0 => self.trie.encode_raw(self.node.clone(), &mut HashMap::new()),
+            Node::Leaf(ref leaf) => {
+                let leaf_ref = unsafe { leaf.as_ref() };
+                let key = leaf_ref.key.offset(self.skip).to_owned();
+                let leaf = Node::from_leaf(key, leaf_ref.value.clone());
+                let encoded = self.trie.encode_raw(leaf.clone(), &mut HashMap::new());
+                unsafe { Node::dealloc(leaf) };
+                encoded
+            }
+            Node::Extension(ref ext) => {
+                let ext_ref = unsafe { ext.as_ref() };
+                let prefix = ext_ref.prefix.offset(self.skip).to_owned();
+                let ext = Node::from_extension(prefix, ext_ref.node.clone());
+                let encoded = self.trie.encode_raw(ext.clone(), &mut HashMap::new());
+                // Only the new extension is freed, its child belongs to the trie.
+                if let Node::Extension(ext) = ext {
+                    drop(unsafe { to_owned(ext) });
+                }
+                encoded
+            }
+            _ => unreachable!("only keys and extension prefixes are skipped"),
+        };
+        H::digest(&encoded).to_vec()
+    }
+
+    // Iterates with the full keys, starting at the node on its path.
+    fn entries(&self) -> TrieIterator<'a, D, H, C> {
+        let mut nibble = NibbleVec::from_raw(self.prefix.clone(), false);
+        nibble.truncate(nibble.len() - self.skip);
+        let mut iter = self.trie.iter();
+        iter.nibble = nibble;
+        iter.nodes = vec![self.node.clone().into()];
+        iter
+    }
+}
+
+// The state of one end of a `TrieIterator`, swapped in by `next_back`.
+struct Cursor {
+    nibble: NibbleVec,
+    nodes: Vec<TraceNode>,
+    started: bool,
+}
+
+impl Cursor {
+    fn new(nodes: Vec<TraceNode>) -> Self {
+        Cursor {
+            nibble: NibbleVec::from_raw(vec![], false),
+            nodes,
+            started: false,
+        }
+    }
 }
 
-impl<'a, D> Iterator for TrieIterator<'a, D>
+impl<'a, D, H, C> Iterator for TrieIterator<'a, D, H, C>
 where
     D: DB + Clone,
+    H: Hasher,
+    C: NodeCodec,
 {
     type Item = (Vec<u8>, Vec<u8>);
 
     fn next(&mut self) -> Option<Self::Item> {
+        self.next_with_meta().map(|(key, value, _)| (key, value))
+    }
+}
+
+// An iteration stopped early, e.g. by `take`, still owns the nodes it recovered.
+impl<'a, D, H, C> Drop for TrieIterator<'a, D, H, C>
+where
+    D: DB + Clone,
+    H: Hasher,
+    C: NodeCodec,
+{
+    fn drop(&mut self) {
+        for n in self.recovered_nodes.borrow_mut().drain(..) {
+            unsafe { Node::dealloc(n) }
+        }
+    }
+}
+
+/// The back end walks the trie in the other direction, the iteration ends once both
+/// ends reach the same entry.
+impl<'a, D, H, C> DoubleEndedIterator for TrieIterator<'a, D, H, C>
+where
+    D: DB + Clone,
+    H: Hasher,
+    C: NodeCodec,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.swap_ends();
+        let item = self.next_with_meta();
+        self.swap_ends();
+        item.map(|(key, value, _)| (key, value))
+    }
+}
+
+impl<'a, D, H, C> TrieIterator<'a, D, H, C>
+where
+    D: DB + Clone,
+    H: Hasher,
+    C: NodeCodec,
+{
+    fn swap_ends(&mut self) {
+        let trie = self.trie;
+        let back = self
+            .back
+            .get_or_insert_with(|| Cursor::new(vec![trie.root.clone().into()]));
+        std::mem::swap(&mut self.nibble, &mut back.nibble);
+        std::mem::swap(&mut self.nodes, &mut back.nodes);
+        std::mem::swap(&mut self.started, &mut back.started);
+        self.reverse = !self.reverse;
+    }
+
+    // Whether the entry at `nibble` was already yielded by the other end.
+    fn crossed(&self) -> bool {
+        let back = match &self.back {
+            Some(back) if back.started => back,
+            _ => return false,
+        };
+        fn key(nibble: &NibbleVec) -> &[u8] {
+            match nibble.as_bytes().split_last() {
+                Some((16, key)) => key,
+                _ => nibble.as_bytes(),
+            }
+        }
+        let order = key(&self.nibble).cmp(key(&back.nibble));
+        if self.reverse {
+            order != Ordering::Greater
+        } else {
+            order != Ordering::Less
+        }
+    }
+
+    fn meta(&self) -> LeafMeta {
+        let len = self.nodes.len();
+        LeafMeta {
+            depth: self.nibble.len(),
+            parent_hash: if len > 1 {
+                self.nodes[len - 2].hash
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Positions the iterator so the next entry is the first one with a key >= start,
+    /// recovering only the nodes on the path to it.
+    fn seek(&mut self, start: &[u8]) {
+        debug_assert!(!self.reverse);
+        let target = NibbleVec::from_raw(start.to_vec(), false);
+        let mut rest = target.as_bytes();
+        while let Some(top) = self.nodes.last() {
+            match top.node.clone() {
+                Node::Empty => return,
+                Node::Hash(hash_node) => {
+                    let hash = unsafe { hash_node.as_ref() }.hash;
+                    match self.trie.recover_from_db(&hash) {
+                        Ok(n) => {
+                            self.nodes.pop();
+                            self.recovered_nodes.borrow_mut().push(n.clone());
+                            self.nodes.push(TraceNode {
+                                hash: Some(hash),
+                                ..n.into()
+                            });
+                        }
+                        Err(e) => {
+                            self.nodes.clear();
+                            self.error = Some(e);
+                            return;
+                        }
+                    }
+                }
+                Node::Leaf(leaf) => {
+                    let key = unsafe { leaf.as_ref() }.key.clone();
+                    let key = match key.as_bytes().split_last() {
+                        Some((16, key)) => key,
+                        _ => key.as_bytes(),
+                    };
+                    if key < rest {
+                        self.nodes.pop();
+                    }
+                    return;
+                }
+                Node::Extension(ext) => {
+                    let (prefix, child) = {
+                        let ext_ref = unsafe { ext.as_ref() };
+                        (ext_ref.prefix.clone(), ext_ref.node.clone())
+                    };
+                    let len = prefix.len().min(rest.len());
+                    match prefix.as_bytes()[..len].cmp(&rest[..len]) {
+                        Ordering::Greater => return,
+                        Ordering::Less => {
+                            self.nodes.pop();
+                            return;
+                        }
+                        // Every key below starts with the rest of start.
+                        Ordering::Equal if rest.len() <= prefix.len() => return,
+                        Ordering::Equal => {
+                            // Same as visiting the extension in `next_with_meta`.
+                            self.nibble.extend_from_slice(&prefix);
+                            self.nodes.last_mut().unwrap().status = TraceStatus::End;
+                            self.nodes.push(child.into());
+                            rest = &rest[prefix.len()..];
+                        }
+                    }
+                }
+                Node::Branch(branch) => {
+                    if rest.is_empty() {
+                        return;
+                    }
+                    // The value and the children before rest[0] are smaller than start.
+                    let i = rest[0];
+                    let child = unsafe { branch.as_ref() }.children[i as usize].clone();
+                    self.nibble.push(i);
+                    let top = self.nodes.last_mut().unwrap();
+                    top.status = TraceStatus::Child(i);
+                    top.advance(false);
+                    self.nodes.push(child.into());
+                    rest = &rest[1..];
+                }
+            }
+        }
+    }
+
+    fn next_with_meta(&mut self) -> Option<(Vec<u8>, Vec<u8>, LeafMeta)> {
+        self.next_with(|nibble, value, meta| (nibble.encode_raw().0, value.to_vec(), meta))
+    }
+
+    /// Advances to the next entry and passes its key nibbles and value to `f`, borrowed
+    /// from the iterator and the node.
+    fn next_with<R>(&mut self, f: impl FnOnce(&NibbleSlice, &[u8], LeafMeta) -> R) -> Option<R> {
         loop {
             let mut now = self.nodes.last().cloned();
             if let Some(ref mut now) = now {
-                self.nodes.last_mut().unwrap().advance();
+                self.nodes.last_mut().unwrap().advance(self.reverse);
 
                 match (now.status.clone(), &now.node) {
                     (TraceStatus::End, node) => {
@@ -149,7 +954,8 @@ where
                                     .truncate(cur_len - unsafe { ext.as_ref() }.prefix.len());
                             }
 
-                            Node::Branch(_) => {
+                            // In reverse the child nibble was popped before the value.
+                            Node::Branch(_) if !self.reverse => {
                                 self.nibble.pop();
                             }
                             _ => {}
@@ -165,42 +971,61 @@ where
                     }
 
                     (TraceStatus::Doing, Node::Leaf(ref leaf)) => {
-                        self.nibble.extend_from_slice(&unsafe { leaf.as_ref() }.key);
-                        return Some((
-                            self.nibble.encode_raw().0,
-                            unsafe { leaf.as_ref() }.value.clone(),
-                        ));
+                        let meta = self.meta();
+                        let leaf_ref = unsafe { leaf.as_ref() };
+                        self.nibble.extend_from_slice(&leaf_ref.key);
+                        if self.crossed() {
+                            self.nodes.clear();
+                            continue;
+                        }
+                        self.started = true;
+                        return Some(f(&self.nibble, &leaf_ref.value, meta));
                     }
 
                     (TraceStatus::Doing, Node::Branch(ref branch)) => {
-                        let value = unsafe { branch.as_ref() }.value.clone();
-                        if let Some(data) = value {
-                            return Some((self.nibble.encode_raw().0, data));
+                        if self.reverse {
+                            self.nibble.pop();
+                        }
+                        let branch_ref = unsafe { branch.as_ref() };
+                        if let Some(data) = &branch_ref.value {
+                            if self.crossed() {
+                                self.nodes.clear();
+                                continue;
+                            }
+                            self.started = true;
+                            return Some(f(&self.nibble, data, self.meta()));
                         } else {
                             continue;
                         }
                     }
 
                     (TraceStatus::Doing, Node::Hash(ref hash_node)) => {
-                        if let Ok(n) = self
-                            .trie
-                            .recover_from_db(&unsafe { hash_node.as_ref() }.hash.clone())
-                        {
-                            self.nodes.pop();
-                            self.recovered_nodes.borrow_mut().push(n.clone());
-                            self.nodes.push(n.into());
-                        } else {
-                            #[cfg(feature = "tracing")]
-                            tracing::error!(
-                                "Iterator::next - Could not recover hash node from database"
-                            );
-                            return None;
+                        let hash = unsafe { hash_node.as_ref() }.hash;
+                        match self.trie.recover_from_db(&hash) {
+                            Ok(n) => {
+                                self.nodes.pop();
+                                self.recovered_nodes.borrow_mut().push(n.clone());
+                                self.nodes.push(TraceNode {
+                                    hash: Some(hash),
+                                    ..n.into()
+                                });
+                            }
+                            Err(e) => {
+                                #[cfg(feature = "tracing")]
+                                tracing::error!(
+                                    "Iterator::next - Could not recover hash node from database"
+                                );
+                                // The iteration ends here, also for a later `next`.
+                                self.nodes.clear();
+                                self.error = Some(e);
+                                return None;
+                            }
                         }
                     }
 
                     (TraceStatus::Child(i), Node::Branch(ref branch)) => {
-                        if i == 0 {
-                            self.nibble.push(0);
+                        if i == if self.reverse { 15 } else { 0 } {
+                            self.nibble.push(i);
                         } else {
                             self.nibble.pop();
                             self.nibble.push(i);
@@ -215,6 +1040,12 @@ where
                     _ => {}
                 }
             } else {
+                // Once one end is done so is the other, only then the recovered nodes
+                // are not used any more.
+                self.back
+                    .get_or_insert_with(|| Cursor::new(vec![]))
+                    .nodes
+                    .clear();
                 for n in self.recovered_nodes.borrow_mut().drain(..) {
                     unsafe { Node::dealloc(n) }
                 }
@@ -228,49 +1059,93 @@ impl<D> PatriciaTrie<D>
 where
     D: DB + Clone,
 {
-    pub fn iter(&self) -> TrieIterator<D> {
-        let nodes = vec![self.root.clone().into()];
-        TrieIterator {
-            trie: self,
-            nibble: NibbleVec::from_raw(vec![], false),
-            nodes,
-            recovered_nodes: Default::default(),
-        }
-    }
     pub fn new(db: D) -> Self {
-        Self {
-            root: Node::Empty,
-            root_hash: sha3::Keccak256::digest(rlp::NULL_RLP.as_ref()).to_vec(),
+        Self::create(db)
+    }
 
-            recovered_nodes_hashes: Default::default(),
+    /// Creates an empty trie which keeps at most `cache_capacity` nodes expanded in `get` op.
+    pub fn with_cache_capacity(db: D, cache_capacity: usize) -> Self {
+        PatriciaTrieBuilder::new()
+            .db(db)
+            .cache_capacity(cache_capacity)
+            .build()
+    }
 
-            db,
-            backup_db: None,
+    /// Opens the trie at `root`. Any stored node can be opened this way, but
+    /// `subtrie_from` checks that its hash points to a node of a trie.
+    pub fn from(db: D, root: &[u8]) -> TrieResult<Self> {
+        Self::open(db, root)
+    }
 
-            cached_tries: Default::default(),
-        }
+    /// Opens the subtree under the stored node `node_hash` as a standalone trie, e.g.
+    /// a node found with `reachable_hashes`.
+    ///
+    /// Keys are relative to the node, i.e. without the nibbles on the path to it; use
+    /// `get_nibbles` if the path has an odd number of nibbles. The node is checked to
+    /// hash to `node_hash` and to be a leaf, an extension or a branch, otherwise
+    /// `TrieError::InvalidData` is returned.
+    pub fn subtrie_from(db: D, node_hash: &[u8]) -> TrieResult<Self> {
+        Self::open_subtrie(db, node_hash)
     }
 
-    pub fn from(db: D, root: &[u8]) -> TrieResult<Self> {
-        match db.get(root).map_err(|e| TrieError::DB(e.to_string()))? {
-            Some(data) => {
-                let mut trie = Self {
-                    root: Node::Empty,
-                    root_hash: root.to_vec(),
+    /// Removes every node from `db` which is not reachable from one of `live_roots`
+    /// and returns the number of removed nodes, see `collect_garbage`.
+    pub fn gc(db: &mut D, live_roots: &[&[u8]]) -> TrieResult<usize> {
+        Self::collect_garbage(db, live_roots)
+    }
 
-                    recovered_nodes_hashes: Default::default(),
+    /// Same as `gc`, for tries created with `with_value_store`, the values they refer
+    /// to are kept. See `collect_garbage_with_value_store`.
+    pub fn gc_with_value_store(
+        db: &mut D,
+        live_roots: &[&[u8]],
+        value_store: ValueStore,
+    ) -> TrieResult<usize> {
+        Self::collect_garbage_with_value_store(db, live_roots, value_store)
+    }
 
-                    db,
-                    backup_db: None,
+    /// Returns the metadata stored by `commit_with_meta` along with root, `None` if there
+    /// is none. Works for tries with any hasher and codec.
+    pub fn load_meta(db: &D, root: &[u8]) -> TrieResult<Option<Vec<u8>>> {
+        db.get(&[root, META_SUFFIX].concat())
+            .map_err(|e| TrieError::DB(e.to_string()))
+    }
 
-                    cached_tries: Default::default(),
-                };
+    /// Creates an empty trie whose root hash is `empty_root`, for chains which don't
+    /// use the hash of the empty node. Open it with the builder and the same empty root.
+    pub fn new_with_empty_root(db: D, empty_root: [u8; 32]) -> Self {
+        PatriciaTrieBuilder::new()
+            .db(db)
+            .empty_root(empty_root)
+            .build()
+    }
 
-                trie.root = trie.decode_node(&data)?;
-                Ok(trie)
-            }
-            None => Err(TrieError::InvalidStateRoot),
-        }
+    /// Creates an empty trie which stores large values by hash, see `ValueStore`.
+    pub fn with_value_store(db: D, value_store: ValueStore) -> Self {
+        PatriciaTrieBuilder::new()
+            .db(db)
+            .value_store(value_store)
+            .build()
+    }
+
+    /// Opens a trie created by `with_value_store` at `root`.
+    pub fn from_with_value_store(db: D, root: &[u8], value_store: ValueStore) -> TrieResult<Self> {
+        PatriciaTrieBuilder::new()
+            .db(db)
+            .value_store(value_store)
+            .root(root)
+            .build_from_root()
+    }
+
+    /// Same as `from`, but every node recovered from the database, including the root,
+    /// is checked to hash to the key it was stored under.
+    /// Returns `TrieError::CorruptedNode` on mismatch instead of trusting the data.
+    pub fn from_strict(db: D, root: &[u8]) -> TrieResult<Self> {
+        PatriciaTrieBuilder::new()
+            .db(db)
+            .strict_integrity(true)
+            .root(root)
+            .build_from_root()
     }
 
     // extract specified height statedb in full node mode
@@ -279,28 +1154,19 @@ where
         backup_db: D,
         root_hash: &[u8],
     ) -> TrieResult<(Self, Vec<Vec<u8>>)> {
-        let mut pt = Self {
-            root: Node::Empty,
-            root_hash: sha3::Keccak256::digest(rlp::NULL_RLP.as_ref()).to_vec(),
-
-            recovered_nodes_hashes: Default::default(),
-
-            db,
-            backup_db: Some(backup_db),
-
-            cached_tries: Default::default(),
-        };
-
-        let root = pt.recover_from_db(root_hash)?;
-        pt.root = root.clone();
-        pt.root_hash = root_hash.to_vec();
+        let pt = PatriciaTrieBuilder::new()
+            .db(db)
+            .backup_db(backup_db)
+            .root(root_hash)
+            .build_from_root()?;
+        let root = pt.root.clone();
 
         let mut addr_list = vec![];
         pt.iter().for_each(|(k, _v)| addr_list.push(k));
         let mut cache: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
         let encoded = pt.cache_node(root, &mut cache)?;
         {
-            cache.insert(sha3::Keccak256::digest(&encoded).to_vec(), encoded);
+            cache.insert(HasherKeccak::digest(&encoded).to_vec(), encoded);
 
             // store data in backup db
             pt.backup_db
@@ -316,1004 +1182,7151 @@ where
             .map_err(|e| TrieError::DB(e.to_string()))?;
         Ok((pt, addr_list))
     }
+
+    /// Builds a trie in `db` from key/value pairs and commits it.
+    pub fn from_iter_in<I>(db: D, iter: I) -> TrieResult<Self>
+    where
+        I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    {
+        let mut trie = Self::new(db);
+        for (key, value) in iter {
+            trie.insert(key, value)?;
+        }
+        trie.commit()?;
+        Ok(trie)
+    }
 }
 
-impl<D> Trie<D> for PatriciaTrie<D>
+impl<D, H, C> PatriciaTrie<D, H, C>
 where
     D: DB + Clone,
+    H: Hasher,
+    C: NodeCodec,
 {
-    /// Returns the value for key stored in the trie.
-    fn get(&self, key: &[u8]) -> TrieResult<Option<Vec<u8>>> {
-        self.get_at(self.root.clone(), &NibbleVec::from_raw(key.to_vec(), true))
+    /// Iterates over the entries in ascending key order.
+    ///
+    /// The iteration ends early if a node can't be recovered from the database, which
+    /// looks the same as the end of the trie. Use `try_iter` to tell them apart.
+    pub fn iter(&self) -> TrieIterator<'_, D, H, C> {
+        let nodes = vec![self.root.clone().into()];
+        TrieIterator {
+            trie: self,
+            nibble: NibbleVec::from_raw(vec![], false),
+            nodes,
+            recovered_nodes: Default::default(),
+            reverse: false,
+            started: false,
+            back: None,
+            error: None,
+        }
     }
 
-    /// Checks that the key is present in the trie
-    fn contains(&self, key: &[u8]) -> TrieResult<bool> {
-        Ok(self
-            .get_at(self.root.clone(), &NibbleVec::from_raw(key.to_vec(), true))?
-            .map_or(false, |_| true))
+    /// Same as `iter`, but a node which can't be recovered from the database yields the
+    /// error, which ends the iteration.
+    pub fn try_iter(&self) -> impl Iterator<Item = TrieResult<(Vec<u8>, Vec<u8>)>> + '_ {
+        let mut iter = self.iter();
+        std::iter::from_fn(move || match iter.next() {
+            Some(entry) => Some(Ok(entry)),
+            None => iter.error.take().map(Err),
+        })
     }
 
-    /// Inserts value into trie and modifies it if it exists
-    fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) -> TrieResult<()> {
-        if value.is_empty() {
-            self.remove(&key)?;
-            return Ok(());
+    /// Iterates over the entries with keys in `[start, end)`, in ascending key order.
+    ///
+    /// Only the nodes on the path to the first key >= start are recovered to begin.
+    pub fn range(&self, start: &[u8], end: &[u8]) -> impl Iterator<Item = (Vec<u8>, Vec<u8>)> + '_ {
+        let end = end.to_vec();
+        self.iter_from(start).take_while(move |(key, _)| *key < end)
+    }
+
+    /// Returns up to `max` entries in ascending key order, beginning at `resume_from` or
+    /// at the first key, and the key to resume from for the next chunk, `None` once the
+    /// trie is exhausted.
+    ///
+    /// Nothing is kept between calls, every chunk seeks to `resume_from` again.
+    pub fn iter_chunked(&self, resume_from: Option<Vec<u8>>, max: usize) -> TrieResult<Chunk> {
+        if let Some(key) = &resume_from {
+            self.check_key_len(key.len())?;
         }
-        let root = self.root.clone();
-        self.root = self.insert_at(root, &NibbleVec::from_raw(key, true), value.to_vec())?;
-        Ok(())
+        let mut iter = self.iter_from(resume_from.as_deref().unwrap_or(&[]));
+        let chunk = (&mut iter).take(max).collect::<Vec<_>>();
+        let next = iter.next();
+        if let Some(e) = iter.error.take() {
+            return Err(e);
+        }
+        Ok((chunk, next.map(|(key, _)| key)))
     }
 
-    /// Removes any existing value for key from the trie.
-    fn remove(&mut self, key: &[u8]) -> TrieResult<bool> {
-        let (n, removed) =
-            self.delete_at(self.root.clone(), &NibbleVec::from_raw(key.to_vec(), true))?;
-        self.root = n;
-        Ok(removed)
+    /// Same as `iter`, but stops after `max` entries.
+    pub fn iter_limited(&self, max: usize) -> impl Iterator<Item = (Vec<u8>, Vec<u8>)> + '_ {
+        self.iter().take(max)
     }
 
-    /// Saves all the nodes in the db, clears the cache data, recalculates the root.
-    /// Returns the root hash of the trie.
-    fn root(&mut self) -> TrieResult<Vec<u8>> {
-        self.commit()
+    /// Returns every entry in ascending key order, or `TrieError::TooManyEntries` if
+    /// there are more than `max`, so a truncated result is never mistaken for the whole
+    /// trie. At most `max` entries are collected either way.
+    pub fn try_collect_limited(&self, max: usize) -> TrieResult<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut iter = self.try_iter();
+        let entries = (&mut iter).take(max).collect::<TrieResult<Vec<_>>>()?;
+        match iter.next() {
+            Some(Ok(_)) => Err(TrieError::TooManyEntries(max)),
+            Some(Err(e)) => Err(e),
+            None => Ok(entries),
+        }
     }
 
-    /// Prove constructs a merkle proof for key. The result contains all encoded nodes
-    /// on the path to the value at key. The value itself is also included in the last
-    /// node and can be retrieved by verifying the proof.
-    ///
-    /// If the trie does not contain a value for key, the returned proof contains all
-    /// nodes of the longest existing prefix of the key (at least the root node), ending
-    /// with the node that proves the absence of the key.
-    fn get_proof(&self, key: &[u8]) -> TrieResult<Vec<Vec<u8>>> {
-        let mut path =
-            self.get_path_at(self.root.clone(), &NibbleVec::from_raw(key.to_vec(), true))?;
-        match self.root {
-            Node::Empty => {}
-            _ => path.push(self.root.clone()),
+    /// Returns every key in ascending byte order, which puts a key before the keys it
+    /// is a prefix of, without copying the values. A node which can't be recovered from
+    /// the database is an error as in `try_iter`.
+    pub fn sorted_keys(&self) -> TrieResult<Vec<Vec<u8>>> {
+        let mut iter = self.iter();
+        let keys =
+            std::iter::from_fn(|| iter.next_with(|nibble, _, _| nibble.encode_raw().0)).collect();
+        match iter.error.take() {
+            Some(e) => Err(e),
+            None => Ok(keys),
         }
-        let mut _cache = HashMap::new();
-        let proof = Ok(path
-            .iter()
-            .rev()
-            .map(|n| self.encode_raw(n.clone(), &mut _cache))
-            .collect());
-        if !path.is_empty() {
-            // exclude root
-            for n in path.drain(..path.len() - 1) {
-                unsafe { Node::dealloc(n) };
+    }
+
+    /// Checks whether any key maps to value, comparing the values in key order until one
+    /// matches, so O(n) in the number of entries. Keys and values are not copied.
+    pub fn contains_value(&self, value: &[u8]) -> TrieResult<bool> {
+        let mut iter = self.iter();
+        while let Some(found) = iter.next_with(|_, v, _| v == value) {
+            if found {
+                return Ok(true);
             }
         }
-        proof
+        iter.error.take().map_or(Ok(false), Err)
     }
 
-    /// return value if key exists, None if key not exist, Error if proof is wrong
-    fn verify_proof(
-        &self,
-        root_hash: &[u8],
-        key: &[u8],
-        proof: Vec<Vec<u8>>,
-    ) -> TrieResult<Option<Vec<u8>>> {
-        let memdb = MemoryDB::new(true);
-        for node_encoded in proof.into_iter() {
-            let hash = sha3::Keccak256::digest(&node_encoded);
+    /// Same as `iter`, but begins at the first key >= start.
+    fn iter_from(&self, start: &[u8]) -> TrieIterator<'_, D, H, C> {
+        let mut iter = self.iter();
+        iter.seek(start);
+        iter
+    }
 
-            if root_hash == hash.as_slice() || node_encoded.len() >= KECCAK_SIZE {
-                memdb.insert(hash.to_vec(), node_encoded).unwrap();
+    /// Same as `iter`, but yields the entries in descending key order.
+    pub fn iter_rev(&self) -> TrieIterator<'_, D, H, C> {
+        let mut iter = self.iter();
+        iter.reverse = true;
+        iter
+    }
+
+    /// Same as `iter`, but every entry also carries its `LeafMeta`.
+    pub fn iter_with_meta(&self) -> impl Iterator<Item = (Vec<u8>, Vec<u8>, LeafMeta)> + '_ {
+        let mut iter = self.iter();
+        if !self.dirty {
+            if let Ok(root_hash) = self.root_hash.as_slice().try_into() {
+                iter.nodes[0].hash = Some(root_hash);
             }
         }
-        let trie = PatriciaTrie::from(memdb, root_hash).or(Err(TrieError::InvalidProof))?;
-        trie.get(key).or(Err(TrieError::InvalidProof))
+        std::iter::from_fn(move || iter.next_with_meta())
     }
-}
 
-impl<D> PatriciaTrie<D>
-where
-    D: DB + Clone,
-{
-    fn get_at(&self, n: Node, partial: &NibbleSlice) -> TrieResult<Option<Vec<u8>>> {
-        match n {
-            Node::Empty => Ok(None),
-            Node::Leaf(leaf) => {
-                let leaf_ref = unsafe { leaf.as_ref() };
+    /// Same as `iter().map(..)`, but `f` borrows the key and the value instead of
+    /// getting copies, so entries can be decoded without allocating them first.
+    pub fn iter_map<'a, T, F>(&'a self, mut f: F) -> impl Iterator<Item = T> + 'a
+    where
+        F: FnMut(&[u8], &[u8]) -> T + 'a,
+    {
+        let mut iter = self.iter();
+        let mut key = vec![];
+        std::iter::from_fn(move || {
+            iter.next_with(|nibble, value, _| {
+                nibble.encode_raw_into(&mut key);
+                f(&key, value)
+            })
+        })
+    }
 
-                if &*leaf_ref.key == partial {
-                    Ok(Some(leaf_ref.value.clone()))
-                } else {
-                    Ok(None)
-                }
-            }
-            Node::Branch(branch) => {
-                let branch_ref = unsafe { branch.as_ref() };
+    /// Same as `iter`, but the subtrees under the top branch are traversed on the
+    /// threads of the rayon pool, in no particular order.
+    ///
+    /// Every traversal recovers its own nodes. Stops early like `iter` if a node is
+    /// missing from the database.
+    #[cfg(feature = "rayon")]
+    pub fn par_iter(&self) -> impl ParallelIterator<Item = (Vec<u8>, Vec<u8>)> + '_ {
+        let (value, subtrees) = self.split_top();
+        value
+            .into_par_iter()
+            .chain(
+                subtrees
+                    .into_par_iter()
+                    .flat_map_iter(move |(nibble, node, expanded)| {
+                        let mut iter = self.iter();
+                        iter.nibble = nibble;
+                        iter.nodes = vec![node.into()];
+                        // The expanded node must outlive the traversal.
+                        std::iter::from_fn(move || {
+                            let _ = &expanded;
+                            iter.next()
+                        })
+                    }),
+            )
+    }
 
-                if partial.is_empty() || partial.at(0) == 16 {
-                    Ok(branch_ref.value.clone())
-                } else {
-                    let index = partial.at(0);
-                    self.get_at(branch_ref.children[index].clone(), partial.offset(1))
+    // Returns the value of the top branch and its children with their paths, and the
+    // expanded trie holding the branch if it is stored.
+    #[cfg(feature = "rayon")]
+    #[allow(clippy::type_complexity)]
+    fn split_top(
+        &self,
+    ) -> (
+        Option<(Vec<u8>, Vec<u8>)>,
+        Vec<(NibbleVec, Node, Option<Arc<Self>>)>,
+    ) {
+        let mut nibble = NibbleVec::from_raw(vec![], false);
+        let mut node = self.root.clone();
+        let mut expanded = None;
+        loop {
+            node = match node {
+                Node::Extension(ref ext) if nibble.is_empty() => {
+                    let ext_ref = unsafe { ext.as_ref() };
+                    nibble.extend_from_slice(&ext_ref.prefix);
+                    ext_ref.node.clone()
                 }
-            }
-            Node::Extension(extension) => {
-                let extension_ref = unsafe { extension.as_ref() };
-
-                let prefix = &extension_ref.prefix;
-                let match_len = partial.common_prefix(prefix);
-                if match_len == prefix.len() {
-                    self.get_at(extension_ref.node.clone(), partial.offset(match_len))
-                } else {
-                    Ok(None)
+                Node::Hash(ref hash_node) => {
+                    match self.expand_cached(unsafe { hash_node.as_ref() }.hash) {
+                        Ok(trie) => {
+                            let root = trie.root.clone();
+                            expanded = Some(trie);
+                            root
+                        }
+                        // Left for the traversal to fail on.
+                        Err(_) => return (None, vec![(nibble, node, None)]),
+                    }
                 }
-            }
-            Node::Hash(hash_node) => unsafe {
-                // Check if there is a saved expanded node in the cache
-                // and return a value from it.
-                // Otherwise return a value and add a node to the cache.
-                let hash = hash_node.as_ref().hash;
-                let cached_tries_ref = self.cached_tries.read().unwrap();
-                if let Some(trie) = cached_tries_ref.get(&hash) {
-                    trie.get_at(trie.root.clone(), partial)
-                } else {
-                    let trie = PatriciaTrie::from(self.db.clone(), hash.as_slice()).unwrap();
-                    let result = trie.get_at(trie.root.clone(), partial)?;
-                    drop(cached_tries_ref);
-                    let mut cached_tries_mut = self.cached_tries.write().unwrap();
-                    cached_tries_mut.insert(hash, trie);
-                    Ok(result)
+                Node::Branch(ref branch) => {
+                    let branch_ref = unsafe { branch.as_ref() };
+                    let value = branch_ref
+                        .value
+                        .clone()
+                        .map(|value| (nibble.encode_raw().0, value));
+                    let subtrees = branch_ref
+                        .children
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, child)| !matches!(child, Node::Empty))
+                        .map(|(i, child)| {
+                            let mut nibble = nibble.clone();
+                            nibble.push(i as u8);
+                            (nibble, child.clone(), expanded.clone())
+                        })
+                        .collect();
+                    return (value, subtrees);
                 }
-            },
+                _ => return (None, vec![(nibble, node, expanded)]),
+            };
         }
     }
 
-    fn insert_at(&mut self, n: Node, partial: &NibbleSlice, value: Vec<u8>) -> TrieResult<Node> {
-        match n {
-            Node::Empty => Ok(Node::from_leaf(partial.to_owned(), value)),
-            Node::Leaf(mut leaf) => unsafe {
-                let leaf_mut = leaf.as_mut();
+    /// Creates an empty trie, same as `new` but for any hasher and codec.
+    pub fn create(db: D) -> Self {
+        PatriciaTrieBuilder::default().db(db).build()
+    }
 
-                let old_partial = &leaf_mut.key;
-                let match_index = partial.common_prefix(old_partial);
-                if match_index == old_partial.len() {
-                    // replace leaf value
-                    leaf_mut.value = value;
-                    return Ok(Node::Leaf(leaf));
-                }
-                let mut branch = BranchNode {
-                    children: empty_children(),
-                    value: None,
-                };
+    /// Opens the trie at `root`, same as `from` but for any hasher and codec.
+    pub fn open(db: D, root: &[u8]) -> TrieResult<Self> {
+        PatriciaTrieBuilder::default()
+            .db(db)
+            .root(root)
+            .build_from_root()
+    }
 
-                let leaf_owned = to_owned(leaf_mut);
-                let old_partial = &leaf_owned.key;
-                let n = Node::from_leaf(
-                    old_partial.offset(match_index + 1).to_owned(),
-                    leaf_owned.value,
-                );
-                branch.insert(old_partial.at(match_index), n);
+    /// Same as `subtrie_from`, but for any hasher and codec.
+    pub fn open_subtrie(db: D, node_hash: &[u8]) -> TrieResult<Self> {
+        let mut trie = Self::create(db);
+        let root = trie.recover_checked(node_hash, true)?;
+        if let Node::Empty | Node::Hash(_) = root {
+            unsafe { Node::dealloc(root) };
+            return Err(TrieError::InvalidData);
+        }
+        trie.root = root;
+        trie.root_hash = node_hash.to_vec();
+        Ok(trie)
+    }
 
-                let n = Node::from_leaf(partial.offset(match_index + 1).to_owned(), value);
-                branch.insert(partial.at(match_index), n);
+    /// Same as `gc`, but for any hasher and codec.
+    ///
+    /// Unlike the pruning done by `commit`, this is safe for tries sharing a database:
+    /// the nodes reachable from any live root are kept. The database must list its keys
+    /// with `DB::keys`, keys which are not node hashes are left alone. Values stored by
+    /// hash with a `ValueStore` are not tracked, collect the tries storing them with
+    /// `collect_garbage_with_value_store`.
+    pub fn collect_garbage(db: &mut D, live_roots: &[&[u8]]) -> TrieResult<usize> {
+        Self::collect_garbage_in(db, live_roots, None)
+    }
 
-                let branch = Node::Branch(NonNull::new(Box::leak(Box::new(branch))).unwrap());
-                if match_index == 0 {
-                    // no common prefix
-                    Ok(branch)
-                } else {
-                    // create an extension node with a common prefix
-                    let common_prefix = partial.slice(0, match_index).to_owned();
-                    Ok(Node::from_extension(common_prefix, branch))
-                }
-            },
-            Node::Branch(mut branch) => {
-                let branch_mut = unsafe { branch.as_mut() };
+    /// Same as `collect_garbage`, for tries created with `value_store`. The values the
+    /// live tries refer to are kept, every entry of these tries is read.
+    pub fn collect_garbage_with_value_store(
+        db: &mut D,
+        live_roots: &[&[u8]],
+        value_store: ValueStore,
+    ) -> TrieResult<usize> {
+        Self::collect_garbage_in(db, live_roots, Some(value_store))
+    }
 
-                if partial.at(0) == 16 {
-                    branch_mut.value = Some(value);
-                    return Ok(Node::Branch(branch));
+    fn collect_garbage_in(
+        db: &mut D,
+        live_roots: &[&[u8]],
+        value_store: Option<ValueStore>,
+    ) -> TrieResult<usize> {
+        let keys = db
+            .keys()
+            .map_err(|e| TrieError::DB(e.to_string()))?
+            .ok_or_else(|| TrieError::DB("the db can't list its keys".to_string()))?;
+        let mut live = HashSet::new();
+        for root in live_roots {
+            let trie = PatriciaTrieBuilder::<D, H, C> {
+                value_store,
+                ..Default::default()
+            }
+            .db(db.clone())
+            .root(root)
+            .build_from_root()?;
+            live.extend(trie.reachable_hashes()?);
+            if let Some(store) = value_store {
+                for entry in trie.try_iter() {
+                    let (_, value) = entry?;
+                    if value.len() > store.threshold {
+                        live.insert(H::digest(&value));
+                    }
                 }
-
-                let child = branch_mut.children[partial.at(0)].clone();
-                let new_child = self.insert_at(child, partial.offset(1), value)?;
-                branch_mut.children[partial.at(0)] = new_child;
-                Ok(Node::Branch(branch))
             }
-            Node::Extension(mut ext) => unsafe {
-                let ext_mut = ext.as_mut();
-
-                let match_index = partial.common_prefix(&ext_mut.prefix);
+        }
+        let dead = keys
+            .into_iter()
+            .filter(|key| {
+                key.as_slice()
+                    .try_into()
+                    .is_ok_and(|hash: [u8; 32]| !live.contains(&hash))
+            })
+            .collect::<Vec<_>>();
+        db.remove_batch(&dead)
+            .map_err(|e| TrieError::DB(e.to_string()))?;
+        Ok(dead.len())
+    }
 
-                if match_index == 0 {
-                    let ext_owned = to_owned(ext_mut);
-                    let mut branch = BranchNode {
-                        children: empty_children(),
-                        value: None,
-                    };
-                    branch.insert(
-                        ext_owned.prefix.at(0),
-                        if ext_owned.prefix.len() == 1 {
-                            ext_owned.node
-                        } else {
-                            Node::from_extension(
-                                ext_owned.prefix.offset(1).to_owned(),
-                                ext_owned.node,
-                            )
-                        },
-                    );
-                    let branch = Box::leak(Box::new(branch));
-                    let node = Node::Branch(NonNull::new(branch).unwrap());
+    /// Rebuilds the trie from its entries in `dest_db` and continues on it, returns the
+    /// root hash, the same as `compute_root`.
+    ///
+    /// `dest_db` then holds the live nodes only, unlike the database of the trie which
+    /// may have kept the nodes of older roots. Uncommitted changes are included. The
+    /// entries are read in chunks, so the whole trie is not loaded at once.
+    pub fn compact(&mut self, dest_db: D) -> TrieResult<Vec<u8>> {
+        const CHUNK: usize = 1024;
+
+        let mut builder = self.builder_like().db(dest_db);
+        builder.deferred_nodes = None;
+        // Only the root of the uncommitted changes, if any, is a new one.
+        builder.root_history = None;
+        let mut compacted = builder.build();
+        let mut entries = self.try_iter();
+        loop {
+            let chunk = (&mut entries).take(CHUNK).collect::<TrieResult<Vec<_>>>()?;
+            if chunk.is_empty() {
+                break;
+            }
+            compacted.insert_many_sorted(&chunk)?;
+        }
+        drop(entries);
+        let root_hash = compacted.commit()?;
+        compacted.defer_commit = self.defer_commit;
+        compacted.root_history = self.root_history.take();
+        if let Some(history) = compacted.root_history.as_ref().filter(|_| self.dirty) {
+            history.record(&self.root_hash, &root_hash);
+        }
+        *self = compacted;
+        Ok(root_hash)
+    }
 
-                    return self.insert_at(node, partial, value);
-                }
+    /// Rebuilds the trie from its entries in `dest_db` with the hasher `H2` and commits
+    /// it, e.g. to migrate a chain to another hash function. The trie is unchanged.
+    ///
+    /// Uncommitted changes are included. The settings are kept, except for the caches,
+    /// the witness recorder and the root history. The empty root becomes the one of `H2`
+    /// unless another one was set with `empty_root`.
+    pub fn rehash<H2: Hasher>(&self, dest_db: D) -> TrieResult<PatriciaTrie<D, H2, C>> {
+        const CHUNK: usize = 1024;
+
+        let custom_empty_root = self.empty_root != H::digest(&C::encode_empty());
+        let mut rehashed = PatriciaTrieBuilder::<D, H2, C> {
+            read_cache: self.cached_tries.is_some(),
+            strict: self.strict,
+            value_store: self.value_store,
+            allow_empty_values: self.allow_empty_values,
+            max_decode_depth: self.max_decode_depth,
+            inline_small_nodes: self.inline_small_nodes,
+            max_key_len: self.max_key_len,
+            empty_root: Some(self.empty_root).filter(|_| custom_empty_root),
+            ..Default::default()
+        }
+        .db(dest_db)
+        .build();
+        let mut entries = self.try_iter();
+        loop {
+            let chunk = (&mut entries).take(CHUNK).collect::<TrieResult<Vec<_>>>()?;
+            if chunk.is_empty() {
+                break;
+            }
+            rehashed.insert_many_sorted(&chunk)?;
+        }
+        rehashed.commit()?;
+        Ok(rehashed)
+    }
 
-                let sub_node = ext_mut.node.clone();
-                let prefix = ext_mut.prefix.clone();
+    /// Loads the root of an empty trie from the database.
+    ///
+    /// The root node is checked against its hash even if the trie is not strict, so a
+    /// wrong root is reported here instead of by a later access.
+    fn load_state_root(mut self, root: &[u8]) -> TrieResult<Self> {
+        if root == self.empty_root {
+            return Ok(self);
+        }
+        self.root = self.recover_checked(root, true).map_err(|e| match e {
+            TrieError::MissingNode(root) | TrieError::CorruptedNode(root) => {
+                TrieError::InvalidStateRoot(root)
+            }
+            e => e,
+        })?;
+        self.root_hash = root.to_vec();
+        Ok(self)
+    }
 
-                if match_index == prefix.len() {
-                    let new_node = self.insert_at(sub_node, partial.offset(match_index), value)?;
-                    to_owned(ext_mut);
-                    return Ok(Node::from_extension(prefix, new_node));
-                }
+    /// Same as `load_state_root`, but the root is any stored node.
+    fn load_root(mut self, hash: &[u8]) -> TrieResult<Self> {
+        self.root = self.recover_from_db(hash)?;
+        self.root_hash = hash.to_vec();
+        Ok(self)
+    }
 
-                let new_ext = Node::from_extension(prefix.offset(match_index).to_owned(), sub_node);
-                let new_node = self.insert_at(new_ext, partial.offset(match_index), value)?;
-                ext_mut.prefix = prefix.slice(0, match_index).to_owned();
-                ext_mut.node = new_node;
-                Ok(Node::Extension(ext))
-            },
-            Node::Hash(hash_node) => {
-                // Consume hash node and insert in-place an expanded node
-                let hash_node_owned = unsafe { to_owned(hash_node) };
+    /// Returns a builder with the settings of this trie, for tries sharing its database.
+    fn builder_like(&self) -> PatriciaTrieBuilder<D, H, C> {
+        PatriciaTrieBuilder {
+            read_cache: self.cached_tries.is_some(),
+            deferred_nodes: Some(self.deferred_nodes.clone()),
+            witness: self.witness.clone(),
+            root_history: self.root_history.clone(),
+            ..self.encoding_like(self.db.clone())
+        }
+    }
 
-                self.recovered_nodes_hashes.insert(hash_node_owned.hash);
-                let n = self.recover_from_db(&hash_node_owned.hash)?;
-                self.insert_at(n, partial, value)
-            }
+    /// Returns a builder on `db` with the settings of this trie that decide how nodes
+    /// and values are stored, e.g. for a trie reading the nodes of a proof.
+    fn encoding_like<D2>(&self, db: D2) -> PatriciaTrieBuilder<D2, H, C> {
+        PatriciaTrieBuilder {
+            db: Some(db),
+            read_cache: false,
+            strict: self.strict,
+            value_store: self.value_store,
+            allow_empty_values: self.allow_empty_values,
+            max_decode_depth: self.max_decode_depth,
+            inline_small_nodes: self.inline_small_nodes,
+            max_key_len: self.max_key_len,
+            empty_root: Some(self.empty_root),
+            ..Default::default()
         }
     }
 
-    fn delete_at(&mut self, n: Node, partial: &NibbleSlice) -> TrieResult<(Node, bool)> {
-        let (new_n, deleted) = match n {
-            Node::Empty => Ok((Node::Empty, false)),
-            Node::Leaf(leaf) => unsafe {
-                let leaf_ref = leaf.as_ref();
+    /// Returns the root hash as of the last commit, without recomputing it.
+    ///
+    /// Changes made since then are not reflected, see `is_dirty`.
+    /// Use `root` to commit them and get the new hash.
+    pub fn root_hash(&self) -> &[u8] {
+        &self.root_hash
+    }
 
-                if &*leaf_ref.key == partial {
-                    to_owned(leaf);
-                    return Ok((Node::Empty, true));
-                }
-                Ok((Node::Leaf(leaf), false))
-            },
-            Node::Branch(mut branch) => {
-                let branch_mut = unsafe { branch.as_mut() };
+    /// Returns the database backing the trie, e.g. to open another root on it.
+    pub fn db(&self) -> &D {
+        &self.db
+    }
 
-                let index = partial.at(0);
-                if index == 16 {
-                    branch_mut.value = None;
-                    return Ok((Node::Branch(branch), true));
-                }
+    /// Returns the database backing the trie.
+    ///
+    /// Nodes expanded by `get` are cached, so changing stored nodes behind the trie's
+    /// back is not noticed by later reads. Call `clear_cache` after such changes.
+    pub fn db_mut(&mut self) -> &mut D {
+        &mut self.db
+    }
 
-                let node = branch_mut.children[index].clone();
+    /// Limits how deeply nodes embedded in a stored node may be nested, deeper encodings
+    /// are rejected with `TrieError::InvalidData` instead of exhausting the stack.
+    ///
+    /// Applies to the nodes decoded from now on, the root of an opened trie is already
+    /// decoded with the default `DEFAULT_MAX_DECODE_DEPTH`.
+    pub fn set_max_decode_depth(&mut self, max_depth: usize) {
+        self.max_decode_depth = max_depth;
+    }
 
-                let (new_n, deleted) = self.delete_at(node, partial.offset(1))?;
-                if deleted {
-                    branch_mut.children[index] = new_n;
-                }
+    /// Makes commits keep their nodes in memory instead of writing them to the
+    /// database, until `flush_commit` writes them in one batch. E.g. `root` can be
+    /// called after every transaction and the nodes flushed once per block, nodes
+    /// replaced in between are never written.
+    ///
+    /// The trie reads the deferred nodes as if they were stored. They are lost if the
+    /// trie is dropped before the flush, and other tries opened on the database don't
+    /// see them.
+    pub fn set_defer_commit(&mut self, defer: bool) {
+        self.defer_commit = defer;
+    }
 
-                Ok((Node::Branch(branch), deleted))
-            }
-            Node::Extension(mut ext) => {
-                let ext_ref = unsafe { ext.as_mut() };
+    /// Commits the trie and writes the nodes of the deferred commits, see
+    /// `set_defer_commit`. Returns the root hash.
+    ///
+    /// The nodes removed are those replaced since the last flush, and so stored.
+    pub fn flush_commit(&mut self) -> TrieResult<Vec<u8>> {
+        let defer = std::mem::replace(&mut self.defer_commit, false);
+        let root_hash = self.commit();
+        self.defer_commit = defer;
+        root_hash
+    }
 
-                let prefix = &ext_ref.prefix;
-                let match_len = partial.common_prefix(prefix);
+    /// Same as `root`, but writes meta with the nodes in the same batch, e.g. the header
+    /// of the block the root belongs to, so both or neither are stored. See `load_meta`.
+    ///
+    /// The metadata is stored under the root hash followed by `b"meta"` and is kept once
+    /// the root is replaced. Committing the root again replaces it. With `defer_commit`
+    /// it is written by `flush_commit` along with the nodes.
+    pub fn commit_with_meta(&mut self, meta: &[u8]) -> TrieResult<Vec<u8>> {
+        let mut pending = self.encode_commit();
+        let key = [&pending.root_hash[..], META_SUFFIX].concat();
+        pending.nodes.insert(key, meta.to_vec());
+        self.write_commit(pending)
+    }
 
-                if match_len == prefix.len() {
-                    let (new_n, deleted) =
-                        self.delete_at(ext_ref.node.clone(), partial.offset(match_len))?;
+    /// Flushes the database, so the nodes written by the commits so far are durable.
+    ///
+    /// Commits never flush on their own. Deferred nodes are not written yet, call
+    /// `flush_commit` first. A no-op for `MemoryDB`.
+    pub fn flush(&self) -> TrieResult<()> {
+        self.db.flush().map_err(|e| TrieError::DB(e.to_string()))
+    }
 
-                    if deleted {
-                        ext_ref.node = new_n;
-                    }
+    /// Keeps the proofs of the `capacity` most recently proven keys, so `get_proof` for
+    /// a hot key doesn't walk the path again. Replaces the proofs cached so far.
+    ///
+    /// Only proofs against the committed root are cached: while the trie has uncommitted
+    /// changes `get_proof` bypasses the cache, and `commit` drops the cached proofs.
+    pub fn with_proof_cache(mut self, capacity: usize) -> Self {
+        self.proof_cache = Arc::new(Mutex::new(LruCache::new(capacity)));
+        self
+    }
 
-                    Ok((Node::Extension(ext), deleted))
-                } else {
-                    Ok((Node::Extension(ext), false))
-                }
-            }
-            Node::Hash(hash_node) => {
-                // Consume hash node and insert in-place an expanded node
-                let hash_node_owned = unsafe { to_owned(hash_node) };
-                self.recovered_nodes_hashes.insert(hash_node_owned.hash);
-                let n = self.recover_from_db(&hash_node_owned.hash)?;
-                self.delete_at(n, partial)
-            }
-        }?;
-
-        if deleted {
-            let degenerated = self.degenerate(new_n)?;
-            Ok((degenerated, deleted))
-        } else {
-            Ok((new_n, deleted))
+    /// Records every node the trie reads from the database from now on, so the nodes
+    /// returned by `take_witness` are enough to run the same operations on a trie
+    /// opened by `from_witness`, as in stateless execution.
+    ///
+    /// The current root node is recorded, enable recording before modifying the trie.
+    /// The nodes cached so far are dropped so they are read, and recorded, again.
+    /// The cache isn't shared with other tries any more, see
+    /// `PatriciaTrieBuilder::node_cache`.
+    pub fn with_witness_recorder(mut self) -> Self {
+        let witness = WitnessRecorder::new();
+        if !self.dirty && self.root_hash != self.empty_root {
+            let data = self.encode_raw(self.root.clone(), &mut HashMap::new());
+            witness.record(&self.root_hash, &data);
+        }
+        if let Some(cached_tries) = &self.cached_tries {
+            let capacity = cached_tries.lock().capacity();
+            self.cached_tries = Some(Arc::new(Mutex::new(LruCache::new(capacity))));
         }
+        self.witness = Some(witness);
+        self
     }
 
-    fn degenerate(&mut self, n: Node) -> TrieResult<Node> {
-        match n {
-            Node::Branch(mut branch) => {
-                let branch_mut = unsafe { branch.as_mut() };
+    /// Notifies `history` of every root committed from now on, e.g. a `VecRootHistory`.
+    ///
+    /// With a database which keeps the replaced nodes, such as `MemoryDB::new(false)`,
+    /// every recorded root can be opened again to read the trie as it was then.
+    pub fn with_root_history(mut self, history: impl RootHistory + 'static) -> Self {
+        self.root_history = Some(Arc::new(history));
+        self
+    }
 
-                let mut used_indexes = vec![];
-                for (index, node) in branch_mut.children.iter().enumerate() {
-                    match node {
-                        Node::Empty => continue,
-                        _ => used_indexes.push(index),
-                    }
-                }
-                // if only a value node, transmute to leaf.
-                if used_indexes.is_empty() && branch_mut.value.is_some() {
-                    let key = NibbleVec::from_raw([].to_vec(), true);
-                    // Drop branch node and replace it with leaf
-                    let branch_owned = unsafe { to_owned(branch_mut) };
-                    Ok(Node::from_leaf(key, branch_owned.value.unwrap()))
-                    // if only one node. make an extension.
-                } else if used_indexes.len() == 1 && branch_mut.value.is_none() {
-                    let used_index = used_indexes[0];
-                    let n = branch_mut.children[used_index].clone();
-                    unsafe { to_owned(branch_mut) };
-
-                    let new_node =
-                        Node::from_extension(NibbleVec::from_hex(vec![used_index as u8]), n);
-                    self.degenerate(new_node)
-                } else {
-                    Ok(Node::Branch(branch))
-                }
-            }
-            Node::Extension(ext) => {
-                let ext_ref = unsafe { ext.as_ref() };
+    /// Returns the nodes recorded since `with_witness_recorder` or the last call,
+    /// sorted by hash. Empty if the trie doesn't record them.
+    pub fn take_witness(&self) -> Vec<Vec<u8>> {
+        self.witness
+            .as_ref()
+            .map(WitnessRecorder::take)
+            .unwrap_or_default()
+    }
 
-                let prefix = &ext_ref.prefix;
-                match ext_ref.node.clone() {
-                    Node::Extension(mut sub_ext) => unsafe {
-                        // merge extension nodes
-                        let sub_ext_mut = sub_ext.as_mut();
-                        sub_ext_mut.prefix = prefix.join(&sub_ext_mut.prefix);
-                        // drop parent extension node after merging
-                        to_owned(ext);
-                        self.degenerate(Node::Extension(sub_ext))
-                    },
-                    Node::Leaf(mut leaf) => unsafe {
-                        let leaf_mut = leaf.as_mut();
-                        leaf_mut.key = prefix.join(&leaf_mut.key);
-                        // drop parent extension and creating a leaf instead
-                        to_owned(ext);
-                        Ok(Node::Leaf(leaf))
-                    },
-                    // try again after recovering node from the db.
-                    Node::Hash(hash_node) => unsafe {
-                        let hash = hash_node.as_ref().hash;
-                        self.recovered_nodes_hashes.insert(hash);
-                        let recovered_node = self.recover_from_db(&hash)?;
-                        let n = Node::from_extension(ext_ref.prefix.clone(), recovered_node);
-                        to_owned(ext);
-                        to_owned(hash_node);
-                        self.degenerate(n)
-                    },
-                    _ => Ok(Node::Extension(ext)),
-                }
-            }
-            _ => Ok(n),
+    /// Drops the nodes cached by `get` and the proofs cached by `get_proof`,
+    /// they are recovered from the database again.
+    pub fn clear_cache(&mut self) {
+        if let Some(cached_tries) = &self.cached_tries {
+            cached_tries.lock().clear();
         }
+        self.proof_cache.lock().clear();
     }
 
-    // Get nodes path along the key, only the nodes whose encode length is greater than
-    // hash length are added.
-    // For embedded nodes whose data are already contained in their parent node, we don't need to
-    // add them in the path.
-    // In the code below, we only add the nodes get by `get_node_from_hash`, because they contains
-    // all data stored in db, including nodes whose encoded data is less than hash length.
-    fn get_path_at(&self, n: Node, partial: &NibbleSlice) -> TrieResult<Vec<Node>> {
-        match n {
-            Node::Empty | Node::Leaf(_) => Ok(vec![]),
-            Node::Branch(branch) => {
-                let branch_ref = unsafe { branch.as_ref() };
+    /// Checks whether the trie was modified since the last commit,
+    /// i.e. whether `root_hash` is stale.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
 
-                if partial.is_empty() || partial.at(0) == 16 {
-                    Ok(vec![])
-                } else {
-                    let node = branch_ref.children[partial.at(0)].clone();
-                    self.get_path_at(node, partial.offset(1))
-                }
+    /// Copies the entries to a new trie on `dest_db`, with `prefix` prepended to
+    /// every key. The settings of this trie are kept.
+    ///
+    /// Only the top node changes, the stored nodes below it are copied to `dest_db`
+    /// as they are, so the next commit writes a single node. Uncommitted changes are
+    /// copied too. Every key is read to check that the prefixed keys are not too long.
+    pub fn prefixed(&self, prefix: &[u8], dest_db: D) -> TrieResult<Self> {
+        self.check_key_len(prefix.len())?;
+        if !prefix.is_empty() {
+            let mut longest = 0;
+            for entry in self.try_iter() {
+                longest = longest.max(entry?.0.len());
             }
-            Node::Extension(ext) => {
-                let ext_ref = unsafe { ext.as_ref() };
-
-                let prefix = &ext_ref.prefix;
-                let match_len = partial.common_prefix(prefix);
+            self.check_key_len(prefix.len().saturating_add(longest))?;
+        }
+        let mut cache = HashMap::new();
+        self.cache_node(self.root.clone(), &mut cache)?;
+        dest_db
+            .insert_batch(cache)
+            .map_err(|e| TrieError::DB(e.to_string()))?;
 
-                if match_len == prefix.len() {
-                    self.get_path_at(ext_ref.node.clone(), partial.offset(match_len))
-                } else {
-                    Ok(vec![])
-                }
+        let mut trie = PatriciaTrieBuilder {
+            deferred_nodes: None,
+            ..self.builder_like()
+        }
+        .db(dest_db)
+        .build();
+        let root = self.root.deep_copy();
+        trie.root = match root {
+            _ if prefix.is_empty() => root,
+            Node::Empty => Node::Empty,
+            Node::Leaf(leaf) => {
+                let leaf = unsafe { to_owned(leaf) };
+                let key = NibbleVec::from_raw(prefix.to_vec(), false).join(&leaf.key);
+                Node::from_leaf(key, leaf.value)
             }
-            Node::Hash(hash_node) => {
-                let n = self.recover_from_db(&unsafe { hash_node.as_ref() }.hash.clone())?;
-                let mut rest = self.get_path_at(n.clone(), partial)?;
-                rest.push(n);
-                Ok(rest)
+            Node::Extension(ext) => {
+                let ext = unsafe { to_owned(ext) };
+                let key = NibbleVec::from_raw(prefix.to_vec(), false).join(&ext.prefix);
+                Node::from_extension(key, ext.node)
             }
+            root => Node::from_extension(NibbleVec::from_raw(prefix.to_vec(), false), root),
+        };
+        trie.dirty = !matches!(trie.root, Node::Empty);
+        Ok(trie)
+    }
+
+    /// Checks whether both tries hold the same entries, however they were built.
+    ///
+    /// If both tries are committed, equal root hashes answer without traversing them.
+    pub fn content_eq<D2>(&self, other: &PatriciaTrie<D2, H, C>) -> TrieResult<bool>
+    where
+        D2: DB + Clone,
+    {
+        if !self.dirty && !other.dirty && self.root_hash == other.root_hash {
+            return Ok(true);
+        }
+        let (mut a, mut b) = (self.iter(), other.iter());
+        let eq = (&mut a).eq(&mut b);
+        match a.error.take().or_else(|| b.error.take()) {
+            Some(e) => Err(e),
+            None => Ok(eq),
         }
     }
 
-    fn commit(&mut self) -> TrieResult<Vec<u8>> {
-        let mut cache = HashMap::new();
-        let encoded = self.encode_node(self.root.clone(), &mut cache);
-        let root_hash = if encoded.len() < KECCAK_SIZE {
-            let hash = sha3::Keccak256::digest(&encoded);
-            cache.insert(hash.to_vec(), encoded);
-            hash.to_vec()
-        } else {
-            encoded
-        };
+    /// Computes the root hash of the current entries without writing anything,
+    /// unlike `root`. The trie stays dirty.
+    pub fn compute_root(&self) -> Vec<u8> {
+        self.encode_commit().root_hash
+    }
 
-        let cached_keys: HashSet<[u8; 32]> =
-            HashSet::from_iter(cache.keys().map(|k| k.as_slice().try_into().unwrap()));
+    /// Same as `root`, but only commits if the root hash differs from the last commit's
+    /// and returns the new one, `None` if it is the same. Nothing is written then, and
+    /// the trie is not dirty any more.
+    pub fn commit_if_changed(&mut self) -> TrieResult<Option<Vec<u8>>> {
+        let pending = self.encode_commit();
+        if pending.root_hash == self.root_hash {
+            // Same root, same nodes: whatever changed was undone.
+            self.dirty = false;
+            return Ok(None);
+        }
+        self.write_commit(pending).map(Some)
+    }
 
-        // Remove all recovered node hashes from the database which are now invalid, i.e.
-        // the root was changed so the hash was also changed.
-        let keys_to_remove = self
-            .recovered_nodes_hashes
-            .difference(&cached_keys)
-            .map(|h| h.to_vec())
-            .collect::<Vec<Vec<u8>>>();
+    /// Same as `root`, but writes the new nodes in batches of at most `batch_size` while
+    /// encoding them, instead of one batch of all of them, e.g. for a bulk load too
+    /// large to be encoded in memory at once. The written nodes are also replaced by
+    /// hash nodes in memory.
+    ///
+    /// Falls back to `root` for deferred commits and while deferred nodes are pending.
+    /// If a batch fails to be written its nodes are kept as deferred nodes, so the trie
+    /// stays readable and the next commit writes them.
+    pub fn commit_streaming(&mut self, batch_size: usize) -> TrieResult<Vec<u8>> {
+        if self.defer_commit
+            || !self.deferred_nodes.read().is_empty()
+            || !self.deferred_pruned.is_empty()
+        {
+            return self.commit();
+        }
+        let batch_size = batch_size.max(1);
+        let (mut cache, mut written) = (HashMap::new(), HashSet::new());
+        self.spill_at(self.root.clone(), &mut cache, &mut written, batch_size)?;
+        self.spill(&mut cache, &mut written)?;
+
+        // Only the root node is left to encode, a node spilled before is not stale.
+        let mut pending = self.encode_commit();
+        pending.pruned.retain(|hash| !written.contains(hash));
+        self.write_commit(pending)
+    }
 
-        self.db
-            .insert_batch(cache)
-            .map_err(|e| TrieError::DB(e.to_string()))?;
+    /// Computes what `commit` would write to the database without writing anything.
+    pub fn commit_preview(&self) -> TrieResult<CommitPreview> {
+        let pending = self.encode_commit();
+        Ok(CommitPreview {
+            root_hash: pending.root_hash,
+            new_nodes: pending.nodes.len(),
+            new_bytes: pending.nodes.values().map(|node| node.len()).sum(),
+            pruned: pending.pruned,
+        })
+    }
 
-        self.db
-            .remove_batch(keys_to_remove)
-            .map_err(|e| TrieError::DB(e.to_string()))?;
+    /// Same as `root`, but also returns the nodes the commit wrote and removed, e.g. to
+    /// apply the same changes to the database of a peer.
+    pub fn commit_with_changeset(&mut self) -> TrieResult<(Vec<u8>, ChangeSet)> {
+        let pending = self.encode_commit();
+        let mut inserted = pending
+            .nodes
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect::<Vec<_>>();
+        inserted.sort();
+        let removed = pending.pruned.iter().map(|hash| hash.to_vec()).collect();
+        let root_hash = self.write_commit(pending)?;
+        Ok((root_hash, ChangeSet { inserted, removed }))
+    }
 
-        self.root_hash = root_hash.clone();
-        self.recovered_nodes_hashes.clear();
-        unsafe { Node::dealloc(self.root.clone()) };
-        self.root = self.recover_from_db(&self.root_hash)?;
-        Ok(root_hash)
+    /// Traverses every node reachable from the root and checks that each node stored
+    /// in the database hashes to the key it is stored under.
+    /// Returns `TrieError::CorruptedNode` with the offending hash on the first mismatch.
+    ///
+    /// Nodes modified since the last commit are not stored yet, so only their
+    /// stored descendants are checked.
+    pub fn verify_integrity(&self) -> TrieResult<()> {
+        if self.root_hash == self.empty_root {
+            return Ok(());
+        }
+        if let Some(data) = self
+            .db
+            .get(&self.root_hash)
+            .map_err(|e| TrieError::DB(e.to_string()))?
+        {
+            Self::check_node_hash(&self.root_hash, &data)?;
+        }
+        self.verify_integrity_at(self.root.clone())
     }
 
-    /// `cache` is the buffer for generated hashes of nodes mapped to raw data.
-    fn encode_node(&self, n: Node, cache: &mut HashMap<Vec<u8>, Vec<u8>>) -> Vec<u8> {
-        // Returns the hash value directly to avoid double counting.
-        if let Node::Hash(hash_node) = n {
-            return unsafe { hash_node.as_ref() }.hash.to_vec();
+    /// Collects the hashes of all stored nodes reachable from the root, including the
+    /// root itself. These are the database keys the trie depends on, so everything else
+    /// can be pruned once the hashes of all live roots are known.
+    ///
+    /// Nodes modified since the last commit are not stored yet, so only their
+    /// stored descendants are collected.
+    pub fn reachable_hashes(&self) -> TrieResult<HashSet<[u8; 32]>> {
+        Ok(self.subtree_refcounts()?.into_keys().collect())
+    }
+
+    /// Counts how many stored nodes refer to each of the hashes in `reachable_hashes`,
+    /// the root counting as referred to once. A count above one is a subtree shared by
+    /// several parents, or twice by the same one, and stored once.
+    ///
+    /// Every stored node is read once, the nodes below a shared subtree are counted for
+    /// the subtree itself and not for each of its parents.
+    pub fn subtree_refcounts(&self) -> TrieResult<HashMap<[u8; 32], usize>> {
+        let mut counts = HashMap::new();
+        if self.deferred_nodes.read().contains_key(&self.root_hash)
+            || self
+                .db
+                .contains(&self.root_hash)
+                .map_err(|e| TrieError::DB(e.to_string()))?
+        {
+            counts.insert(self.root_hash.as_slice().try_into().unwrap(), 1);
         }
+        self.refcounts_at(self.root.clone(), &mut counts)?;
+        Ok(counts)
+    }
 
-        let data = self.encode_raw(n, cache);
-        // Nodes smaller than 32 bytes are stored inside their parent,
-        // Nodes equal to 32 bytes are returned directly
-        if data.len() < KECCAK_SIZE {
-            data
-        } else {
-            let hash = sha3::Keccak256::digest(&data);
-            cache.insert(hash.to_vec(), data);
-            hash.to_vec()
+    /// Inserts value for a key given as nibbles, which can have an odd length.
+    ///
+    /// Keys are terminated by the nibble `16`, which selects the value slot of a branch
+    /// when the key ends there. The terminator is appended unless `key` ends with it,
+    /// any other nibble must be below 16. A byte key passed to `insert` is the same
+    /// as its nibbles here, so both APIs address the same values. `iter` yields keys
+    /// as bytes, which drops the last nibble of an odd length key.
+    pub fn insert_nibbles(&mut self, key: NibbleVec, value: Vec<u8>) -> TrieResult<()> {
+        let key = Self::leaf_nibbles(&key)?;
+        self.check_key_len(key.len() / 2)?;
+        if value.is_empty() && !self.allow_empty_values {
+            let (n, removed) = self.delete_at(self.root.clone(), &key)?;
+            self.root = n;
+            self.dirty |= removed.is_some();
+            return Ok(());
         }
+        let root = self.root.clone();
+        self.root = self.insert_at(root, &key, value)?;
+        self.dirty = true;
+        Ok(())
     }
 
-    fn encode_raw(&self, n: Node, cache: &mut HashMap<Vec<u8>, Vec<u8>>) -> Vec<u8> {
-        match n {
-            Node::Empty => rlp::NULL_RLP.to_vec(),
-            Node::Leaf(leaf) => {
-                let leaf_ref = unsafe { leaf.as_ref() };
+    /// Returns the value for a key given as nibbles, see `insert_nibbles`.
+    pub fn get_nibbles(&self, key: &NibbleSlice) -> TrieResult<Option<Vec<u8>>> {
+        let key = Self::leaf_nibbles(key)?;
+        self.check_key_len(key.len() / 2)?;
+        self.get_at(self.root.clone(), &key)
+    }
 
-                let mut stream = RlpStream::new_list(2);
-                stream.append(&leaf_ref.key.encode_compact());
-                stream.append(&leaf_ref.value);
-                stream.out().to_vec()
-            }
-            Node::Branch(branch) => {
-                let branch_ref = unsafe { branch.as_ref() };
+    /// Passes the value for key to `f` without cloning it, `None` if the trie does not
+    /// contain key.
+    ///
+    /// The value is borrowed from the node holding it, for a stored node the expanded
+    /// copy kept in the cache. The cache is not locked while `f` runs.
+    pub fn with_value<F, R>(&self, key: &[u8], f: F) -> TrieResult<R>
+    where
+        F: FnOnce(Option<&[u8]>) -> R,
+    {
+        self.check_key_len(key.len())?;
+        self.with_value_at(
+            self.root.clone(),
+            &NibbleVec::from_raw(key.to_vec(), true),
+            &mut 0,
+            f,
+        )
+    }
 
-                let mut stream = RlpStream::new_list(17);
-                for i in 0..16 {
-                    let n = branch_ref.children[i].clone();
-                    let data = self.encode_node(n, cache);
-                    if data.len() == KECCAK_SIZE {
-                        stream.append(&data);
-                    } else {
-                        stream.append_raw(&data, 1);
-                    }
-                }
+    /// Same as `get`, together with the number of nodes on the path to the value,
+    /// e.g. to estimate the cost of reading it.
+    ///
+    /// A node referenced by hash counts twice, once for the reference and once for the
+    /// node recovered from it, so the depth of a key depends on how the nodes on its
+    /// path are stored.
+    pub fn get_with_depth(&self, key: &[u8]) -> TrieResult<Option<(Vec<u8>, usize)>> {
+        self.check_key_len(key.len())?;
+        let mut depth = 0;
+        let value = self.with_value_at(
+            self.root.clone(),
+            &NibbleVec::from_raw(key.to_vec(), true),
+            &mut depth,
+            |value| value.map(<[u8]>::to_vec),
+        )?;
+        Ok(value.map(|value| (value, depth)))
+    }
 
-                match &branch_ref.value {
-                    Some(v) => stream.append(v),
-                    None => stream.append_empty_data(),
-                };
-                stream.out().to_vec()
-            }
-            Node::Extension(ext) => {
-                let ext_ref = unsafe { ext.as_ref() };
+    /// Returns the length of the value for key without cloning it, `None` if the trie
+    /// does not contain key. See `with_value`.
+    pub fn value_len(&self, key: &[u8]) -> TrieResult<Option<usize>> {
+        self.with_value(key, |value| value.map(<[u8]>::len))
+    }
 
-                let mut stream = RlpStream::new_list(2);
-                stream.append(&ext_ref.prefix.encode_compact());
-                let data = self.encode_node(ext_ref.node.clone(), cache);
-                if data.len() == KECCAK_SIZE {
-                    stream.append(&data);
-                } else {
-                    stream.append_raw(&data, 1);
-                }
-                stream.out().to_vec()
-            }
-            Node::Hash(_hash) => unreachable!(),
-        }
+    /// Checks whether the value for key equals `candidate`, comparing every byte
+    /// without stopping at the first difference.
+    ///
+    /// Meant for secrets compared right after a lookup, where `==` would leak through
+    /// its timing how many leading bytes of `candidate` are right. Only the comparison
+    /// is constant-time: the lookup itself, the length of the stored value and whether
+    /// key exists can still be observed. An absent key never matches.
+    pub fn verify_value(&self, key: &[u8], candidate: &[u8]) -> TrieResult<bool> {
+        self.with_value(key, |value| match value {
+            Some(value) => constant_time_eq(value, candidate),
+            None => false,
+        })
     }
 
-    #[allow(clippy::only_used_in_recursion)]
-    fn decode_node(&self, data: &[u8]) -> TrieResult<Node> {
-        let r = Rlp::new(data);
+    /// Same as `insert`, for a key encoded as 8 big-endian bytes, so the keys of
+    /// e.g. block numbers are ordered like the numbers.
+    pub fn insert_u64(&mut self, key: u64, value: Vec<u8>) -> TrieResult<()> {
+        self.insert(key.to_be_bytes().to_vec(), value)
+    }
 
-        match r.prototype()? {
-            Prototype::Data(0) => Ok(Node::Empty),
-            Prototype::List(2) => {
-                let key = r.at(0)?.data()?;
-                let key = NibbleVec::from_compact(key.to_vec());
+    /// Same as `get`, see `insert_u64`.
+    pub fn get_u64(&self, key: u64) -> TrieResult<Option<Vec<u8>>> {
+        self.get(&key.to_be_bytes())
+    }
 
-                if key.is_leaf() {
-                    Ok(Node::from_leaf(key, r.at(1)?.data()?.to_vec()))
-                } else {
-                    let n = self.decode_node(r.at(1)?.as_raw())?;
+    /// Same as `remove`, see `insert_u64`.
+    pub fn remove_u64(&mut self, key: u64) -> TrieResult<bool> {
+        self.remove(&key.to_be_bytes())
+    }
 
-                    Ok(Node::from_extension(key, n))
-                }
-            }
-            Prototype::List(17) => {
-                let mut nodes = empty_children();
-                #[allow(clippy::needless_range_loop)]
-                for i in 0..nodes.len() {
-                    let rlp_data = r.at(i)?;
-                    let n = self.decode_node(rlp_data.as_raw())?;
-                    nodes[i] = n;
-                }
+    /// Same as `iter`, with the keys decoded back from `insert_u64`. A key which is
+    /// not 8 bytes long yields `TrieError::InvalidData`.
+    pub fn iter_u64(&self) -> impl Iterator<Item = TrieResult<(u64, Vec<u8>)>> + '_ {
+        self.iter().map(|(key, value)| {
+            let key = key
+                .as_slice()
+                .try_into()
+                .map_err(|_| TrieError::InvalidData)?;
+            Ok((u64::from_be_bytes(key), value))
+        })
+    }
 
-                // The last element is a value node.
-                let value_rlp = r.at(16)?;
-                let value = if value_rlp.is_empty() {
-                    None
-                } else {
-                    Some(value_rlp.data()?.to_vec())
-                };
+    /// Returns the values for a batch of keys, aligned with the order of `keys`.
+    ///
+    /// Keys are sorted internally so the descent and the expansion of hash nodes
+    /// are shared by keys with a common prefix.
+    pub fn get_many(&self, keys: &[&[u8]]) -> TrieResult<Vec<Option<Vec<u8>>>> {
+        let mut results = vec![None; keys.len()];
+        self.with_values(keys, |i, value| results[i] = Some(value.to_vec()))?;
+        Ok(results)
+    }
 
-                Ok(Node::from_branch(nodes, value))
-            }
-            _ => {
-                if r.is_data() && r.size() == KECCAK_SIZE {
-                    Ok(Node::from_hash(r.data()?.try_into().unwrap()))
-                } else {
-                    Err(TrieError::InvalidData)
-                }
-            }
-        }
+    /// Expands the stored nodes on the paths to keys into the node cache, so the
+    /// following reads of these keys don't access the database.
+    ///
+    /// Shared paths are walked once, and concurrent calls never expand the same node
+    /// twice. Only as many nodes as the cache capacity stay cached, see
+    /// `with_cache_capacity`.
+    pub fn prefetch(&self, keys: &[&[u8]]) -> TrieResult<()> {
+        self.with_values(keys, |_, _| {})
     }
 
-    fn recover_from_db(&self, key: &[u8]) -> TrieResult<Node> {
-        match self.db.get(key).map_err(|e| TrieError::DB(e.to_string()))? {
-            Some(value) => Ok(self.decode_node(&value)?),
-            None => Ok(Node::Empty),
+    fn with_values<F: FnMut(usize, &[u8])>(&self, keys: &[&[u8]], mut f: F) -> TrieResult<()> {
+        for key in keys {
+            self.check_key_len(key.len())?;
         }
-    }
+        let nibbles = keys
+            .iter()
+            .map(|key| NibbleVec::from_raw(key.to_vec(), true))
+            .collect::<Vec<_>>();
+        let mut partials = nibbles
+            .iter()
+            .enumerate()
+            .map(|(i, key)| (i, &**key))
+            .collect::<Vec<(usize, &NibbleSlice)>>();
+        partials.sort_by(|a, b| a.1.as_bytes().cmp(b.1.as_bytes()));
 
-    fn cache_node(&self, n: Node, cache: &mut HashMap<Vec<u8>, Vec<u8>>) -> TrieResult<Vec<u8>> {
-        match n {
-            Node::Empty => Ok(rlp::NULL_RLP.to_vec()),
-            Node::Leaf(leaf) => {
-                let leaf_ref = unsafe { leaf.as_ref() };
+        self.with_values_at(self.root.clone(), &partials, &mut f)
+    }
 
-                let mut stream = RlpStream::new_list(2);
-                stream.append(&leaf_ref.key.encode_compact());
-                stream.append(&leaf_ref.value);
-                Ok(stream.out().to_vec())
-            }
-            Node::Branch(branch) => {
-                let branch_ref = unsafe { branch.as_ref() };
+    /// Inserts the value `f` returns for the value of key, `None` if the trie does not
+    /// contain key, e.g. to add to a counter. Same as `get` and then `insert`, with a
+    /// single descent.
+    ///
+    /// As with `insert`, an empty value removes key unless empty values are allowed.
+    pub fn insert_with<F: FnOnce(Option<Vec<u8>>) -> Vec<u8>>(
+        &mut self,
+        key: Vec<u8>,
+        f: F,
+    ) -> TrieResult<()> {
+        self.check_key_len(key.len())?;
+        let allow_empty_values = self.allow_empty_values;
+        let mut changed = false;
+        let root = self.root.clone();
+        let (root, _) = self.insert_with_at(root, &NibbleVec::from_raw(key, true), |old| {
+            let value = f(old.clone());
+            changed = match old {
+                Some(old) => old != value,
+                None => !value.is_empty() || allow_empty_values,
+            };
+            value
+        })?;
+        self.root = root;
+        self.dirty |= changed;
+        Ok(())
+    }
 
-                let mut stream = RlpStream::new_list(17);
-                for i in 0..16 {
-                    let n = branch_ref.children[i].clone();
-                    let data = self.cache_node(n, cache)?;
-                    if data.len() == KECCAK_SIZE {
-                        stream.append(&data);
-                    } else {
-                        stream.append_raw(&data, 1);
-                    }
-                }
+    /// Same as `insert`, but returns the value key had before, `None` if the trie did
+    /// not contain it, like `HashMap::insert`.
+    pub fn insert_returning(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> TrieResult<Option<Vec<u8>>> {
+        let mut previous = None;
+        self.insert_with(key, |old| {
+            previous = old;
+            value
+        })?;
+        Ok(previous)
+    }
 
-                match &branch_ref.value {
-                    Some(v) => stream.append(v),
-                    None => stream.append_empty_data(),
-                };
-                Ok(stream.out().to_vec())
-            }
-            Node::Extension(ext) => {
-                let ext_ref = unsafe { ext.as_ref() };
+    /// Returns the value for key, or inserts the value returned by `f` and returns it
+    /// if the trie does not contain key. `f` is only called on a miss.
+    pub fn get_or_insert_with<F: FnOnce() -> Vec<u8>>(
+        &mut self,
+        key: Vec<u8>,
+        f: F,
+    ) -> TrieResult<Vec<u8>> {
+        if let Some(value) = self.get(&key)? {
+            return Ok(value);
+        }
+        let value = f();
+        self.insert(key, value.clone())?;
+        Ok(value)
+    }
 
-                let mut stream = RlpStream::new_list(2);
-                stream.append(&ext_ref.prefix.encode_compact());
-                let data = self.cache_node(ext_ref.node.clone(), cache)?;
-                if data.len() == KECCAK_SIZE {
-                    stream.append(&data);
-                } else {
-                    stream.append_raw(&data, 1);
-                }
-                Ok(stream.out().to_vec())
-            }
-            Node::Hash(hash_node) => {
-                let hash = unsafe { hash_node.as_ref() }.hash;
-                let next_node = self.recover_from_db(&hash)?;
-                let data = match self.cache_node(next_node.clone(), cache) {
-                    Ok(data) => {
-                        unsafe { Node::dealloc(next_node) };
-                        data
-                    }
-                    Err(e) => {
-                        unsafe { Node::dealloc(next_node) };
-                        return Err(e);
-                    }
-                };
-                cache.insert(hash.to_vec(), data);
-                Ok(hash.to_vec())
+    /// Inserts the entries with a single descent, so the nodes on a shared key prefix
+    /// are visited once instead of once per entry. Same as inserting the entries in
+    /// order, but faster when they are sorted by key.
+    ///
+    /// On error, some of the entries may be inserted already.
+    pub fn insert_many_sorted(&mut self, sorted: &[(Vec<u8>, Vec<u8>)]) -> TrieResult<()> {
+        for (key, _) in sorted {
+            self.check_key_len(key.len())?;
+        }
+        if !self.allow_empty_values && sorted.iter().any(|(_, value)| value.is_empty()) {
+            // Empty values remove keys, which the batched descent doesn't handle.
+            for (key, value) in sorted {
+                self.insert(key.clone(), value.clone())?;
             }
+            return Ok(());
         }
+        let items = sorted
+            .iter()
+            .map(|(key, value)| (NibbleVec::from_raw(key.clone(), true), value.clone()))
+            .collect::<Vec<_>>();
+        let root = self.root.clone();
+        self.root = self.insert_many_at(root, 0, &items)?;
+        self.dirty |= !items.is_empty();
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use rand::distributions::Alphanumeric;
-    use rand::seq::SliceRandom;
-    use rand::{thread_rng, Rng};
-    use sha3::Digest;
-    use std::collections::{HashMap, HashSet};
+    /// Same as `remove`, but returns the removed value, `None` if key was absent.
+    pub fn remove_value(&mut self, key: &[u8]) -> TrieResult<Option<Vec<u8>>> {
+        self.check_key_len(key.len())?;
+        #[cfg(feature = "tracing")]
+        let span = tracing::debug_span!(
+            "remove",
+            key_len = key.len(),
+            removed = tracing::field::Empty,
+            nodes_recovered = tracing::field::Empty,
+        )
+        .entered();
+        #[cfg(feature = "tracing")]
+        let recovered = self.recovered_nodes_hashes.len();
 
-    use super::{PatriciaTrie, Trie};
-    use crate::db::{MemoryDB, DB};
+        let (n, removed) =
+            self.delete_at(self.root.clone(), &NibbleVec::from_raw(key.to_vec(), true))?;
+        self.root = n;
+        self.dirty |= removed.is_some();
 
-    #[test]
-    fn test_is_sync() {
-        fn is_sync<T: Send + Sync>() {}
+        #[cfg(feature = "tracing")]
+        {
+            span.record("removed", removed.is_some());
+            span.record(
+                "nodes_recovered",
+                self.recovered_nodes_hashes.len() - recovered,
+            );
+        }
+        Ok(removed)
+    }
 
-        is_sync::<PatriciaTrie<MemoryDB>>()
+    /// Returns the total size of the nodes `get_proof` returns for key, without
+    /// collecting them.
+    pub fn proof_size(&self, key: &[u8]) -> TrieResult<usize> {
+        self.check_key_len(key.len())?;
+        let mut values = HashMap::new();
+        let root_size = match self.root {
+            Node::Empty => 0,
+            _ => self.encode_raw(self.root.clone(), &mut values).len(),
+        };
+        let path_size = self.proof_size_at(
+            self.root.clone(),
+            &NibbleVec::from_raw(key.to_vec(), true),
+            &mut values,
+        )?;
+        let values_size = match self.value_store {
+            Some(_) => values.values().map(Vec::len).sum(),
+            None => 0,
+        };
+        Ok(root_size + path_size + values_size)
+    }
+
+    /// Returns the value for key together with its proof, walking the path once.
+    ///
+    /// The value is `None` if the trie does not contain key, the proof is then an
+    /// exclusion proof. Same as `get` and `get_proof`, without the proof cache.
+    #[allow(clippy::type_complexity)]
+    pub fn get_with_proof(&self, key: &[u8]) -> TrieResult<(Option<Vec<u8>>, Vec<Vec<u8>>)> {
+        self.check_key_len(key.len())?;
+        let (value, path) =
+            self.get_with_path_at(self.root.clone(), &NibbleVec::from_raw(key.to_vec(), true))?;
+        Ok((value, self.encode_path(path)))
+    }
+
+    /// Returns the encoding of the node holding the value for key, a leaf or a branch,
+    /// `None` if the trie does not contain key.
+    ///
+    /// The encoding is the one its parent refers to: a node encoded in less than
+    /// `C::INLINE_THRESHOLD` bytes is embedded in its parent, a longer one is stored
+    /// under its hash. The root node is always stored.
+    pub fn node_encoding(&self, key: &[u8]) -> TrieResult<Option<Vec<u8>>> {
+        self.check_key_len(key.len())?;
+        self.node_encoding_at(self.root.clone(), &NibbleVec::from_raw(key.to_vec(), true))
+    }
+
+    /// Returns a proof for all of keys, smaller than the union of their proofs from
+    /// `get_proof`. See `CompactMultiproof` and `verify_compact_multiproof`.
+    pub fn get_compact_multiproof(&self, keys: &[&[u8]]) -> TrieResult<CompactMultiproof> {
+        for key in keys {
+            self.check_key_len(key.len())?;
+        }
+        multiproof::encode(self, keys)
+    }
+
+    /// Same as `get_proof`, but also tells whether the proof proves the presence or
+    /// the absence of key. The path is walked once, as in `get_with_proof`.
+    ///
+    /// An exclusion proof ends with the node in which the path to key diverges from
+    /// the trie, see `get_proof`.
+    pub fn get_proof_kind(&self, key: &[u8]) -> TrieResult<(ProofKind, Vec<Vec<u8>>)> {
+        let (value, proof) = self.get_with_proof(key)?;
+        let kind = match value {
+            Some(value) => ProofKind::Inclusion(value),
+            None => ProofKind::Exclusion,
+        };
+        Ok((kind, proof))
+    }
+
+    /// Saves the current state of the trie, including uncommitted changes,
+    /// so it can be restored with `rollback_to`.
+    ///
+    /// Only nodes modified since the last commit are copied, the rest is shared
+    /// through hash nodes. A commit after the checkpoint may prune nodes from the db
+    /// which the checkpoint still refers to, so roll back before committing.
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            root: self.root.deep_copy(),
+            root_hash: self.root_hash.clone(),
+            dirty: self.dirty,
+            recovered_nodes_hashes: self.recovered_nodes_hashes.clone(),
+        }
+    }
+
+    /// Discards all changes made since `checkpoint` was taken.
+    pub fn rollback_to(&mut self, mut checkpoint: Checkpoint) {
+        let root = std::mem::replace(&mut checkpoint.root, Node::Empty);
+        unsafe { Node::dealloc(std::mem::replace(&mut self.root, root)) };
+        self.root_hash = std::mem::take(&mut checkpoint.root_hash);
+        self.dirty = checkpoint.dirty;
+        self.recovered_nodes_hashes = std::mem::take(&mut checkpoint.recovered_nodes_hashes);
+    }
+
+    /// Checks that any key starting with `prefix` is present in the trie.
+    ///
+    /// Unlike `contains`, the key doesn't have to match exactly, so an empty prefix
+    /// checks that the trie is not empty.
+    pub fn contains_prefix(&self, prefix: &[u8]) -> TrieResult<bool> {
+        self.check_key_len(prefix.len())?;
+        self.contains_prefix_at(
+            self.root.clone(),
+            &NibbleVec::from_raw(prefix.to_vec(), false),
+        )
+    }
+
+    /// Returns the entries whose keys start with prefix as a `Subtree`, `None` if there
+    /// are none. The handle borrows the trie and shares its database and cache.
+    pub fn subtree(&self, prefix: &[u8]) -> TrieResult<Option<Subtree<'_, D, H, C>>> {
+        self.check_key_len(prefix.len())?;
+        let partial = NibbleVec::from_raw(prefix.to_vec(), false);
+        let found = self.subtree_at(self.root.clone(), &partial, None)?;
+        Ok(found.map(|(node, skip, owner)| Subtree {
+            trie: self,
+            prefix: prefix.to_vec(),
+            node,
+            skip,
+            _owner: owner,
+        }))
+    }
+
+    /// Returns the longest key in the trie which is a prefix of key, together with its
+    /// value. Key itself is its own longest prefix if the trie contains it.
+    pub fn longest_prefix(&self, key: &[u8]) -> TrieResult<Option<(Vec<u8>, Vec<u8>)>> {
+        self.check_key_len(key.len())?;
+        let partial = NibbleVec::from_raw(key.to_vec(), false);
+        let found = self.longest_prefix_at(self.root.clone(), &partial, 0)?;
+        // Keys are whole bytes, so a prefix always ends at an even nibble.
+        Ok(found.map(|(len, value)| (key[..len / 2].to_vec(), value)))
+    }
+
+    /// Returns the path, as nibbles, of the deepest node on the paths of both keys, e.g.
+    /// the branch they take different children of, and the hash of that node. Keys
+    /// don't need to be in the trie, the paths end where the trie has no node for them.
+    ///
+    /// The proofs of both keys share the nodes down to it, so the rest of each proof
+    /// shows where they diverge. The hash is computed from the nodes in memory, for a
+    /// node embedded in its parent it is the hash of its encoding.
+    pub fn common_ancestor(&self, key_a: &[u8], key_b: &[u8]) -> TrieResult<(Vec<u8>, Vec<u8>)> {
+        self.check_key_len(key_a.len().max(key_b.len()))?;
+        let a = NibbleVec::from_raw(key_a.to_vec(), true);
+        let b = NibbleVec::from_raw(key_b.to_vec(), true);
+        let (depth, hash) = self.common_ancestor_at(self.root.clone(), &a, &b, 0)?;
+        Ok((a.as_bytes()[..depth].to_vec(), hash))
+    }
+
+    /// Returns the entry with the smallest key, recovering only the nodes on its path.
+    pub fn first(&self) -> TrieResult<Option<(Vec<u8>, Vec<u8>)>> {
+        self.edge_at(self.root.clone(), &mut NibbleVec::from_hex(vec![]), false)
+    }
+
+    /// Returns the entry with the largest key, recovering only the nodes on its path.
+    pub fn last(&self) -> TrieResult<Option<(Vec<u8>, Vec<u8>)>> {
+        self.edge_at(self.root.clone(), &mut NibbleVec::from_hex(vec![]), true)
+    }
+
+    /// Removes every key starting with `prefix`, returning the number of removed keys.
+    ///
+    /// The subtree under the prefix is dropped as a whole, its stored nodes are
+    /// still recovered to count the keys and to prune them on the next commit.
+    pub fn remove_prefix(&mut self, prefix: &[u8]) -> TrieResult<usize> {
+        self.check_key_len(prefix.len())?;
+        let (n, removed) = self.remove_prefix_at(
+            self.root.clone(),
+            &NibbleVec::from_raw(prefix.to_vec(), false),
+        )?;
+        self.root = n;
+        self.dirty |= removed > 0;
+        Ok(removed)
+    }
+
+    /// Removes every key in `[start, end)`, returning the number of removed keys.
+    ///
+    /// Subtrees entirely within the range are dropped as a whole, as in
+    /// `remove_prefix`, only the paths to both ends of the range are walked.
+    pub fn remove_range(&mut self, start: &[u8], end: &[u8]) -> TrieResult<usize> {
+        self.check_key_len(start.len().max(end.len()))?;
+        if start >= end {
+            return Ok(0);
+        }
+        let start = NibbleVec::from_raw(start.to_vec(), false);
+        let end = NibbleVec::from_raw(end.to_vec(), false);
+        let (n, removed) = self.remove_range_at(
+            self.root.clone(),
+            &mut NibbleVec::from_hex(vec![]),
+            start.as_bytes(),
+            end.as_bytes(),
+        )?;
+        self.root = n;
+        self.dirty |= removed > 0;
+        Ok(removed)
+    }
+
+    /// Removes the keys, returning the number of removed keys.
+    ///
+    /// The result is the same as removing the keys one by one, but the deletions are
+    /// done in a single descent and every node they touch is merged with its children
+    /// once, on the way back up. On error, some of the keys may be removed already.
+    pub fn remove_batch(&mut self, keys: &[&[u8]]) -> TrieResult<usize> {
+        for key in keys {
+            self.check_key_len(key.len())?;
+        }
+        let mut keys = keys
+            .iter()
+            .map(|key| NibbleVec::from_raw(key.to_vec(), true))
+            .collect::<Vec<_>>();
+        keys.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+        keys.dedup();
+        let (n, removed) = self.remove_many_at(self.root.clone(), 0, &keys)?;
+        self.root = n;
+        self.dirty |= removed > 0;
+        Ok(removed)
+    }
+
+    /// Predicts how inserting key would change the structure of the trie, without
+    /// modifying it. Nodes embedded in their parent are counted like the others.
+    pub fn insert_cost(&self, key: &[u8]) -> TrieResult<InsertCost> {
+        self.check_key_len(key.len())?;
+        let mut cost = InsertCost {
+            kind: InsertKind::NewLeaf,
+            modified_nodes: 0,
+            new_nodes: 0,
+        };
+        cost.kind = self.insert_cost_at(
+            self.root.clone(),
+            &NibbleVec::from_raw(key.to_vec(), true),
+            &mut cost,
+        )?;
+        Ok(cost)
+    }
+
+    /// Writes the trie as of the last commit as a single blob, read back with
+    /// `from_reader`.
+    ///
+    /// The blob holds a header, the root hash and every stored node reachable from it
+    /// as (hash, encoding) pairs, sorted by hash. The lengths are little endian.
+    /// Values stored by hash with a `ValueStore` are not included.
+    pub fn to_writer(&self, w: &mut impl Write) -> TrieResult<()> {
+        let trie = self
+            .builder_like()
+            .root(&self.root_hash)
+            .build_from_root()?;
+        let mut hashes = trie.reachable_hashes()?.into_iter().collect::<Vec<_>>();
+        hashes.sort();
+
+        w.write_all(SERIALIZED_MAGIC)?;
+        w.write_all(&[SERIALIZED_VERSION])?;
+        w.write_all(&self.root_hash)?;
+        w.write_all(&(hashes.len() as u64).to_le_bytes())?;
+        for hash in hashes.iter() {
+            let node = self
+                .get_stored(hash)?
+                .ok_or_else(|| TrieError::MissingNode(hash.to_vec()))?;
+            w.write_all(hash)?;
+            w.write_all(&(node.len() as u32).to_le_bytes())?;
+            w.write_all(&node)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the node tree to `w` for debugging, one node per line indented by depth.
+    ///
+    /// Each line shows the node type, the key or prefix nibbles and the value truncated
+    /// to `DUMP_VALUE_LEN` bytes. Nodes are marked as embedded in their parent or hashed,
+    /// with the hash, and stored nodes are recovered as they are reached.
+    pub fn dump(&self, w: &mut impl Write) -> TrieResult<()> {
+        self.dump_at(self.root.clone(), w, 0, None)
+    }
+
+    /// Writes the nodes of the subtree holding the keys starting with `prefix` to the
+    /// database and returns the hash of the subtree, an empty subtree hashes to the root
+    /// of the empty trie.
+    ///
+    /// The rest of the trie stays uncommitted and `root_hash` is not updated. The written
+    /// subtree is replaced by its hash node, unless it is the root or small enough to be
+    /// embedded in its parent, so it is freed from memory and a later `commit` doesn't
+    /// encode it again. That commit writes the same root as without `commit_subtree`.
+    pub fn commit_subtree(&mut self, prefix: &[u8]) -> TrieResult<Vec<u8>> {
+        self.check_key_len(prefix.len())?;
+        let (n, hash) = self.commit_subtree_at(
+            self.root.clone(),
+            &NibbleVec::from_raw(prefix.to_vec(), false),
+            true,
+        )?;
+        self.root = n;
+        Ok(hash)
+    }
+}
+
+impl PatriciaTrie<MemoryDB> {
+    /// Opens the trie at `root` over a witness, a set of encoded nodes such as the
+    /// union of proofs for the keys a block touches. The nodes are loaded into a new
+    /// light `MemoryDB` keyed by their hashes.
+    ///
+    /// Operations only work as long as the nodes they need are part of the witness,
+    /// needing any other node fails with `TrieError::MissingNode`.
+    pub fn from_witness(root: &[u8], nodes: Vec<Vec<u8>>) -> TrieResult<Self> {
+        let memdb = MemoryDB::new(true);
+        memdb
+            .insert_batch(
+                nodes
+                    .into_iter()
+                    .map(|node| (HasherKeccak::digest(&node).to_vec(), node)),
+            )
+            .map_err(|e| TrieError::DB(e.to_string()))?;
+        Self::from(memdb, root)
+    }
+
+    /// Reads a trie written by `to_writer` into a new light `MemoryDB`.
+    ///
+    /// Every node is checked to hash to the key it was written with. Returns
+    /// `TrieError::InvalidData` if the header doesn't match.
+    pub fn from_reader(r: &mut impl Read) -> TrieResult<Self> {
+        let mut header = [0; 5];
+        r.read_exact(&mut header)?;
+        if &header[..4] != SERIALIZED_MAGIC || header[4] != SERIALIZED_VERSION {
+            return Err(TrieError::InvalidData);
+        }
+        let mut root = [0; HASH_SIZE];
+        r.read_exact(&mut root)?;
+        let mut count = [0; 8];
+        r.read_exact(&mut count)?;
+
+        let memdb = MemoryDB::new(true);
+        for _ in 0..u64::from_le_bytes(count) {
+            let mut hash = [0; HASH_SIZE];
+            r.read_exact(&mut hash)?;
+            let mut len = [0; 4];
+            r.read_exact(&mut len)?;
+            let mut node = vec![];
+            r.by_ref()
+                .take(u32::from_le_bytes(len) as u64)
+                .read_to_end(&mut node)?;
+            if node.len() != u32::from_le_bytes(len) as usize {
+                return Err(TrieError::InvalidData);
+            }
+            Self::check_node_hash(&hash, &node)?;
+            memdb
+                .insert(hash.to_vec(), node)
+                .map_err(|e| TrieError::DB(e.to_string()))?;
+        }
+        Self::from(memdb, &root)
+    }
+}
+
+impl FromIterator<(Vec<u8>, Vec<u8>)> for PatriciaTrie<MemoryDB> {
+    /// Builds a committed trie backed by a new light `MemoryDB`. The trie accepts keys
+    /// of any length, `DEFAULT_MAX_KEY_LEN` does not apply.
+    fn from_iter<I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>>(iter: I) -> Self {
+        let mut trie = PatriciaTrieBuilder::new()
+            .db(MemoryDB::new(true))
+            .max_key_len(usize::MAX)
+            .build();
+        for (key, value) in iter {
+            trie.insert(key, value)
+                .expect("MemoryDB operations never fail");
+        }
+        trie.commit().expect("MemoryDB operations never fail");
+        trie
+    }
+}
+
+impl<'a, D, H, C> IntoIterator for &'a PatriciaTrie<D, H, C>
+where
+    D: DB + Clone,
+    H: Hasher,
+    C: NodeCodec,
+{
+    type Item = (Vec<u8>, Vec<u8>);
+    type IntoIter = TrieIterator<'a, D, H, C>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<D, H, C> Trie<D> for PatriciaTrie<D, H, C>
+where
+    D: DB + Clone,
+    H: Hasher,
+    C: NodeCodec,
+{
+    /// Returns the value for key stored in the trie.
+    fn get(&self, key: &[u8]) -> TrieResult<Option<Vec<u8>>> {
+        self.check_key_len(key.len())?;
+        self.get_at(self.root.clone(), &NibbleVec::from_raw(key.to_vec(), true))
+    }
+
+    /// Checks that the key is present in the trie
+    fn contains(&self, key: &[u8]) -> TrieResult<bool> {
+        self.check_key_len(key.len())?;
+        Ok(self
+            .get_at(self.root.clone(), &NibbleVec::from_raw(key.to_vec(), true))?
+            .is_some())
+    }
+
+    /// Inserts value into trie and modifies it if it exists
+    fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) -> TrieResult<()> {
+        #[cfg(feature = "tracing")]
+        let span = tracing::debug_span!(
+            "insert",
+            key_len = key.len(),
+            value_len = value.len(),
+            nodes_recovered = tracing::field::Empty,
+        )
+        .entered();
+        #[cfg(feature = "tracing")]
+        let recovered = self.recovered_nodes_hashes.len();
+
+        self.check_key_len(key.len())?;
+        if value.is_empty() && !self.allow_empty_values {
+            self.remove(&key)?;
+            return Ok(());
+        }
+        let root = self.root.clone();
+        self.root = self.insert_at(root, &NibbleVec::from_raw(key, true), value.to_vec())?;
+        self.dirty = true;
+
+        #[cfg(feature = "tracing")]
+        span.record(
+            "nodes_recovered",
+            self.recovered_nodes_hashes.len() - recovered,
+        );
+        Ok(())
+    }
+
+    /// Removes any existing value for key from the trie.
+    fn remove(&mut self, key: &[u8]) -> TrieResult<bool> {
+        Ok(self.remove_value(key)?.is_some())
+    }
+
+    /// Saves all the nodes in the db, clears the cache data, recalculates the root.
+    /// Returns the root hash of the trie.
+    fn root(&mut self) -> TrieResult<Vec<u8>> {
+        self.commit()
+    }
+
+    /// Prove constructs a merkle proof for key. The result contains all encoded nodes
+    /// on the path to the value at key. The value itself is also included in the last
+    /// node and can be retrieved by verifying the proof.
+    ///
+    /// If the trie does not contain a value for key, the returned proof contains all
+    /// nodes of the longest existing prefix of the key (at least the root node), ending
+    /// with the node that proves the absence of the key.
+    fn get_proof(&self, key: &[u8]) -> TrieResult<Vec<Vec<u8>>> {
+        self.check_key_len(key.len())?;
+        if self.dirty {
+            return self.compute_proof(key);
+        }
+        let cache_key = (self.root_hash.clone(), key.to_vec());
+        if let Some(proof) = self.proof_cache.lock().get(&cache_key) {
+            return Ok(proof.clone());
+        }
+        let proof = self.compute_proof(key)?;
+        self.proof_cache.lock().insert(cache_key, proof.clone());
+        Ok(proof)
+    }
+
+    /// return value if key exists, None if key not exist, Error if proof is wrong
+    fn verify_proof(
+        &self,
+        root_hash: &[u8],
+        key: &[u8],
+        proof: Vec<Vec<u8>>,
+    ) -> TrieResult<Option<Vec<u8>>> {
+        if root_hash == self.empty_root {
+            return Ok(None);
+        }
+        let memdb = MemoryDB::new(true);
+        for node_encoded in proof.into_iter() {
+            let hash = H::digest(&node_encoded);
+
+            // A referenced value is looked up by hash, however short it is.
+            if root_hash == hash.as_slice()
+                || !self.inline_small_nodes
+                || self.value_store.is_some()
+                || node_encoded.len() >= C::INLINE_THRESHOLD
+            {
+                memdb.insert(hash.to_vec(), node_encoded).unwrap();
+            }
+        }
+        let trie = self
+            .encoding_like(memdb)
+            .root(root_hash)
+            .build_from_root()
+            .or(Err(TrieError::InvalidProof))?;
+        trie.get(key).or(Err(TrieError::InvalidProof))
+    }
+}
+
+impl<D, H, C> PatriciaTrie<D, H, C>
+where
+    D: DB + Clone,
+    H: Hasher,
+    C: NodeCodec,
+{
+    /// Builds the proof for key without consulting the proof cache.
+    fn compute_proof(&self, key: &[u8]) -> TrieResult<Vec<Vec<u8>>> {
+        let path = self.get_path_at(self.root.clone(), &NibbleVec::from_raw(key.to_vec(), true))?;
+        Ok(self.encode_path(path))
+    }
+
+    // Encodes the stored nodes returned by `get_path_at` into a proof, root first.
+    // The values they refer to in the value store follow them, sorted.
+    fn encode_path(&self, mut path: Vec<Node>) -> Vec<Vec<u8>> {
+        match self.root {
+            Node::Empty => {}
+            _ => path.push(self.root.clone()),
+        }
+        let mut values = HashMap::new();
+        let mut proof: Vec<_> = path
+            .iter()
+            .rev()
+            .map(|n| self.encode_raw(n.clone(), &mut values))
+            .collect();
+        if self.value_store.is_some() {
+            let mut values: Vec<_> = values.into_values().collect();
+            values.sort();
+            proof.extend(values);
+        }
+        if !path.is_empty() {
+            // exclude root
+            for n in path.drain(..path.len() - 1) {
+                unsafe { Node::dealloc(n) };
+            }
+        }
+        proof
+    }
+
+    fn get_at(&self, n: Node, partial: &NibbleSlice) -> TrieResult<Option<Vec<u8>>> {
+        self.with_value_at(n, partial, &mut 0, |value| value.map(|v| v.to_vec()))
+    }
+
+    /// Passes the value found at `partial` to `f` without cloning it. `depth` counts
+    /// the nodes on the path.
+    pub(crate) fn with_value_at<F, R>(
+        &self,
+        n: Node,
+        partial: &NibbleSlice,
+        depth: &mut usize,
+        f: F,
+    ) -> TrieResult<R>
+    where
+        F: FnOnce(Option<&[u8]>) -> R,
+    {
+        *depth += 1;
+        match n {
+            Node::Empty => Ok(f(None)),
+            Node::Leaf(leaf) => {
+                let leaf_ref = unsafe { leaf.as_ref() };
+
+                if &*leaf_ref.key == partial {
+                    Ok(f(Some(&leaf_ref.value)))
+                } else {
+                    Ok(f(None))
+                }
+            }
+            Node::Branch(branch) => {
+                let branch_ref = unsafe { branch.as_ref() };
+
+                if partial.is_empty() || partial.at(0) == 16 {
+                    Ok(f(branch_ref.value.as_deref()))
+                } else {
+                    let index = partial.at(0);
+                    self.with_value_at(
+                        branch_ref.children[index].clone(),
+                        partial.offset(1),
+                        depth,
+                        f,
+                    )
+                }
+            }
+            Node::Extension(extension) => {
+                let extension_ref = unsafe { extension.as_ref() };
+
+                let prefix = &extension_ref.prefix;
+                let match_len = partial.common_prefix(prefix);
+                if match_len == prefix.len() {
+                    self.with_value_at(
+                        extension_ref.node.clone(),
+                        partial.offset(match_len),
+                        depth,
+                        f,
+                    )
+                } else {
+                    Ok(f(None))
+                }
+            }
+            Node::Hash(hash_node) => {
+                let trie = self.expand_cached(unsafe { hash_node.as_ref() }.hash)?;
+                self.with_value_at(trie.root.clone(), partial, depth, f)
+            }
+        }
+    }
+
+    // `partials` are sorted, so keys going through the same child are adjacent.
+    // `f` gets the index and the value of every key found.
+    fn with_values_at<F>(
+        &self,
+        n: Node,
+        partials: &[(usize, &NibbleSlice)],
+        f: &mut F,
+    ) -> TrieResult<()>
+    where
+        F: FnMut(usize, &[u8]),
+    {
+        match n {
+            Node::Empty => Ok(()),
+            Node::Leaf(leaf) => {
+                let leaf_ref = unsafe { leaf.as_ref() };
+
+                for (i, partial) in partials {
+                    if &*leaf_ref.key == *partial {
+                        f(*i, &leaf_ref.value);
+                    }
+                }
+                Ok(())
+            }
+            Node::Branch(branch) => {
+                let branch_ref = unsafe { branch.as_ref() };
+
+                let index_of = |partial: &NibbleSlice| {
+                    if partial.is_empty() {
+                        16
+                    } else {
+                        partial.at(0)
+                    }
+                };
+                let mut start = 0;
+                while start < partials.len() {
+                    let index = index_of(partials[start].1);
+                    let len = partials[start..]
+                        .iter()
+                        .take_while(|(_, partial)| index_of(partial) == index)
+                        .count();
+                    let group = &partials[start..start + len];
+                    if index == 16 {
+                        if let Some(value) = &branch_ref.value {
+                            for (i, _) in group {
+                                f(*i, value);
+                            }
+                        }
+                    } else {
+                        let group = group
+                            .iter()
+                            .map(|(i, partial)| (*i, partial.offset(1)))
+                            .collect::<Vec<_>>();
+                        self.with_values_at(branch_ref.children[index].clone(), &group, f)?;
+                    }
+                    start += len;
+                }
+                Ok(())
+            }
+            Node::Extension(extension) => {
+                let extension_ref = unsafe { extension.as_ref() };
+
+                let prefix = &extension_ref.prefix;
+                let matched = partials
+                    .iter()
+                    .filter(|(_, partial)| partial.common_prefix(prefix) == prefix.len())
+                    .map(|(i, partial)| (*i, partial.offset(prefix.len())))
+                    .collect::<Vec<_>>();
+                if matched.is_empty() {
+                    Ok(())
+                } else {
+                    self.with_values_at(extension_ref.node.clone(), &matched, f)
+                }
+            }
+            Node::Hash(hash_node) => {
+                let trie = self.expand_cached(unsafe { hash_node.as_ref() }.hash)?;
+                self.with_values_at(trie.root.clone(), partials, f)
+            }
+        }
+    }
+
+    /// Checks if there is a saved expanded node in the cache and returns it.
+    /// Otherwise expands the node from the db and adds it to the cache, if enabled.
+    ///
+    /// The lock is not held while the node is read, so concurrent readers may expand
+    /// the same node. The first one cached is kept, the others are dropped. An evicted
+    /// trie stays alive until the returned reference is dropped, then its root is
+    /// deallocated.
+    fn expand_cached(&self, hash: [u8; 32]) -> TrieResult<Arc<Self>> {
+        let cached_tries = match &self.cached_tries {
+            Some(cached_tries) => cached_tries,
+            None => return Ok(Arc::new(self.builder_like().build().load_root(&hash)?)),
+        };
+        if let Some(trie) = cached_tries.lock().get(&hash) {
+            return Ok(trie.clone());
+        }
+        let trie = Arc::new(self.builder_like().build().load_root(&hash)?);
+        let mut cached_tries = cached_tries.lock();
+        if let Some(cached) = cached_tries.get(&hash) {
+            return Ok(cached.clone());
+        }
+        cached_tries.insert(hash, trie.clone());
+        Ok(trie)
+    }
+
+    // A branch value is the smallest key of its subtree, so it is visited first
+    // from the left and last from the right.
+    fn edge_at(
+        &self,
+        n: Node,
+        nibble: &mut NibbleVec,
+        rightmost: bool,
+    ) -> TrieResult<Option<(Vec<u8>, Vec<u8>)>> {
+        match n {
+            Node::Empty => Ok(None),
+            Node::Leaf(leaf) => {
+                let leaf_ref = unsafe { leaf.as_ref() };
+                nibble.extend_from_slice(&leaf_ref.key);
+                Ok(Some((nibble.encode_raw().0, leaf_ref.value.clone())))
+            }
+            Node::Branch(branch) => {
+                let branch_ref = unsafe { branch.as_ref() };
+                let value = |nibble: &NibbleVec| {
+                    branch_ref
+                        .value
+                        .clone()
+                        .map(|value| (nibble.encode_raw().0, value))
+                };
+
+                if !rightmost {
+                    if let Some(entry) = value(nibble) {
+                        return Ok(Some(entry));
+                    }
+                }
+                let mut indexes = (0..16).collect::<Vec<_>>();
+                if rightmost {
+                    indexes.reverse();
+                }
+                for i in indexes {
+                    if let Node::Empty = branch_ref.children[i] {
+                        continue;
+                    }
+                    nibble.push(i as u8);
+                    if let Some(entry) =
+                        self.edge_at(branch_ref.children[i].clone(), nibble, rightmost)?
+                    {
+                        return Ok(Some(entry));
+                    }
+                    nibble.pop();
+                }
+                Ok(if rightmost { value(nibble) } else { None })
+            }
+            Node::Extension(ext) => {
+                let ext_ref = unsafe { ext.as_ref() };
+                nibble.extend_from_slice(&ext_ref.prefix);
+                self.edge_at(ext_ref.node.clone(), nibble, rightmost)
+            }
+            Node::Hash(hash_node) => {
+                let trie = self.expand_cached(unsafe { hash_node.as_ref() }.hash)?;
+                self.edge_at(trie.root.clone(), nibble, rightmost)
+            }
+        }
+    }
+
+    fn contains_prefix_at(&self, n: Node, partial: &NibbleSlice) -> TrieResult<bool> {
+        match n {
+            Node::Empty => Ok(false),
+            Node::Leaf(leaf) => {
+                let leaf_ref = unsafe { leaf.as_ref() };
+                Ok(leaf_ref.key.common_prefix(partial) == partial.len())
+            }
+            Node::Branch(branch) => {
+                let branch_ref = unsafe { branch.as_ref() };
+
+                if partial.is_empty() {
+                    // A branch always holds at least two values or children.
+                    Ok(true)
+                } else {
+                    let index = partial.at(0);
+                    self.contains_prefix_at(branch_ref.children[index].clone(), partial.offset(1))
+                }
+            }
+            Node::Extension(extension) => {
+                let extension_ref = unsafe { extension.as_ref() };
+
+                let prefix = &extension_ref.prefix;
+                let match_len = partial.common_prefix(prefix);
+                if match_len == partial.len() {
+                    // The prefix ends inside or right at the end of the extension.
+                    Ok(true)
+                } else if match_len == prefix.len() {
+                    self.contains_prefix_at(extension_ref.node.clone(), partial.offset(match_len))
+                } else {
+                    Ok(false)
+                }
+            }
+            Node::Hash(hash_node) => {
+                let trie = self.expand_cached(unsafe { hash_node.as_ref() }.hash)?;
+                self.contains_prefix_at(trie.root.clone(), partial)
+            }
+        }
+    }
+
+    // Returns the first node below which every key starts with `partial`, see `Subtree`.
+    // `owner` is the expanded trie `n` belongs to, if any.
+    #[allow(clippy::type_complexity)]
+    fn subtree_at(
+        &self,
+        n: Node,
+        partial: &NibbleSlice,
+        owner: Option<Arc<Self>>,
+    ) -> TrieResult<Option<(Node, usize, Option<Arc<Self>>)>> {
+        match n {
+            Node::Empty => Ok(None),
+            Node::Leaf(ref leaf) => {
+                let key = &unsafe { leaf.as_ref() }.key;
+                if key.common_prefix(partial) == partial.len() {
+                    Ok(Some((n.clone(), partial.len(), owner)))
+                } else {
+                    Ok(None)
+                }
+            }
+            Node::Branch(ref branch) => {
+                if partial.is_empty() {
+                    return Ok(Some((n.clone(), 0, owner)));
+                }
+                let child = unsafe { branch.as_ref() }.children[partial.at(0)].clone();
+                self.subtree_at(child, partial.offset(1), owner)
+            }
+            Node::Extension(ref ext) => {
+                let (prefix, child) = {
+                    let ext_ref = unsafe { ext.as_ref() };
+                    (ext_ref.prefix.clone(), ext_ref.node.clone())
+                };
+                let match_len = partial.common_prefix(&prefix);
+                if match_len == prefix.len() {
+                    self.subtree_at(child, partial.offset(match_len), owner)
+                } else if match_len == partial.len() {
+                    // The prefix ends inside the extension.
+                    Ok(Some((n.clone(), match_len, owner)))
+                } else {
+                    Ok(None)
+                }
+            }
+            Node::Hash(hash_node) => {
+                let trie = self.expand_cached(unsafe { hash_node.as_ref() }.hash)?;
+                let root = trie.root.clone();
+                self.subtree_at(root, partial, Some(trie))
+            }
+        }
+    }
+
+    // `a` and `b` are the rest of both keys below `n`, found `depth` nibbles into them.
+    // Returns the depth of the node where they split and its hash.
+    fn common_ancestor_at(
+        &self,
+        n: Node,
+        a: &NibbleSlice,
+        b: &NibbleSlice,
+        depth: usize,
+    ) -> TrieResult<(usize, Vec<u8>)> {
+        let next = match n {
+            Node::Branch(ref branch) if a.at(0) == b.at(0) && a.at(0) != 16 => {
+                match unsafe { branch.as_ref() }.children[a.at(0)].clone() {
+                    Node::Empty => None,
+                    child => Some((child, 1)),
+                }
+            }
+            Node::Extension(ref ext) => {
+                let ext_ref = unsafe { ext.as_ref() };
+                let len = ext_ref.prefix.len();
+                if a.common_prefix(&ext_ref.prefix) == len
+                    && b.common_prefix(&ext_ref.prefix) == len
+                {
+                    Some((ext_ref.node.clone(), len))
+                } else {
+                    None
+                }
+            }
+            Node::Hash(ref hash_node) => {
+                let trie = self.expand_cached(unsafe { hash_node.as_ref() }.hash)?;
+                return self.common_ancestor_at(trie.root.clone(), a, b, depth);
+            }
+            _ => None,
+        };
+        match next {
+            Some((child, len)) => {
+                self.common_ancestor_at(child, a.offset(len), b.offset(len), depth + len)
+            }
+            None => Ok((
+                depth,
+                H::digest(&self.encode_raw(n, &mut HashMap::new())).to_vec(),
+            )),
+        }
+    }
+
+    // `n` is found `depth` nibbles into the key, returns the length in nibbles of the
+    // longest prefix found. A value found deeper replaces the ones above it.
+    fn longest_prefix_at(
+        &self,
+        n: Node,
+        partial: &NibbleSlice,
+        depth: usize,
+    ) -> TrieResult<Option<(usize, Vec<u8>)>> {
+        match n {
+            Node::Empty => Ok(None),
+            Node::Leaf(leaf) => {
+                let leaf_ref = unsafe { leaf.as_ref() };
+
+                let key = leaf_ref.key.slice(0, leaf_ref.key.len() - 1);
+                if partial.common_prefix(key) == key.len() {
+                    Ok(Some((depth + key.len(), leaf_ref.value.clone())))
+                } else {
+                    Ok(None)
+                }
+            }
+            Node::Branch(branch) => {
+                let branch_ref = unsafe { branch.as_ref() };
+
+                if !partial.is_empty() {
+                    let child = branch_ref.children[partial.at(0)].clone();
+                    if let Some(found) =
+                        self.longest_prefix_at(child, partial.offset(1), depth + 1)?
+                    {
+                        return Ok(Some(found));
+                    }
+                }
+                Ok(branch_ref.value.clone().map(|value| (depth, value)))
+            }
+            Node::Extension(extension) => {
+                let extension_ref = unsafe { extension.as_ref() };
+
+                let prefix = &extension_ref.prefix;
+                if partial.common_prefix(prefix) == prefix.len() {
+                    self.longest_prefix_at(
+                        extension_ref.node.clone(),
+                        partial.offset(prefix.len()),
+                        depth + prefix.len(),
+                    )
+                } else {
+                    Ok(None)
+                }
+            }
+            Node::Hash(hash_node) => {
+                let trie = self.expand_cached(unsafe { hash_node.as_ref() }.hash)?;
+                self.longest_prefix_at(trie.root.clone(), partial, depth)
+            }
+        }
+    }
+
+    /// Inserts the entries into `n`, found at `depth` nibbles of all their keys.
+    fn insert_many_at(
+        &mut self,
+        n: Node,
+        depth: usize,
+        items: &[(NibbleVec, Vec<u8>)],
+    ) -> TrieResult<Node> {
+        let (key, value) = match items {
+            [] => return Ok(n),
+            [(key, value)] => return self.insert_at(n, key.offset(depth), value.clone()),
+            [first, ..] => first,
+        };
+        match n {
+            Node::Branch(mut branch) => {
+                let mut rest = items;
+                while let Some((key, _)) = rest.first() {
+                    // Sorted entries form a single group per child.
+                    let index = key.at(depth);
+                    let len = rest
+                        .iter()
+                        .take_while(|(key, _)| key.at(depth) == index)
+                        .count();
+                    let (group, tail) = rest.split_at(len);
+                    if index == 16 {
+                        unsafe { branch.as_mut() }.value = Some(group[len - 1].1.clone());
+                    } else {
+                        let child = unsafe { branch.as_ref() }.children[index].clone();
+                        let new_child = self.insert_many_at(child, depth + 1, group)?;
+                        unsafe { branch.as_mut() }.children[index] = new_child;
+                    }
+                    rest = tail;
+                }
+                Ok(Node::Branch(branch))
+            }
+            Node::Extension(mut ext) => {
+                let (prefix_len, node) = {
+                    let ext_ref = unsafe { ext.as_ref() };
+                    (ext_ref.prefix.len(), ext_ref.node.clone())
+                };
+                let all_match = items.iter().all(|(key, _)| {
+                    key.offset(depth)
+                        .common_prefix(&unsafe { ext.as_ref() }.prefix)
+                        == prefix_len
+                });
+                if all_match {
+                    let new_node = self.insert_many_at(node, depth + prefix_len, items)?;
+                    unsafe { ext.as_mut() }.node = new_node;
+                    Ok(Node::Extension(ext))
+                } else {
+                    // Splits the extension, the rest is inserted into what replaces it.
+                    let n =
+                        self.insert_at(Node::Extension(ext), key.offset(depth), value.clone())?;
+                    self.insert_many_at(n, depth, &items[1..])
+                }
+            }
+            Node::Hash(hash_node) => {
+                let hash = unsafe { hash_node.as_ref() }.hash;
+                let n = self.recover_from_db(&hash)?;
+                match self.insert_many_at(n.clone(), depth, items) {
+                    Ok(new_node) => {
+                        unsafe { to_owned(hash_node) };
+                        self.recovered_nodes_hashes.insert(hash);
+                        Ok(new_node)
+                    }
+                    Err(e) => {
+                        unsafe { Node::dealloc(n) };
+                        Err(e)
+                    }
+                }
+            }
+            n => {
+                let n = self.insert_at(n, key.offset(depth), value.clone())?;
+                self.insert_many_at(n, depth, &items[1..])
+            }
+        }
+    }
+
+    fn insert_at(&mut self, n: Node, partial: &NibbleSlice, value: Vec<u8>) -> TrieResult<Node> {
+        Ok(self.insert_with_at(n, partial, |_| value)?.0)
+    }
+
+    // Same as `insert_at`, the value is `f` of the existing one. An empty value removes
+    // the key unless empty values are allowed, as with `delete_at`. Returns the new
+    // node and whether a value was removed.
+    fn insert_with_at<F>(
+        &mut self,
+        n: Node,
+        partial: &NibbleSlice,
+        f: F,
+    ) -> TrieResult<(Node, bool)>
+    where
+        F: FnOnce(Option<Vec<u8>>) -> Vec<u8>,
+    {
+        let removes = |value: &[u8]| value.is_empty() && !self.allow_empty_values;
+        let (new_n, removed) = match n {
+            Node::Empty => {
+                let value = f(None);
+                if removes(&value) {
+                    return Ok((Node::Empty, false));
+                }
+                (Node::from_leaf(partial.to_owned(), value), false)
+            }
+            Node::Leaf(mut leaf) => unsafe {
+                let (match_index, old_len) = {
+                    let old_partial = &leaf.as_ref().key;
+                    (partial.common_prefix(old_partial), old_partial.len())
+                };
+                if match_index == old_len {
+                    // replace leaf value
+                    let old = std::mem::take(&mut leaf.as_mut().value);
+                    let value = f(Some(old));
+                    if removes(&value) {
+                        to_owned(leaf);
+                        return Ok((Node::Empty, true));
+                    }
+                    leaf.as_mut().value = value;
+                    return Ok((Node::Leaf(leaf), false));
+                }
+                let value = f(None);
+                if removes(&value) {
+                    return Ok((Node::Leaf(leaf), false));
+                }
+                let mut branch = BranchNode {
+                    children: empty_children(),
+                    value: None,
+                };
+
+                let leaf_owned = to_owned(leaf);
+                let old_partial = &leaf_owned.key;
+                let n = Node::from_leaf(
+                    old_partial.offset(match_index + 1).to_owned(),
+                    leaf_owned.value,
+                );
+                branch.insert(old_partial.at(match_index), n);
+
+                let n = Node::from_leaf(partial.offset(match_index + 1).to_owned(), value);
+                branch.insert(partial.at(match_index), n);
+
+                let branch = Node::from_branch(branch.children, branch.value);
+                if match_index == 0 {
+                    // no common prefix
+                    return Ok((branch, false));
+                } else {
+                    // create an extension node with a common prefix
+                    let common_prefix = partial.slice(0, match_index).to_owned();
+                    return Ok((Node::from_extension(common_prefix, branch), false));
+                }
+            },
+            Node::Branch(mut branch) => {
+                if partial.at(0) == 16 {
+                    let old = unsafe { branch.as_mut() }.value.take();
+                    let existed = old.is_some();
+                    let value = f(old);
+                    if removes(&value) {
+                        (Node::Branch(branch), existed)
+                    } else {
+                        unsafe { branch.as_mut() }.value = Some(value);
+                        return Ok((Node::Branch(branch), false));
+                    }
+                } else {
+                    let child = unsafe { branch.as_ref() }.children[partial.at(0)].clone();
+                    let (new_child, removed) = self.insert_with_at(child, partial.offset(1), f)?;
+                    unsafe { branch.as_mut() }.children[partial.at(0)] = new_child;
+                    (Node::Branch(branch), removed)
+                }
+            }
+            Node::Extension(mut ext) => unsafe {
+                let match_index = partial.common_prefix(&ext.as_ref().prefix);
+                let sub_node = ext.as_ref().node.clone();
+                let prefix = ext.as_ref().prefix.clone();
+
+                if match_index == prefix.len() {
+                    let (new_node, removed) =
+                        self.insert_with_at(sub_node, partial.offset(match_index), f)?;
+                    ext.as_mut().node = new_node;
+                    (Node::Extension(ext), removed)
+                } else {
+                    // The key is not in the trie, the extension is only split for a value.
+                    let value = f(None);
+                    if removes(&value) {
+                        return Ok((Node::Extension(ext), false));
+                    }
+                    if match_index == 0 {
+                        let ext_owned = to_owned(ext);
+                        let mut branch = BranchNode {
+                            children: empty_children(),
+                            value: None,
+                        };
+                        branch.insert(
+                            ext_owned.prefix.at(0),
+                            if ext_owned.prefix.len() == 1 {
+                                ext_owned.node
+                            } else {
+                                Node::from_extension(
+                                    ext_owned.prefix.offset(1).to_owned(),
+                                    ext_owned.node,
+                                )
+                            },
+                        );
+                        let node = Node::from_branch(branch.children, branch.value);
+
+                        return Ok((self.insert_at(node, partial, value)?, false));
+                    }
+
+                    let new_ext =
+                        Node::from_extension(prefix.offset(match_index).to_owned(), sub_node);
+                    let new_node = self.insert_at(new_ext, partial.offset(match_index), value)?;
+                    ext.as_mut().prefix = prefix.slice(0, match_index).to_owned();
+                    ext.as_mut().node = new_node;
+                    return Ok((Node::Extension(ext), false));
+                }
+            },
+            Node::Hash(hash_node) => {
+                // Insert into the expanded node, the hash node is consumed only on success
+                // so a missing node deeper down leaves the trie untouched.
+                let hash = unsafe { hash_node.as_ref() }.hash;
+                let n = self.recover_from_db(&hash)?;
+                match self.insert_with_at(n.clone(), partial, f) {
+                    Ok(result) => {
+                        unsafe { to_owned(hash_node) };
+                        self.recovered_nodes_hashes.insert(hash);
+                        return Ok(result);
+                    }
+                    Err(e) => {
+                        unsafe { Node::dealloc(n) };
+                        return Err(e);
+                    }
+                }
+            }
+        };
+
+        if removed {
+            Ok((self.degenerate(new_n)?, true))
+        } else {
+            Ok((new_n, false))
+        }
+    }
+
+    /// Returns the new node and the removed value, if any.
+    fn delete_at(&mut self, n: Node, partial: &NibbleSlice) -> TrieResult<(Node, Option<Vec<u8>>)> {
+        let (new_n, deleted) = match n {
+            Node::Empty => Ok((Node::Empty, None)),
+            Node::Leaf(leaf) => unsafe {
+                if &*leaf.as_ref().key == partial {
+                    let value = to_owned(leaf).value;
+                    return Ok((Node::Empty, Some(value)));
+                }
+                Ok((Node::Leaf(leaf), None))
+            },
+            Node::Branch(mut branch) => {
+                let index = partial.at(0);
+                if index == 16 {
+                    // The branch may be left with a single child, which is merged below.
+                    let value = unsafe { branch.as_mut() }.value.take();
+                    Ok((Node::Branch(branch), value))
+                } else {
+                    let node = unsafe { branch.as_ref() }.children[index].clone();
+
+                    let (new_n, deleted) = self.delete_at(node, partial.offset(1))?;
+                    if deleted.is_some() {
+                        unsafe { branch.as_mut() }.children[index] = new_n;
+                    }
+
+                    Ok((Node::Branch(branch), deleted))
+                }
+            }
+            Node::Extension(mut ext) => {
+                let (prefix_len, match_len, node) = {
+                    let ext_ref = unsafe { ext.as_ref() };
+                    let prefix = &ext_ref.prefix;
+                    (
+                        prefix.len(),
+                        partial.common_prefix(prefix),
+                        ext_ref.node.clone(),
+                    )
+                };
+
+                if match_len == prefix_len {
+                    let (new_n, deleted) = self.delete_at(node, partial.offset(match_len))?;
+
+                    if deleted.is_some() {
+                        unsafe { ext.as_mut() }.node = new_n;
+                    }
+
+                    Ok((Node::Extension(ext), deleted))
+                } else {
+                    Ok((Node::Extension(ext), None))
+                }
+            }
+            Node::Hash(hash_node) => {
+                // Same as in `insert_at`, the hash node is consumed only on success.
+                let hash = unsafe { hash_node.as_ref() }.hash;
+                let n = self.recover_from_db(&hash)?;
+                match self.delete_at(n.clone(), partial) {
+                    Ok(result) => {
+                        unsafe { to_owned(hash_node) };
+                        self.recovered_nodes_hashes.insert(hash);
+                        Ok(result)
+                    }
+                    Err(e) => {
+                        unsafe { Node::dealloc(n) };
+                        Err(e)
+                    }
+                }
+            }
+        }?;
+
+        if deleted.is_some() {
+            let degenerated = self.degenerate(new_n)?;
+            Ok((degenerated, deleted))
+        } else {
+            Ok((new_n, deleted))
+        }
+    }
+
+    /// Removes the sorted keys from `n`, found at `depth` nibbles of all of them.
+    fn remove_many_at(
+        &mut self,
+        n: Node,
+        depth: usize,
+        keys: &[NibbleVec],
+    ) -> TrieResult<(Node, usize)> {
+        if keys.is_empty() {
+            return Ok((n, 0));
+        }
+        let (new_n, removed) = match n {
+            Node::Empty => (Node::Empty, 0),
+            Node::Leaf(leaf) => {
+                let found = {
+                    let leaf_key = &unsafe { leaf.as_ref() }.key;
+                    keys.iter().any(|key| key.offset(depth) == &**leaf_key)
+                };
+                if found {
+                    unsafe { to_owned(leaf) };
+                    return Ok((Node::Empty, 1));
+                }
+                (Node::Leaf(leaf), 0)
+            }
+            Node::Branch(mut branch) => {
+                let mut removed = 0;
+                let mut rest = keys;
+                while let Some(key) = rest.first() {
+                    let index = key.at(depth);
+                    let len = rest.iter().take_while(|key| key.at(depth) == index).count();
+                    let (group, tail) = rest.split_at(len);
+                    if index == 16 {
+                        removed += unsafe { branch.as_mut() }.value.take().is_some() as usize;
+                    } else {
+                        let child = unsafe { branch.as_ref() }.children[index].clone();
+                        let (new_child, child_removed) =
+                            self.remove_many_at(child, depth + 1, group)?;
+                        if child_removed > 0 {
+                            unsafe { branch.as_mut() }.children[index] = new_child;
+                            removed += child_removed;
+                        }
+                    }
+                    rest = tail;
+                }
+                (Node::Branch(branch), removed)
+            }
+            Node::Extension(mut ext) => {
+                let (prefix, node) = {
+                    let ext_ref = unsafe { ext.as_ref() };
+                    (ext_ref.prefix.clone(), ext_ref.node.clone())
+                };
+                // Only the keys running through the whole prefix can be below it.
+                let start =
+                    keys.partition_point(|key| key.offset(depth).as_bytes() < prefix.as_bytes());
+                let len = keys[start..]
+                    .iter()
+                    .take_while(|key| key.offset(depth).common_prefix(&prefix) == prefix.len())
+                    .count();
+                let (new_node, removed) =
+                    self.remove_many_at(node, depth + prefix.len(), &keys[start..start + len])?;
+                if removed > 0 {
+                    unsafe { ext.as_mut() }.node = new_node;
+                }
+                (Node::Extension(ext), removed)
+            }
+            Node::Hash(hash_node) => {
+                let hash = unsafe { hash_node.as_ref() }.hash;
+                let n = self.recover_from_db(&hash)?;
+                match self.remove_many_at(n.clone(), depth, keys) {
+                    Ok((new_n, removed)) if removed > 0 => {
+                        unsafe { to_owned(hash_node) };
+                        self.recovered_nodes_hashes.insert(hash);
+                        // Already merged with its children.
+                        return Ok((new_n, removed));
+                    }
+                    Ok((n, _)) => {
+                        unsafe { Node::dealloc(n) };
+                        return Ok((Node::Hash(hash_node), 0));
+                    }
+                    Err(e) => {
+                        unsafe { Node::dealloc(n) };
+                        return Err(e);
+                    }
+                }
+            }
+        };
+
+        if removed > 0 {
+            Ok((self.degenerate(new_n)?, removed))
+        } else {
+            Ok((new_n, removed))
+        }
+    }
+
+    fn remove_prefix_at(&mut self, n: Node, partial: &NibbleSlice) -> TrieResult<(Node, usize)> {
+        if partial.is_empty() {
+            let mut hashes = vec![];
+            let removed = self.count_values_at(n.clone(), &mut hashes)?;
+            self.recovered_nodes_hashes.extend(hashes);
+            unsafe { Node::dealloc(n) };
+            return Ok((Node::Empty, removed));
+        }
+
+        let (new_n, removed) = match n {
+            Node::Empty => Ok((Node::Empty, 0)),
+            Node::Leaf(leaf) => {
+                if unsafe { leaf.as_ref() }.key.common_prefix(partial) == partial.len() {
+                    unsafe { to_owned(leaf) };
+                    return Ok((Node::Empty, 1));
+                }
+                Ok((Node::Leaf(leaf), 0))
+            }
+            Node::Branch(mut branch) => {
+                let index = partial.at(0);
+                let child = unsafe { branch.as_ref() }.children[index].clone();
+                let (new_child, removed) = self.remove_prefix_at(child, partial.offset(1))?;
+                if removed > 0 {
+                    unsafe { branch.as_mut() }.children[index] = new_child;
+                }
+                Ok((Node::Branch(branch), removed))
+            }
+            Node::Extension(mut ext) => {
+                let (prefix_len, match_len, node) = {
+                    let ext_ref = unsafe { ext.as_ref() };
+                    (
+                        ext_ref.prefix.len(),
+                        partial.common_prefix(&ext_ref.prefix),
+                        ext_ref.node.clone(),
+                    )
+                };
+                if match_len == partial.len() {
+                    // The prefix ends inside the extension, everything below it matches.
+                    return self.remove_prefix_at(Node::Extension(ext), NibbleSlice::from_hex(&[]));
+                }
+                if match_len < prefix_len {
+                    return Ok((Node::Extension(ext), 0));
+                }
+
+                let (new_n, removed) = self.remove_prefix_at(node, partial.offset(match_len))?;
+                if removed > 0 {
+                    unsafe { ext.as_mut() }.node = new_n;
+                }
+                Ok((Node::Extension(ext), removed))
+            }
+            Node::Hash(hash_node) => {
+                // Same as in `insert_at`, the hash node is consumed only on success.
+                let hash = unsafe { hash_node.as_ref() }.hash;
+                let n = self.recover_from_db(&hash)?;
+                match self.remove_prefix_at(n.clone(), partial) {
+                    Ok((new_n, 0)) => {
+                        unsafe { Node::dealloc(new_n) };
+                        Ok((Node::Hash(hash_node), 0))
+                    }
+                    Ok(result) => {
+                        unsafe { to_owned(hash_node) };
+                        self.recovered_nodes_hashes.insert(hash);
+                        Ok(result)
+                    }
+                    Err(e) => {
+                        unsafe { Node::dealloc(n) };
+                        Err(e)
+                    }
+                }
+            }
+        }?;
+
+        if removed > 0 {
+            Ok((self.degenerate(new_n)?, removed))
+        } else {
+            Ok((new_n, removed))
+        }
+    }
+
+    /// Removes the keys in `[start, end)` from `n`, found at the nibbles in `path`.
+    fn remove_range_at(
+        &mut self,
+        n: Node,
+        path: &mut NibbleVec,
+        start: &[u8],
+        end: &[u8],
+    ) -> TrieResult<(Node, usize)> {
+        // The keys under `path` are `path` itself and the keys extending it.
+        let below = |bound: &[u8]| path.as_bytes() < bound && !bound.starts_with(path.as_bytes());
+        if below(start) || path.as_bytes() >= end {
+            return Ok((n, 0));
+        }
+        if path.as_bytes() >= start && below(end) {
+            return self.remove_prefix_at(n, NibbleSlice::from_hex(&[]));
+        }
+
+        let (new_n, removed) = match n {
+            Node::Empty => (Node::Empty, 0),
+            Node::Leaf(leaf) => {
+                let in_range = {
+                    let key = &unsafe { leaf.as_ref() }.key;
+                    let len = path.len();
+                    path.extend_from_slice(key);
+                    if path.as_bytes().last() == Some(&16) {
+                        path.pop();
+                    }
+                    let in_range = path.as_bytes() >= start && path.as_bytes() < end;
+                    path.truncate(len);
+                    in_range
+                };
+                if in_range {
+                    unsafe { to_owned(leaf) };
+                    return Ok((Node::Empty, 1));
+                }
+                (Node::Leaf(leaf), 0)
+            }
+            Node::Branch(mut branch) => {
+                // The branch value is at `path`, which is in the range as checked above
+                // unless it's before start.
+                let mut removed = 0;
+                if path.as_bytes() >= start {
+                    removed += unsafe { branch.as_mut() }.value.take().is_some() as usize;
+                }
+                for i in 0..16 {
+                    let child = unsafe { branch.as_ref() }.children[i].clone();
+                    path.push(i as u8);
+                    let result = self.remove_range_at(child, path, start, end);
+                    path.pop();
+                    let (new_child, child_removed) = result?;
+                    if child_removed > 0 {
+                        unsafe { branch.as_mut() }.children[i] = new_child;
+                        removed += child_removed;
+                    }
+                }
+                (Node::Branch(branch), removed)
+            }
+            Node::Extension(mut ext) => {
+                let (prefix, node) = {
+                    let ext_ref = unsafe { ext.as_ref() };
+                    (ext_ref.prefix.clone(), ext_ref.node.clone())
+                };
+                let len = path.len();
+                path.extend_from_slice(&prefix);
+                let result = self.remove_range_at(node, path, start, end);
+                path.truncate(len);
+                let (new_node, removed) = result?;
+                if removed > 0 {
+                    unsafe { ext.as_mut() }.node = new_node;
+                }
+                (Node::Extension(ext), removed)
+            }
+            Node::Hash(hash_node) => {
+                // Same as in `insert_at`, the hash node is consumed only on success.
+                let hash = unsafe { hash_node.as_ref() }.hash;
+                let n = self.recover_from_db(&hash)?;
+                return match self.remove_range_at(n.clone(), path, start, end) {
+                    Ok((new_n, 0)) => {
+                        unsafe { Node::dealloc(new_n) };
+                        Ok((Node::Hash(hash_node), 0))
+                    }
+                    Ok(result) => {
+                        unsafe { to_owned(hash_node) };
+                        self.recovered_nodes_hashes.insert(hash);
+                        Ok(result)
+                    }
+                    Err(e) => {
+                        unsafe { Node::dealloc(n) };
+                        Err(e)
+                    }
+                };
+            }
+        };
+
+        if removed > 0 {
+            Ok((self.degenerate(new_n)?, removed))
+        } else {
+            Ok((new_n, removed))
+        }
+    }
+
+    /// Counts the values under `n`, collecting the hashes of the stored nodes in `hashes`.
+    fn count_values_at(&self, n: Node, hashes: &mut Vec<[u8; 32]>) -> TrieResult<usize> {
+        match n {
+            Node::Empty => Ok(0),
+            Node::Leaf(_) => Ok(1),
+            Node::Branch(branch) => {
+                let branch_ref = unsafe { branch.as_ref() };
+                let mut count = branch_ref.value.is_some() as usize;
+                for child in branch_ref.children.iter() {
+                    count += self.count_values_at(child.clone(), hashes)?;
+                }
+                Ok(count)
+            }
+            Node::Extension(ext) => {
+                let ext_ref = unsafe { ext.as_ref() };
+                self.count_values_at(ext_ref.node.clone(), hashes)
+            }
+            Node::Hash(hash_node) => {
+                let hash = unsafe { hash_node.as_ref() }.hash;
+                let n = self.recover_from_db(&hash)?;
+                hashes.push(hash);
+                let result = self.count_values_at(n.clone(), hashes);
+                unsafe { Node::dealloc(n) };
+                result
+            }
+        }
+    }
+
+    // Follows the cases of `insert_at`.
+    fn insert_cost_at(
+        &self,
+        n: Node,
+        partial: &NibbleSlice,
+        cost: &mut InsertCost,
+    ) -> TrieResult<InsertKind> {
+        match n {
+            Node::Empty => {
+                cost.new_nodes += 1;
+                Ok(InsertKind::NewLeaf)
+            }
+            Node::Leaf(leaf) => {
+                let leaf_ref = unsafe { leaf.as_ref() };
+                let old_partial = &leaf_ref.key;
+                let match_index = partial.common_prefix(old_partial);
+                if match_index == old_partial.len() {
+                    cost.modified_nodes += 1;
+                    return Ok(InsertKind::Overwrite);
+                }
+                // A branch with a leaf for each key, unless it ends at the branch and
+                // becomes its value, under an extension with the common prefix.
+                cost.new_nodes += 1
+                    + (old_partial.at(match_index) != 16) as usize
+                    + (partial.at(match_index) != 16) as usize
+                    + (match_index > 0) as usize;
+                Ok(InsertKind::SplitLeaf)
+            }
+            Node::Branch(branch) => {
+                cost.modified_nodes += 1;
+                let branch_ref = unsafe { branch.as_ref() };
+                if partial.at(0) == 16 {
+                    return Ok(match branch_ref.value {
+                        Some(_) => InsertKind::Overwrite,
+                        None => InsertKind::NewLeaf,
+                    });
+                }
+                let child = branch_ref.children[partial.at(0)].clone();
+                self.insert_cost_at(child, partial.offset(1), cost)
+            }
+            Node::Extension(ext) => {
+                let ext_ref = unsafe { ext.as_ref() };
+                let prefix = &ext_ref.prefix;
+                let match_index = partial.common_prefix(prefix);
+                if match_index == prefix.len() {
+                    cost.modified_nodes += 1;
+                    return self.insert_cost_at(
+                        ext_ref.node.clone(),
+                        partial.offset(match_index),
+                        cost,
+                    );
+                }
+                // The extension keeps the common prefix if there is one. A branch takes
+                // the rest of the prefix, with an extension unless a single nibble is left.
+                if match_index > 0 {
+                    cost.modified_nodes += 1;
+                }
+                cost.new_nodes += 1
+                    + (prefix.len() - match_index > 1) as usize
+                    + (partial.at(match_index) != 16) as usize;
+                Ok(InsertKind::SplitExtension)
+            }
+            Node::Hash(hash_node) => {
+                let n = self.recover_from_db(&unsafe { hash_node.as_ref() }.hash)?;
+                let result = self.insert_cost_at(n.clone(), partial, cost);
+                unsafe { Node::dealloc(n) };
+                result
+            }
+        }
+    }
+
+    // `stored` is the hash the node was recovered from.
+    fn dump_at<W: Write>(
+        &self,
+        n: Node,
+        w: &mut W,
+        depth: usize,
+        stored: Option<[u8; 32]>,
+    ) -> TrieResult<()> {
+        fn nibbles(hex: &[u8]) -> String {
+            hex.iter()
+                .filter(|&&nibble| nibble < 16)
+                .map(|nibble| format!("{:x}", nibble))
+                .collect()
+        }
+        fn value(value: &[u8]) -> String {
+            if value.len() > DUMP_VALUE_LEN {
+                format!(
+                    "{}.. ({} bytes)",
+                    Hex(&value[..DUMP_VALUE_LEN]),
+                    value.len()
+                )
+            } else {
+                Hex(value).to_string()
+            }
+        }
+
+        let indent = "  ".repeat(depth);
+        if let Node::Hash(hash_node) = n {
+            let hash = unsafe { hash_node.as_ref() }.hash;
+            let n = self.recover_from_db(&hash)?;
+            let result = self.dump_at(n.clone(), w, depth, Some(hash));
+            unsafe { Node::dealloc(n) };
+            return result;
+        }
+
+        let status = match stored {
+            Some(hash) => format!("stored {}", Hex(&hash)),
+            None => {
+                let data = self.encode_raw(n.clone(), &mut HashMap::new());
+                if depth > 0 && self.inline_small_nodes && data.len() < C::INLINE_THRESHOLD {
+                    "embedded".to_string()
+                } else {
+                    format!("hashed {}", Hex(&H::digest(&data)))
+                }
+            }
+        };
+        match n {
+            Node::Empty => writeln!(w, "{}empty", indent)?,
+            Node::Leaf(leaf) => {
+                let leaf_ref = unsafe { leaf.as_ref() };
+                writeln!(
+                    w,
+                    "{}leaf key={} value={} ({})",
+                    indent,
+                    nibbles(leaf_ref.key.as_bytes()),
+                    value(&leaf_ref.value),
+                    status
+                )?;
+            }
+            Node::Extension(ext) => {
+                let (prefix, child) = {
+                    let ext_ref = unsafe { ext.as_ref() };
+                    (nibbles(ext_ref.prefix.as_bytes()), ext_ref.node.clone())
+                };
+                writeln!(w, "{}extension prefix={} ({})", indent, prefix, status)?;
+                self.dump_at(child, w, depth + 1, None)?;
+            }
+            Node::Branch(branch) => {
+                let (children, branch_value) = {
+                    let branch_ref = unsafe { branch.as_ref() };
+                    (branch_ref.children.clone(), branch_ref.value.clone())
+                };
+                match branch_value {
+                    Some(v) => writeln!(w, "{}branch value={} ({})", indent, value(&v), status)?,
+                    None => writeln!(w, "{}branch ({})", indent, status)?,
+                }
+                for (i, child) in children.iter().enumerate() {
+                    if let Node::Empty = child {
+                        continue;
+                    }
+                    writeln!(w, "{}  [{:x}]", indent, i)?;
+                    self.dump_at(child.clone(), w, depth + 2, None)?;
+                }
+            }
+            Node::Hash(_) => unreachable!(),
+        }
+        Ok(())
+    }
+
+    // Returns the node replacing `n` and the hash of the subtree under partial.
+    fn commit_subtree_at(
+        &mut self,
+        n: Node,
+        partial: &NibbleSlice,
+        is_root: bool,
+    ) -> TrieResult<(Node, Vec<u8>)> {
+        let covers_subtree = match &n {
+            _ if partial.is_empty() => true,
+            Node::Empty => return Ok((n, self.empty_root.to_vec())),
+            Node::Leaf(leaf) => {
+                if unsafe { leaf.as_ref() }.key.common_prefix(partial) < partial.len() {
+                    return Ok((n, self.empty_root.to_vec()));
+                }
+                true
+            }
+            Node::Extension(ext) => {
+                let prefix = &unsafe { ext.as_ref() }.prefix;
+                let match_len = partial.common_prefix(prefix);
+                if match_len < partial.len() && match_len < prefix.len() {
+                    return Ok((n, self.empty_root.to_vec()));
+                }
+                // The subtree is the extension itself if the partial ends inside its prefix.
+                match_len == partial.len()
+            }
+            Node::Branch(_) | Node::Hash(_) => false,
+        };
+        if covers_subtree {
+            return self.write_subtree(n, is_root);
+        }
+
+        match n {
+            Node::Branch(mut branch) => {
+                let index = partial.at(0);
+                let child = unsafe { branch.as_ref() }.children[index].clone();
+                let (new_child, hash) = self.commit_subtree_at(child, partial.offset(1), false)?;
+                unsafe { branch.as_mut() }.children[index] = new_child;
+                Ok((Node::Branch(branch), hash))
+            }
+            Node::Extension(mut ext) => {
+                let (prefix_len, child) = {
+                    let ext_ref = unsafe { ext.as_ref() };
+                    (ext_ref.prefix.len(), ext_ref.node.clone())
+                };
+                let (new_child, hash) =
+                    self.commit_subtree_at(child, partial.offset(prefix_len), false)?;
+                unsafe { ext.as_mut() }.node = new_child;
+                Ok((Node::Extension(ext), hash))
+            }
+            Node::Hash(hash_node) => {
+                // Everything below is stored already, the expanded nodes are only used
+                // to find the subtree.
+                let n = self.recover_from_db(&unsafe { hash_node.as_ref() }.hash)?;
+                match self.commit_subtree_at(n.clone(), partial, false) {
+                    Ok((n, hash)) => {
+                        unsafe { Node::dealloc(n) };
+                        Ok((Node::Hash(hash_node), hash))
+                    }
+                    Err(e) => {
+                        unsafe { Node::dealloc(n) };
+                        Err(e)
+                    }
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn write_subtree(&mut self, n: Node, is_root: bool) -> TrieResult<(Node, Vec<u8>)> {
+        match &n {
+            Node::Empty => return Ok((n, self.empty_root.to_vec())),
+            Node::Hash(hash_node) => {
+                let hash = unsafe { hash_node.as_ref() }.hash.to_vec();
+                return Ok((n, hash));
+            }
+            _ => {}
+        }
+        let mut cache = HashMap::new();
+        let encoded = self.encode_node(n.clone(), &mut cache);
+        let embedded = encoded.len() < C::INLINE_THRESHOLD;
+        let hash = if embedded {
+            let hash = H::digest(&encoded);
+            cache.insert(hash.to_vec(), encoded);
+            hash.to_vec()
+        } else {
+            encoded
+        };
+
+        for key in cache.keys() {
+            // Not removed by the next commit even if a recovered node had the same content.
+            self.recovered_nodes_hashes
+                .remove::<[u8; 32]>(key.as_slice().try_into().unwrap());
+        }
+        self.db
+            .insert_batch(cache)
+            .map_err(|e| TrieError::DB(e.to_string()))?;
+
+        if embedded || is_root {
+            return Ok((n, hash));
+        }
+        unsafe { Node::dealloc(n) };
+        Ok((Node::from_hash(hash.as_slice().try_into().unwrap()), hash))
+    }
+
+    // Loops instead of recursing on the merged nodes, a chain of stored extensions can be
+    // arbitrarily long.
+    fn degenerate(&mut self, mut n: Node) -> TrieResult<Node> {
+        loop {
+            n = match n {
+                Node::Branch(branch) => {
+                    let mut used_indexes = vec![];
+                    let has_value = {
+                        let branch_ref = unsafe { branch.as_ref() };
+                        for (index, node) in branch_ref.children.iter().enumerate() {
+                            match node {
+                                Node::Empty => continue,
+                                _ => used_indexes.push(index),
+                            }
+                        }
+                        branch_ref.value.is_some()
+                    };
+                    // A batch removal can empty the branch.
+                    if used_indexes.is_empty() && !has_value {
+                        unsafe { to_owned(branch) };
+                        return Ok(Node::Empty);
+                    // if only a value node, transmute to leaf.
+                    } else if used_indexes.is_empty() && has_value {
+                        let key = NibbleVec::from_raw([].to_vec(), true);
+                        // Drop branch node and replace it with leaf
+                        let branch_owned = unsafe { to_owned(branch) };
+                        return Ok(Node::from_leaf(key, branch_owned.value.unwrap()));
+                        // if only one node. make an extension.
+                    } else if used_indexes.len() == 1 && !has_value {
+                        let used_index = used_indexes[0];
+                        let child = unsafe { branch.as_ref() }.children[used_index].clone();
+                        let n = match child {
+                            // Recover a hashed child before dropping the branch,
+                            // so a missing node leaves the branch intact.
+                            Node::Hash(hash_node) => {
+                                let hash = unsafe { hash_node.as_ref() }.hash;
+                                let recovered_node = self.recover_from_db(&hash)?;
+                                self.recovered_nodes_hashes.insert(hash);
+                                unsafe { to_owned(hash_node) };
+                                recovered_node
+                            }
+                            n => n,
+                        };
+                        unsafe { to_owned(branch) };
+
+                        Node::from_extension(NibbleVec::from_hex(vec![used_index as u8]), n)
+                    } else {
+                        return Ok(Node::Branch(branch));
+                    }
+                }
+                Node::Extension(ext) => {
+                    let (prefix, node) = {
+                        let ext_ref = unsafe { ext.as_ref() };
+                        (ext_ref.prefix.clone(), ext_ref.node.clone())
+                    };
+                    match node {
+                        Node::Extension(mut sub_ext) => unsafe {
+                            // merge extension nodes
+                            let joined = prefix.join(&sub_ext.as_ref().prefix);
+                            sub_ext.as_mut().prefix = joined;
+                            // drop parent extension node after merging
+                            to_owned(ext);
+                            Node::Extension(sub_ext)
+                        },
+                        Node::Leaf(mut leaf) => unsafe {
+                            let joined = prefix.join(&leaf.as_ref().key);
+                            leaf.as_mut().key = joined;
+                            // drop parent extension and creating a leaf instead
+                            to_owned(ext);
+                            return Ok(Node::Leaf(leaf));
+                        },
+                        Node::Empty => {
+                            unsafe { to_owned(ext) };
+                            return Ok(Node::Empty);
+                        }
+                        // try again after recovering node from the db.
+                        Node::Hash(hash_node) => unsafe {
+                            let hash = hash_node.as_ref().hash;
+                            let recovered_node = self.recover_from_db(&hash)?;
+                            self.recovered_nodes_hashes.insert(hash);
+                            let n = Node::from_extension(prefix, recovered_node);
+                            to_owned(ext);
+                            to_owned(hash_node);
+                            n
+                        },
+                        _ => return Ok(Node::Extension(ext)),
+                    }
+                }
+                _ => return Ok(n),
+            };
+        }
+    }
+
+    // Get nodes path along the key, only the nodes whose encode length is greater than
+    // hash length are added.
+    // For embedded nodes whose data are already contained in their parent node, we don't need to
+    // add them in the path.
+    // In the code below, we only add the nodes get by `get_node_from_hash`, because they contains
+    // all data stored in db, including nodes whose encoded data is less than hash length.
+    // Embedded nodes are nested at most `max_decode_depth` times, which bounds the recursion
+    // within a stored node.
+    fn get_path_at(&self, n: Node, partial: &NibbleSlice) -> TrieResult<Vec<Node>> {
+        match n {
+            Node::Empty | Node::Leaf(_) => Ok(vec![]),
+            Node::Branch(branch) => {
+                let branch_ref = unsafe { branch.as_ref() };
+
+                if partial.is_empty() || partial.at(0) == 16 {
+                    Ok(vec![])
+                } else {
+                    let node = branch_ref.children[partial.at(0)].clone();
+                    self.get_path_at(node, partial.offset(1))
+                }
+            }
+            Node::Extension(ext) => {
+                let ext_ref = unsafe { ext.as_ref() };
+
+                let prefix = &ext_ref.prefix;
+                let match_len = partial.common_prefix(prefix);
+
+                if match_len == prefix.len() {
+                    self.get_path_at(ext_ref.node.clone(), partial.offset(match_len))
+                } else {
+                    Ok(vec![])
+                }
+            }
+            Node::Hash(hash_node) => {
+                let n = self.recover_from_db(&unsafe { hash_node.as_ref() }.hash.clone())?;
+                let mut rest = self.get_path_at(n.clone(), partial)?;
+                rest.push(n);
+                Ok(rest)
+            }
+        }
+    }
+
+    // Same walk as `get_path_at`, also returning the value at the end of the path.
+    fn get_with_path_at(
+        &self,
+        n: Node,
+        partial: &NibbleSlice,
+    ) -> TrieResult<(Option<Vec<u8>>, Vec<Node>)> {
+        match n {
+            Node::Empty => Ok((None, vec![])),
+            Node::Leaf(leaf) => {
+                let leaf_ref = unsafe { leaf.as_ref() };
+
+                if &*leaf_ref.key == partial {
+                    Ok((Some(leaf_ref.value.clone()), vec![]))
+                } else {
+                    Ok((None, vec![]))
+                }
+            }
+            Node::Branch(branch) => {
+                let branch_ref = unsafe { branch.as_ref() };
+
+                if partial.is_empty() || partial.at(0) == 16 {
+                    Ok((branch_ref.value.clone(), vec![]))
+                } else {
+                    let node = branch_ref.children[partial.at(0)].clone();
+                    self.get_with_path_at(node, partial.offset(1))
+                }
+            }
+            Node::Extension(ext) => {
+                let ext_ref = unsafe { ext.as_ref() };
+
+                let prefix = &ext_ref.prefix;
+                let match_len = partial.common_prefix(prefix);
+
+                if match_len == prefix.len() {
+                    self.get_with_path_at(ext_ref.node.clone(), partial.offset(match_len))
+                } else {
+                    Ok((None, vec![]))
+                }
+            }
+            Node::Hash(hash_node) => {
+                let n = self.recover_from_db(&unsafe { hash_node.as_ref() }.hash.clone())?;
+                let (value, mut rest) = self.get_with_path_at(n.clone(), partial)?;
+                rest.push(n);
+                Ok((value, rest))
+            }
+        }
+    }
+
+    fn node_encoding_at(&self, n: Node, partial: &NibbleSlice) -> TrieResult<Option<Vec<u8>>> {
+        let holds_value = match n {
+            Node::Empty => false,
+            Node::Leaf(ref leaf) => &*unsafe { leaf.as_ref() }.key == partial,
+            Node::Branch(ref branch) => {
+                if partial.is_empty() || partial.at(0) == 16 {
+                    unsafe { branch.as_ref() }.value.is_some()
+                } else {
+                    let node = unsafe { branch.as_ref() }.children[partial.at(0)].clone();
+                    return self.node_encoding_at(node, partial.offset(1));
+                }
+            }
+            Node::Extension(ref ext) => {
+                let ext_ref = unsafe { ext.as_ref() };
+
+                let match_len = partial.common_prefix(&ext_ref.prefix);
+                if match_len == ext_ref.prefix.len() {
+                    let node = ext_ref.node.clone();
+                    return self.node_encoding_at(node, partial.offset(match_len));
+                }
+                false
+            }
+            Node::Hash(ref hash_node) => {
+                let trie = self.expand_cached(unsafe { hash_node.as_ref() }.hash)?;
+                return self.node_encoding_at(trie.root.clone(), partial);
+            }
+        };
+        Ok(holds_value.then(|| self.encode_raw(n, &mut HashMap::new())))
+    }
+
+    // Same walk as `get_path_at`, only the sizes of the stored nodes are kept. The
+    // values they refer to are collected in `values`.
+    fn proof_size_at(
+        &self,
+        n: Node,
+        partial: &NibbleSlice,
+        values: &mut HashMap<Vec<u8>, Vec<u8>>,
+    ) -> TrieResult<usize> {
+        match n {
+            Node::Empty | Node::Leaf(_) => Ok(0),
+            Node::Branch(branch) => {
+                let branch_ref = unsafe { branch.as_ref() };
+
+                if partial.is_empty() || partial.at(0) == 16 {
+                    Ok(0)
+                } else {
+                    let node = branch_ref.children[partial.at(0)].clone();
+                    self.proof_size_at(node, partial.offset(1), values)
+                }
+            }
+            Node::Extension(ext) => {
+                let ext_ref = unsafe { ext.as_ref() };
+
+                let prefix = &ext_ref.prefix;
+                let match_len = partial.common_prefix(prefix);
+
+                if match_len == prefix.len() {
+                    self.proof_size_at(ext_ref.node.clone(), partial.offset(match_len), values)
+                } else {
+                    Ok(0)
+                }
+            }
+            Node::Hash(hash_node) => {
+                let n = self.recover_from_db(&unsafe { hash_node.as_ref() }.hash)?;
+                let size = self.encode_raw(n.clone(), values).len();
+                let rest = self.proof_size_at(n.clone(), partial, values);
+                unsafe { Node::dealloc(n) };
+                Ok(size + rest?)
+            }
+        }
+    }
+
+    fn commit(&mut self) -> TrieResult<Vec<u8>> {
+        let pending = self.encode_commit();
+        self.write_commit(pending)
+    }
+
+    fn write_commit(&mut self, pending: PendingCommit) -> TrieResult<Vec<u8>> {
+        let PendingCommit {
+            root_hash,
+            nodes,
+            pruned,
+        } = pending;
+
+        // Nodes created and replaced between two flushes are dropped here, including the
+        // previous root unless it was flushed.
+        let mut deferred_nodes = self.deferred_nodes.write();
+        deferred_nodes.remove(&self.root_hash);
+        for hash in pruned {
+            deferred_nodes.remove(&hash[..]);
+            self.deferred_pruned.insert(hash);
+        }
+        deferred_nodes.extend(nodes);
+        let (nodes, pruned) = if self.defer_commit {
+            Default::default()
+        } else {
+            let nodes = std::mem::take(&mut *deferred_nodes);
+            let pruned = self
+                .deferred_pruned
+                .drain()
+                .filter(|hash| !nodes.contains_key(&hash[..]))
+                .collect::<Vec<_>>();
+            (nodes, pruned)
+        };
+        drop(deferred_nodes);
+
+        // The db operations issued: one batch insert of the nodes and one batch remove.
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!(
+            "commit",
+            root_hash = %Hex(&root_hash),
+            nodes_written = nodes.len(),
+            bytes_written = nodes.values().map(|node| node.len()).sum::<usize>(),
+            nodes_pruned = pruned.len(),
+        )
+        .entered();
+
+        if !self.defer_commit {
+            self.db
+                .insert_batch(nodes)
+                .map_err(|e| TrieError::DB(e.to_string()))?;
+
+            self.db
+                .remove_batch(pruned)
+                .map_err(|e| TrieError::DB(e.to_string()))?;
+        }
+
+        if let Some(history) = &self.root_history {
+            history.record(&self.root_hash, &root_hash);
+        }
+        self.root_hash = root_hash.clone();
+        self.dirty = false;
+        self.recovered_nodes_hashes.clear();
+        // Proofs of the old root are not served again.
+        self.proof_cache.lock().clear();
+        unsafe { Node::dealloc(self.root.clone()) };
+        self.root = Node::Empty;
+        if self.root_hash != self.empty_root {
+            self.root = self.recover_from_db(&self.root_hash)?;
+        }
+        Ok(root_hash)
+    }
+
+    /// Encodes the pending changes without writing them.
+    fn encode_commit(&self) -> PendingCommit {
+        let mut cache = HashMap::new();
+        let encoded = self.encode_node(self.root.clone(), &mut cache);
+        let root_hash = if let Node::Empty = self.root {
+            cache.insert(self.empty_root.to_vec(), encoded);
+            self.empty_root.to_vec()
+        } else if encoded.len() < C::INLINE_THRESHOLD {
+            let hash = H::digest(&encoded);
+            cache.insert(hash.to_vec(), encoded);
+            hash.to_vec()
+        } else {
+            encoded
+        };
+
+        let cached_keys: HashSet<[u8; 32]> =
+            HashSet::from_iter(cache.keys().map(|k| k.as_slice().try_into().unwrap()));
+
+        // Remove all recovered node hashes from the database which are now invalid, i.e.
+        // the root was changed so the hash was also changed.
+        let keys_to_remove = self
+            .recovered_nodes_hashes
+            .difference(&cached_keys)
+            .cloned()
+            .collect();
+        PendingCommit {
+            root_hash,
+            nodes: cache,
+            pruned: keys_to_remove,
+        }
+    }
+
+    // Encodes the nodes below `n` children first, replacing those stored by hash with
+    // hash nodes, and writes `cache` once it holds `batch_size` of them.
+    fn spill_at(
+        &self,
+        n: Node,
+        cache: &mut HashMap<Vec<u8>, Vec<u8>>,
+        written: &mut HashSet<[u8; 32]>,
+        batch_size: usize,
+    ) -> TrieResult<()> {
+        match n {
+            Node::Branch(mut branch) => {
+                for i in 0..16 {
+                    let child = unsafe { branch.as_ref() }.children[i].clone();
+                    if let Some(hash_node) = self.spill_child(child, cache, written, batch_size)? {
+                        unsafe { branch.as_mut() }.children[i] = hash_node;
+                    }
+                }
+                Ok(())
+            }
+            Node::Extension(mut ext) => {
+                let child = unsafe { ext.as_ref() }.node.clone();
+                if let Some(hash_node) = self.spill_child(child, cache, written, batch_size)? {
+                    unsafe { ext.as_mut() }.node = hash_node;
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Returns the hash node replacing `n` once encoded, `None` if it is embedded.
+    fn spill_child(
+        &self,
+        n: Node,
+        cache: &mut HashMap<Vec<u8>, Vec<u8>>,
+        written: &mut HashSet<[u8; 32]>,
+        batch_size: usize,
+    ) -> TrieResult<Option<Node>> {
+        if let Node::Empty | Node::Hash(_) = n {
+            return Ok(None);
+        }
+        self.spill_at(n.clone(), cache, written, batch_size)?;
+        let encoded = self.encode_node(n.clone(), cache);
+        if cache.len() >= batch_size {
+            self.spill(cache, written)?;
+        }
+        if encoded.len() != HASH_SIZE {
+            return Ok(None);
+        }
+        unsafe { Node::dealloc(n) };
+        Ok(Some(Node::from_hash(encoded.try_into().unwrap())))
+    }
+
+    // Writes the nodes in `cache` and adds their hashes to `written`.
+    fn spill(
+        &self,
+        cache: &mut HashMap<Vec<u8>, Vec<u8>>,
+        written: &mut HashSet<[u8; 32]>,
+    ) -> TrieResult<()> {
+        let nodes = std::mem::take(cache);
+        if let Err(e) = self.db.insert_batch(nodes.clone()) {
+            // Hash nodes already refer to them.
+            self.deferred_nodes.write().extend(nodes);
+            return Err(TrieError::DB(e.to_string()));
+        }
+        for hash in nodes.keys() {
+            written.insert(hash.as_slice().try_into().unwrap());
+        }
+        Ok(())
+    }
+
+    /// `cache` is the buffer for generated hashes of nodes mapped to raw data.
+    pub(crate) fn encode_node(&self, n: Node, cache: &mut HashMap<Vec<u8>, Vec<u8>>) -> Vec<u8> {
+        // Returns the hash value directly to avoid double counting.
+        if let Node::Hash(hash_node) = n {
+            return unsafe { hash_node.as_ref() }.hash.to_vec();
+        }
+
+        // The empty node is never stored.
+        let inline = matches!(n, Node::Empty) || self.inline_small_nodes;
+        let data = self.encode_raw(n, cache);
+        // Nodes smaller than the inline threshold are stored inside their parent,
+        // larger ones are referenced by hash
+        if inline && data.len() < C::INLINE_THRESHOLD {
+            data
+        } else {
+            let hash = H::digest(&data);
+            cache.insert(hash.to_vec(), data);
+            hash.to_vec()
+        }
+    }
+
+    fn encode_raw(&self, n: Node, cache: &mut HashMap<Vec<u8>, Vec<u8>>) -> Vec<u8> {
+        match n {
+            Node::Empty => C::encode_empty(),
+            Node::Leaf(leaf) => {
+                let leaf_ref = unsafe { leaf.as_ref() };
+                let value = self.encode_value(&leaf_ref.value, cache);
+                C::encode_leaf(leaf_ref.key.as_bytes(), &value)
+            }
+            Node::Branch(branch) => {
+                let branch_ref = unsafe { branch.as_ref() };
+
+                let mut children: [Vec<u8>; 16] = Default::default();
+                for (i, child) in children.iter_mut().enumerate() {
+                    *child = self.encode_node(branch_ref.children[i].clone(), cache);
+                }
+                let value = branch_ref
+                    .value
+                    .as_deref()
+                    .map(|value| self.encode_value(value, cache));
+                C::encode_branch(&children, value.as_deref())
+            }
+            Node::Extension(ext) => {
+                let ext_ref = unsafe { ext.as_ref() };
+
+                let data = self.encode_node(ext_ref.node.clone(), cache);
+                C::encode_extension(ext_ref.prefix.as_bytes(), &data)
+            }
+            Node::Hash(_hash) => unreachable!(),
+        }
+    }
+
+    pub(crate) fn decode_node(&self, data: &[u8]) -> TrieResult<Node> {
+        self.decode_node_at(data, 0)
+    }
+
+    // `depth` is the number of parents the node is embedded in.
+    fn decode_node_at(&self, data: &[u8], depth: usize) -> TrieResult<Node> {
+        if depth > self.max_decode_depth {
+            return Err(TrieError::InvalidData);
+        }
+
+        match C::decode(data)? {
+            DecodedNode::Empty => Ok(Node::Empty),
+            DecodedNode::Leaf(key, value) => Ok(Node::from_leaf(
+                NibbleVec::from_hex(key),
+                self.decode_value(value)?,
+            )),
+            DecodedNode::Extension(prefix, child) => {
+                let n = self.decode_node_at(&child, depth + 1)?;
+                Ok(Node::from_extension(NibbleVec::from_hex(prefix), n))
+            }
+            DecodedNode::Branch(children, value) => {
+                let mut nodes = empty_children();
+                let decoded = children
+                    .iter()
+                    .zip(nodes.iter_mut())
+                    .try_for_each(|(child, node)| {
+                        *node = self.decode_node_at(child, depth + 1)?;
+                        Ok(())
+                    })
+                    .and_then(|_| value.map(|value| self.decode_value(value)).transpose());
+                match decoded {
+                    Ok(value) => Ok(Node::from_branch(nodes, value)),
+                    Err(e) => {
+                        for node in nodes {
+                            unsafe { Node::dealloc(node) };
+                        }
+                        Err(e)
+                    }
+                }
+            }
+            DecodedNode::Hash(hash) => Ok(Node::from_hash(hash)),
+        }
+    }
+
+    /// Returns the form in which `value` is stored in its node, adding the values
+    /// stored by hash to `cache`.
+    fn encode_value<'v>(
+        &self,
+        value: &'v [u8],
+        cache: &mut HashMap<Vec<u8>, Vec<u8>>,
+    ) -> Cow<'v, [u8]> {
+        match self.value_store {
+            Some(store) if value.len() > store.threshold => {
+                let hash = H::digest(value);
+                cache.insert(hash.to_vec(), value.to_vec());
+                Cow::Owned([&[VALUE_REF][..], &hash].concat())
+            }
+            None if !self.allow_empty_values => Cow::Borrowed(value),
+            _ => Cow::Owned([&[INLINE_VALUE][..], value].concat()),
+        }
+    }
+
+    fn decode_value(&self, data: Vec<u8>) -> TrieResult<Vec<u8>> {
+        if self.value_store.is_none() && !self.allow_empty_values {
+            return Ok(data);
+        }
+        match data.split_first() {
+            Some((&INLINE_VALUE, value)) => Ok(value.to_vec()),
+            Some((&VALUE_REF, hash)) if hash.len() == HASH_SIZE => {
+                let value = self
+                    .get_stored(hash)?
+                    .ok_or_else(|| TrieError::MissingNode(hash.to_vec()))?;
+                if self.strict {
+                    Self::check_node_hash(hash, &value)?;
+                }
+                Ok(value)
+            }
+            _ => Err(TrieError::InvalidData),
+        }
+    }
+
+    pub(crate) fn recover_from_db(&self, key: &[u8]) -> TrieResult<Node> {
+        self.recover_checked(key, self.strict)
+    }
+
+    // Same as `self.db.get`, but sees the nodes of deferred commits.
+    fn get_stored(&self, key: &[u8]) -> TrieResult<Option<Vec<u8>>> {
+        if let Some(value) = self.deferred_nodes.read().get(key) {
+            return Ok(Some(value.clone()));
+        }
+        let value = self.db.get(key).map_err(|e| TrieError::DB(e.to_string()))?;
+        if let (Some(witness), Some(value)) = (&self.witness, &value) {
+            witness.record(key, value);
+        }
+        Ok(value)
+    }
+
+    fn recover_checked(&self, key: &[u8], check_hash: bool) -> TrieResult<Node> {
+        match self.get_stored(key)? {
+            Some(value) => {
+                if check_hash {
+                    Self::check_node_hash(key, &value)?;
+                }
+                Ok(self.decode_node(&value)?)
+            }
+            None => Err(TrieError::MissingNode(key.to_vec())),
+        }
+    }
+
+    fn check_node_hash(key: &[u8], data: &[u8]) -> TrieResult<()> {
+        if H::digest(data) == key {
+            Ok(())
+        } else {
+            Err(TrieError::CorruptedNode(key.to_vec()))
+        }
+    }
+
+    fn verify_integrity_at(&self, n: Node) -> TrieResult<()> {
+        match n {
+            Node::Empty | Node::Leaf(_) => Ok(()),
+            Node::Branch(branch) => {
+                let branch_ref = unsafe { branch.as_ref() };
+                for child in branch_ref.children.iter() {
+                    self.verify_integrity_at(child.clone())?;
+                }
+                Ok(())
+            }
+            Node::Extension(ext) => {
+                let ext_ref = unsafe { ext.as_ref() };
+                self.verify_integrity_at(ext_ref.node.clone())
+            }
+            Node::Hash(hash_node) => {
+                let hash = unsafe { hash_node.as_ref() }.hash;
+                let n = self.recover_checked(&hash, true)?;
+                let result = self.verify_integrity_at(n.clone());
+                unsafe { Node::dealloc(n) };
+                result
+            }
+        }
+    }
+
+    /// Returns `TrieError::KeyTooLong` if a key of len bytes is over `max_key_len`.
+    fn check_key_len(&self, len: usize) -> TrieResult<()> {
+        if len > self.max_key_len {
+            return Err(TrieError::KeyTooLong(self.max_key_len));
+        }
+        Ok(())
+    }
+
+    /// Appends the `16` terminator to `key` and checks that it is the only nibble
+    /// out of range.
+    fn leaf_nibbles(key: &NibbleSlice) -> TrieResult<NibbleVec> {
+        let hex = key.as_bytes();
+        let hex = match hex.split_last() {
+            Some((16, rest)) => rest,
+            _ => hex,
+        };
+        if hex.iter().any(|&nibble| nibble > 15) {
+            return Err(TrieError::InvalidData);
+        }
+        let mut key = NibbleSlice::from_hex(hex).to_owned();
+        key.push(16);
+        Ok(key)
+    }
+
+    fn refcounts_at(&self, n: Node, counts: &mut HashMap<[u8; 32], usize>) -> TrieResult<()> {
+        match n {
+            Node::Empty | Node::Leaf(_) => Ok(()),
+            Node::Branch(branch) => {
+                let branch_ref = unsafe { branch.as_ref() };
+                for child in branch_ref.children.iter() {
+                    self.refcounts_at(child.clone(), counts)?;
+                }
+                Ok(())
+            }
+            Node::Extension(ext) => {
+                let ext_ref = unsafe { ext.as_ref() };
+                self.refcounts_at(ext_ref.node.clone(), counts)
+            }
+            Node::Hash(hash_node) => {
+                let hash = unsafe { hash_node.as_ref() }.hash;
+                // Subtrees are shared by content, a known hash has been traversed already.
+                let count = counts.entry(hash).or_default();
+                *count += 1;
+                if *count > 1 {
+                    return Ok(());
+                }
+                let n = self.recover_from_db(&hash)?;
+                let result = self.refcounts_at(n.clone(), counts);
+                unsafe { Node::dealloc(n) };
+                result
+            }
+        }
+    }
+
+    fn cache_node(&self, n: Node, cache: &mut HashMap<Vec<u8>, Vec<u8>>) -> TrieResult<Vec<u8>> {
+        match n {
+            Node::Empty => Ok(C::encode_empty()),
+            Node::Leaf(leaf) => {
+                let leaf_ref = unsafe { leaf.as_ref() };
+                let value = self.encode_value(&leaf_ref.value, cache);
+                Ok(C::encode_leaf(leaf_ref.key.as_bytes(), &value))
+            }
+            Node::Branch(branch) => {
+                let branch_ref = unsafe { branch.as_ref() };
+
+                let mut children: [Vec<u8>; 16] = Default::default();
+                for (i, child) in children.iter_mut().enumerate() {
+                    *child = self.cache_node(branch_ref.children[i].clone(), cache)?;
+                }
+                let value = branch_ref
+                    .value
+                    .as_deref()
+                    .map(|value| self.encode_value(value, cache));
+                Ok(C::encode_branch(&children, value.as_deref()))
+            }
+            Node::Extension(ext) => {
+                let ext_ref = unsafe { ext.as_ref() };
+
+                let data = self.cache_node(ext_ref.node.clone(), cache)?;
+                Ok(C::encode_extension(ext_ref.prefix.as_bytes(), &data))
+            }
+            Node::Hash(hash_node) => {
+                let hash = unsafe { hash_node.as_ref() }.hash;
+                let next_node = self.recover_from_db(&hash)?;
+                let data = match self.cache_node(next_node.clone(), cache) {
+                    Ok(data) => {
+                        unsafe { Node::dealloc(next_node) };
+                        data
+                    }
+                    Err(e) => {
+                        unsafe { Node::dealloc(next_node) };
+                        return Err(e);
+                    }
+                };
+                cache.insert(hash.to_vec(), data);
+                Ok(hash.to_vec())
+            }
+        }
+    }
+}
+
+// Compares all of `candidate` even when the lengths differ, so the time taken only
+// depends on the lengths.
+fn constant_time_eq(value: &[u8], candidate: &[u8]) -> bool {
+    let mut diff = (value.len() != candidate.len()) as u8;
+    for (i, b) in candidate.iter().enumerate() {
+        diff |= value.get(i).copied().unwrap_or(!*b) ^ b;
+    }
+    std::hint::black_box(diff) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use parking_lot::Mutex;
+    use rand::distributions::Alphanumeric;
+    use rand::seq::SliceRandom;
+    use rand::{thread_rng, Rng};
+    use sha3::Digest;
+    use std::collections::{BTreeSet, HashMap, HashSet};
+    use std::convert::TryInto;
+    use std::sync::Arc;
+
+    use super::{
+        InsertKind, LeafMeta, NodeCache, PatriciaTrie, PatriciaTrieBuilder, ProofKind, Trie,
+        TrieResult, ValueStore, VecRootHistory, WitnessRecorder, DEFAULT_MAX_KEY_LEN,
+    };
+    use crate::codec::{NodeCodec, RlpCodec};
+    use crate::db::{MemoryDB, DB};
+    use crate::errors::{MemDBError, TrieError};
+    use crate::hasher::{Hasher, HasherKeccak};
+    use crate::nibbles::NibbleVec;
+    use crate::node::Node;
+
+    #[test]
+    fn test_is_sync() {
+        fn is_sync<T: Send + Sync>() {}
+
+        is_sync::<PatriciaTrie<MemoryDB>>()
+    }
+
+    #[test]
+    fn test_trie_insert() {
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::new(memdb);
+        trie.insert(b"test".to_vec(), b"test".to_vec()).unwrap();
+        trie.insert(b"tswq".to_vec(), b"test2".to_vec()).unwrap();
+    }
+
+    #[test]
+    fn test_trie_get() {
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::new(memdb);
+        trie.insert(b"test".to_vec(), b"test".to_vec()).unwrap();
+        let v = trie.get(b"test").unwrap();
+
+        assert_eq!(Some(b"test".to_vec()), v)
+    }
+
+    #[test]
+    fn test_trie_random_insert() {
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::new(memdb);
+
+        for _ in 0..1000 {
+            let rand_str: String = thread_rng().sample_iter(&Alphanumeric).take(30).collect();
+            let val = rand_str.as_bytes();
+            trie.insert(val.to_vec(), val.to_vec()).unwrap();
+
+            let v = trie.get(val).unwrap();
+            assert_eq!(v.map(|v| v.to_vec()), Some(val.to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_trie_contains() {
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::new(memdb);
+        trie.insert(b"test".to_vec(), b"test".to_vec()).unwrap();
+        assert!(trie.contains(b"test").unwrap());
+        assert!(!trie.contains(b"test2").unwrap());
+    }
+
+    #[test]
+    fn test_trie_remove() {
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::new(memdb);
+        trie.insert(b"test".to_vec(), b"test".to_vec()).unwrap();
+        trie.insert(b"test2".to_vec(), b"test".to_vec()).unwrap();
+        let removed = trie.remove(b"test2").unwrap();
+        assert!(removed)
+    }
+
+    #[test]
+    fn test_trie_random_remove() {
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::new(memdb);
+
+        for _ in 0..1000 {
+            let rand_str: String = thread_rng().sample_iter(&Alphanumeric).take(30).collect();
+            let val = rand_str.as_bytes();
+            trie.insert(val.to_vec(), val.to_vec()).unwrap();
+
+            let removed = trie.remove(val).unwrap();
+            assert!(removed);
+        }
+    }
+
+    #[test]
+    fn test_trie_from_root() {
+        let memdb = MemoryDB::new(true);
+        let root = {
+            let mut trie = PatriciaTrie::new(memdb.clone());
+            trie.insert(b"test".to_vec(), b"test".to_vec()).unwrap();
+            trie.insert(b"test1".to_vec(), b"test".to_vec()).unwrap();
+            trie.insert(b"test2".to_vec(), b"test".to_vec()).unwrap();
+            trie.insert(b"test23".to_vec(), b"test".to_vec()).unwrap();
+            trie.insert(b"test33".to_vec(), b"test".to_vec()).unwrap();
+            trie.insert(b"test44".to_vec(), b"test".to_vec()).unwrap();
+            trie.root().unwrap()
+        };
+
+        let mut trie = PatriciaTrie::from(memdb, &root).unwrap();
+        let v1 = trie.get(b"test33").unwrap();
+        assert_eq!(Some(b"test".to_vec()), v1);
+        let v2 = trie.get(b"test44").unwrap();
+        assert_eq!(Some(b"test".to_vec()), v2);
+        let root2 = trie.root().unwrap();
+        assert_eq!(hex::encode(root), hex::encode(root2));
+    }
+
+    #[test]
+    fn test_trie_from_root_and_insert() {
+        let memdb = MemoryDB::new(true);
+        let root = {
+            let mut trie = PatriciaTrie::new(memdb.clone());
+            trie.insert(b"test".to_vec(), b"test".to_vec()).unwrap();
+            trie.insert(b"test1".to_vec(), b"test".to_vec()).unwrap();
+            trie.insert(b"test2".to_vec(), b"test".to_vec()).unwrap();
+            trie.insert(b"test23".to_vec(), b"test".to_vec()).unwrap();
+            trie.insert(b"test33".to_vec(), b"test".to_vec()).unwrap();
+            trie.insert(b"test44".to_vec(), b"test".to_vec()).unwrap();
+            trie.commit().unwrap()
+        };
+
+        let mut trie = PatriciaTrie::from(memdb, &root).unwrap();
+        trie.insert(b"test55".to_vec(), b"test55".to_vec()).unwrap();
+        trie.commit().unwrap();
+        let v = trie.get(b"test55").unwrap();
+        assert_eq!(Some(b"test55".to_vec()), v);
+    }
+
+    #[test]
+    fn test_trie_from_root_and_delete() {
+        let memdb = MemoryDB::new(true);
+        let root = {
+            let mut trie = PatriciaTrie::new(memdb.clone());
+            trie.insert(b"test".to_vec(), b"test".to_vec()).unwrap();
+            trie.insert(b"test1".to_vec(), b"test".to_vec()).unwrap();
+            trie.insert(b"test2".to_vec(), b"test".to_vec()).unwrap();
+            trie.insert(b"test23".to_vec(), b"test".to_vec()).unwrap();
+            trie.insert(b"test33".to_vec(), b"test".to_vec()).unwrap();
+            trie.insert(b"test44".to_vec(), b"test".to_vec()).unwrap();
+            trie.commit().unwrap()
+        };
+
+        let mut trie = PatriciaTrie::from(memdb, &root).unwrap();
+        let value = trie.get(b"test").unwrap();
+        assert!(value.is_some());
+        let removed = trie.remove(b"test").unwrap();
+        assert!(removed);
+        let value_back = trie.get(b"test").unwrap();
+        assert!(value_back.is_none());
+    }
+
+    #[test]
+    fn test_trie_from_root_and_delete_2() {
+        let memdb = MemoryDB::new(true);
+        let root = {
+            let mut trie = PatriciaTrie::new(memdb.clone());
+            trie.insert(b"test".to_vec(), b"test".to_vec()).unwrap();
+            trie.insert(b"test1".to_vec(), b"test".to_vec()).unwrap();
+            trie.insert(b"test2".to_vec(), b"test".to_vec()).unwrap();
+            trie.insert(b"test23".to_vec(), b"test".to_vec()).unwrap();
+            trie.insert(b"test33".to_vec(), b"test".to_vec()).unwrap();
+            trie.insert(b"test44".to_vec(), b"test".to_vec()).unwrap();
+            trie.commit().unwrap()
+        };
+
+        let mut trie = PatriciaTrie::from(memdb, &root).unwrap();
+        let removed = trie.remove(b"test44").unwrap();
+        assert!(removed);
+        let removed = trie.remove(b"test33").unwrap();
+        assert!(removed);
+        let removed = trie.remove(b"test23").unwrap();
+        assert!(removed);
+    }
+
+    #[test]
+    fn test_multiple_trie_roots() {
+        let k0 = ethereum_types::H256::from_low_u64_le(0);
+        let k1 = ethereum_types::H256::from_low_u64_le(1);
+        let v = ethereum_types::H256::from_low_u64_le(0x1234);
+
+        let root1 = {
+            let memdb = MemoryDB::new(true);
+            let mut trie = PatriciaTrie::new(memdb);
+            trie.insert(k0.as_bytes().to_vec(), v.as_bytes().to_vec())
+                .unwrap();
+            trie.root().unwrap()
+        };
+
+        let root2 = {
+            let memdb = MemoryDB::new(true);
+            let mut trie = PatriciaTrie::new(memdb);
+            trie.insert(k0.as_bytes().to_vec(), v.as_bytes().to_vec())
+                .unwrap();
+            trie.insert(k1.as_bytes().to_vec(), v.as_bytes().to_vec())
+                .unwrap();
+            trie.root().unwrap();
+            trie.remove(k1.as_ref()).unwrap();
+            trie.root().unwrap()
+        };
+
+        let root3 = {
+            let memdb = MemoryDB::new(true);
+            let mut trie1 = PatriciaTrie::new(memdb.clone());
+            trie1
+                .insert(k0.as_bytes().to_vec(), v.as_bytes().to_vec())
+                .unwrap();
+            trie1
+                .insert(k1.as_bytes().to_vec(), v.as_bytes().to_vec())
+                .unwrap();
+            trie1.root().unwrap();
+            let root = trie1.root().unwrap();
+            let mut trie2 = PatriciaTrie::from(memdb, &root).unwrap();
+            trie2.remove(k1.as_bytes()).unwrap();
+            trie2.root().unwrap()
+        };
+
+        assert_eq!(root1, root2);
+        assert_eq!(root2, root3);
+    }
+
+    #[test]
+    fn test_delete_stale_keys_with_random_insert_and_delete() {
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::new(memdb);
+
+        let mut rng = rand::thread_rng();
+        let mut keys = vec![];
+        for _ in 0..100 {
+            let random_bytes: Vec<u8> = (0..rng.gen_range(2, 30))
+                .map(|_| rand::random::<u8>())
+                .collect();
+            trie.insert(random_bytes.clone(), random_bytes.clone())
+                .unwrap();
+            keys.push(random_bytes.clone());
+        }
+        trie.commit().unwrap();
+        let slice = &mut keys;
+        slice.shuffle(&mut rng);
+
+        for key in slice.iter() {
+            trie.remove(key).unwrap();
+        }
+        trie.commit().unwrap();
+
+        let empty_node_key = sha3::Keccak256::digest(rlp::NULL_RLP);
+        let value = trie.db.get(empty_node_key.as_ref()).unwrap().unwrap();
+        assert_eq!(value, &rlp::NULL_RLP)
+    }
+
+    #[test]
+    fn insert_full_branch() {
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::new(memdb);
+
+        trie.insert(b"test".to_vec(), b"test".to_vec()).unwrap();
+        trie.insert(b"test1".to_vec(), b"test".to_vec()).unwrap();
+        trie.insert(b"test2".to_vec(), b"test".to_vec()).unwrap();
+        trie.insert(b"test23".to_vec(), b"test".to_vec()).unwrap();
+        trie.insert(b"test33".to_vec(), b"test".to_vec()).unwrap();
+        trie.insert(b"test44".to_vec(), b"test".to_vec()).unwrap();
+        trie.root().unwrap();
+
+        let v = trie.get(b"test").unwrap();
+        assert_eq!(Some(b"test".to_vec()), v);
+    }
+
+    #[test]
+    fn iterator_trie() {
+        let memdb = MemoryDB::new(true);
+        let root1;
+        let mut kv = HashMap::new();
+        kv.insert(b"test".to_vec(), b"test".to_vec());
+        kv.insert(b"test1".to_vec(), b"test1".to_vec());
+        kv.insert(b"test11".to_vec(), b"test2".to_vec());
+        kv.insert(b"test14".to_vec(), b"test3".to_vec());
+        kv.insert(b"test16".to_vec(), b"test4".to_vec());
+        kv.insert(b"test18".to_vec(), b"test5".to_vec());
+        kv.insert(b"test2".to_vec(), b"test6".to_vec());
+        kv.insert(b"test23".to_vec(), b"test7".to_vec());
+        kv.insert(b"test9".to_vec(), b"test8".to_vec());
+        {
+            let mut trie = PatriciaTrie::new(memdb.clone());
+            let mut kv = kv.clone();
+            kv.iter().for_each(|(k, v)| {
+                trie.insert(k.clone(), v.clone()).unwrap();
+            });
+            root1 = trie.root().unwrap();
+
+            trie.iter()
+                .for_each(|(k, v)| assert_eq!(kv.remove(&k).unwrap(), v));
+            assert!(kv.is_empty());
+        }
+
+        {
+            let mut trie = PatriciaTrie::new(memdb.clone());
+            let mut kv2 = HashMap::new();
+            kv2.insert(b"test".to_vec(), b"test11".to_vec());
+            kv2.insert(b"test1".to_vec(), b"test12".to_vec());
+            kv2.insert(b"test14".to_vec(), b"test13".to_vec());
+            kv2.insert(b"test22".to_vec(), b"test14".to_vec());
+            kv2.insert(b"test9".to_vec(), b"test15".to_vec());
+            kv2.insert(b"test16".to_vec(), b"test16".to_vec());
+            kv2.insert(b"test2".to_vec(), b"test17".to_vec());
+            kv2.iter().for_each(|(k, v)| {
+                trie.insert(k.clone(), v.clone()).unwrap();
+            });
+
+            trie.root().unwrap();
+
+            let mut kv_delete = HashSet::new();
+            kv_delete.insert(b"test".to_vec());
+            kv_delete.insert(b"test1".to_vec());
+            kv_delete.insert(b"test14".to_vec());
+
+            kv_delete.iter().for_each(|k| {
+                trie.remove(k).unwrap();
+            });
+
+            kv2.retain(|k, _| !kv_delete.contains(k));
+
+            trie.root().unwrap();
+            trie.iter()
+                .for_each(|(k, v)| assert_eq!(kv2.remove(&k).unwrap(), v));
+            assert!(kv2.is_empty());
+        }
+
+        let trie = PatriciaTrie::from(memdb, &root1).unwrap();
+        trie.iter()
+            .for_each(|(k, v)| assert_eq!(kv.remove(&k).unwrap(), v));
+        assert!(kv.is_empty());
+    }
+
+    #[test]
+    fn test_extract_backup() {
+        let memdb = MemoryDB::new(true);
+        let memdb2 = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::new(memdb.clone());
+
+        trie.insert(b"test".to_vec(), b"test".to_vec()).unwrap();
+        trie.insert(b"test1".to_vec(), b"test".to_vec()).unwrap();
+        trie.insert(b"test2".to_vec(), b"test".to_vec()).unwrap();
+        trie.insert(b"test23".to_vec(), b"test".to_vec()).unwrap();
+        trie.insert(b"test33".to_vec(), b"test".to_vec()).unwrap();
+        trie.insert(b"test44".to_vec(), b"test".to_vec()).unwrap();
+        let hash = trie.root().unwrap();
+
+        assert!(PatriciaTrie::extract_backup(memdb, memdb2, &hash).is_ok());
+    }
+
+    #[test]
+    fn test_trie_read_cache_disabled() {
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::new(memdb.clone());
+        for i in 0..100u8 {
+            trie.insert(vec![i, i / 4], vec![i; 40]).unwrap();
+        }
+        let root = trie.root().unwrap();
+
+        let mut trie = PatriciaTrieBuilder::new()
+            .db(memdb)
+            .enable_read_cache(false)
+            .node_cache(NodeCache::new(16))
+            .root(&root)
+            .build_from_root()
+            .unwrap();
+        assert!(trie.cached_tries.is_none());
+        for _ in 0..2 {
+            for i in 0..100u8 {
+                assert_eq!(trie.get(&[i, i / 4]).unwrap(), Some(vec![i; 40]));
+            }
+            assert_eq!(trie.first().unwrap(), Some((vec![0, 0], vec![0; 40])));
+            assert_eq!(trie.get(&[100, 25]).unwrap(), None);
+        }
+        trie.clear_cache();
+
+        // Writes still work, and tries built like this one don't cache either.
+        trie.insert(vec![7, 1], b"new".to_vec()).unwrap();
+        let root = trie.root().unwrap();
+        assert_eq!(trie.get(&[7, 1]).unwrap(), Some(b"new".to_vec()));
+        let compacted = trie.compact(MemoryDB::new(true)).unwrap();
+        assert_eq!(compacted, root);
+        assert!(trie.cached_tries.is_none());
+        assert_eq!(trie.get(&[7, 1]).unwrap(), Some(b"new".to_vec()));
+    }
+
+    #[test]
+    fn test_trie_inline_small_nodes_disabled() {
+        fn count_nodes(n: &Node) -> usize {
+            match n {
+                Node::Empty | Node::Hash(_) => 0,
+                Node::Leaf(_) => 1,
+                Node::Branch(branch) => {
+                    let children = &unsafe { branch.as_ref() }.children;
+                    1 + children.iter().map(count_nodes).sum::<usize>()
+                }
+                Node::Extension(ext) => 1 + count_nodes(&unsafe { ext.as_ref() }.node),
+            }
+        }
+
+        // Short keys and values, most leaves are embedded by default.
+        let entries = (0..100u8).map(|i| (vec![i / 10, i], vec![i]));
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::new(memdb.clone());
+        let stored = MemoryDB::new(true);
+        let mut separate = PatriciaTrieBuilder::new()
+            .db(stored.clone())
+            .inline_small_nodes(false)
+            .build();
+        for (key, value) in entries.clone() {
+            trie.insert(key.clone(), value.clone()).unwrap();
+            separate.insert(key, value).unwrap();
+        }
+        let nodes = count_nodes(&separate.root);
+        assert_eq!(count_nodes(&trie.root), nodes);
+        let root = trie.root().unwrap();
+        let separate_root = separate.root().unwrap();
+        assert_ne!(separate_root, root);
+        assert!(memdb.keys().unwrap().unwrap().len() < nodes);
+        assert_eq!(stored.keys().unwrap().unwrap().len(), nodes);
+        assert_eq!(separate.reachable_hashes().unwrap().len(), nodes);
+
+        let separate = PatriciaTrieBuilder::new()
+            .db(stored.clone())
+            .inline_small_nodes(false)
+            .root(&separate_root)
+            .build_from_root()
+            .unwrap();
+        for (key, value) in entries.clone() {
+            assert_eq!(separate.get(&key).unwrap(), Some(value.clone()));
+            let proof = separate.get_proof(&key).unwrap();
+            assert!(proof.len() > trie.get_proof(&key).unwrap().len());
+            assert_eq!(
+                separate.verify_proof(&separate_root, &key, proof).unwrap(),
+                Some(value)
+            );
+        }
+
+        // Changes keep one entry per node, counted on a trie built from scratch, and
+        // the previous root as every commit does.
+        let mut separate = separate;
+        let mut expected = PatriciaTrie::new(MemoryDB::new(true));
+        for (key, value) in entries.filter(|(key, _)| key != &[3, 35]) {
+            expected.insert(key, value).unwrap();
+        }
+        separate.remove(&[3, 35]).unwrap();
+        separate.insert(vec![3, 35, 1], vec![1]).unwrap();
+        expected.insert(vec![3, 35, 1], vec![1]).unwrap();
+        separate.root().unwrap();
+        assert_eq!(
+            stored.keys().unwrap().unwrap().len(),
+            count_nodes(&expected.root) + 1
+        );
+        assert!(stored.contains(&separate_root).unwrap());
+    }
+
+    #[test]
+    fn test_trie_rehash() {
+        #[derive(Debug)]
+        struct HasherSha3;
+
+        impl Hasher for HasherSha3 {
+            fn digest(data: &[u8]) -> [u8; 32] {
+                sha3::Sha3_256::digest(data).into()
+            }
+        }
+
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::new(memdb.clone());
+        for i in 0..100u8 {
+            trie.insert(vec![i, i / 4], vec![i; 40]).unwrap();
+        }
+        trie.root().unwrap();
+        // Uncommitted changes are migrated too.
+        trie.insert(vec![7, 1], b"new".to_vec()).unwrap();
+        trie.remove(&[8, 2]).unwrap();
+        let entries = trie.iter().collect::<Vec<_>>();
+
+        let dest = MemoryDB::new(true);
+        let rehashed = trie.rehash::<HasherSha3>(dest.clone()).unwrap();
+        let root = rehashed.root_hash().to_vec();
+        assert_ne!(root, trie.root().unwrap());
+        assert_eq!(rehashed.iter().collect::<Vec<_>>(), entries);
+        let mut expected = PatriciaTrie::<_, HasherSha3, RlpCodec>::create(MemoryDB::new(true));
+        for (key, value) in &entries {
+            expected.insert(key.clone(), value.clone()).unwrap();
+        }
+        assert_eq!(expected.root().unwrap(), root);
+
+        let reopened = PatriciaTrie::<_, HasherSha3, RlpCodec>::open(dest, &root).unwrap();
+        reopened.verify_integrity().unwrap();
+        assert_eq!(reopened.iter().collect::<Vec<_>>(), entries);
+        assert_eq!(trie.iter().collect::<Vec<_>>(), entries);
+
+        // The empty trie gets the empty root of the new hasher.
+        let empty = PatriciaTrie::new(MemoryDB::new(true));
+        let rehashed = empty.rehash::<HasherSha3>(MemoryDB::new(true)).unwrap();
+        assert_eq!(
+            rehashed.root_hash(),
+            HasherSha3::digest(&RlpCodec::encode_empty())
+        );
+    }
+
+    #[test]
+    fn test_cached_tries_bounded() {
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::with_cache_capacity(memdb, 8);
+
+        let mut keys = vec![];
+        for _ in 0..1000 {
+            let rand_str: String = thread_rng().sample_iter(&Alphanumeric).take(30).collect();
+            let val = rand_str.as_bytes();
+            trie.insert(val.to_vec(), val.to_vec()).unwrap();
+            keys.push(val.to_vec());
+        }
+        trie.root().unwrap();
+
+        for key in keys.iter() {
+            assert_eq!(trie.get(key).unwrap(), Some(key.clone()));
+            assert!(trie.cached_tries.as_ref().unwrap().lock().len() <= 8);
+        }
+        assert_eq!(trie.cached_tries.as_ref().unwrap().lock().len(), 8);
+    }
+
+    #[test]
+    fn test_trie_from_iterator() {
+        let mut kv = HashMap::new();
+        kv.insert(b"test".to_vec(), b"test".to_vec());
+        kv.insert(b"test1".to_vec(), b"test1".to_vec());
+        kv.insert(b"test23".to_vec(), b"test23".to_vec());
+
+        let trie: PatriciaTrie<_> = kv.clone().into_iter().collect();
+        let mut expected = PatriciaTrie::new(MemoryDB::new(true));
+        for (k, v) in kv.clone() {
+            expected.insert(k, v).unwrap();
+        }
+        assert_eq!(trie.root_hash, expected.root().unwrap());
+
+        for (k, v) in &trie {
+            assert_eq!(kv.remove(&k).unwrap(), v);
+        }
+        assert!(kv.is_empty());
+
+        // Keys are not limited in length.
+        let long = vec![7; DEFAULT_MAX_KEY_LEN + 1];
+        let trie: PatriciaTrie<_> = vec![(long.clone(), b"v".to_vec())].into_iter().collect();
+        assert_eq!(trie.get(&long).unwrap(), Some(b"v".to_vec()));
+    }
+
+    #[test]
+    fn test_trie_contains_prefix() {
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::new(memdb);
+        assert!(!trie.contains_prefix(b"").unwrap());
+
+        trie.insert(b"test1".to_vec(), b"test".to_vec()).unwrap();
+        trie.insert(b"test2".to_vec(), b"test".to_vec()).unwrap();
+        trie.insert(b"other".to_vec(), b"test".to_vec()).unwrap();
+
+        let root = trie.root().unwrap();
+        let reopened = PatriciaTrie::from(trie.db.clone(), &root).unwrap();
+        for trie in [&trie, &reopened] {
+            assert!(trie.contains_prefix(b"").unwrap());
+            // ends in the middle of the "test" extension
+            assert!(trie.contains_prefix(b"te").unwrap());
+            assert!(trie.contains_prefix(b"test").unwrap());
+            assert!(trie.contains_prefix(b"test1").unwrap());
+            assert!(trie.contains_prefix(b"oth").unwrap());
+            assert!(!trie.contains_prefix(b"test3").unwrap());
+            assert!(!trie.contains_prefix(b"test11").unwrap());
+            assert!(!trie.contains_prefix(b"tesx").unwrap());
+            assert!(!trie.contains(b"te").unwrap());
+        }
+    }
+
+    #[test]
+    fn test_trie_subtree() {
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::new(memdb.clone());
+        for i in 0..200u8 {
+            trie.insert(vec![i % 4, i, i / 7], vec![i; 40]).unwrap();
+        }
+        trie.insert(b"account-1".to_vec(), vec![1; 40]).unwrap();
+        trie.insert(b"account-2".to_vec(), b"two".to_vec()).unwrap();
+        trie.insert(b"lonely-leaf".to_vec(), vec![3; 40]).unwrap();
+        let root = trie.root().unwrap();
+        let reopened = PatriciaTrie::from(memdb, &root).unwrap();
+
+        let prefixes: [&[u8]; 8] = [
+            &[],
+            &[2],
+            &[2, 6],
+            &[2, 6, 0],
+            b"acc",
+            b"account-",
+            b"lonely",
+            b"lonely-leaf",
+        ];
+        for trie in [&trie, &reopened] {
+            for prefix in prefixes {
+                let expected = trie
+                    .iter()
+                    .filter(|(key, _)| key.starts_with(prefix))
+                    .map(|(key, value)| (key[prefix.len()..].to_vec(), value))
+                    .collect::<Vec<_>>();
+                let subtree = trie.subtree(prefix).unwrap().unwrap();
+                assert_eq!(subtree.prefix(), prefix);
+                let entries = subtree.iter().collect::<Vec<_>>();
+                assert_eq!(entries, expected);
+                assert_eq!(subtree.len().unwrap(), expected.len());
+                // The same entries in a trie of their own.
+                let mut standalone = PatriciaTrie::new(MemoryDB::new(true));
+                for (key, value) in expected {
+                    standalone.insert(key, value).unwrap();
+                }
+                assert_eq!(subtree.hash(), standalone.compute_root());
+            }
+            assert!(trie.subtree(&[2, 7]).unwrap().is_none());
+            assert!(trie.subtree(&[5]).unwrap().is_none());
+            assert!(trie.subtree(b"accounts").unwrap().is_none());
+            assert!(trie.subtree(b"lonely-leaf!").unwrap().is_none());
+        }
+        assert_eq!(trie.subtree(&[]).unwrap().unwrap().hash(), root);
+    }
+
+    #[test]
+    fn test_trie_get_with_depth() {
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::new(memdb.clone());
+        trie.insert(vec![0x10], vec![1; 40]).unwrap();
+        for i in 0..4u8 {
+            trie.insert(vec![0x20, 0x20, 0x20, i], vec![i; 40]).unwrap();
+        }
+        assert_eq!(trie.get_with_depth(&[0x30]).unwrap(), None);
+        // A branch and a leaf, then an extension and a branch on the way to the others.
+        assert_eq!(
+            trie.get_with_depth(&[0x10]).unwrap(),
+            Some((vec![1; 40], 2))
+        );
+        assert_eq!(
+            trie.get_with_depth(&[0x20, 0x20, 0x20, 3]).unwrap(),
+            Some((vec![3; 40], 4))
+        );
+
+        // Every child is stored by hash once committed.
+        let root = trie.root().unwrap();
+        let reopened = PatriciaTrie::from(memdb, &root).unwrap();
+        assert_eq!(
+            reopened.get_with_depth(&[0x10]).unwrap(),
+            Some((vec![1; 40], 3))
+        );
+        assert_eq!(
+            reopened.get_with_depth(&[0x20, 0x20, 0x20, 3]).unwrap(),
+            Some((vec![3; 40], 7))
+        );
+    }
+
+    #[test]
+    fn test_trie_common_ancestor() {
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::new(memdb.clone());
+        let empty_root = trie.root().unwrap();
+        assert_eq!(
+            trie.common_ancestor(b"a", b"b").unwrap(),
+            (vec![], empty_root)
+        );
+
+        // The keys share `abcdef0` before a branch, under an extension from the root.
+        let (a, b) = (vec![0xab, 0xcd, 0xef, 0x01], vec![0xab, 0xcd, 0xef, 0x02]);
+        for key in [&a, &b, &vec![0xab, 0xcd, 0xef, 0x03], &vec![0x11]] {
+            trie.insert(key.clone(), vec![key[3 % key.len()]; 40])
+                .unwrap();
+        }
+        let uncommitted = [
+            trie.common_ancestor(&a, &b).unwrap(),
+            trie.common_ancestor(&a, &[0xab, 0xcd, 0x00]).unwrap(),
+        ];
+        let root = trie.root().unwrap();
+        let reopened = PatriciaTrie::from(memdb, &root).unwrap();
+        let proof = trie.get_proof(&a).unwrap();
+        let hashes = proof
+            .iter()
+            .map(|node| HasherKeccak::digest(node).to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(hashes.len(), 4);
+        assert_eq!(hashes[0], root);
+
+        for trie in [&trie, &reopened] {
+            let branch = (vec![0xa, 0xb, 0xc, 0xd, 0xe, 0xf, 0x0], hashes[2].clone());
+            assert_eq!(trie.common_ancestor(&a, &b).unwrap(), branch);
+            assert_eq!(trie.common_ancestor(&b, &a).unwrap(), branch);
+            // An absent key going through the branch.
+            assert_eq!(
+                trie.common_ancestor(&a, &[0xab, 0xcd, 0xef, 0x04]).unwrap(),
+                branch
+            );
+            // Diverging within the extension prefix, or ending there.
+            for other in [&[0xab, 0xcd, 0x00][..], &[0xab, 0xcd, 0xef]] {
+                assert_eq!(
+                    trie.common_ancestor(&a, other).unwrap(),
+                    (vec![0xa], hashes[1].clone())
+                );
+            }
+            assert_eq!(
+                trie.common_ancestor(&a, &[0x11]).unwrap(),
+                (vec![], root.clone())
+            );
+            // A key with itself ends at its leaf.
+            assert_eq!(
+                trie.common_ancestor(&a, &a).unwrap(),
+                (
+                    vec![0xa, 0xb, 0xc, 0xd, 0xe, 0xf, 0x0, 0x1],
+                    hashes[3].clone()
+                )
+            );
+        }
+        assert_eq!(uncommitted[0].1, hashes[2]);
+        assert_eq!(uncommitted[1].1, hashes[1]);
+    }
+
+    #[test]
+    fn test_trie_longest_prefix() {
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::new(memdb.clone());
+        assert_eq!(trie.longest_prefix(b"10.0.0.1").unwrap(), None);
+
+        for (key, value) in [
+            (&b"10."[..], b"a"),
+            (b"10.0.", b"b"),
+            (b"10.0.0.", b"c"),
+            (b"10.1", b"d"),
+            (b"192.168.", b"e"),
+        ] {
+            trie.insert(key.to_vec(), value.to_vec()).unwrap();
+        }
+        // Long values so that the nodes are stored by hash.
+        for i in 0..20u8 {
+            trie.insert([b"172.", &[i][..]].concat(), vec![i; 40])
+                .unwrap();
+        }
+        let root = trie.root().unwrap();
+        let reopened = PatriciaTrie::from(memdb, &root).unwrap();
+        for trie in [&trie, &reopened] {
+            let longest = |key: &[u8]| trie.longest_prefix(key).unwrap();
+            assert_eq!(
+                longest(b"10.0.0.1"),
+                Some((b"10.0.0.".to_vec(), b"c".to_vec()))
+            );
+            // 10.0.0. diverges after 10.0.
+            assert_eq!(
+                longest(b"10.0.1.1"),
+                Some((b"10.0.".to_vec(), b"b".to_vec()))
+            );
+            // 10.1 is longer than the query.
+            assert_eq!(longest(b"10.2"), Some((b"10.".to_vec(), b"a".to_vec())));
+            assert_eq!(longest(b"10.1"), Some((b"10.1".to_vec(), b"d".to_vec())));
+            assert_eq!(longest(b"10.1.5"), Some((b"10.1".to_vec(), b"d".to_vec())));
+            assert_eq!(
+                longest(b"192.168.1.1"),
+                Some((b"192.168.".to_vec(), b"e".to_vec()))
+            );
+            assert_eq!(
+                longest(&[b"172.", &[7, 1][..]].concat()),
+                Some(([b"172.", &[7][..]].concat(), vec![7; 40]))
+            );
+            assert_eq!(longest(b"172."), None);
+            assert_eq!(longest(b"10"), None);
+            assert_eq!(longest(b"192.16"), None);
+            assert_eq!(longest(b""), None);
+        }
+
+        trie.insert(vec![], b"default".to_vec()).unwrap();
+        assert_eq!(
+            trie.longest_prefix(b"8.8.8.8").unwrap(),
+            Some((vec![], b"default".to_vec()))
+        );
+    }
+
+    #[test]
+    fn test_trie_checkpoint_rollback() {
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::new(memdb.clone());
+        trie.insert(b"test".to_vec(), b"test".to_vec()).unwrap();
+        trie.insert(b"test1".to_vec(), b"test1".to_vec()).unwrap();
+        let committed_root = trie.root().unwrap();
+        trie.insert(b"test2".to_vec(), b"test2".to_vec()).unwrap();
+
+        let checkpoint = trie.checkpoint();
+        trie.insert(b"test23".to_vec(), b"test23".to_vec()).unwrap();
+        trie.insert(b"test1".to_vec(), b"changed".to_vec()).unwrap();
+        trie.remove(b"test").unwrap();
+        trie.rollback_to(checkpoint);
+
+        assert_eq!(trie.get(b"test").unwrap(), Some(b"test".to_vec()));
+        assert_eq!(trie.get(b"test1").unwrap(), Some(b"test1".to_vec()));
+        assert_eq!(trie.get(b"test2").unwrap(), Some(b"test2".to_vec()));
+        assert_eq!(trie.get(b"test23").unwrap(), None);
+
+        let root = trie.root().unwrap();
+        let mut expected = PatriciaTrie::new(MemoryDB::new(true));
+        expected.insert(b"test".to_vec(), b"test".to_vec()).unwrap();
+        expected
+            .insert(b"test1".to_vec(), b"test1".to_vec())
+            .unwrap();
+        expected
+            .insert(b"test2".to_vec(), b"test2".to_vec())
+            .unwrap();
+        assert_eq!(root, expected.root().unwrap());
+
+        // Nodes shared with the checkpoint must not be pruned.
+        let trie = PatriciaTrie::from(memdb, &root).unwrap();
+        assert_eq!(trie.iter().count(), 3);
+        assert_ne!(root, committed_root);
+    }
+
+    #[test]
+    fn test_trie_verify_integrity() {
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::new(memdb.clone());
+        for i in 0..100u8 {
+            trie.insert(vec![i; 8], vec![i; 32]).unwrap();
+        }
+        let root = trie.root().unwrap();
+        assert!(trie.verify_integrity().is_ok());
+        assert!(PatriciaTrie::from_strict(memdb.clone(), &root).is_ok());
+
+        // Replace a leaf-side node with another valid node encoding.
+        let proof = trie.get_proof(&[7; 8]).unwrap();
+        let other = trie.get_proof(&[99; 8]).unwrap();
+        let corrupted = HasherKeccak::digest(proof.last().unwrap());
+        memdb
+            .insert(corrupted.to_vec(), other.last().unwrap().clone())
+            .unwrap();
+
+        let trie = PatriciaTrie::from(memdb.clone(), &root).unwrap();
+        match trie.verify_integrity() {
+            Err(TrieError::CorruptedNode(hash)) => assert_eq!(hash, corrupted.to_vec()),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        // The lenient trie silently trusts the data.
+        assert!(trie.get(&[7; 8]).is_ok());
+
+        let trie = PatriciaTrie::from_strict(memdb, &root).unwrap();
+        assert!(matches!(
+            trie.get(&[7; 8]),
+            Err(TrieError::CorruptedNode(_))
+        ));
+    }
+
+    #[test]
+    fn test_trie_get_many() {
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::new(memdb.clone());
+        let mut keys = vec![];
+        for _ in 0..200 {
+            let rand_str: String = thread_rng().sample_iter(&Alphanumeric).take(20).collect();
+            trie.insert(rand_str.as_bytes().to_vec(), rand_str.as_bytes().to_vec())
+                .unwrap();
+            keys.push(rand_str.as_bytes().to_vec());
+        }
+        trie.insert(b"te".to_vec(), b"te".to_vec()).unwrap();
+        trie.insert(b"test".to_vec(), b"test".to_vec()).unwrap();
+        keys.push(b"te".to_vec());
+        keys.push(b"test".to_vec());
+        keys.push(b"missing".to_vec());
+        keys.push(b"tes".to_vec());
+        keys.push(keys[0].clone());
+        keys.shuffle(&mut thread_rng());
+
+        let root = trie.root().unwrap();
+        let reopened = PatriciaTrie::from(memdb, &root).unwrap();
+        for trie in [&trie, &reopened] {
+            let queries = keys.iter().map(|k| k.as_slice()).collect::<Vec<_>>();
+            let values = trie.get_many(&queries).unwrap();
+            for (key, value) in keys.iter().zip(values) {
+                assert_eq!(trie.get(key).unwrap(), value);
+            }
+        }
+    }
+
+    #[test]
+    fn test_trie_from_missing_root() {
+        let memdb = MemoryDB::new(true);
+        let root = vec![0xab; 32];
+        let err = PatriciaTrie::from(memdb, &root).unwrap_err();
+        assert_eq!(err.clone(), TrieError::InvalidStateRoot(root));
+        assert_eq!(
+            err.to_string(),
+            format!("trie error: invalid state root 0x{}", "ab".repeat(32))
+        );
+    }
+
+    #[test]
+    fn test_trie_root_hash_and_dirty() {
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::new(memdb);
+        let empty_root = trie.root_hash().to_vec();
+        assert!(!trie.is_dirty());
+
+        trie.insert(b"test".to_vec(), b"test".to_vec()).unwrap();
+        assert!(trie.is_dirty());
+        assert_eq!(trie.root_hash(), empty_root.as_slice());
+
+        let root = trie.root().unwrap();
+        assert!(!trie.is_dirty());
+        assert_eq!(trie.root_hash(), root.as_slice());
+
+        assert!(!trie.remove(b"missing").unwrap());
+        assert!(!trie.is_dirty());
+        assert!(trie.remove(b"test").unwrap());
+        assert!(trie.is_dirty());
+        assert_eq!(trie.root_hash(), root.as_slice());
+    }
+
+    #[test]
+    fn test_trie_reachable_hashes() {
+        let memdb = MemoryDB::new(false);
+        let mut trie = PatriciaTrie::new(memdb.clone());
+        for i in 0..100u8 {
+            trie.insert(vec![i, i / 3], vec![i; 32]).unwrap();
+        }
+        let root = trie.root().unwrap();
+
+        // The proof of a key holds the root and every stored node on its path.
+        let mut expected = HashSet::new();
+        for i in 0..100u8 {
+            for node in trie.get_proof(&[i, i / 3]).unwrap() {
+                expected.insert(HasherKeccak::digest(&node));
+            }
+        }
+        let reachable = trie.reachable_hashes().unwrap();
+        assert!(reachable.contains(root.as_slice()));
+        assert_eq!(reachable, expected);
+
+        // Nodes of an older root that were replaced are not reachable from the new one.
+        trie.insert(vec![0, 0], b"changed".to_vec()).unwrap();
+        trie.root().unwrap();
+        let old = PatriciaTrie::from(memdb, &root).unwrap();
+        let stale = old
+            .reachable_hashes()
+            .unwrap()
+            .difference(&trie.reachable_hashes().unwrap())
+            .count();
+        assert!(stale > 0);
+        assert!(!trie.reachable_hashes().unwrap().contains(root.as_slice()));
+    }
+
+    #[test]
+    fn test_trie_subtree_refcounts() {
+        // The subtrees below the nibbles 1 and 2 hold the same keys and values.
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::new(memdb.clone());
+        for i in 0..16u8 {
+            trie.insert(vec![0x10, i], vec![i; 40]).unwrap();
+            trie.insert(vec![0x20, i], vec![i; 40]).unwrap();
+        }
+        trie.insert(vec![0x30], vec![3; 40]).unwrap();
+        let root = trie.root().unwrap();
+
+        let trie = PatriciaTrie::from(memdb, &root).unwrap();
+        let counts = trie.subtree_refcounts().unwrap();
+        let shared = HasherKeccak::digest(&trie.get_proof(&[0x10, 0]).unwrap()[1]);
+        assert_eq!(
+            shared,
+            HasherKeccak::digest(&trie.get_proof(&[0x20, 0]).unwrap()[1])
+        );
+        assert_eq!(counts[&shared], 2);
+        assert_eq!(counts.values().filter(|&&count| count > 1).count(), 1);
+        // The nodes below it are counted once, as the shared subtree is stored once.
+        let leaf = HasherKeccak::digest(trie.get_proof(&[0x10, 5]).unwrap().last().unwrap());
+        assert_eq!(counts[&leaf], 1);
+        assert_eq!(counts[&root[..]], 1);
+        assert_eq!(
+            counts.keys().cloned().collect::<HashSet<_>>(),
+            trie.reachable_hashes().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_trie_nibble_keys() {
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::new(memdb.clone());
+        trie.insert_nibbles(NibbleVec::from_hex(vec![1, 2, 3]), b"odd".to_vec())
+            .unwrap();
+        trie.insert_nibbles(NibbleVec::from_hex(vec![1, 2, 3, 4, 5]), b"longer".to_vec())
+            .unwrap();
+        trie.insert_nibbles(NibbleVec::from_hex(vec![1]), b"short".to_vec())
+            .unwrap();
+        trie.insert(vec![0x12, 0x34], b"bytes".to_vec()).unwrap();
+        let root = trie.root().unwrap();
+
+        // Odd length keys survive the compact encoding of stored nodes.
+        let trie = PatriciaTrie::from(memdb, &root).unwrap();
+        let get = |hex: Vec<u8>| trie.get_nibbles(&NibbleVec::from_hex(hex)).unwrap();
+        assert_eq!(get(vec![1, 2, 3]), Some(b"odd".to_vec()));
+        assert_eq!(get(vec![1, 2, 3, 16]), Some(b"odd".to_vec()));
+        assert_eq!(get(vec![1, 2, 3, 4, 5]), Some(b"longer".to_vec()));
+        assert_eq!(get(vec![1]), Some(b"short".to_vec()));
+        assert_eq!(get(vec![1, 2]), None);
+        assert_eq!(get(vec![1, 2, 3, 4]), Some(b"bytes".to_vec()));
+        assert_eq!(trie.get(&[0x12, 0x34]).unwrap(), Some(b"bytes".to_vec()));
+
+        assert_eq!(
+            trie.get_nibbles(&NibbleVec::from_hex(vec![1, 16, 2])),
+            Err(TrieError::InvalidData)
+        );
+    }
+
+    /// Counts the reads of every key, and the size of every batch inserted.
+    #[derive(Clone, Default)]
+    struct CountingDB {
+        db: MemoryDB,
+        reads: Arc<Mutex<HashMap<Vec<u8>, usize>>>,
+        batches: Arc<Mutex<Vec<usize>>>,
+    }
+
+    impl DB for CountingDB {
+        type Error = MemDBError;
+
+        fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+            *self.reads.lock().entry(key.to_vec()).or_default() += 1;
+            self.db.get(key)
+        }
+
+        fn contains(&self, key: &[u8]) -> Result<bool, Self::Error> {
+            self.db.contains(key)
+        }
+
+        fn insert(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+            self.db.insert(key, value)
+        }
+
+        fn remove(&self, key: &[u8]) -> Result<(), Self::Error> {
+            self.db.remove(key)
+        }
+
+        fn insert_batch<I>(&self, items: I) -> Result<(), Self::Error>
+        where
+            I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+        {
+            let items = items.into_iter().collect::<Vec<_>>();
+            self.batches.lock().push(items.len());
+            self.db.insert_batch(items)
+        }
+
+        fn flush(&self) -> Result<(), Self::Error> {
+            self.db.flush()
+        }
+
+        fn keys(&self) -> Result<Option<Vec<Vec<u8>>>, Self::Error> {
+            self.db.keys()
+        }
+    }
+
+    #[test]
+    fn test_trie_concurrent_get_expands_once() {
+        let db = CountingDB::default();
+        let mut trie = PatriciaTrie::new(db.clone());
+        let keys = (0..200u8)
+            .map(|i| vec![i, i / 7, i / 3])
+            .collect::<Vec<_>>();
+        for key in keys.iter() {
+            trie.insert(key.clone(), vec![key[0]; 32]).unwrap();
+        }
+        let root = trie.root().unwrap();
+
+        let trie = PatriciaTrie::from(db.clone(), &root).unwrap();
+        db.reads.lock().clear();
+        std::thread::scope(|s| {
+            for _ in 0..16 {
+                s.spawn(|| {
+                    for key in keys.iter() {
+                        assert_eq!(trie.get(key).unwrap(), Some(vec![key[0]; 32]));
+                    }
+                });
+            }
+        });
+
+        // Every hash node was expanded, the nodes expanded twice were dropped and only
+        // the cache holds the others.
+        assert!(db.reads.lock().len() > 200);
+        let cached_tries = trie.cached_tries.as_ref().unwrap().lock();
+        assert!(cached_tries.len() > 0);
+        assert!(cached_tries
+            .values()
+            .all(|trie| Arc::strong_count(trie) == 1));
+    }
+
+    #[test]
+    fn test_trie_shared_node_cache() {
+        let db = CountingDB::default();
+        let mut trie = PatriciaTrie::new(db.clone());
+        let keys = (0..200u8).map(|i| vec![i, i / 7]).collect::<Vec<_>>();
+        for key in keys.iter() {
+            trie.insert(key.clone(), vec![key[0]; 32]).unwrap();
+        }
+        let root = trie.root().unwrap();
+
+        let cache = NodeCache::new(64);
+        let open = |cache: Option<&NodeCache<CountingDB>>| {
+            let builder = PatriciaTrieBuilder::new().db(db.clone()).root(&root);
+            match cache {
+                Some(cache) => builder.node_cache(cache.clone()),
+                None => builder,
+            }
+            .build_from_root()
+            .unwrap()
+        };
+        let (a, b, c) = (open(Some(&cache)), open(Some(&cache)), open(None));
+        db.reads.lock().clear();
+        assert_eq!(a.get(&keys[42]).unwrap(), Some(vec![42; 32]));
+        assert!(!db.reads.lock().is_empty());
+
+        // The subtree expanded by `a` is a cache hit for `b`, not for `c`.
+        db.reads.lock().clear();
+        assert_eq!(b.get(&keys[42]).unwrap(), Some(vec![42; 32]));
+        assert!(db.reads.lock().is_empty());
+        assert_eq!(c.get(&keys[42]).unwrap(), Some(vec![42; 32]));
+        assert!(!db.reads.lock().is_empty());
+    }
+
+    #[test]
+    fn test_trie_from_witness() {
+        let mut trie = PatriciaTrie::new(MemoryDB::new(true));
+        for i in 0..100u8 {
+            trie.insert(vec![i], vec![i; 32]).unwrap();
+        }
+        let root = trie.root().unwrap();
+
+        // The witness proves every key touched by the transition.
+        let touched: [&[u8]; 3] = [&[0x20], &[0x05], &[0xf0]];
+        let mut witness = vec![];
+        for key in touched.iter() {
+            witness.extend(trie.get_proof(key).unwrap());
+        }
+
+        let mut stateless = PatriciaTrie::from_witness(&root, witness).unwrap();
+        for t in [&mut trie, &mut stateless] {
+            t.insert(vec![0x20], b"updated".to_vec()).unwrap();
+            assert!(t.remove(&[0x05]).unwrap());
+            t.insert(vec![0xf0], b"new".to_vec()).unwrap();
+        }
+        assert_eq!(stateless.root().unwrap(), trie.root().unwrap());
+        assert_eq!(stateless.get(&[0x20]).unwrap(), Some(b"updated".to_vec()));
+
+        let missing = stateless.get(&[0x42]);
+        assert!(matches!(missing, Err(TrieError::MissingNode(_))));
+        let missing = stateless.insert(vec![0x42, 0], b"value".to_vec());
+        assert!(matches!(missing, Err(TrieError::MissingNode(_))));
+        let missing = stateless.remove(&[0x42]);
+        assert!(matches!(missing, Err(TrieError::MissingNode(_))));
+        // A failed operation leaves the trie usable.
+        assert_eq!(stateless.get(&[0xf0]).unwrap(), Some(b"new".to_vec()));
+        assert_eq!(stateless.root().unwrap(), trie.root().unwrap());
+
+        assert!(matches!(
+            PatriciaTrie::from_witness(&[0; 32], vec![]),
+            Err(TrieError::InvalidStateRoot(_))
+        ));
+    }
+
+    #[test]
+    fn test_trie_iter_with_meta() {
+        let mut trie = PatriciaTrie::new(MemoryDB::new(true));
+        trie.insert(vec![0x10], vec![1; 32]).unwrap();
+        trie.insert(vec![0x20], vec![2; 32]).unwrap();
+        // Not committed yet, so no node has a known hash.
+        assert!(trie
+            .iter_with_meta()
+            .all(|(_, _, meta)| meta.parent_hash.is_none()));
+
+        let root = trie.root().unwrap();
+        let metas = trie
+            .iter_with_meta()
+            .map(|(_, _, meta)| meta)
+            .collect::<Vec<_>>();
+        let meta = LeafMeta {
+            depth: 1,
+            parent_hash: Some(root.as_slice().try_into().unwrap()),
+        };
+        assert_eq!(metas, vec![meta, meta]);
+
+        for i in 0..100u8 {
+            trie.insert(vec![i, i / 4, i / 16], vec![i; 32]).unwrap();
+        }
+        trie.root().unwrap();
+        let entries = trie.iter_with_meta().collect::<Vec<_>>();
+        assert!(entries
+            .iter()
+            .map(|(k, v, _)| (k.clone(), v.clone()))
+            .eq(trie.iter()));
+        for (key, _, meta) in entries.iter() {
+            assert!(meta.depth <= key.len() * 2);
+            // The parent of a stored node is on the proof path of its key.
+            if let Some(hash) = meta.parent_hash {
+                let proof = trie.get_proof(key).unwrap();
+                assert!(proof.iter().any(|node| HasherKeccak::digest(node) == hash));
+            }
+        }
+        assert!(entries.iter().any(|(_, _, meta)| meta.depth > 1));
+    }
+
+    #[test]
+    fn test_trie_commit_preview() {
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::new(memdb.clone());
+        for i in 0..100u8 {
+            trie.insert(vec![i, i / 5], vec![i; 32]).unwrap();
+        }
+        let preview = trie.commit_preview().unwrap();
+        assert!(!memdb.contains(&preview.root_hash).unwrap());
+        assert!(preview.pruned.is_empty());
+
+        assert_eq!(trie.root().unwrap(), preview.root_hash);
+        let stored = trie.reachable_hashes().unwrap();
+        assert_eq!(preview.new_nodes, stored.len());
+        let bytes = stored
+            .iter()
+            .map(|hash| memdb.get(hash).unwrap().unwrap().len())
+            .sum::<usize>();
+        assert_eq!(preview.new_bytes, bytes);
+
+        trie.insert(vec![7, 1], b"changed".to_vec()).unwrap();
+        let preview = trie.commit_preview().unwrap();
+        assert!(!preview.pruned.is_empty());
+        for hash in preview.pruned.iter() {
+            assert!(memdb.contains(hash).unwrap());
+        }
+        assert_eq!(trie.root().unwrap(), preview.root_hash);
+        for hash in preview.pruned.iter() {
+            assert!(!memdb.contains(hash).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_trie_commit_streaming() {
+        let db = CountingDB {
+            db: MemoryDB::new(true),
+            ..Default::default()
+        };
+        let mut trie = PatriciaTrie::new(db.clone());
+        let mut expected = PatriciaTrie::new(MemoryDB::new(true));
+        for i in 0..5000u32 {
+            let key = HasherKeccak::digest(&i.to_be_bytes())[..8].to_vec();
+            trie.insert(key.clone(), vec![i as u8; 40]).unwrap();
+            expected.insert(key, vec![i as u8; 40]).unwrap();
+        }
+        let root = trie.commit_streaming(256).unwrap();
+        assert_eq!(root, expected.root().unwrap());
+        let batches = db.batches.lock().drain(..).collect::<Vec<_>>();
+        // The final batch holds the root, the one before what was left below it.
+        assert!(batches.len() > 20);
+        assert!(batches.iter().all(|&len| len <= 256));
+        assert_eq!(batches[batches.len() - 1], 1);
+
+        // Replaced nodes are pruned as by `root`, none of the spilled ones.
+        for i in (0..5000u32).step_by(7) {
+            let key = HasherKeccak::digest(&i.to_be_bytes())[..8].to_vec();
+            trie.insert(key.clone(), b"new".to_vec()).unwrap();
+            expected.insert(key, b"new".to_vec()).unwrap();
+        }
+        trie.remove(&HasherKeccak::digest(&1u32.to_be_bytes())[..8])
+            .unwrap();
+        expected
+            .remove(&HasherKeccak::digest(&1u32.to_be_bytes())[..8])
+            .unwrap();
+        let root = trie.commit_streaming(100).unwrap();
+        assert_eq!(root, expected.root().unwrap());
+        assert!(db.batches.lock().iter().all(|&len| len <= 100));
+        let keys = |db: Option<Vec<Vec<u8>>>| db.unwrap().into_iter().collect::<HashSet<_>>();
+        assert_eq!(
+            keys(db.keys().unwrap()),
+            keys(expected.db().keys().unwrap())
+        );
+        let reopened = PatriciaTrie::from(db.clone(), &root).unwrap();
+        reopened.verify_integrity().unwrap();
+        assert_eq!(reopened.iter().count(), 4999);
+
+        // Deferred commits are not streamed.
+        trie.set_defer_commit(true);
+        trie.insert(vec![1], b"a".to_vec()).unwrap();
+        let root = trie.commit_streaming(1).unwrap();
+        assert_eq!(trie.get(&[1]).unwrap(), Some(b"a".to_vec()));
+        assert!(PatriciaTrie::from(db.db.clone(), &root).is_err());
+        let flushed = trie.flush_commit().unwrap();
+        assert_eq!(flushed, root);
+        assert!(PatriciaTrie::from(db.db, &root).is_ok());
+    }
+
+    #[test]
+    fn test_trie_commit_with_meta() {
+        let db = CountingDB {
+            db: MemoryDB::new(true),
+            ..Default::default()
+        };
+        let mut trie = PatriciaTrie::new(db.clone());
+        for i in 0..100u8 {
+            trie.insert(vec![i, i / 4], vec![i; 40]).unwrap();
+        }
+        let first = trie.commit_with_meta(b"block 1").unwrap();
+        let nodes = trie.reachable_hashes().unwrap().len();
+        trie.insert(vec![100, 1], b"new".to_vec()).unwrap();
+        let second = trie.commit_with_meta(b"block 2").unwrap();
+        // The metadata is written in the batch of the nodes, one per commit.
+        assert_eq!(db.batches.lock().len(), 2);
+        assert_eq!(db.batches.lock()[0], nodes + 1);
+        drop(trie);
+
+        assert_eq!(
+            PatriciaTrie::load_meta(&db, &first).unwrap(),
+            Some(b"block 1".to_vec())
+        );
+        assert_eq!(
+            PatriciaTrie::load_meta(&db, &second).unwrap(),
+            Some(b"block 2".to_vec())
+        );
+        let mut trie = PatriciaTrie::from(db.clone(), &second).unwrap();
+        trie.verify_integrity().unwrap();
+        assert_eq!(trie.get(&[100, 1]).unwrap(), Some(b"new".to_vec()));
+        assert_eq!(trie.iter().count(), 101);
+
+        // A plain commit stores none.
+        trie.insert(vec![101, 1], b"newer".to_vec()).unwrap();
+        let third = trie.root().unwrap();
+        assert_eq!(PatriciaTrie::load_meta(&db, &third).unwrap(), None);
+
+        // Committing the first root again replaces its metadata.
+        trie.remove(&[100, 1]).unwrap();
+        trie.remove(&[101, 1]).unwrap();
+        assert_eq!(trie.commit_with_meta(b"block 3").unwrap(), first);
+        assert_eq!(
+            PatriciaTrie::load_meta(&db, &first).unwrap(),
+            Some(b"block 3".to_vec())
+        );
+        assert_eq!(
+            PatriciaTrie::load_meta(&db, &HasherKeccak::digest(b"unknown")).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_trie_commit_if_changed() {
+        use crate::replay::{DBAccess, RecordingDB};
+
+        let db = RecordingDB::new(MemoryDB::new(true));
+        let mut trie = PatriciaTrie::new(db.clone());
+        assert_eq!(trie.commit_if_changed().unwrap(), None);
+        for i in 0..100u8 {
+            trie.insert(vec![i, i / 5], vec![i; 32]).unwrap();
+        }
+        let root = trie.compute_root();
+        assert_eq!(trie.commit_if_changed().unwrap(), Some(root.clone()));
+        assert!(!db.log().is_empty());
+
+        db.clear_log();
+        assert_eq!(trie.commit_if_changed().unwrap(), None);
+        // Changes which leave the root as it was.
+        trie.insert(vec![7, 1], vec![7; 32]).unwrap();
+        trie.insert(vec![200], b"temporary".to_vec()).unwrap();
+        trie.remove(&[200]).unwrap();
+        assert!(trie.is_dirty());
+        assert_eq!(trie.commit_if_changed().unwrap(), None);
+        assert!(!trie.is_dirty());
+        assert!(db
+            .log()
+            .iter()
+            .all(|access| matches!(access, DBAccess::Get(..))));
+        assert_eq!(trie.root_hash(), &root[..]);
+
+        trie.insert(vec![7, 1], b"changed".to_vec()).unwrap();
+        let changed = trie.commit_if_changed().unwrap().unwrap();
+        assert_ne!(changed, root);
+        let reopened = PatriciaTrie::from(db, &changed).unwrap();
+        reopened.verify_integrity().unwrap();
+        assert_eq!(reopened.get(&[7, 1]).unwrap(), Some(b"changed".to_vec()));
+    }
+
+    #[test]
+    fn test_trie_remove_prefix() {
+        let build = |keys: &[&[u8]]| {
+            let mut trie = PatriciaTrie::new(MemoryDB::new(true));
+            for key in keys {
+                trie.insert(key.to_vec(), [key, &[0; 32][..]].concat())
+                    .unwrap();
+            }
+            trie
+        };
+
+        // The prefix ends inside the extension below the root branch.
+        let mut trie = build(&[&[0xab, 0xcd, 0x01], &[0xab, 0xcd, 0x02], &[0x11]]);
+        assert_eq!(trie.remove_prefix(&[0xab]).unwrap(), 2);
+        assert_eq!(trie.root().unwrap(), build(&[&[0x11]]).root().unwrap());
+
+        // The prefix lands on the value slot of a branch.
+        let keys: [&[u8]; 4] = [&[0x12], &[0x12, 0x01], &[0x12, 0x02], &[0x13]];
+        let mut trie = build(&keys);
+        assert_eq!(trie.remove_prefix(&[0x12, 0x01]).unwrap(), 1);
+        assert_eq!(trie.remove_prefix(&[0x12, 0x01]).unwrap(), 0);
+        assert_eq!(trie.remove_prefix(&[0x12]).unwrap(), 2);
+        assert_eq!(trie.root().unwrap(), build(&[&[0x13]]).root().unwrap());
+        assert_eq!(trie.remove_prefix(&[]).unwrap(), 1);
+        assert_eq!(trie.root().unwrap(), build(&[]).root().unwrap());
+
+        // Removing committed subtrees prunes their nodes.
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::new(memdb.clone());
+        let mut expected = PatriciaTrie::new(MemoryDB::new(true));
+        for i in 0..200u8 {
+            let key = vec![i / 10, i];
+            trie.insert(key.clone(), vec![i; 32]).unwrap();
+            if i / 10 != 5 {
+                expected.insert(key, vec![i; 32]).unwrap();
+            }
+        }
+        let old_root = trie.root().unwrap();
+        let old_hashes = trie.reachable_hashes().unwrap();
+        assert_eq!(trie.remove_prefix(&[5]).unwrap(), 10);
+        let root = trie.root().unwrap();
+        assert_eq!(root, expected.root().unwrap());
+        // Like any commit, the old root itself is kept.
+        let live = trie.reachable_hashes().unwrap();
+        for hash in old_hashes.difference(&live) {
+            assert_eq!(memdb.contains(hash).unwrap(), hash == old_root.as_slice());
+        }
+
+        let trie = PatriciaTrie::from(memdb, &root).unwrap();
+        assert_eq!(trie.iter().count(), 190);
+        assert_eq!(trie.get(&[5, 50]).unwrap(), None);
+        assert_eq!(trie.get(&[6, 60]).unwrap(), Some(vec![60; 32]));
+    }
+
+    #[test]
+    fn test_trie_value_store() {
+        let memdb = MemoryDB::new(true);
+        let store = ValueStore { threshold: 64 };
+        let mut trie = PatriciaTrie::with_value_store(memdb.clone(), store);
+        let code = vec![0x60; 1000];
+        trie.insert(b"account1".to_vec(), code.clone()).unwrap();
+        trie.insert(b"account2".to_vec(), code.clone()).unwrap();
+        trie.insert(b"account3".to_vec(), b"small".to_vec())
+            .unwrap();
+        trie.insert(b"account".to_vec(), code.clone()).unwrap();
+
+        // The three copies are written once.
+        let preview = trie.commit_preview().unwrap();
+        assert!(preview.new_bytes < 2 * code.len());
+        let root = trie.root().unwrap();
+        assert_eq!(
+            memdb.get(&HasherKeccak::digest(&code)).unwrap(),
+            Some(code.clone())
+        );
+        // The proof ends with the referenced value, the nodes only hold its hash.
+        let mut proof = trie.get_proof(b"account1").unwrap();
+        assert_eq!(proof.pop(), Some(code.clone()));
+        for node in &proof {
+            assert!(node.len() < code.len());
+        }
+
+        let trie = PatriciaTrie::from_with_value_store(memdb.clone(), &root, store).unwrap();
+        assert_eq!(trie.get(b"account1").unwrap(), Some(code.clone()));
+        assert_eq!(trie.get(b"account2").unwrap(), Some(code.clone()));
+        assert_eq!(trie.get(b"account").unwrap(), Some(code.clone()));
+        assert_eq!(trie.get(b"account3").unwrap(), Some(b"small".to_vec()));
+        assert_eq!(trie.iter().count(), 4);
+
+        for (key, value) in &[
+            (&b"account1"[..], Some(code.clone())),
+            (b"account3", Some(b"small".to_vec())),
+            (b"account4", None),
+        ] {
+            let proof = trie.get_proof(key).unwrap();
+            assert_eq!(
+                trie.proof_size(key).unwrap(),
+                proof.iter().map(Vec::len).sum()
+            );
+            assert_eq!(trie.verify_proof(&root, key, proof).unwrap(), *value);
+            let (proven, proof) = trie.get_with_proof(key).unwrap();
+            assert_eq!(proven, *value);
+            assert_eq!(trie.verify_proof(&root, key, proof).unwrap(), *value);
+        }
+        // Without the value the proof is invalid.
+        let mut proof = trie.get_proof(b"account1").unwrap();
+        proof.pop();
+        assert_eq!(
+            trie.verify_proof(&root, b"account1", proof),
+            Err(TrieError::InvalidProof)
+        );
+
+        // The default encoding is unchanged.
+        let mut plain = PatriciaTrie::new(MemoryDB::new(true));
+        for (key, value) in trie.iter() {
+            plain.insert(key, value).unwrap();
+        }
+        assert_ne!(plain.root().unwrap(), root);
+        assert_eq!(plain.get(b"account1").unwrap(), Some(code));
+    }
+
+    #[test]
+    fn test_trie_db_and_clear_cache() {
+        let mut trie = PatriciaTrie::new(MemoryDB::new(true));
+        for i in 0..50u8 {
+            trie.insert(vec![i], vec![i; 32]).unwrap();
+        }
+        let root = trie.root().unwrap();
+        let leaf = trie.get_proof(&[7]).unwrap().pop().unwrap();
+
+        let other = PatriciaTrie::from(trie.db().clone(), &root).unwrap();
+        assert_eq!(other.get(&[7]).unwrap(), Some(vec![7; 32]));
+
+        // The expanded node is still cached after it was removed from the database.
+        assert_eq!(trie.get(&[7]).unwrap(), Some(vec![7; 32]));
+        trie.db_mut().remove(&HasherKeccak::digest(&leaf)).unwrap();
+        assert_eq!(trie.get(&[7]).unwrap(), Some(vec![7; 32]));
+
+        trie.clear_cache();
+        assert!(matches!(trie.get(&[7]), Err(TrieError::MissingNode(_))));
+    }
+
+    /// Extension nodes embedded `depth` times in each other.
+    fn nested_extensions(depth: usize) -> Vec<u8> {
+        let mut node = rlp::encode_list::<Vec<u8>, _>(&[vec![0x20], b"v".to_vec()]).to_vec();
+        for _ in 0..depth {
+            let mut stream = rlp::RlpStream::new_list(2);
+            stream.append(&vec![0x11u8]);
+            stream.append_raw(&node, 1);
+            node = stream.out().to_vec();
+        }
+        node
+    }
+
+    #[test]
+    fn test_trie_max_decode_depth() {
+        let memdb = MemoryDB::new(true);
+        let shallow = nested_extensions(10);
+        let deep = nested_extensions(10_000);
+        for node in [&shallow, &deep] {
+            memdb
+                .insert(HasherKeccak::digest(node).to_vec(), node.clone())
+                .unwrap();
+        }
+
+        let trie = PatriciaTrie::from(memdb.clone(), &HasherKeccak::digest(&shallow)).unwrap();
+        assert_eq!(trie.get(&[0x11; 5]).unwrap(), Some(b"v".to_vec()));
+
+        let deep_hash = HasherKeccak::digest(&deep);
+        assert!(matches!(
+            PatriciaTrie::from(memdb.clone(), &deep_hash),
+            Err(TrieError::InvalidData)
+        ));
+        assert_eq!(
+            trie.verify_proof(&deep_hash, &[0x11; 5000], vec![deep]),
+            Err(TrieError::InvalidProof)
+        );
+
+        // The limit applies to the nodes expanded later on.
+        let mut parent = rlp::RlpStream::new_list(2);
+        parent.append(&vec![0x00u8, 0x11]);
+        parent.append(&HasherKeccak::digest(&shallow).to_vec());
+        let parent = parent.out().to_vec();
+        memdb
+            .insert(HasherKeccak::digest(&parent).to_vec(), parent.clone())
+            .unwrap();
+        let mut trie = PatriciaTrie::from(memdb, &HasherKeccak::digest(&parent)).unwrap();
+        trie.set_max_decode_depth(5);
+        assert_eq!(trie.get(&[0x11; 6]), Err(TrieError::InvalidData));
+    }
+
+    #[test]
+    fn test_trie_builder() {
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrieBuilder::new().db(memdb.clone()).build();
+        for i in 0..20u8 {
+            trie.insert(vec![i], vec![i; 32]).unwrap();
+        }
+        let root = trie.root().unwrap();
+
+        let trie = PatriciaTrieBuilder::new()
+            .db(memdb.clone())
+            .strict_integrity(true)
+            .cache_capacity(1)
+            .root(&root)
+            .build_from_root()
+            .unwrap();
+        assert!(trie.strict);
+        assert_eq!(trie.get(&[3]).unwrap(), Some(vec![3; 32]));
+        assert_eq!(trie.cached_tries.as_ref().unwrap().lock().len(), 1);
+
+        let backup = MemoryDB::new(true);
+        let trie = PatriciaTrieBuilder::new()
+            .db(memdb.clone())
+            .backup_db(backup.clone())
+            .build();
+        assert!(trie.backup_db.is_some());
+
+        assert_eq!(
+            PatriciaTrieBuilder::new()
+                .db(memdb)
+                .root(&[0; 32])
+                .build_from_root()
+                .err(),
+            Some(TrieError::InvalidStateRoot(vec![0; 32]))
+        );
+    }
+
+    #[test]
+    fn test_trie_first_last() {
+        let mut trie = PatriciaTrie::new(MemoryDB::new(true));
+        assert_eq!(trie.first().unwrap(), None);
+        assert_eq!(trie.last().unwrap(), None);
+
+        // A key which is a prefix of the others sits in a branch value slot.
+        trie.insert(vec![0x12], b"a".to_vec()).unwrap();
+        trie.insert(vec![0x12, 0x34], b"b".to_vec()).unwrap();
+        assert_eq!(trie.first().unwrap(), Some((vec![0x12], b"a".to_vec())));
+        assert_eq!(
+            trie.last().unwrap(),
+            Some((vec![0x12, 0x34], b"b".to_vec()))
+        );
+
+        let mut rng = thread_rng();
+        for _ in 0..200 {
+            let key: Vec<u8> = (0..rng.gen_range(1, 5)).map(|_| rng.gen()).collect();
+            trie.insert(key, vec![7; 40]).unwrap();
+        }
+        let root = trie.root().unwrap();
+        let trie = PatriciaTrie::from(trie.db().clone(), &root).unwrap();
+        assert_eq!(trie.first().unwrap(), trie.iter().next());
+        assert_eq!(trie.last().unwrap(), trie.iter().last());
+    }
+
+    #[test]
+    fn test_trie_custom_empty_root() {
+        let memdb = MemoryDB::new(true);
+        let empty_root = [0; 32];
+        let mut trie = PatriciaTrie::new_with_empty_root(memdb.clone(), empty_root);
+        assert_eq!(trie.root_hash(), &empty_root);
+        assert_eq!(trie.root().unwrap(), empty_root.to_vec());
+        assert!(trie.verify_integrity().is_ok());
+        assert_eq!(trie.verify_proof(&empty_root, b"key", vec![]), Ok(None));
+
+        for db in [memdb.clone(), MemoryDB::new(true)] {
+            let trie = PatriciaTrieBuilder::new()
+                .db(db)
+                .empty_root(empty_root)
+                .root(&empty_root)
+                .build_from_root()
+                .unwrap();
+            assert_eq!(trie.iter().count(), 0);
+        }
+
+        // Removing the last key gets back to the empty root.
+        trie.insert(b"key".to_vec(), b"value".to_vec()).unwrap();
+        let root = trie.root().unwrap();
+        assert_ne!(root, empty_root.to_vec());
+        trie.remove(b"key").unwrap();
+        assert_eq!(trie.root().unwrap(), empty_root.to_vec());
+
+        // The default empty root is unchanged.
+        let mut trie = PatriciaTrie::new(MemoryDB::new(true));
+        let empty = HasherKeccak::digest(&rlp::NULL_RLP);
+        assert_eq!(trie.root().unwrap(), empty.to_vec());
+        assert!(PatriciaTrie::from(MemoryDB::new(true), &empty).is_ok());
+        assert_eq!(crate::verify_proof(&empty, b"key", vec![]), Ok(None));
+    }
+
+    #[test]
+    fn test_trie_iter_rev() {
+        let mut trie = PatriciaTrie::new(MemoryDB::new(true));
+        assert_eq!(trie.iter_rev().count(), 0);
+
+        let mut rng = thread_rng();
+        for _ in 0..300 {
+            let key: Vec<u8> = (0..rng.gen_range(1, 4)).map(|_| rng.gen()).collect();
+            trie.insert(key, vec![rng.gen(); rng.gen_range(1, 40)])
+                .unwrap();
+        }
+        trie.insert(vec![], b"empty key".to_vec()).unwrap();
+        let mut forward = trie.iter().collect::<Vec<_>>();
+        forward.reverse();
+        assert_eq!(trie.iter_rev().collect::<Vec<_>>(), forward);
+
+        let root = trie.root().unwrap();
+        let trie = PatriciaTrie::from(trie.db().clone(), &root).unwrap();
+        assert!(trie.iter_rev().eq(forward.into_iter()));
     }
 
     #[test]
-    fn test_trie_insert() {
+    fn test_trie_proof_cache() {
+        let db = CountingDB::default();
+        let mut trie = PatriciaTrie::new(db.clone()).with_proof_cache(2);
+        for i in 0..100u8 {
+            trie.insert(vec![i, i / 3], vec![i; 40]).unwrap();
+        }
+        let root = trie.root().unwrap();
+        let reads = |db: &CountingDB| db.reads.lock().values().sum::<usize>();
+
+        // A miss walks the path, a hit doesn't read the database.
+        let before = reads(&db);
+        let proof = trie.get_proof(&[7, 2]).unwrap();
+        let after_miss = reads(&db);
+        assert!(after_miss > before);
+        assert_eq!(trie.get_proof(&[7, 2]).unwrap(), proof);
+        assert_eq!(reads(&db), after_miss);
+        assert_eq!(trie.proof_cache.lock().len(), 1);
+
+        // Uncommitted changes bypass the cache.
+        trie.insert(vec![7, 2], b"new".to_vec()).unwrap();
+        let dirty_proof = trie.get_proof(&[7, 2]).unwrap();
+        assert_ne!(dirty_proof, proof);
+        assert_eq!(trie.proof_cache.lock().len(), 1);
+
+        // After the commit the proof of the new root is served.
+        let new_root = trie.root().unwrap();
+        assert_eq!(trie.proof_cache.lock().len(), 0);
+        let new_proof = trie.get_proof(&[7, 2]).unwrap();
+        assert_eq!(
+            trie.verify_proof(&new_root, &[7, 2], new_proof).unwrap(),
+            Some(b"new".to_vec())
+        );
+        assert!(trie.verify_proof(&root, &[7, 2], proof).is_ok());
+
+        let cached = PatriciaTrie::new(db.clone()).with_proof_cache(0);
+        cached.get_proof(&[1]).unwrap();
+        assert_eq!(cached.proof_cache.lock().len(), 0);
+    }
+
+    #[test]
+    fn test_trie_commit_subtree() {
+        let kv = (0..200u16)
+            .map(|i| {
+                (
+                    vec![(i % 4) as u8, i as u8, (i >> 8) as u8],
+                    vec![i as u8; 20],
+                )
+            })
+            .collect::<Vec<_>>();
+        let mut expected = PatriciaTrie::new(MemoryDB::new(true));
+        for (k, v) in kv.iter() {
+            expected.insert(k.clone(), v.clone()).unwrap();
+        }
+        let expected_root = expected.root().unwrap();
+
         let memdb = MemoryDB::new(true);
-        let mut trie = PatriciaTrie::new(memdb);
-        trie.insert(b"test".to_vec(), b"test".to_vec()).unwrap();
-        trie.insert(b"tswq".to_vec(), b"test2".to_vec()).unwrap();
+        let mut trie = PatriciaTrie::new(memdb.clone());
+        for (k, v) in kv.iter() {
+            trie.insert(k.clone(), v.clone()).unwrap();
+        }
+        let hash = trie.commit_subtree(&[1]).unwrap();
+        assert!(memdb.contains(&hash).unwrap());
+        assert!(trie.is_dirty());
+        assert_eq!(trie.commit_subtree(&[1]).unwrap(), hash);
+        assert_eq!(trie.commit_subtree(&[9]).unwrap(), trie.empty_root.to_vec());
+        // A prefix ending inside a key and a subtree below an already committed one.
+        trie.commit_subtree(&[2, 7]).unwrap();
+        trie.commit_subtree(&[1, 5]).unwrap();
+
+        for (k, v) in kv.iter() {
+            assert_eq!(trie.get(k).unwrap(), Some(v.clone()));
+        }
+        assert_eq!(trie.root().unwrap(), expected_root);
+
+        let trie = PatriciaTrie::from(memdb, &expected_root).unwrap();
+        assert_eq!(trie.iter().count(), kv.len());
     }
 
     #[test]
-    fn test_trie_get() {
+    fn test_trie_dump() {
         let memdb = MemoryDB::new(true);
-        let mut trie = PatriciaTrie::new(memdb);
-        trie.insert(b"test".to_vec(), b"test".to_vec()).unwrap();
-        let v = trie.get(b"test").unwrap();
+        let mut trie = PatriciaTrie::new(memdb.clone());
+        trie.insert(b"doe".to_vec(), b"reindeer".to_vec()).unwrap();
+        trie.insert(b"dog".to_vec(), b"puppy".to_vec()).unwrap();
+        trie.insert(b"dogglesworth".to_vec(), vec![7; 40]).unwrap();
+
+        let mut out = vec![];
+        trie.dump(&mut out).unwrap();
+        let uncommitted = String::from_utf8(out).unwrap();
+        assert!(uncommitted.starts_with(&format!(
+            "extension prefix=646f6 (hashed 0x{})",
+            hex::encode(trie.commit_preview().unwrap().root_hash)
+        )));
+        assert!(uncommitted.contains(
+            "leaf key=76c6573776f727468 value=0x07070707070707070707070707070707.. (40 bytes)"
+        ));
+        assert!(uncommitted.contains("(embedded)"));
+
+        let root = trie.root().unwrap();
+        let trie = PatriciaTrie::from(memdb, &root).unwrap();
+        let mut out = vec![];
+        trie.dump(&mut out).unwrap();
+        let committed = String::from_utf8(out).unwrap();
+        assert!(committed.starts_with(&format!(
+            "extension prefix=646f6 (hashed 0x{})",
+            hex::encode(&root)
+        )));
+        assert!(committed.contains("(stored 0x"));
+        assert_eq!(committed.replace("stored", "hashed"), uncommitted);
+    }
 
-        assert_eq!(Some(b"test".to_vec()), v)
+    #[test]
+    fn test_trie_insert_cost() {
+        let cost = |trie: &PatriciaTrie<MemoryDB>, key: &[u8]| {
+            let cost = trie.insert_cost(key).unwrap();
+            (cost.kind, cost.modified_nodes, cost.new_nodes)
+        };
+
+        let mut trie = PatriciaTrie::new(MemoryDB::new(true));
+        assert_eq!(cost(&trie, b"dog"), (InsertKind::NewLeaf, 0, 1));
+        trie.insert(b"dog".to_vec(), b"puppy".to_vec()).unwrap();
+        assert_eq!(cost(&trie, b"dog"), (InsertKind::Overwrite, 1, 0));
+        // Extension "do", branch and two leaves.
+        assert_eq!(cost(&trie, b"doe"), (InsertKind::SplitLeaf, 0, 4));
+        // "do" ends at the branch, only the old key gets a leaf.
+        assert_eq!(cost(&trie, b"do"), (InsertKind::SplitLeaf, 0, 3));
+
+        trie.insert(b"doe".to_vec(), b"reindeer".to_vec()).unwrap();
+        let root = trie.root().unwrap();
+        let trie = PatriciaTrie::from(trie.db().clone(), &root).unwrap();
+        // The extension and the branch are modified, the branch gets a new leaf.
+        assert_eq!(cost(&trie, b"dof"), (InsertKind::NewLeaf, 2, 1));
+        // The extension keeps "do", a branch takes its last nibble and a new leaf.
+        assert_eq!(cost(&trie, b"dot"), (InsertKind::SplitExtension, 1, 2));
+        // A branch at "d", with an extension for the rest of "do" and a new leaf.
+        assert_eq!(cost(&trie, b"dx"), (InsertKind::SplitExtension, 1, 3));
+        assert_eq!(cost(&trie, b"x"), (InsertKind::SplitExtension, 0, 3));
+        assert_eq!(cost(&trie, b"doe"), (InsertKind::Overwrite, 3, 0));
+        assert!(!trie.is_dirty());
     }
 
     #[test]
-    fn test_trie_random_insert() {
+    fn test_trie_range() {
+        let mut trie = PatriciaTrie::new(MemoryDB::new(true));
+        let mut map = std::collections::BTreeMap::new();
+        let mut rng = thread_rng();
+        for _ in 0..500 {
+            let key: Vec<u8> = (0..rng.gen_range(1, 4))
+                .map(|_| rng.gen_range(0, 64))
+                .collect();
+            map.insert(key.clone(), key.clone());
+            trie.insert(key.clone(), key).unwrap();
+        }
+        map.insert(vec![], b"empty".to_vec());
+        trie.insert(vec![], b"empty".to_vec()).unwrap();
+        let root = trie.root().unwrap();
+        let trie = PatriciaTrie::from(trie.db().clone(), &root).unwrap();
+
+        let assert_range = |start: &[u8], end: &[u8]| {
+            let expected = map
+                .range(start.to_vec()..end.to_vec().max(start.to_vec()))
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect::<Vec<_>>();
+            assert_eq!(trie.range(start, end).collect::<Vec<_>>(), expected);
+        };
+        // Bounds straddling the children at 0x1f / 0x20, and inside keys.
+        assert_range(&[0x1f], &[0x21]);
+        assert_range(&[0x1f, 0xff], &[0x20, 0x01]);
+        assert_range(&[0x10], &[0x30, 0x05]);
+        assert_range(&[], &[0x01]);
+        assert_range(&[], &[0xff]);
+        assert_range(&[0x3f, 0x3f, 0x3f, 0x00], &[0xff]);
+        assert_range(&[0x20], &[0x20]);
+        for _ in 0..200 {
+            let bound = |rng: &mut rand::rngs::ThreadRng| -> Vec<u8> {
+                (0..rng.gen_range(0, 4))
+                    .map(|_| rng.gen_range(0, 70))
+                    .collect()
+            };
+            let (start, end) = (bound(&mut rng), bound(&mut rng));
+            assert_range(&start, &end);
+        }
+    }
+
+    #[test]
+    fn test_trie_iter_chunked() {
         let memdb = MemoryDB::new(true);
-        let mut trie = PatriciaTrie::new(memdb);
+        let mut trie = PatriciaTrie::new(memdb.clone());
+        let mut expected = vec![];
+        for i in 0..200u8 {
+            let key = HasherKeccak::digest(&[i]).to_vec();
+            trie.insert(key.clone(), vec![i; 40]).unwrap();
+            expected.push((key, vec![i; 40]));
+        }
+        expected.sort();
+        let root = trie.root().unwrap();
+        let trie = PatriciaTrie::from(memdb.clone(), &root).unwrap();
+
+        for &max in [1, 7, 64, 200, 500].iter() {
+            let mut entries = vec![];
+            let mut resume_from = None;
+            loop {
+                let (chunk, next) = trie.iter_chunked(resume_from, max).unwrap();
+                assert!(chunk.len() <= max);
+                entries.extend(chunk);
+                match next {
+                    Some(key) => resume_from = Some(key),
+                    None => break,
+                }
+            }
+            assert_eq!(entries, expected);
+        }
 
-        for _ in 0..1000 {
-            let rand_str: String = thread_rng().sample_iter(&Alphanumeric).take(30).collect();
-            let val = rand_str.as_bytes();
-            trie.insert(val.to_vec(), val.to_vec()).unwrap();
+        let (chunk, next) = trie.iter_chunked(Some(vec![0xff; 33]), 10).unwrap();
+        assert!(chunk.is_empty());
+        assert_eq!(next, None);
+
+        let leaf = trie.get_proof(&expected[150].0).unwrap().pop().unwrap();
+        memdb.remove(&HasherKeccak::digest(&leaf)).unwrap();
+        let trie = PatriciaTrie::from(memdb, &root).unwrap();
+        let (chunk, _) = trie.iter_chunked(None, 100).unwrap();
+        assert_eq!(chunk, expected[..100]);
+        assert!(trie.iter_chunked(None, 200).is_err());
+        assert!(trie.iter_chunked(Some(expected[150].0.clone()), 1).is_err());
+    }
 
-            let v = trie.get(val).unwrap();
-            assert_eq!(v.map(|v| v.to_vec()), Some(val.to_vec()));
+    #[test]
+    fn test_trie_try_iter() {
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::new(memdb.clone());
+        let mut expected = vec![];
+        for i in 0..200u8 {
+            let key = HasherKeccak::digest(&[i]).to_vec();
+            trie.insert(key.clone(), vec![i; 40]).unwrap();
+            expected.push((key, vec![i; 40]));
         }
+        expected.sort();
+        let root = trie.root().unwrap();
+
+        let trie = PatriciaTrie::from(memdb.clone(), &root).unwrap();
+        let entries = trie.try_iter().collect::<TrieResult<Vec<_>>>().unwrap();
+        assert_eq!(entries, expected);
+
+        let leaf = trie.get_proof(&expected[150].0).unwrap().pop().unwrap();
+        let leaf_hash = HasherKeccak::digest(&leaf);
+        memdb.remove(&leaf_hash).unwrap();
+        let trie = PatriciaTrie::from(memdb, &root).unwrap();
+        // `iter` just stops at the missing node.
+        assert_eq!(trie.iter().count(), 150);
+        let mut entries = trie.try_iter().collect::<Vec<_>>();
+        assert_eq!(entries.len(), 151);
+        assert_eq!(
+            entries.pop().unwrap(),
+            Err(TrieError::MissingNode(leaf_hash.to_vec()))
+        );
+        assert!(entries
+            .into_iter()
+            .zip(expected.iter())
+            .all(|(entry, expected)| &entry.unwrap() == expected));
     }
 
     #[test]
-    fn test_trie_contains() {
+    fn test_trie_sorted_keys() {
+        let mut rng = thread_rng();
+        for _ in 0..50 {
+            let memdb = MemoryDB::new(true);
+            let mut trie = PatriciaTrie::new(memdb.clone());
+            let mut expected = BTreeSet::new();
+            // Short keys over few bytes, so that many are prefixes of others.
+            for _ in 0..rng.gen_range(0, 200) {
+                let key = (0..rng.gen_range(0, 4))
+                    .map(|_| [0x00, 0x01, 0x10, 0xff][rng.gen_range(0, 4)])
+                    .collect::<Vec<u8>>();
+                let value = vec![rng.gen(); rng.gen_range(1, 40)];
+                trie.insert(key.clone(), value).unwrap();
+                expected.insert(key);
+            }
+            let expected = expected.into_iter().collect::<Vec<_>>();
+            let root = trie.root().unwrap();
+            let reopened = PatriciaTrie::from(memdb, &root).unwrap();
+            for trie in [&trie, &reopened] {
+                let keys = trie.iter().map(|(key, _)| key).collect::<Vec<_>>();
+                assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+                assert_eq!(keys, expected);
+                assert_eq!(trie.sorted_keys().unwrap(), expected);
+            }
+        }
+
         let memdb = MemoryDB::new(true);
-        let mut trie = PatriciaTrie::new(memdb);
-        trie.insert(b"test".to_vec(), b"test".to_vec()).unwrap();
-        assert!(trie.contains(b"test").unwrap());
-        assert!(!trie.contains(b"test2").unwrap());
+        let mut trie = PatriciaTrie::new(memdb.clone());
+        for i in 0..100u8 {
+            trie.insert(vec![i, i / 3], vec![i; 40]).unwrap();
+        }
+        let root = trie.root().unwrap();
+        let leaf = trie.get_proof(&[60, 20]).unwrap().pop().unwrap();
+        memdb.remove(&HasherKeccak::digest(&leaf)).unwrap();
+        let trie = PatriciaTrie::from(memdb, &root).unwrap();
+        assert!(matches!(trie.sorted_keys(), Err(TrieError::MissingNode(_))));
     }
 
     #[test]
-    fn test_trie_remove() {
+    fn test_trie_contains_value() {
         let memdb = MemoryDB::new(true);
-        let mut trie = PatriciaTrie::new(memdb);
-        trie.insert(b"test".to_vec(), b"test".to_vec()).unwrap();
-        trie.insert(b"test2".to_vec(), b"test".to_vec()).unwrap();
-        let removed = trie.remove(b"test2").unwrap();
-        assert!(removed)
+        let mut trie = PatriciaTrie::new(memdb.clone());
+        assert!(!trie.contains_value(b"").unwrap());
+        for i in 0..100u8 {
+            trie.insert(vec![i, i / 3], vec![i; 40]).unwrap();
+        }
+        trie.insert(vec![], b"top".to_vec()).unwrap();
+        let root = trie.root().unwrap();
+
+        let trie = PatriciaTrie::from(memdb.clone(), &root).unwrap();
+        assert!(trie.contains_value(&[0; 40]).unwrap());
+        assert!(trie.contains_value(&[99; 40]).unwrap());
+        assert!(trie.contains_value(b"top").unwrap());
+        assert!(!trie.contains_value(&[99; 39]).unwrap());
+        assert!(!trie.contains_value(&[100; 40]).unwrap());
+
+        // A missing node is an error unless the value is found before it.
+        let leaf = trie.get_proof(&[60, 20]).unwrap().pop().unwrap();
+        memdb.remove(&HasherKeccak::digest(&leaf)).unwrap();
+        let trie = PatriciaTrie::from(memdb, &root).unwrap();
+        assert!(trie.contains_value(&[10; 40]).unwrap());
+        assert!(matches!(
+            trie.contains_value(&[100; 40]),
+            Err(TrieError::MissingNode(_))
+        ));
     }
 
     #[test]
-    fn test_trie_random_remove() {
+    fn test_trie_iter_stopped_early() {
         let memdb = MemoryDB::new(true);
-        let mut trie = PatriciaTrie::new(memdb);
+        let mut trie = PatriciaTrie::new(memdb.clone());
+        for i in 0..2000u16 {
+            trie.insert(i.to_be_bytes().to_vec(), vec![1; 40]).unwrap();
+        }
+        let root = trie.root().unwrap();
 
-        for _ in 0..1000 {
-            let rand_str: String = thread_rng().sample_iter(&Alphanumeric).take(30).collect();
-            let val = rand_str.as_bytes();
-            trie.insert(val.to_vec(), val.to_vec()).unwrap();
+        let mut other = PatriciaTrie::new(MemoryDB::new(true));
+        other.insert(vec![0, 0], vec![2; 40]).unwrap();
 
-            let removed = trie.remove(val).unwrap();
-            assert!(removed);
+        let trie = PatriciaTrie::from(memdb, &root).unwrap();
+        for _ in 0..100 {
+            // The nodes recovered by an iteration are freed when it is dropped.
+            let mut iter = trie.iter_from(&[3, 0]);
+            assert_eq!((&mut iter).take(10).count(), 10);
+            let recovered = iter.recovered_nodes.clone();
+            assert!(!recovered.borrow().is_empty());
+            drop(iter);
+            assert!(recovered.borrow().is_empty());
+
+            let (chunk, next) = trie.iter_chunked(None, 10).unwrap();
+            assert_eq!((chunk.len(), next), (10, Some(vec![0, 10])));
+            assert_eq!(trie.range(&[1, 0], &[1, 5]).count(), 5);
+            assert_eq!(trie.iter_limited(3).count(), 3);
+            assert_eq!(
+                trie.try_collect_limited(3),
+                Err(TrieError::TooManyEntries(3))
+            );
+            assert!(trie.contains_value(&[1; 40]).unwrap());
+            assert!(!trie.content_eq(&other).unwrap());
         }
     }
 
     #[test]
-    fn test_trie_from_root() {
+    fn test_trie_iter_limited() {
         let memdb = MemoryDB::new(true);
-        let root = {
-            let mut trie = PatriciaTrie::new(memdb.clone());
-            trie.insert(b"test".to_vec(), b"test".to_vec()).unwrap();
-            trie.insert(b"test1".to_vec(), b"test".to_vec()).unwrap();
-            trie.insert(b"test2".to_vec(), b"test".to_vec()).unwrap();
-            trie.insert(b"test23".to_vec(), b"test".to_vec()).unwrap();
-            trie.insert(b"test33".to_vec(), b"test".to_vec()).unwrap();
-            trie.insert(b"test44".to_vec(), b"test".to_vec()).unwrap();
-            trie.root().unwrap()
-        };
+        let mut trie = PatriciaTrie::new(memdb.clone());
+        assert_eq!(trie.try_collect_limited(0).unwrap(), vec![]);
+        let mut expected = vec![];
+        for i in 0..100u8 {
+            trie.insert(vec![i, i / 3], vec![i; 40]).unwrap();
+            expected.push((vec![i, i / 3], vec![i; 40]));
+        }
+        let root = trie.root().unwrap();
+        let trie = PatriciaTrie::from(memdb.clone(), &root).unwrap();
+
+        assert_eq!(trie.iter_limited(10).collect::<Vec<_>>(), expected[..10]);
+        assert_eq!(trie.iter_limited(1000).count(), 100);
+        assert_eq!(trie.iter_limited(0).count(), 0);
+
+        assert_eq!(trie.try_collect_limited(100).unwrap(), expected);
+        assert_eq!(trie.try_collect_limited(1000).unwrap(), expected);
+        assert_eq!(
+            trie.try_collect_limited(99),
+            Err(TrieError::TooManyEntries(99))
+        );
+        assert_eq!(
+            trie.try_collect_limited(0),
+            Err(TrieError::TooManyEntries(0))
+        );
+
+        // A missing node is an error rather than the end of the trie.
+        let leaf = trie.get_proof(&[60, 20]).unwrap().pop().unwrap();
+        memdb.remove(&HasherKeccak::digest(&leaf)).unwrap();
+        let trie = PatriciaTrie::from(memdb, &root).unwrap();
+        assert!(matches!(
+            trie.try_collect_limited(100),
+            Err(TrieError::MissingNode(_))
+        ));
+        // The limit is reached before the missing node.
+        assert_eq!(
+            trie.try_collect_limited(59),
+            Err(TrieError::TooManyEntries(59))
+        );
+    }
+
+    #[test]
+    fn test_trie_degenerate_deep() {
+        // Branches nested along the key, every deletion makes the parents degenerate.
+        let mut trie = PatriciaTrie::new(MemoryDB::new(true));
+        for i in 1..=64 {
+            trie.insert(vec![0; i], vec![i as u8]).unwrap();
+        }
+        trie.root().unwrap();
+        for i in 1..64 {
+            assert!(trie.remove(&vec![0; i]).unwrap());
+        }
+        let mut expected = PatriciaTrie::new(MemoryDB::new(true));
+        expected.insert(vec![0; 64], vec![64]).unwrap();
+        assert_eq!(trie.root().unwrap(), expected.root().unwrap());
+
+        // A branch over a long chain of stored extensions, merged once the branch is
+        // left with a single child.
+        let memdb = MemoryDB::new(true);
+        let mut child = rlp::encode_list::<Vec<u8>, _>(&[vec![0x3f], vec![0; 40]]).to_vec();
+        for _ in 0..500 {
+            let hash = HasherKeccak::digest(&child).to_vec();
+            memdb.insert(hash.clone(), child).unwrap();
+            let mut ext = rlp::RlpStream::new_list(2);
+            ext.append(&vec![0x11u8]).append(&hash);
+            child = ext.out().to_vec();
+        }
+        let hash = HasherKeccak::digest(&child).to_vec();
+        memdb.insert(hash.clone(), child).unwrap();
+        let mut branch = rlp::RlpStream::new_list(17);
+        for i in 0..16 {
+            match i {
+                1 => branch.append(&hash),
+                2 => branch.append_raw(&rlp::encode_list::<Vec<u8>, _>(&[vec![0x3f], vec![2]]), 1),
+                _ => branch.append_empty_data(),
+            };
+        }
+        branch.append_empty_data();
+        let root = branch.out().to_vec();
+        let root_hash = HasherKeccak::digest(&root).to_vec();
+        memdb.insert(root_hash.clone(), root).unwrap();
+
+        let mut trie = PatriciaTrie::from(memdb, &root_hash).unwrap();
+        let key = [vec![0x11; 250], vec![0x1f]].concat();
+        assert_eq!(trie.get(&key).unwrap(), Some(vec![0; 40]));
+        std::thread::Builder::new()
+            .stack_size(64 * 1024)
+            .spawn(move || {
+                assert!(trie.remove(&[0x2f]).unwrap());
+                let mut expected = PatriciaTrie::new(MemoryDB::new(true));
+                expected.insert(key.clone(), vec![0; 40]).unwrap();
+                assert_eq!(trie.root().unwrap(), expected.root().unwrap());
+                assert_eq!(trie.get(&key).unwrap(), Some(vec![0; 40]));
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_trie_commit_with_changeset() {
+        let mut trie = PatriciaTrie::new(MemoryDB::new(true));
+        for i in 0..100u8 {
+            trie.insert(vec![i, i / 4], vec![i; 40]).unwrap();
+        }
+        let (root, changeset) = trie.commit_with_changeset().unwrap();
+        assert!(changeset.removed.is_empty());
+        let replica = MemoryDB::new(true);
+        replica.insert_batch(changeset.inserted).unwrap();
+        let replica_trie = PatriciaTrie::from(replica.clone(), &root).unwrap();
+        assert_eq!(replica_trie.iter().count(), 100);
+        replica_trie.verify_integrity().unwrap();
+
+        for i in 0..10u8 {
+            trie.insert(vec![i, i / 4], b"new".to_vec()).unwrap();
+        }
+        trie.remove(&[50, 12]).unwrap();
+        let (root, changeset) = trie.commit_with_changeset().unwrap();
+        assert!(!changeset.removed.is_empty());
+        for hash in changeset.removed.iter() {
+            assert!(!trie.db().contains(hash).unwrap());
+        }
+        for (hash, node) in changeset.inserted.iter() {
+            assert_eq!(trie.db().get(hash).unwrap().as_ref(), Some(node));
+        }
+        replica.insert_batch(changeset.inserted).unwrap();
+        replica.remove_batch(&changeset.removed).unwrap();
+
+        let replica_trie = PatriciaTrie::from(replica, &root).unwrap();
+        assert_eq!(replica_trie.get(&[3, 0]).unwrap(), Some(b"new".to_vec()));
+        assert_eq!(replica_trie.get(&[50, 12]).unwrap(), None);
+        assert_eq!(replica_trie.iter().count(), 99);
+    }
+
+    #[test]
+    fn test_trie_insert_with() {
+        // Counters which are removed when they drop to zero.
+        fn add(delta: i64) -> impl FnOnce(Option<Vec<u8>>) -> Vec<u8> {
+            move |old| {
+                let old = old.map_or(0, |old| i64::from_be_bytes(old.try_into().unwrap()));
+                match old + delta {
+                    0 => vec![],
+                    sum => sum.to_be_bytes().to_vec(),
+                }
+            }
+        }
+
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::new(memdb.clone());
+        let mut sums = HashMap::new();
+        let mut rng = thread_rng();
+        for round in 0..3 {
+            for _ in 0..500 {
+                // Short keys so that some are prefixes of others, held by branches.
+                let key = (0..rng.gen_range(0, 3))
+                    .map(|_| rng.gen_range(0, 4))
+                    .collect::<Vec<u8>>();
+                let delta = rng.gen_range(-2, 3);
+                trie.insert_with(key.clone(), add(delta)).unwrap();
+                *sums.entry(key).or_insert(0) += delta;
+            }
+            sums.retain(|_, sum| *sum != 0);
+            let root = trie.root().unwrap();
+            // Also through the hash nodes of a reopened trie.
+            if round == 1 {
+                trie = PatriciaTrie::from(memdb.clone(), &root).unwrap();
+            }
+        }
+
+        let mut expected = PatriciaTrie::new(MemoryDB::new(true));
+        for (key, sum) in sums.iter() {
+            assert_eq!(trie.get(key).unwrap(), Some(sum.to_be_bytes().to_vec()));
+            expected
+                .insert(key.clone(), sum.to_be_bytes().to_vec())
+                .unwrap();
+        }
+        assert_eq!(trie.iter().count(), sums.len());
+        assert_eq!(trie.root().unwrap(), expected.root().unwrap());
+        PatriciaTrie::from(memdb.clone(), &trie.root().unwrap())
+            .unwrap()
+            .verify_integrity()
+            .unwrap();
 
+        // Nothing changes for an unchanged value or an empty one for a missing key,
+        // wherever the key leaves the trie.
+        let root = trie.root().unwrap();
         let mut trie = PatriciaTrie::from(memdb, &root).unwrap();
-        let v1 = trie.get(b"test33").unwrap();
-        assert_eq!(Some(b"test".to_vec()), v1);
-        let v2 = trie.get(b"test44").unwrap();
-        assert_eq!(Some(b"test".to_vec()), v2);
-        let root2 = trie.root().unwrap();
-        assert_eq!(hex::encode(root), hex::encode(root2));
+        let (key, sum) = sums.iter().next().unwrap();
+        trie.insert_with(key.clone(), |old| old.unwrap()).unwrap();
+        for missing in &[vec![9], vec![0, 9], vec![1, 2, 3, 4], vec![3; 5]] {
+            if !sums.contains_key(missing) {
+                trie.insert_with(missing.clone(), add(0)).unwrap();
+            }
+        }
+        assert!(!trie.is_dirty());
+        assert_eq!(trie.root().unwrap(), root);
+
+        // An empty value removes the key in the same descent.
+        trie.insert_with(key.clone(), add(-sum)).unwrap();
+        assert!(trie.is_dirty());
+        expected.remove(key).unwrap();
+        assert_eq!(trie.get(key).unwrap(), None);
+        assert_eq!(trie.root().unwrap(), expected.root().unwrap());
     }
 
     #[test]
-    fn test_trie_from_root_and_insert() {
+    fn test_trie_insert_returning() {
         let memdb = MemoryDB::new(true);
-        let root = {
-            let mut trie = PatriciaTrie::new(memdb.clone());
-            trie.insert(b"test".to_vec(), b"test".to_vec()).unwrap();
-            trie.insert(b"test1".to_vec(), b"test".to_vec()).unwrap();
-            trie.insert(b"test2".to_vec(), b"test".to_vec()).unwrap();
-            trie.insert(b"test23".to_vec(), b"test".to_vec()).unwrap();
-            trie.insert(b"test33".to_vec(), b"test".to_vec()).unwrap();
-            trie.insert(b"test44".to_vec(), b"test".to_vec()).unwrap();
-            trie.commit().unwrap()
-        };
+        let mut trie = PatriciaTrie::new(memdb.clone());
+        for i in 0..50u8 {
+            assert_eq!(
+                trie.insert_returning(vec![i, i / 4], vec![i; 40]).unwrap(),
+                None
+            );
+        }
+        // A key held by a branch, as a prefix of the others.
+        assert_eq!(
+            trie.insert_returning(vec![], b"top".to_vec()).unwrap(),
+            None
+        );
+        let root = trie.root().unwrap();
 
         let mut trie = PatriciaTrie::from(memdb, &root).unwrap();
-        trie.insert(b"test55".to_vec(), b"test55".to_vec()).unwrap();
-        trie.commit().unwrap();
-        let v = trie.get(b"test55").unwrap();
-        assert_eq!(Some(b"test55".to_vec()), v);
+        assert_eq!(
+            trie.insert_returning(vec![7, 1], b"new".to_vec()).unwrap(),
+            Some(vec![7; 40])
+        );
+        assert_eq!(
+            trie.insert_returning(vec![7, 1], b"newer".to_vec())
+                .unwrap(),
+            Some(b"new".to_vec())
+        );
+        assert_eq!(
+            trie.insert_returning(vec![], b"top2".to_vec()).unwrap(),
+            Some(b"top".to_vec())
+        );
+        assert_eq!(trie.get(&[7, 1]).unwrap(), Some(b"newer".to_vec()));
+        assert_eq!(trie.get(&[]).unwrap(), Some(b"top2".to_vec()));
+
+        // As with `insert`, an empty value removes the key.
+        assert_eq!(
+            trie.insert_returning(vec![8, 2], vec![]).unwrap(),
+            Some(vec![8; 40])
+        );
+        assert_eq!(trie.get(&[8, 2]).unwrap(), None);
+        assert_eq!(trie.insert_returning(vec![8, 2], vec![]).unwrap(), None);
+        assert_eq!(trie.iter().count(), 50);
     }
 
     #[test]
-    fn test_trie_from_root_and_delete() {
+    fn test_trie_key_too_long() {
+        let mut trie = PatriciaTrie::new(MemoryDB::new(true));
+        let long = vec![7; DEFAULT_MAX_KEY_LEN + 1];
+        assert_eq!(
+            trie.insert(long.clone(), b"v".to_vec()),
+            Err(TrieError::KeyTooLong(DEFAULT_MAX_KEY_LEN))
+        );
+        assert_eq!(
+            trie.get(&long),
+            Err(TrieError::KeyTooLong(DEFAULT_MAX_KEY_LEN))
+        );
+        assert!(!trie.is_dirty());
+        trie.insert(long[1..].to_vec(), b"v".to_vec()).unwrap();
+        assert_eq!(trie.get(&long[1..]).unwrap(), Some(b"v".to_vec()));
+
+        let mut trie = PatriciaTrieBuilder::new()
+            .db(MemoryDB::new(true))
+            .max_key_len(4)
+            .build();
+        trie.insert(vec![1; 4], b"v".to_vec()).unwrap();
+        let root = trie.root().unwrap();
+        assert_eq!(
+            trie.insert(vec![1; 5], b"v".to_vec()),
+            Err(TrieError::KeyTooLong(4))
+        );
+        let long: &[u8] = &[1; 5];
+        let err = Some(TrieError::KeyTooLong(4));
+        assert_eq!(trie.contains(long).err(), err);
+        assert_eq!(trie.remove(long).err(), err);
+        assert_eq!(trie.remove_value(long).err(), err);
+        assert_eq!(trie.with_value(long, |_| ()).err(), err);
+        assert_eq!(trie.get_with_depth(long).err(), err);
+        assert_eq!(trie.get_many(&[&[1; 4], long]).err(), err);
+        assert_eq!(trie.get_proof(long).err(), err);
+        assert_eq!(trie.get_with_proof(long).err(), err);
+        assert_eq!(trie.proof_size(long).err(), err);
+        assert_eq!(trie.node_encoding(long).err(), err);
+        assert_eq!(trie.get_compact_multiproof(&[long]).err(), err);
+        assert_eq!(trie.contains_prefix(long).err(), err);
+        assert_eq!(trie.subtree(long).err(), err);
+        assert_eq!(trie.longest_prefix(long).err(), err);
+        assert_eq!(trie.common_ancestor(&[1; 4], long).err(), err);
+        assert_eq!(trie.remove_prefix(long).err(), err);
+        assert_eq!(trie.remove_range(&[0], long).err(), err);
+        assert_eq!(trie.remove_batch(&[long]).err(), err);
+        assert_eq!(trie.insert_cost(long).err(), err);
+        assert_eq!(trie.iter_chunked(Some(long.to_vec()), 1).err(), err);
+        assert!(!trie.is_dirty());
+        assert_eq!(
+            trie.insert_with(vec![1; 5], |_| b"v".to_vec()),
+            Err(TrieError::KeyTooLong(4))
+        );
+        // No entry of a batch is inserted if one key is too long.
+        let sorted = vec![(vec![0; 4], b"v".to_vec()), (vec![2; 5], b"v".to_vec())];
+        assert_eq!(
+            trie.insert_many_sorted(&sorted),
+            Err(TrieError::KeyTooLong(4))
+        );
+        // Nibble keys are limited to twice as many nibbles.
+        let nibbles = NibbleVec::from_hex(vec![3; 9]);
+        assert_eq!(
+            trie.insert_nibbles(nibbles, b"v".to_vec()),
+            Err(TrieError::KeyTooLong(4))
+        );
+        trie.insert_nibbles(NibbleVec::from_hex(vec![3; 8]), b"v".to_vec())
+            .unwrap();
+        assert_eq!(trie.get(&[0x33; 4]).unwrap(), Some(b"v".to_vec()));
+        trie.remove(&[0x33; 4]).unwrap();
+        assert_eq!(trie.root().unwrap(), root);
+
+        // A prefixed copy is checked against its longest key.
+        let mut trie = PatriciaTrieBuilder::new()
+            .db(MemoryDB::new(true))
+            .max_key_len(4)
+            .build();
+        trie.insert(vec![1; 3], b"v".to_vec()).unwrap();
+        trie.insert(vec![2], b"v".to_vec()).unwrap();
+        let prefixed = trie.prefixed(&[9], MemoryDB::new(true)).unwrap();
+        assert_eq!(prefixed.get(&[9, 1, 1, 1]).unwrap(), Some(b"v".to_vec()));
+        assert_eq!(
+            trie.prefixed(&[9, 9], MemoryDB::new(true)).err(),
+            Some(TrieError::KeyTooLong(4))
+        );
+    }
+
+    #[test]
+    fn test_trie_get_or_insert_with() {
         let memdb = MemoryDB::new(true);
-        let root = {
+        let mut trie = PatriciaTrie::new(memdb.clone());
+        for i in 0..20u8 {
+            trie.insert(vec![i], vec![i; 40]).unwrap();
+        }
+        let root = trie.root().unwrap();
+        let mut trie = PatriciaTrie::from(memdb, &root).unwrap();
+
+        let calls = std::cell::Cell::new(0);
+        let f = || {
+            calls.set(calls.get() + 1);
+            b"default".to_vec()
+        };
+        assert_eq!(trie.get_or_insert_with(vec![7], f).unwrap(), vec![7; 40]);
+        assert_eq!(calls.get(), 0);
+        assert!(!trie.is_dirty());
+
+        assert_eq!(
+            trie.get_or_insert_with(vec![42], f).unwrap(),
+            b"default".to_vec()
+        );
+        assert_eq!(calls.get(), 1);
+        assert_eq!(trie.get(&[42]).unwrap(), Some(b"default".to_vec()));
+        assert_eq!(
+            trie.get_or_insert_with(vec![42], f).unwrap(),
+            b"default".to_vec()
+        );
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn test_trie_subtrie_from() {
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::new(memdb.clone());
+        for i in 0..100u8 {
+            trie.insert(vec![i, i / 4], vec![i; 40]).unwrap();
+        }
+        let root = trie.root().unwrap();
+
+        // The branch under the nibble 3 holds the keys 0x30..=0x3f.
+        let proof = trie.get_proof(&[0x35, 0x0d]).unwrap();
+        let child_hash = HasherKeccak::digest(&proof[1]);
+        let hashes = trie.reachable_hashes().unwrap();
+        assert!(hashes.contains(&child_hash));
+
+        let subtrie = PatriciaTrie::subtrie_from(memdb.clone(), &child_hash).unwrap();
+        assert_eq!(subtrie.root_hash(), child_hash);
+        assert_eq!(
+            subtrie
+                .get_nibbles(&NibbleVec::from_hex(vec![5, 0, 13]))
+                .unwrap(),
+            Some(vec![0x35; 40])
+        );
+        assert_eq!(subtrie.iter().count(), 16);
+        subtrie.verify_integrity().unwrap();
+        let opened = PatriciaTrie::from(memdb.clone(), &child_hash).unwrap();
+        assert_eq!(
+            opened
+                .get_nibbles(&NibbleVec::from_hex(vec![5, 0, 13]))
+                .unwrap(),
+            Some(vec![0x35; 40])
+        );
+
+        for hash in hashes.iter() {
+            let subtrie = PatriciaTrie::subtrie_from(memdb.clone(), hash).unwrap();
+            assert!(subtrie.iter().count() > 0);
+        }
+        assert_eq!(
+            PatriciaTrie::subtrie_from(memdb.clone(), &root)
+                .unwrap()
+                .iter()
+                .count(),
+            100
+        );
+
+        assert!(matches!(
+            PatriciaTrie::subtrie_from(memdb.clone(), &[0; 32]),
+            Err(TrieError::MissingNode(_))
+        ));
+        memdb.insert(vec![1; 32], proof[1].clone()).unwrap();
+        assert!(matches!(
+            PatriciaTrie::subtrie_from(memdb.clone(), &[1; 32]),
+            Err(TrieError::CorruptedNode(_))
+        ));
+        let empty = rlp::NULL_RLP.to_vec();
+        let empty_hash = HasherKeccak::digest(&empty);
+        memdb.insert(empty_hash.to_vec(), empty).unwrap();
+        assert!(matches!(
+            PatriciaTrie::subtrie_from(memdb, &empty_hash),
+            Err(TrieError::InvalidData)
+        ));
+    }
+
+    #[test]
+    fn test_trie_gc() {
+        let mut memdb = MemoryDB::new(true);
+        let mut roots = vec![];
+        let mut reachable = vec![];
+        for n in 0..4u8 {
+            // The tries share the nodes of the common entries.
             let mut trie = PatriciaTrie::new(memdb.clone());
-            trie.insert(b"test".to_vec(), b"test".to_vec()).unwrap();
-            trie.insert(b"test1".to_vec(), b"test".to_vec()).unwrap();
-            trie.insert(b"test2".to_vec(), b"test".to_vec()).unwrap();
-            trie.insert(b"test23".to_vec(), b"test".to_vec()).unwrap();
-            trie.insert(b"test33".to_vec(), b"test".to_vec()).unwrap();
-            trie.insert(b"test44".to_vec(), b"test".to_vec()).unwrap();
-            trie.commit().unwrap()
+            for i in 0..50u8 {
+                trie.insert(vec![i, i / 4], vec![i; 40]).unwrap();
+            }
+            for i in 0..20u8 {
+                trie.insert(vec![n, i], vec![n; 40]).unwrap();
+            }
+            roots.push(trie.root().unwrap());
+            reachable.push(trie.reachable_hashes().unwrap());
+        }
+        // Not a node, kept.
+        memdb.insert(b"meta".to_vec(), b"data".to_vec()).unwrap();
+
+        let live: HashSet<_> = reachable[..3].iter().flatten().cloned().collect();
+        let orphaned = reachable[3].difference(&live).count();
+        assert!(orphaned > 0);
+        let live_roots = roots[..3].iter().map(|r| r.as_slice()).collect::<Vec<_>>();
+        assert_eq!(PatriciaTrie::gc(&mut memdb, &live_roots).unwrap(), orphaned);
+        assert_eq!(memdb.keys().unwrap().unwrap().len(), live.len() + 1);
+
+        for root in roots[..3].iter() {
+            let trie = PatriciaTrie::from(memdb.clone(), root).unwrap();
+            trie.verify_integrity().unwrap();
+            // [n, 0] is in both sets of entries.
+            assert_eq!(trie.iter().count(), 50 + 20 - 1);
+        }
+        assert!(PatriciaTrie::from(memdb.clone(), &roots[3]).is_err());
+        assert_eq!(PatriciaTrie::gc(&mut memdb, &live_roots).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_trie_gc_value_store() {
+        let mut memdb = MemoryDB::new(true);
+        let store = ValueStore { threshold: 64 };
+        let shared = vec![0x60; 1000];
+        let mut roots = vec![];
+        for n in 0..2u8 {
+            let mut trie = PatriciaTrie::with_value_store(memdb.clone(), store);
+            for i in 0..20u8 {
+                trie.insert(vec![i], vec![i; 40]).unwrap();
+            }
+            trie.insert(b"shared".to_vec(), shared.clone()).unwrap();
+            trie.insert(b"own".to_vec(), vec![n; 1000]).unwrap();
+            roots.push(trie.root().unwrap());
+        }
+
+        let removed = PatriciaTrie::gc_with_value_store(&mut memdb, &[&roots[0]], store).unwrap();
+        assert!(removed > 1);
+        let trie = PatriciaTrie::from_with_value_store(memdb.clone(), &roots[0], store).unwrap();
+        assert_eq!(trie.get(b"shared").unwrap(), Some(shared.clone()));
+        assert_eq!(trie.get(b"own").unwrap(), Some(vec![0; 1000]));
+        assert_eq!(trie.iter().count(), 22);
+        // The value only the collected trie referred to is removed.
+        assert_eq!(memdb.get(&HasherKeccak::digest(&[1; 1000])).unwrap(), None);
+        assert_eq!(
+            PatriciaTrie::gc_with_value_store(&mut memdb, &[&roots[0]], store).unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_trie_compact() {
+        // Nothing is ever removed, so the replaced nodes pile up.
+        let memdb = MemoryDB::new(false);
+        let mut trie = PatriciaTrie::new(memdb.clone());
+        for round in 0..5u8 {
+            for i in 0..3000u16 {
+                let key = i.to_be_bytes().to_vec();
+                trie.insert(key, [&[round][..], &[0; 40]].concat()).unwrap();
+            }
+            trie.root().unwrap();
+        }
+        trie.remove(&[0, 7]).unwrap();
+        let expected = trie.iter().collect::<Vec<_>>();
+        let root = trie.compute_root();
+
+        let dest = MemoryDB::new(true);
+        assert_eq!(trie.compact(dest.clone()).unwrap(), root);
+        assert!(!trie.is_dirty());
+        let live = trie.reachable_hashes().unwrap();
+        assert_eq!(dest.keys().unwrap().unwrap().len(), live.len());
+        assert!(memdb.keys().unwrap().unwrap().len() > live.len() * 3);
+
+        let reopened = PatriciaTrie::from(dest.clone(), &root).unwrap();
+        reopened.verify_integrity().unwrap();
+        assert_eq!(reopened.iter().collect::<Vec<_>>(), expected);
+        // The trie now commits to the new database.
+        trie.insert(vec![0, 7], b"back".to_vec()).unwrap();
+        let root = trie.root().unwrap();
+        let reopened = PatriciaTrie::from(dest, &root).unwrap();
+        assert_eq!(reopened.get(&[0, 7]).unwrap(), Some(b"back".to_vec()));
+    }
+
+    #[test]
+    fn test_trie_content_eq() {
+        let mut a = PatriciaTrie::new(MemoryDB::new(true));
+        let mut b = PatriciaTrie::new(Arc::new(MemoryDB::new(true)));
+        for i in 0..100u8 {
+            a.insert(vec![i, i / 4], vec![i; 40]).unwrap();
+            b.insert(vec![99 - i, (99 - i) / 4], vec![99 - i; 40])
+                .unwrap();
+        }
+        b.insert(b"extra".to_vec(), b"value".to_vec()).unwrap();
+        assert!(!a.content_eq(&b).unwrap());
+        b.remove(b"extra").unwrap();
+        assert!(a.content_eq(&b).unwrap());
+
+        let root = a.root().unwrap();
+        assert_eq!(b.root().unwrap(), root);
+        let db = crate::RecordingDB::new(b.db().clone());
+        let b = PatriciaTrie::from(db.clone(), &root).unwrap();
+        db.clear_log();
+        assert!(a.content_eq(&b).unwrap());
+        assert!(db.log().is_empty());
+
+        a.insert(vec![7, 1], b"changed".to_vec()).unwrap();
+        assert!(!a.content_eq(&b).unwrap());
+        assert!(!db.log().is_empty());
+        a.insert(vec![7, 1], vec![7; 40]).unwrap();
+        assert!(a.content_eq(&b).unwrap());
+        a.remove(&[7, 1]).unwrap();
+        assert!(!b.content_eq(&a).unwrap());
+    }
+
+    #[test]
+    fn test_trie_to_writer_from_reader() {
+        let mut trie = PatriciaTrie::new(MemoryDB::new(true));
+        let mut blob = vec![];
+        trie.to_writer(&mut blob).unwrap();
+        let empty = PatriciaTrie::from_reader(&mut blob.as_slice()).unwrap();
+        assert_eq!(empty.root_hash(), trie.root_hash());
+        assert_eq!(empty.iter().count(), 0);
+
+        for i in 0..100u8 {
+            trie.insert(vec![i, i / 4], vec![i; 40]).unwrap();
+        }
+        let root = trie.root().unwrap();
+        // Changes after the commit are not written.
+        trie.insert(b"uncommitted".to_vec(), b"value".to_vec())
+            .unwrap();
+        let mut blob = vec![];
+        trie.to_writer(&mut blob).unwrap();
+        assert_eq!(&blob[..4], b"ctri");
+
+        let read = PatriciaTrie::from_reader(&mut blob.as_slice()).unwrap();
+        assert_eq!(read.root_hash(), root.as_slice());
+        assert_eq!(read.iter().count(), 100);
+        read.verify_integrity().unwrap();
+        let mut again = vec![];
+        read.to_writer(&mut again).unwrap();
+        assert_eq!(again, blob);
+
+        let mut corrupted = blob.clone();
+        let last = corrupted.len() - 1;
+        corrupted[last] ^= 1;
+        assert!(matches!(
+            PatriciaTrie::from_reader(&mut corrupted.as_slice()),
+            Err(TrieError::CorruptedNode(_))
+        ));
+        assert!(matches!(
+            PatriciaTrie::from_reader(&mut &blob[..blob.len() - 1]),
+            Err(TrieError::InvalidData)
+        ));
+        assert!(matches!(
+            PatriciaTrie::from_reader(&mut &b"ctrx"[..]),
+            Err(TrieError::Io(_))
+        ));
+        corrupted = blob.clone();
+        corrupted[4] = 2;
+        assert_eq!(
+            PatriciaTrie::from_reader(&mut corrupted.as_slice()).err(),
+            Some(TrieError::InvalidData)
+        );
+
+        // The nodes of a deferred commit are written before they are flushed.
+        let memdb = MemoryDB::new(true);
+        let mut deferred = PatriciaTrie::new(memdb.clone());
+        deferred.set_defer_commit(true);
+        for i in 0..100u8 {
+            deferred.insert(vec![i, i / 4], vec![i; 40]).unwrap();
+        }
+        assert_eq!(deferred.root().unwrap(), root);
+        assert_eq!(memdb.bytes_len(), 0);
+        let mut deferred_blob = vec![];
+        deferred.to_writer(&mut deferred_blob).unwrap();
+        assert_eq!(deferred_blob, blob);
+    }
+
+    #[test]
+    fn test_trie_with_value() {
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::new(memdb.clone());
+        for i in 0..50u8 {
+            trie.insert(vec![i, i / 4], vec![i; 4096]).unwrap();
+        }
+        trie.insert(vec![1], b"branch".to_vec()).unwrap();
+        let root = trie.root().unwrap();
+        let trie = PatriciaTrie::from(memdb, &root).unwrap();
+
+        for _ in 0..2 {
+            // The second round borrows from the cached nodes.
+            for i in 0..50u8 {
+                let sum = trie
+                    .with_value(&[i, i / 4], |value| {
+                        value.map(|v| v.iter().map(|&b| b as usize).sum::<usize>())
+                    })
+                    .unwrap();
+                assert_eq!(sum, Some(i as usize * 4096));
+            }
+        }
+        assert_eq!(
+            trie.with_value(&[1], |value| value.map(<[u8]>::to_vec))
+                .unwrap(),
+            Some(b"branch".to_vec())
+        );
+        assert!(trie.with_value(&[99], |value| value.is_none()).unwrap());
+    }
+
+    #[test]
+    fn test_trie_value_len() {
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::new(memdb.clone());
+        for i in 0..50u8 {
+            trie.insert(vec![i, i / 4], vec![i; i as usize * 20 + 1])
+                .unwrap();
+        }
+        trie.insert(vec![1], b"branch".to_vec()).unwrap();
+        let root = trie.root().unwrap();
+        let trie = PatriciaTrie::from(memdb, &root).unwrap();
+
+        for key in (0..50u8)
+            .map(|i| vec![i, i / 4])
+            .chain([vec![1], vec![99], vec![]])
+        {
+            let expected = trie.get(&key).unwrap().map(|value| value.len());
+            assert_eq!(trie.value_len(&key).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_trie_prefix_keys() {
+        // Every key is a byte prefix of the next, so values sit in branch value slots.
+        let keys: Vec<Vec<u8>> = vec![
+            vec![],
+            vec![0x12],
+            vec![0x12, 0x34],
+            vec![0x12, 0x34, 0x56],
+            vec![0x12, 0x35],
+            vec![0x13],
+        ];
+        let mut orders = vec![keys.clone(), keys.iter().rev().cloned().collect()];
+        let mut rng = thread_rng();
+        for _ in 0..20 {
+            let mut order = keys.clone();
+            order.shuffle(&mut rng);
+            orders.push(order);
+        }
+
+        let memdb = MemoryDB::new(true);
+        let mut root = None;
+        for order in orders.iter() {
+            let mut trie = PatriciaTrie::new(memdb.clone());
+            for key in order.iter() {
+                trie.insert(key.clone(), [b"v", &key[..]].concat()).unwrap();
+            }
+            for key in keys.iter() {
+                assert_eq!(trie.get(key).unwrap(), Some([b"v", &key[..]].concat()));
+            }
+            assert_eq!(trie.get(&[0x12, 0x34, 0x56, 0x78]).unwrap(), None);
+            assert_eq!(trie.get(&[0x12, 0x30]).unwrap(), None);
+            let hash = trie.root().unwrap();
+            assert_eq!(*root.get_or_insert(hash.clone()), hash);
+
+            let mut trie = PatriciaTrie::from(memdb.clone(), &hash).unwrap();
+            assert_eq!(
+                trie.iter().map(|(k, _)| k).collect::<Vec<_>>(),
+                keys.iter()
+                    .cloned()
+                    .collect::<std::collections::BTreeSet<_>>()
+                    .into_iter()
+                    .collect::<Vec<_>>()
+            );
+            for key in keys.iter() {
+                let proof = trie.get_proof(key).unwrap();
+                assert_eq!(
+                    trie.verify_proof(&hash, key, proof).unwrap(),
+                    Some([b"v", &key[..]].concat())
+                );
+            }
+
+            // Removing a prefix leaves the longer keys, and the other way around.
+            let (removed, kept) = order.split_at(3);
+            for key in removed {
+                assert!(trie.remove(key).unwrap());
+            }
+            for key in removed {
+                assert_eq!(trie.get(key).unwrap(), None);
+            }
+            let mut expected = PatriciaTrie::new(MemoryDB::new(true));
+            for key in kept {
+                assert_eq!(trie.get(key).unwrap(), Some([b"v", &key[..]].concat()));
+                expected
+                    .insert(key.clone(), trie.get(key).unwrap().unwrap())
+                    .unwrap();
+            }
+            assert_eq!(trie.root().unwrap(), expected.root().unwrap());
+        }
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_trie_par_iter() {
+        use rayon::iter::ParallelIterator;
+
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::new(memdb.clone());
+        let mut rng = thread_rng();
+        for _ in 0..2000 {
+            let key: Vec<u8> = (0..rng.gen_range(1, 6)).map(|_| rng.gen()).collect();
+            trie.insert(key, vec![rng.gen(); 40]).unwrap();
+        }
+        trie.insert(vec![], b"empty key".to_vec()).unwrap();
+
+        let assert_par_iter = |trie: &PatriciaTrie<MemoryDB>| {
+            let mut entries = trie.par_iter().collect::<Vec<_>>();
+            entries.sort();
+            assert_eq!(entries, trie.iter().collect::<Vec<_>>());
         };
+        assert_par_iter(&trie);
+        let root = trie.root().unwrap();
+        assert_par_iter(&PatriciaTrie::from(memdb.clone(), &root).unwrap());
+
+        // A stored branch below a common prefix.
+        let mut trie = PatriciaTrie::new(memdb);
+        for i in 0..200u8 {
+            trie.insert(vec![0xab, 0xcd, i], vec![i; 40]).unwrap();
+        }
+        let root = trie.root().unwrap();
+        let trie = PatriciaTrie::from(trie.db().clone(), &root).unwrap();
+        assert_eq!(trie.split_top().1.len(), 13);
+        assert_par_iter(&trie);
+        assert_par_iter(&PatriciaTrie::new(MemoryDB::new(true)));
+    }
 
+    #[test]
+    fn test_trie_remove_value() {
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::new(memdb.clone());
+        for i in 0..100u8 {
+            trie.insert(vec![i, i / 4], vec![i; 40]).unwrap();
+            trie.insert(vec![i], vec![i; 2]).unwrap();
+        }
+        let root = trie.root().unwrap();
         let mut trie = PatriciaTrie::from(memdb, &root).unwrap();
-        let value = trie.get(b"test").unwrap();
-        assert!(value.is_some());
-        let removed = trie.remove(b"test").unwrap();
-        assert!(removed);
-        let value_back = trie.get(b"test").unwrap();
-        assert!(value_back.is_none());
+
+        for key in [vec![42, 10], vec![42], vec![7], vec![7, 1]] {
+            let expected = trie.get(&key).unwrap();
+            assert!(expected.is_some());
+            assert_eq!(trie.remove_value(&key).unwrap(), expected);
+            assert_eq!(trie.get(&key).unwrap(), None);
+        }
+        assert_eq!(trie.remove_value(&[42]).unwrap(), None);
+        assert_eq!(trie.remove_value(&[200, 50]).unwrap(), None);
+        assert_eq!(trie.iter().count(), 196);
     }
 
     #[test]
-    fn test_trie_from_root_and_delete_2() {
-        let memdb = MemoryDB::new(true);
-        let root = {
-            let mut trie = PatriciaTrie::new(memdb.clone());
-            trie.insert(b"test".to_vec(), b"test".to_vec()).unwrap();
-            trie.insert(b"test1".to_vec(), b"test".to_vec()).unwrap();
-            trie.insert(b"test2".to_vec(), b"test".to_vec()).unwrap();
-            trie.insert(b"test23".to_vec(), b"test".to_vec()).unwrap();
-            trie.insert(b"test33".to_vec(), b"test".to_vec()).unwrap();
-            trie.insert(b"test44".to_vec(), b"test".to_vec()).unwrap();
-            trie.commit().unwrap()
+    fn test_trie_defer_commit() {
+        use crate::replay::{DBAccess, RecordingDB};
+
+        let inserts = |db: &RecordingDB<MemoryDB>| {
+            db.log()
+                .iter()
+                .filter(|access| matches!(access, DBAccess::Insert(..)))
+                .count()
         };
+        let mut rng = thread_rng();
+        let block = (0..50)
+            .map(|_| {
+                (
+                    rng.gen::<[u8; 4]>().to_vec(),
+                    rng.gen::<[u8; 16]>().to_vec(),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let db = RecordingDB::new(MemoryDB::new(true));
+        let mut trie = PatriciaTrie::new(db.clone());
+        let mut roots = vec![];
+        for (key, value) in block.iter() {
+            trie.insert(key.clone(), value.clone()).unwrap();
+            roots.push(trie.root().unwrap());
+        }
 
-        let mut trie = PatriciaTrie::from(memdb, &root).unwrap();
-        let removed = trie.remove(b"test44").unwrap();
-        assert!(removed);
-        let removed = trie.remove(b"test33").unwrap();
-        assert!(removed);
-        let removed = trie.remove(b"test23").unwrap();
-        assert!(removed);
+        let deferred_db = RecordingDB::new(MemoryDB::new(true));
+        let mut deferred = PatriciaTrie::new(deferred_db.clone());
+        deferred.set_defer_commit(true);
+        for ((key, value), root) in block.iter().zip(roots.iter()) {
+            deferred.insert(key.clone(), value.clone()).unwrap();
+            assert_eq!(&deferred.root().unwrap(), root);
+            assert_eq!(deferred.get(key).unwrap().as_ref(), Some(value));
+        }
+        assert!(!deferred.is_dirty());
+        assert_eq!(inserts(&deferred_db), 0);
+        assert_eq!(&deferred.flush_commit().unwrap(), roots.last().unwrap());
+        assert!(inserts(&deferred_db) < inserts(&db));
+        PatriciaTrie::from(deferred_db.clone(), roots.last().unwrap())
+            .unwrap()
+            .verify_integrity()
+            .unwrap();
+
+        // Nodes replaced within the next block are neither written nor left behind, only
+        // the previous root is kept as by every commit.
+        for (key, _) in block.iter().take(20) {
+            trie.insert(key.clone(), b"v2".to_vec()).unwrap();
+            trie.root().unwrap();
+            deferred.insert(key.clone(), b"v2".to_vec()).unwrap();
+            deferred.root().unwrap();
+        }
+        assert_eq!(deferred.flush_commit().unwrap(), trie.root().unwrap());
+        assert_eq!(
+            deferred_db.inner().keys().unwrap().unwrap().len(),
+            trie.reachable_hashes().unwrap().len() + 1
+        );
     }
 
     #[test]
-    fn test_multiple_trie_roots() {
-        let k0 = ethereum_types::H256::from_low_u64_le(0);
-        let k1 = ethereum_types::H256::from_low_u64_le(1);
-        let v = ethereum_types::H256::from_low_u64_le(0x1234);
+    fn test_trie_root_history() {
+        // The nodes of older roots are kept.
+        let memdb = MemoryDB::new(false);
+        let history = VecRootHistory::new();
+        let mut trie = PatriciaTrie::new(memdb.clone()).with_root_history(history.clone());
+        let (mut roots, mut eras) = (vec![], vec![]);
+        let mut entries = HashMap::new();
+        for era in 0..5u8 {
+            for i in (0..50u8).filter(|i| i % 5 <= era) {
+                trie.insert(vec![i, i / 3], vec![era; 40]).unwrap();
+                entries.insert(vec![i, i / 3], vec![era; 40]);
+            }
+            trie.remove(&[era * 10, era * 10 / 3]).unwrap();
+            entries.remove(&vec![era * 10, era * 10 / 3]);
+            roots.push(trie.root().unwrap());
+            eras.push(entries.clone());
+        }
+        // A commit without changes is recorded too.
+        roots.push(trie.root().unwrap());
+        assert_eq!(history.roots(), roots);
+        assert_eq!(roots[4], roots[5]);
+
+        for (root, entries) in roots.iter().zip(eras) {
+            let trie = PatriciaTrie::from(memdb.clone(), root).unwrap();
+            assert_eq!(trie.iter().collect::<HashMap<_, _>>(), entries);
+        }
 
-        let root1 = {
-            let memdb = MemoryDB::new(true);
-            let mut trie = PatriciaTrie::new(memdb);
-            trie.insert(k0.as_bytes().to_vec(), v.as_bytes().to_vec())
-                .unwrap();
-            trie.root().unwrap()
-        };
+        // Set on the builder, the history is kept across a compaction.
+        let history = VecRootHistory::new();
+        let mut trie = PatriciaTrieBuilder::new()
+            .db(memdb)
+            .root_history(history.clone())
+            .root(&roots[0])
+            .build_from_root()
+            .unwrap();
+        trie.insert(vec![1], b"a".to_vec()).unwrap();
+        let new_root = trie.compact(MemoryDB::new(true)).unwrap();
+        trie.compact(MemoryDB::new(true)).unwrap();
+        trie.insert(vec![2], b"b".to_vec()).unwrap();
+        let last_root = trie.root().unwrap();
+        assert_eq!(history.roots(), vec![new_root, last_root]);
+    }
 
-        let root2 = {
-            let memdb = MemoryDB::new(true);
-            let mut trie = PatriciaTrie::new(memdb);
-            trie.insert(k0.as_bytes().to_vec(), v.as_bytes().to_vec())
-                .unwrap();
-            trie.insert(k1.as_bytes().to_vec(), v.as_bytes().to_vec())
-                .unwrap();
-            trie.root().unwrap();
-            trie.remove(k1.as_ref()).unwrap();
-            trie.root().unwrap()
+    #[test]
+    fn test_trie_flush() {
+        use crate::replay::{DBAccess, RecordingDB};
+
+        let db = RecordingDB::new(MemoryDB::new(true));
+        let flushes = || {
+            db.log()
+                .iter()
+                .filter(|access| matches!(access, DBAccess::Flush))
+                .count()
         };
+        let mut trie = PatriciaTrie::new(db.clone());
+        for i in 0..50u8 {
+            trie.insert(vec![i], vec![i; 40]).unwrap();
+        }
+        trie.root().unwrap();
+        trie.insert(vec![7], b"v2".to_vec()).unwrap();
+        trie.remove(&[8]).unwrap();
+        let root = trie.root().unwrap();
+        let mut reopened = PatriciaTrie::from(db.clone(), &root).unwrap();
+        reopened.get(&[3]).unwrap();
+        reopened.insert(vec![3], b"v2".to_vec()).unwrap();
+        reopened.set_defer_commit(true);
+        reopened.root().unwrap();
+        reopened.flush_commit().unwrap();
+        assert_eq!(flushes(), 0);
+
+        trie.flush().unwrap();
+        assert_eq!(flushes(), 1);
+        assert_eq!(db.log().last(), Some(&DBAccess::Flush));
+    }
 
-        let root3 = {
-            let memdb = MemoryDB::new(true);
-            let mut trie1 = PatriciaTrie::new(memdb.clone());
-            trie1
-                .insert(k0.as_bytes().to_vec(), v.as_bytes().to_vec())
+    #[test]
+    fn test_trie_prefixed() {
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::new(memdb.clone());
+        let mut rng = thread_rng();
+        let mut keys = vec![];
+        for i in 0..300usize {
+            let key: Vec<u8> = (0..rng.gen_range(1, 6)).map(|_| rng.gen()).collect();
+            trie.insert(key.clone(), [&key[..], &[0; 40]].concat())
                 .unwrap();
-            trie1
-                .insert(k1.as_bytes().to_vec(), v.as_bytes().to_vec())
+            keys.push(key);
+            if i == 200 {
+                trie.root().unwrap();
+            }
+        }
+
+        let dest = MemoryDB::new(true);
+        let mut prefixed = trie.prefixed(&[0x01], dest.clone()).unwrap();
+        let mut expected = PatriciaTrie::new(MemoryDB::new(true));
+        for key in keys.iter() {
+            let prefixed_key = [&[0x01][..], key].concat();
+            assert_eq!(prefixed.get(&prefixed_key).unwrap(), trie.get(key).unwrap());
+            expected
+                .insert(prefixed_key, trie.get(key).unwrap().unwrap())
                 .unwrap();
-            trie1.root().unwrap();
-            let root = trie1.root().unwrap();
-            let mut trie2 = PatriciaTrie::from(memdb, &root).unwrap();
-            trie2.remove(k1.as_bytes()).unwrap();
-            trie2.root().unwrap()
-        };
+        }
+        assert_eq!(prefixed.get(&keys[0]).unwrap(), None);
+        let root = prefixed.root().unwrap();
+        assert_eq!(root, expected.root().unwrap());
+        let reopened = PatriciaTrie::from(dest, &root).unwrap();
+        reopened.verify_integrity().unwrap();
+        assert!(reopened.content_eq(&expected).unwrap());
+
+        // Leaf and extension roots absorb the prefix.
+        for keys in [vec![vec![2, 3]], vec![vec![2, 3], vec![2, 4]]] {
+            let mut trie = PatriciaTrie::new(MemoryDB::new(true));
+            let mut expected = PatriciaTrie::new(MemoryDB::new(true));
+            for key in keys {
+                trie.insert(key.clone(), vec![9; 40]).unwrap();
+                expected
+                    .insert([&[7, 7][..], &key].concat(), vec![9; 40])
+                    .unwrap();
+            }
+            let mut prefixed = trie.prefixed(&[7, 7], MemoryDB::new(true)).unwrap();
+            assert_eq!(prefixed.root().unwrap(), expected.root().unwrap());
+        }
+    }
 
-        assert_eq!(root1, root2);
-        assert_eq!(root2, root3);
+    #[test]
+    fn test_trie_allow_empty_values() {
+        let mut trie = PatriciaTrie::new(MemoryDB::new(true));
+        trie.insert(vec![1, 2], b"value".to_vec()).unwrap();
+        trie.insert(vec![1, 2], vec![]).unwrap();
+        assert_eq!(trie.get(&[1, 2]).unwrap(), None);
+        assert_eq!(trie.iter().count(), 0);
+
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrieBuilder::new()
+            .db(memdb.clone())
+            .allow_empty_values(true)
+            .build();
+        // An empty leaf value and an empty branch value.
+        trie.insert(vec![1, 2], vec![]).unwrap();
+        trie.insert(vec![1], vec![]).unwrap();
+        trie.insert(vec![1, 3], b"value".to_vec()).unwrap();
+        assert_eq!(trie.get(&[1, 2]).unwrap(), Some(vec![]));
+        assert_eq!(trie.get(&[1, 4]).unwrap(), None);
+        let root = trie.root().unwrap();
+
+        let trie = PatriciaTrieBuilder::new()
+            .db(memdb)
+            .allow_empty_values(true)
+            .root(&root)
+            .build_from_root()
+            .unwrap();
+        assert_eq!(trie.get(&[1]).unwrap(), Some(vec![]));
+        assert_eq!(trie.get(&[1, 2]).unwrap(), Some(vec![]));
+        assert!(trie.contains(&[1, 2]).unwrap());
+        for (key, value) in &[
+            (vec![1], vec![]),
+            (vec![1, 2], vec![]),
+            (vec![1, 3], b"value".to_vec()),
+        ] {
+            let proof = trie.get_proof(key).unwrap();
+            assert_eq!(
+                trie.verify_proof(&root, key, proof).unwrap(),
+                Some(value.clone())
+            );
+        }
+        let proof = trie.get_proof(&[1, 4]).unwrap();
+        assert_eq!(trie.verify_proof(&root, &[1, 4], proof).unwrap(), None);
+        assert_eq!(
+            trie.iter().collect::<Vec<_>>(),
+            vec![
+                (vec![1], vec![]),
+                (vec![1, 2], vec![]),
+                (vec![1, 3], b"value".to_vec())
+            ]
+        );
     }
 
     #[test]
-    fn test_delete_stale_keys_with_random_insert_and_delete() {
+    fn test_trie_insert_many_sorted() {
+        let mut rng = thread_rng();
+        let mut entries = (0..1000)
+            .map(|_| {
+                let key: Vec<u8> = (0..rng.gen_range(1, 5))
+                    .map(|_| rng.gen_range(0, 4))
+                    .collect();
+                // Distinct values, commits don't expect subtrees shared by content.
+                (key, rng.gen::<[u8; 32]>().to_vec())
+            })
+            .collect::<Vec<_>>();
+        entries.shuffle(&mut rng);
+        let (first, second) = entries.split_at(500);
+
+        let mut expected = PatriciaTrie::new(MemoryDB::new(true));
         let memdb = MemoryDB::new(true);
-        let mut trie = PatriciaTrie::new(memdb);
+        let mut trie = PatriciaTrie::new(memdb.clone());
+        for (key, value) in first {
+            expected.insert(key.clone(), value.clone()).unwrap();
+        }
+        let mut sorted = first.to_vec();
+        // A stable sort keeps the last duplicate last.
+        sorted.sort_by(|a, b| a.0.cmp(&b.0));
+        trie.insert_many_sorted(&sorted).unwrap();
+        assert_eq!(trie.root().unwrap(), expected.root().unwrap());
+
+        // Into stored nodes.
+        let root = trie.root().unwrap();
+        let mut trie = PatriciaTrie::from(memdb.clone(), &root).unwrap();
+        for (key, value) in second {
+            expected.insert(key.clone(), value.clone()).unwrap();
+        }
+        let mut sorted = second.to_vec();
+        sorted.sort_by(|a, b| a.0.cmp(&b.0));
+        trie.insert_many_sorted(&sorted).unwrap();
+        let root = trie.root().unwrap();
+        assert_eq!(root, expected.root().unwrap());
+        PatriciaTrie::from(memdb, &root)
+            .unwrap()
+            .verify_integrity()
+            .unwrap();
+    }
 
-        let mut rng = rand::thread_rng();
-        let mut keys = vec![];
-        for _ in 0..100 {
-            let random_bytes: Vec<u8> = (0..rng.gen_range(2, 30))
-                .map(|_| rand::random::<u8>())
-                .collect();
-            trie.insert(random_bytes.clone(), random_bytes.clone())
-                .unwrap();
-            keys.push(random_bytes.clone());
+    #[test]
+    fn test_trie_from_corrupted_root() {
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::new(memdb.clone());
+        for i in 0..20u8 {
+            trie.insert(vec![i], vec![i; 40]).unwrap();
         }
-        trie.commit().unwrap();
-        let slice = &mut keys;
-        slice.shuffle(&mut rng);
+        let root = trie.root().unwrap();
+        let mut node = memdb.get(&root).unwrap().unwrap();
+        *node.last_mut().unwrap() ^= 1;
+        memdb.insert(root.clone(), node).unwrap();
+
+        assert_eq!(
+            PatriciaTrie::from(memdb.clone(), &root).err(),
+            Some(TrieError::InvalidStateRoot(root.clone()))
+        );
+        memdb.insert(root.clone(), b"garbage".to_vec()).unwrap();
+        assert_eq!(
+            PatriciaTrie::from(memdb, &root).err(),
+            Some(TrieError::InvalidStateRoot(root))
+        );
+    }
 
-        for key in slice.iter() {
-            trie.remove(key).unwrap();
+    #[test]
+    fn test_trie_iter_map() {
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::new(memdb.clone());
+        for i in 0..100u8 {
+            trie.insert(vec![i, i / 4], vec![i; 40]).unwrap();
+            trie.insert(vec![i], vec![i; 3]).unwrap();
         }
-        trie.commit().unwrap();
+        let lens = |key: &[u8], value: &[u8]| (key.to_vec(), value.len());
+        let expected = trie
+            .iter()
+            .map(|(key, value)| lens(&key, &value))
+            .collect::<Vec<_>>();
+        assert_eq!(trie.iter_map(lens).collect::<Vec<_>>(), expected);
+
+        let root = trie.root().unwrap();
+        let trie = PatriciaTrie::from(memdb, &root).unwrap();
+        assert_eq!(trie.iter_map(lens).collect::<Vec<_>>(), expected);
+        // The closure may borrow from the caller.
+        let mut sum = 0;
+        trie.iter_map(|_, value| sum += value.len()).for_each(drop);
+        assert_eq!(sum, 100 * 43);
+    }
 
-        let empty_node_key = sha3::Keccak256::digest(rlp::NULL_RLP);
-        let value = trie.db.get(empty_node_key.as_ref()).unwrap().unwrap();
-        assert_eq!(value, &rlp::NULL_RLP)
+    #[test]
+    fn test_trie_remove_batch() {
+        let mut rng = thread_rng();
+        let keys = (0..1000)
+            .map(|_| {
+                (0..rng.gen_range(1, 5))
+                    .map(|_| rng.gen_range(0, 4))
+                    .collect::<Vec<u8>>()
+            })
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::new(memdb.clone());
+        for key in keys.iter() {
+            trie.insert(key.clone(), [&key[..], &[0; 32]].concat())
+                .unwrap();
+        }
+        let root = trie.root().unwrap();
+
+        for round in 0..2 {
+            // Absent keys and duplicates are ignored.
+            let mut removed = keys.choose_multiple(&mut rng, 200).collect::<Vec<_>>();
+            let absent = vec![9, 9];
+            removed.push(&absent);
+            removed.push(removed[0]);
+
+            let mut expected = PatriciaTrie::from(memdb.clone(), &root).unwrap();
+            for key in removed.iter() {
+                expected.remove(key).unwrap();
+            }
+            let mut trie = PatriciaTrie::from(memdb.clone(), &root).unwrap();
+            if round == 1 {
+                // Uncommitted nodes, the keys have at most 4 bytes.
+                trie.insert(vec![3; 5], vec![1; 40]).unwrap();
+                expected.insert(vec![3; 5], vec![1; 40]).unwrap();
+            }
+            let removed = removed.iter().map(|key| key.as_slice()).collect::<Vec<_>>();
+            assert_eq!(trie.remove_batch(&removed).unwrap(), 200);
+            // Not committed, the next round starts from the same root.
+            assert_eq!(trie.compute_root(), expected.compute_root());
+        }
     }
 
     #[test]
-    fn insert_full_branch() {
+    fn test_trie_get_with_proof() {
         let memdb = MemoryDB::new(true);
-        let mut trie = PatriciaTrie::new(memdb);
+        let mut trie = PatriciaTrie::new(memdb.clone());
+        for i in 0..200u8 {
+            trie.insert(vec![i, i / 3], vec![i; 40]).unwrap();
+        }
+        let check = |trie: &PatriciaTrie<MemoryDB>| {
+            for key in [vec![7, 2], vec![7, 3], vec![100, 33], vec![250, 1], vec![]] {
+                let (value, proof) = trie.get_with_proof(&key).unwrap();
+                assert_eq!(value, trie.get(&key).unwrap());
+                assert_eq!(proof, trie.get_proof(&key).unwrap());
+            }
+        };
+        // Uncommitted, then committed and reopened with every node stored.
+        check(&trie);
+        let root = trie.root().unwrap();
+        check(&trie);
+        let trie = PatriciaTrie::from(memdb, &root).unwrap();
+        check(&trie);
+        let (value, proof) = trie.get_with_proof(&[7, 2]).unwrap();
+        assert_eq!(value, Some(vec![7; 40]));
+        assert_eq!(trie.verify_proof(&root, &[7, 2], proof).unwrap(), value);
+
+        let empty = PatriciaTrie::new(MemoryDB::new(true));
+        assert_eq!(empty.get_with_proof(&[1]).unwrap(), (None, vec![]));
+
+        // `get_proof_kind` reads every node on the path once.
+        let db = CountingDB::default();
+        let mut trie = PatriciaTrie::new(db.clone());
+        for i in 0..200u8 {
+            trie.insert(vec![i, i / 3], vec![i; 40]).unwrap();
+        }
+        let root = trie.root().unwrap();
+        let trie = PatriciaTrie::from(db.clone(), &root).unwrap();
+        db.reads.lock().clear();
+        let (kind, proof) = trie.get_proof_kind(&[7, 2]).unwrap();
+        assert_eq!(kind, ProofKind::Inclusion(vec![7; 40]));
+        let reads = db.reads.lock();
+        assert_eq!(reads.len(), proof.len() - 1);
+        assert!(reads.values().all(|&count| count == 1));
+    }
 
-        trie.insert(b"test".to_vec(), b"test".to_vec()).unwrap();
-        trie.insert(b"test1".to_vec(), b"test".to_vec()).unwrap();
-        trie.insert(b"test2".to_vec(), b"test".to_vec()).unwrap();
-        trie.insert(b"test23".to_vec(), b"test".to_vec()).unwrap();
-        trie.insert(b"test33".to_vec(), b"test".to_vec()).unwrap();
-        trie.insert(b"test44".to_vec(), b"test".to_vec()).unwrap();
+    #[test]
+    fn test_trie_verify_value() {
+        let mut trie = PatriciaTrie::new(MemoryDB::new(true));
+        trie.insert(b"secret".to_vec(), b"hunter2".to_vec())
+            .unwrap();
+        trie.insert(b"short".to_vec(), vec![1]).unwrap();
         trie.root().unwrap();
 
-        let v = trie.get(b"test").unwrap();
-        assert_eq!(Some(b"test".to_vec()), v);
+        assert!(trie.verify_value(b"secret", b"hunter2").unwrap());
+        assert!(!trie.verify_value(b"secret", b"hunter3").unwrap());
+        assert!(!trie.verify_value(b"secret", b"hunter").unwrap());
+        assert!(!trie.verify_value(b"secret", b"hunter22").unwrap());
+        assert!(!trie.verify_value(b"secret", b"").unwrap());
+        assert!(trie.verify_value(b"short", &[1]).unwrap());
+        assert!(!trie.verify_value(b"missing", b"").unwrap());
+        assert!(!trie.verify_value(b"missing", b"hunter2").unwrap());
     }
 
     #[test]
-    fn iterator_trie() {
-        let memdb = MemoryDB::new(true);
-        let root1;
-        let mut kv = HashMap::new();
-        kv.insert(b"test".to_vec(), b"test".to_vec());
-        kv.insert(b"test1".to_vec(), b"test1".to_vec());
-        kv.insert(b"test11".to_vec(), b"test2".to_vec());
-        kv.insert(b"test14".to_vec(), b"test3".to_vec());
-        kv.insert(b"test16".to_vec(), b"test4".to_vec());
-        kv.insert(b"test18".to_vec(), b"test5".to_vec());
-        kv.insert(b"test2".to_vec(), b"test6".to_vec());
-        kv.insert(b"test23".to_vec(), b"test7".to_vec());
-        kv.insert(b"test9".to_vec(), b"test8".to_vec());
-        {
-            let mut trie = PatriciaTrie::new(memdb.clone());
-            let mut kv = kv.clone();
-            kv.iter().for_each(|(k, v)| {
-                trie.insert(k.clone(), v.clone()).unwrap();
-            });
-            root1 = trie.root().unwrap();
+    fn test_trie_u64_keys() {
+        let mut trie = PatriciaTrie::new(MemoryDB::new(true));
+        let keys = (0..300u64)
+            .map(|i| i * 1_000_003 + (i % 7) * (u64::MAX / 8))
+            .collect::<Vec<_>>();
+        for &key in &keys {
+            trie.insert_u64(key, key.to_le_bytes().to_vec()).unwrap();
+        }
+        trie.root().unwrap();
+        for &key in &keys {
+            assert_eq!(trie.get_u64(key).unwrap(), Some(key.to_le_bytes().to_vec()));
+            assert_eq!(
+                trie.get(&key.to_be_bytes()).unwrap(),
+                trie.get_u64(key).unwrap()
+            );
+        }
+        assert_eq!(trie.get_u64(1).unwrap(), None);
+
+        // Big-endian keys iterate in numeric order.
+        let mut sorted = keys.clone();
+        sorted.sort_unstable();
+        let entries = trie.iter_u64().collect::<TrieResult<Vec<_>>>().unwrap();
+        assert_eq!(
+            entries.iter().map(|(key, _)| *key).collect::<Vec<_>>(),
+            sorted
+        );
+
+        assert!(trie.remove_u64(keys[3]).unwrap());
+        assert!(!trie.remove_u64(keys[3]).unwrap());
+        assert_eq!(trie.get_u64(keys[3]).unwrap(), None);
+        assert_eq!(trie.iter_u64().count(), keys.len() - 1);
+
+        trie.insert(vec![1, 2, 3], vec![4]).unwrap();
+        assert!(trie
+            .iter_u64()
+            .any(|entry| entry == Err(TrieError::InvalidData)));
+    }
 
-            trie.iter()
-                .for_each(|(k, v)| assert_eq!(kv.remove(&k).unwrap(), v));
-            assert!(kv.is_empty());
+    #[test]
+    fn test_trie_iter_double_ended() {
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::new(memdb.clone());
+        let mut rng = rand::thread_rng();
+        for _ in 0..500 {
+            let len = rng.gen_range(1, 5);
+            let key = (0..len)
+                .map(|_| rng.gen_range(0, 4) * 40)
+                .collect::<Vec<u8>>();
+            trie.insert(key.clone(), [&key[..], &[0; 32]].concat())
+                .unwrap();
+        }
+        trie.insert(vec![], vec![9]).unwrap();
+        let root = trie.root().unwrap();
+
+        let trie = PatriciaTrie::from(memdb, &root).unwrap();
+        let forward = trie.iter().collect::<Vec<_>>();
+        let mut reversed = forward.clone();
+        reversed.reverse();
+        assert_eq!(trie.iter().rev().collect::<Vec<_>>(), reversed);
+        assert_eq!(trie.iter_rev().rev().collect::<Vec<_>>(), forward);
+
+        // Alternating ends meet in the middle without yielding an entry twice.
+        for step in 1..5 {
+            let mut iter = trie.iter();
+            let (mut front, mut back) = (vec![], vec![]);
+            let mut i = 0;
+            loop {
+                let next = if i % step == 0 {
+                    iter.next_back().map(|entry| back.push(entry))
+                } else {
+                    iter.next().map(|entry| front.push(entry))
+                };
+                if next.is_none() {
+                    break;
+                }
+                i += 1;
+            }
+            assert_eq!(iter.next(), None);
+            assert_eq!(iter.next_back(), None);
+            back.reverse();
+            front.extend(back);
+            assert_eq!(front, forward);
         }
 
-        {
-            let mut trie = PatriciaTrie::new(memdb.clone());
-            let mut kv2 = HashMap::new();
-            kv2.insert(b"test".to_vec(), b"test11".to_vec());
-            kv2.insert(b"test1".to_vec(), b"test12".to_vec());
-            kv2.insert(b"test14".to_vec(), b"test13".to_vec());
-            kv2.insert(b"test22".to_vec(), b"test14".to_vec());
-            kv2.insert(b"test9".to_vec(), b"test15".to_vec());
-            kv2.insert(b"test16".to_vec(), b"test16".to_vec());
-            kv2.insert(b"test2".to_vec(), b"test17".to_vec());
-            kv2.iter().for_each(|(k, v)| {
-                trie.insert(k.clone(), v.clone()).unwrap();
-            });
+        // The back end starts after the front one is done.
+        let mut iter = trie.iter();
+        assert_eq!(iter.by_ref().count(), forward.len());
+        assert_eq!(iter.next_back(), None);
+    }
 
-            trie.root().unwrap();
+    #[test]
+    fn test_trie_node_encoding() {
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::new(memdb.clone());
+        for i in 0..50u8 {
+            trie.insert(vec![i, 1], vec![i; 40]).unwrap();
+            // Short values in leaves short enough to be embedded.
+            trie.insert(vec![i, 2], vec![i]).unwrap();
+        }
+        trie.insert(vec![7], vec![7; 40]).unwrap();
+        let root = trie.root().unwrap();
+
+        let trie = PatriciaTrie::from(memdb.clone(), &root).unwrap();
+        for key in [vec![3, 1], vec![7], vec![49, 1]] {
+            let encoding = trie.node_encoding(&key).unwrap().unwrap();
+            assert!(encoding.len() >= RlpCodec::INLINE_THRESHOLD);
+            assert_eq!(
+                memdb.get(&HasherKeccak::digest(&encoding)).unwrap(),
+                Some(encoding.clone())
+            );
+            // The same node ends the proof.
+            assert_eq!(trie.get_proof(&key).unwrap().last(), Some(&encoding));
+        }
+        let embedded = trie.node_encoding(&[3, 2]).unwrap().unwrap();
+        assert!(embedded.len() < RlpCodec::INLINE_THRESHOLD);
+        assert!(!memdb.contains(&HasherKeccak::digest(&embedded)).unwrap());
 
-            let mut kv_delete = HashSet::new();
-            kv_delete.insert(b"test".to_vec());
-            kv_delete.insert(b"test1".to_vec());
-            kv_delete.insert(b"test14".to_vec());
+        assert_eq!(trie.node_encoding(&[3]).unwrap(), None);
+        assert_eq!(trie.node_encoding(&[3, 3]).unwrap(), None);
+        assert_eq!(trie.node_encoding(&[200, 1]).unwrap(), None);
+    }
 
-            kv_delete.iter().for_each(|k| {
-                trie.remove(k).unwrap();
-            });
+    #[test]
+    fn test_trie_prefetch() {
+        let db = CountingDB::default();
+        let mut trie = PatriciaTrie::new(db.clone());
+        let keys = (0..200u8).map(|i| vec![i, i / 7]).collect::<Vec<_>>();
+        for key in keys.iter() {
+            trie.insert(key.clone(), vec![key[0]; 32]).unwrap();
+        }
+        let root = trie.root().unwrap();
 
-            kv2.retain(|k, _| !kv_delete.contains(k));
+        let trie = PatriciaTrie::from(db.clone(), &root).unwrap();
+        let queries = keys
+            .iter()
+            .step_by(3)
+            .map(Vec::as_slice)
+            .collect::<Vec<_>>();
+        db.reads.lock().clear();
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| trie.prefetch(&queries).unwrap());
+            }
+        });
+        assert!(db.reads.lock().values().all(|&reads| reads == 1));
 
-            trie.root().unwrap();
-            trie.iter()
-                .for_each(|(k, v)| assert_eq!(kv2.remove(&k).unwrap(), v));
-            assert!(kv2.is_empty());
+        db.reads.lock().clear();
+        for key in queries {
+            assert_eq!(trie.get(key).unwrap(), Some(vec![key[0]; 32]));
         }
-
-        let trie = PatriciaTrie::from(memdb, &root1).unwrap();
-        trie.iter()
-            .for_each(|(k, v)| assert_eq!(kv.remove(&k).unwrap(), v));
-        assert!(kv.is_empty());
+        assert!(db.reads.lock().is_empty());
     }
 
     #[test]
-    fn test_extract_backup() {
+    fn test_trie_witness_recorder() {
         let memdb = MemoryDB::new(true);
-        let memdb2 = MemoryDB::new(true);
         let mut trie = PatriciaTrie::new(memdb.clone());
+        for i in 0..200u8 {
+            trie.insert(vec![i, i / 3], vec![i; 40]).unwrap();
+        }
+        let root = trie.root().unwrap();
 
-        trie.insert(b"test".to_vec(), b"test".to_vec()).unwrap();
-        trie.insert(b"test1".to_vec(), b"test".to_vec()).unwrap();
-        trie.insert(b"test2".to_vec(), b"test".to_vec()).unwrap();
-        trie.insert(b"test23".to_vec(), b"test".to_vec()).unwrap();
-        trie.insert(b"test33".to_vec(), b"test".to_vec()).unwrap();
-        trie.insert(b"test44".to_vec(), b"test".to_vec()).unwrap();
-        let hash = trie.root().unwrap();
+        let touched = [vec![5, 1], vec![99, 33], vec![180, 60], vec![181, 1]];
+        let mut trie = PatriciaTrie::from(memdb.clone(), &root)
+            .unwrap()
+            .with_witness_recorder();
+        let values = touched
+            .iter()
+            .map(|key| trie.get(key).unwrap())
+            .collect::<Vec<_>>();
+        trie.insert(vec![42, 1], vec![1; 40]).unwrap();
+        trie.remove(&[43, 14]).unwrap();
+        let new_root = trie.root().unwrap();
+        let witness = trie.take_witness();
+        assert!(trie.take_witness().is_empty());
+        assert!(witness.len() < memdb.keys().unwrap().unwrap().len());
+
+        // The witness alone replays the same operations.
+        let mut stateless = PatriciaTrie::from_witness(&root, witness).unwrap();
+        for (key, value) in touched.iter().zip(values) {
+            assert_eq!(stateless.get(key).unwrap(), value);
+        }
+        stateless.insert(vec![42, 1], vec![1; 40]).unwrap();
+        stateless.remove(&[43, 14]).unwrap();
+        assert_eq!(stateless.root().unwrap(), new_root);
+        assert!(stateless.get(&[7, 2]).is_err());
+
+        // Recorded from the builder, the root node is read and recorded as well.
+        let recorder = WitnessRecorder::new();
+        let trie = PatriciaTrieBuilder::new()
+            .db(memdb)
+            .root(&root)
+            .witness_recorder(recorder.clone())
+            .build_from_root()
+            .unwrap();
+        trie.get(&[5, 1]).unwrap();
+        let mut proof = trie.get_proof(&[5, 1]).unwrap();
+        proof.sort_by_key(|node| HasherKeccak::digest(node));
+        assert_eq!(recorder.take(), proof);
+    }
 
-        assert!(PatriciaTrie::extract_backup(memdb, memdb2, &hash).is_ok());
+    #[test]
+    fn test_trie_remove_range() {
+        let mut rng = thread_rng();
+        let keys = (0..1000)
+            .map(|_| {
+                (0..rng.gen_range(1, 5))
+                    .map(|_| rng.gen_range(0, 4) * 0x3f)
+                    .collect::<Vec<u8>>()
+            })
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        let memdb = MemoryDB::new(true);
+        let mut trie = PatriciaTrie::new(memdb.clone());
+        for key in keys.iter() {
+            trie.insert(key.clone(), [&key[..], &[0; 32]].concat())
+                .unwrap();
+        }
+        let root = trie.root().unwrap();
+
+        let ranges: [(&[u8], &[u8]); 6] = [
+            (&[0x3f], &[0x7e, 0x3f]),
+            (&[0x3f, 0x3f], &[0x3f, 0x3f, 0x3f]),
+            (&[], &[0x3f]),
+            (&[0xbd, 0x00], &[0xff]),
+            (&[0x10], &[0x20]),
+            (&[0x7e], &[0x3f]),
+        ];
+        for (start, end) in ranges.iter() {
+            let in_range = |key: &Vec<u8>| key.as_slice() >= *start && key.as_slice() < *end;
+
+            let mut expected = PatriciaTrie::from(memdb.clone(), &root).unwrap();
+            for key in keys.iter().filter(|key| in_range(key)) {
+                expected.remove(key).unwrap();
+            }
+            let mut trie = PatriciaTrie::from(memdb.clone(), &root).unwrap();
+            let removed = trie.remove_range(start, end).unwrap();
+            assert_eq!(removed, keys.iter().filter(|key| in_range(key)).count());
+            assert_eq!(trie.is_dirty(), removed > 0);
+            assert_eq!(trie.compute_root(), expected.compute_root());
+
+            let mut surviving = keys
+                .iter()
+                .filter(|key| !in_range(key))
+                .cloned()
+                .collect::<Vec<_>>();
+            surviving.sort();
+            assert_eq!(
+                trie.iter().map(|(key, _)| key).collect::<Vec<_>>(),
+                surviving
+            );
+        }
     }
 }